@@ -1,20 +1,269 @@
-use http::{MediaType, ResponseContentType, HTTP_CLIENT};
+use std::{
+    cell::Cell,
+    io::{self, Read},
+};
+
+use http::{
+    sniff_is_html, BodyTooLarge, Charset, ClientConfig, HttpFetcher, LimitedBody, MediaType,
+    ResponseContentType, CLIENT_CONFIG, HTTP_CLIENT, RESOURCE_HEADER_LEN,
+};
 
 // #[tokio::main]
 fn main() {
-    let res = HTTP_CLIENT
-        .get("http://127.0.0.1:3000")
-        .send()
-        .expect("Could not send request");
+    // Log what the parser does (the encoding it picks, and every token)
+    tracing_subscriber::fmt()
+        .with_max_level(tracing_subscriber::filter::LevelFilter::TRACE)
+        .init();
+
+    match load_document(&*HTTP_CLIENT, &CLIENT_CONFIG, "http://127.0.0.1:3000") {
+        Ok(_) => {}
+        Err(LoadError::NotHtml) => println!("Response is not an HTML document"),
+        Err(LoadError::BodyTooLarge(error)) => println!("Could not load document: {error}"),
+        Err(LoadError::Parse(error)) => println!("Could not parse document: {error:?}"),
+    }
+}
+
+/// Why [`load_document`] didn't produce a document
+#[derive(Debug)]
+enum LoadError {
+    /// The response isn't HTML, so nothing was parsed
+    NotHtml,
+    /// The response body is longer than [`ClientConfig::max_body_bytes`], so reading it stopped
+    /// at the limit
+    BodyTooLarge(BodyTooLarge),
+    Parse(html::HtmlParseError),
+}
+
+/// Ends a [`LimitedBody`] where it goes past its limit, since the parser can't recover from a
+/// failed read, and keeps the error to be reported once parsing is done
+struct CutOffBody<'a, R> {
+    body: LimitedBody<R>,
+    too_large: &'a Cell<Option<BodyTooLarge>>,
+}
+
+impl<R: Read> Read for CutOffBody<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.body.read(buf) {
+            Err(error)
+                if error
+                    .get_ref()
+                    .is_some_and(|error| error.is::<BodyTooLarge>()) =>
+            {
+                let too_large = error.into_inner().unwrap().downcast().unwrap();
+                self.too_large.set(Some(*too_large));
+
+                Ok(0)
+            }
+            result => result,
+        }
+    }
+}
+
+/// Fetches the HTML document at `url`, sniffs its encoding (starting with the `charset` of its
+/// `Content-Type`, if any) and parses it. Fails without parsing anything if the response isn't
+/// HTML, and reads no more of the body than `config` allows.
+fn load_document(
+    fetcher: &impl HttpFetcher,
+    config: &ClientConfig,
+    url: &str,
+) -> Result<html::Document, LoadError> {
+    let res = fetcher.get(url).expect("Could not send request");
 
     println!("Status: {}", res.status());
     println!("Headers: {:?}", res.headers());
 
     assert_eq!(res.status(), http::StatusCode::OK);
-    assert_eq!(
-        *res.content_type().unwrap().media_type(),
-        MediaType::TextHTML
-    );
 
-    let _ = html::HtmlParser::new(res).try_parse();
+    let content_type = res.content_type();
+    let media_type = content_type
+        .as_ref()
+        .map(|content_type| content_type.media_type().clone())
+        .unwrap_or_default();
+    let transport_encoding = content_type
+        .as_ref()
+        .and_then(|content_type| content_type.charset())
+        .and_then(|charset| match charset {
+            Charset::UTF8 => Some(html::CharacterEncoding::Utf8),
+            Charset::Other(label) => label.parse().ok(),
+        });
+
+    let nosniff = res.is_nosniff();
+    let content_length = res.content_length();
+
+    let too_large = Cell::new(None);
+    let mut body = CutOffBody {
+        body: config.limit_body(res),
+        too_large: &too_large,
+    };
+
+    // Sniffing only looks at the start of the body, the rest is parsed as it arrives
+    let mut header = Vec::with_capacity(RESOURCE_HEADER_LEN);
+    (&mut body)
+        .take(RESOURCE_HEADER_LEN as u64)
+        .read_to_end(&mut header)
+        .expect("Could not read response body");
+
+    // Servers often label HTML as a generic binary type, so sniff the body in that case, unless
+    // the server asked for its declared type to be honored
+    let is_html = match media_type {
+        MediaType::TextHTML => true,
+        MediaType::ApplicationOctetStream => !nosniff && sniff_is_html(&header),
+        MediaType::Other(_) => false,
+    };
+
+    if !is_html {
+        return Err(LoadError::NotHtml);
+    }
+
+    let mut parser =
+        html::HtmlParser::new(header.as_slice().chain(body)).content_length(content_length);
+
+    if let Some(transport_encoding) = transport_encoding {
+        parser = parser.transport_encoding(transport_encoding);
+    }
+
+    let document = parser.try_parse();
+
+    // A cut off body can fail to parse too, but being too large is what went wrong
+    if let Some(too_large) = too_large.take() {
+        return Err(LoadError::BodyTooLarge(too_large));
+    }
+
+    document.map_err(LoadError::Parse)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::MockFetcher;
+
+    /// "Привет, мир!" in windows-1251
+    const GREETING: &[u8] = b"\xCF\xF0\xE8\xE2\xE5\xF2, \xEC\xE8\xF0!";
+
+    #[test]
+    fn loads_windows_1251_from_content_type() {
+        let body = [b"<!DOCTYPE html><title>", GREETING, b"</title>"].concat();
+        let fetcher = MockFetcher::new().with_response(
+            "http://mario.example/",
+            http::StatusCode::OK,
+            &[("Content-Type", "text/html; charset=windows-1251")],
+            body,
+        );
+
+        let document =
+            load_document(&fetcher, &ClientConfig::default(), "http://mario.example/").unwrap();
+
+        assert_eq!(document.encoding(), html::CharacterEncoding::Windows1251);
+        assert_eq!(document.title().as_deref(), Some("Привет, мир!"));
+    }
+
+    #[test]
+    fn loads_windows_1251_from_meta() {
+        // Mislabeled as binary, and without a charset, so both the type and the encoding are
+        // sniffed from the body
+        let body = [
+            b"<html><meta charset=windows-1251><title>",
+            GREETING,
+            b"</title></html>",
+        ]
+        .concat();
+        let fetcher = MockFetcher::new().with_response(
+            "http://mario.example/",
+            http::StatusCode::OK,
+            &[("Content-Type", "application/octet-stream")],
+            body,
+        );
+
+        let document =
+            load_document(&fetcher, &ClientConfig::default(), "http://mario.example/").unwrap();
+
+        assert_eq!(document.encoding(), html::CharacterEncoding::Windows1251);
+        assert_eq!(document.title().as_deref(), Some("Привет, мир!"));
+    }
+
+    #[test]
+    fn parses_past_resource_header() {
+        // Only the start of the body is sniffed, but all of it is parsed
+        let body = format!(
+            "<html><!--{}--><title>Mario!</title></html>",
+            " ".repeat(RESOURCE_HEADER_LEN)
+        );
+        let fetcher = MockFetcher::new().with_response(
+            "http://mario.example/",
+            http::StatusCode::OK,
+            &[("Content-Type", "application/octet-stream")],
+            body,
+        );
+
+        let document =
+            load_document(&fetcher, &ClientConfig::default(), "http://mario.example/").unwrap();
+
+        assert_eq!(document.title().as_deref(), Some("Mario!"));
+    }
+
+    #[test]
+    fn limits_body_size() {
+        let body = format!("<!DOCTYPE html><title>Mario!</title>{}", "<p>".repeat(4096));
+        let fetcher = MockFetcher::new().with_response(
+            "http://mario.example/",
+            http::StatusCode::OK,
+            &[("Content-Type", "text/html")],
+            body.clone(),
+        );
+
+        // Going past the limit while sniffing, and while parsing
+        for limit in [64, 4096] {
+            let config = ClientConfig {
+                max_body_bytes: Some(limit),
+                ..ClientConfig::default()
+            };
+
+            assert!(matches!(
+                load_document(&fetcher, &config, "http://mario.example/"),
+                Err(LoadError::BodyTooLarge(BodyTooLarge { limit: l })) if l == limit
+            ));
+        }
+
+        // A body of exactly the limit is parsed
+        let config = ClientConfig {
+            max_body_bytes: Some(body.len() as u64),
+            ..ClientConfig::default()
+        };
+        let document = load_document(&fetcher, &config, "http://mario.example/").unwrap();
+        assert_eq!(document.title().as_deref(), Some("Mario!"));
+    }
+
+    #[test]
+    fn nosniff_skips_html_sniffing() {
+        let body = "<!DOCTYPE html><title>Mario!</title>";
+        let fetcher = MockFetcher::new()
+            .with_response(
+                "http://mario.example/",
+                http::StatusCode::OK,
+                &[("Content-Type", "application/octet-stream")],
+                body,
+            )
+            .with_response(
+                "http://mario.example/nosniff",
+                http::StatusCode::OK,
+                &[
+                    ("Content-Type", "application/octet-stream"),
+                    ("X-Content-Type-Options", "nosniff"),
+                ],
+                body,
+            );
+
+        let document =
+            load_document(&fetcher, &ClientConfig::default(), "http://mario.example/").unwrap();
+        assert_eq!(document.title().as_deref(), Some("Mario!"));
+
+        assert!(matches!(
+            load_document(
+                &fetcher,
+                &ClientConfig::default(),
+                "http://mario.example/nosniff"
+            ),
+            Err(LoadError::NotHtml)
+        ));
+    }
 }
@@ -0,0 +1,60 @@
+#![feature(test)]
+
+extern crate test;
+
+use html::{io_queue::IoQueue, CharacterEncoding, HtmlParser, Lexer, StreamLexer};
+use test::{black_box, Bencher};
+
+fn ascii_document() -> Vec<u8> {
+    "<p class=\"paragraph\">The quick brown fox jumps over the lazy dog.</p>\n"
+        .repeat(4096)
+        .into_bytes()
+}
+
+#[bench]
+fn decode_ascii_fast_path(b: &mut Bencher) {
+    let bytes = ascii_document();
+
+    b.iter(|| CharacterEncoding::Utf8.decode(black_box(&bytes)).len());
+}
+
+#[bench]
+fn decode_ascii_char_by_char(b: &mut Bencher) {
+    let bytes = ascii_document();
+
+    b.iter(|| {
+        let mut decoder = CharacterEncoding::Utf8.decoder();
+        let mut io_queue = IoQueue::new(black_box(&bytes[..]));
+        let mut len = 0;
+
+        while let Ok(Some((c, _))) = decoder.decode(&mut io_queue) {
+            len += c.len_utf8();
+        }
+
+        len
+    });
+}
+
+/// The decoding the parser does, which takes runs of ASCII without the decoder
+#[bench]
+fn lex_ascii_document(b: &mut Bencher) {
+    let bytes = ascii_document();
+
+    b.iter(|| {
+        let mut lexer = StreamLexer::from_bytes(black_box(&bytes), CharacterEncoding::Utf8);
+        let mut len = 0;
+
+        while let Ok(Some(c)) = lexer.next_char() {
+            len += c.len_utf8();
+        }
+
+        len
+    });
+}
+
+#[bench]
+fn parse_ascii_document(b: &mut Bencher) {
+    let bytes = ascii_document();
+
+    b.iter(|| HtmlParser::from_bytes(black_box(&bytes)).parse());
+}
@@ -0,0 +1,39 @@
+#![feature(test)]
+
+extern crate test;
+
+use html::io_queue::IoQueue;
+use test::{black_box, Bencher};
+
+fn document() -> Vec<u8> {
+    "<p class=\"paragraph\">The quick brown fox jumps over the lazy dog.</p>\n"
+        .repeat(4096)
+        .into_bytes()
+}
+
+/// Peeks a few bytes ahead of every byte before consuming it, like the decoders do
+fn scan<R: std::io::Read>(mut io_queue: IoQueue<R>) -> usize {
+    let mut tags = 0;
+
+    while let Some(byte) = io_queue.next_byte() {
+        if byte == b'<' && io_queue.contains_bytes(0, b"p ") {
+            tags += 1;
+        }
+    }
+
+    tags
+}
+
+#[bench]
+fn slice_backed(b: &mut Bencher) {
+    let bytes = document();
+
+    b.iter(|| scan(IoQueue::from_bytes(black_box(&bytes))));
+}
+
+#[bench]
+fn reader_backed(b: &mut Bencher) {
+    let bytes = document();
+
+    b.iter(|| scan(IoQueue::new(black_box(&bytes[..]))));
+}
@@ -1,8 +1,14 @@
-use std::{io::Read, str::FromStr};
+use std::{borrow::Cow, fmt::Display, io::Read, str::FromStr};
 
-use crate::io_queue::IoQueue;
+#[cfg(feature = "encoding-rs")]
+use std::collections::VecDeque;
 
-#[derive(Default, PartialEq, Eq, Clone, Copy)]
+use crate::{
+    encoding_indexes::{jis0208_code_point, WINDOWS_1251, WINDOWS_1252},
+    io_queue::IoQueue,
+};
+
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
 pub enum CharacterEncoding {
     #[default]
     Utf8,
@@ -50,10 +56,17 @@ pub enum CharacterEncoding {
 impl FromStr for CharacterEncoding {
     type Err = ();
 
+    /// https://encoding.spec.whatwg.org/#concept-encoding-get
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         use CharacterEncoding::*;
 
-        Ok(match s {
+        // Remove any leading and trailing ASCII whitespace from label, and match it
+        // ASCII case-insensitively
+        let label = s
+            .trim_matches(['\t', '\n', '\x0C', '\r', ' '])
+            .to_ascii_lowercase();
+
+        Ok(match label.as_str() {
             "unicode-1-1-utf-8" | "unicode11utf8" | "unicode20utf8" | "utf-8" | "utf8"
             | "x-unicode20utf8" => Utf8,
             "866" | "cp866" | "csibm866" | "ibm866" => IBM866,
@@ -127,8 +140,27 @@ impl FromStr for CharacterEncoding {
     }
 }
 
-impl ToString for CharacterEncoding {
-    fn to_string(&self) -> String {
+impl Display for CharacterEncoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.whatwg_name())
+    }
+}
+
+impl CharacterEncoding {
+    /// The `encoding_rs` counterpart of the encoding
+    #[cfg(feature = "encoding-rs")]
+    pub fn to_encoding_rs(&self) -> &'static encoding_rs::Encoding {
+        match self {
+            // Not a label of the replacement encoding, only its name
+            CharacterEncoding::Replacement => encoding_rs::REPLACEMENT,
+            other => encoding_rs::Encoding::for_label(other.whatwg_name().as_bytes())
+                .expect("every encoding name is one of its labels"),
+        }
+    }
+
+    /// The canonical name of the encoding, as listed in the "Name" column of the spec
+    /// (https://encoding.spec.whatwg.org/#names-and-labels)
+    pub const fn whatwg_name(&self) -> &'static str {
         match self {
             CharacterEncoding::Utf8 => "UTF-8",
             CharacterEncoding::IBM866 => "IBM866",
@@ -171,62 +203,251 @@ impl ToString for CharacterEncoding {
             CharacterEncoding::Utf16LE => "UTF-16LE",
             CharacterEncoding::XUserDefined => "x-user-defined",
         }
-        .to_owned()
     }
-}
 
-impl CharacterEncoding {
-    pub fn decoder<R: Read>(&self) -> impl Decoder<R> {
+    /// Whether the encoding is ASCII-compatible, which the spec defines as not being a UTF-16
+    /// encoding (https://html.spec.whatwg.org/#ascii-compatible-encoding). Bytes 0x00 to 0x7F
+    /// decode to the same ASCII characters in all of these, outside of ISO-2022-JP escape
+    /// sequences.
+    pub const fn is_ascii_compatible(&self) -> bool {
+        !self.is_utf16()
+    }
+
+    /// Whether the encoding is UTF-16BE or UTF-16LE
+    pub const fn is_utf16(&self) -> bool {
+        matches!(
+            self,
+            CharacterEncoding::Utf16BE | CharacterEncoding::Utf16LE
+        )
+    }
+
+    /// Whether the encoding maps every byte to a single character on its own (the legacy
+    /// single-byte encodings, https://encoding.spec.whatwg.org/#legacy-single-byte-encodings,
+    /// and x-user-defined)
+    pub const fn is_single_byte(&self) -> bool {
+        use CharacterEncoding::*;
+
+        matches!(
+            self,
+            IBM866
+                | ISO8859_2
+                | ISO8859_3
+                | ISO8859_4
+                | ISO8859_5
+                | ISO8859_6
+                | ISO8859_7
+                | ISO8859_8
+                | ISO8859_8I
+                | ISO8859_10
+                | ISO8859_13
+                | ISO8859_14
+                | ISO8859_15
+                | ISO8859_16
+                | KOI8R
+                | KOI8U
+                | Macintosh
+                | Windows874
+                | Windows1250
+                | Windows1251
+                | Windows1252
+                | Windows1253
+                | Windows1254
+                | Windows1255
+                | Windows1256
+                | Windows1257
+                | Windows1258
+                | XMacCyrillic
+                | XUserDefined
+        )
+    }
+
+    /// Whether the encoding is one of the legacy multi-byte encodings for Chinese, Japanese or
+    /// Korean text (https://encoding.spec.whatwg.org/#legacy-multi-byte-chinese-(simplified)-encodings
+    /// and the sections after it)
+    pub const fn is_cjk_multibyte(&self) -> bool {
+        use CharacterEncoding::*;
+
+        matches!(
+            self,
+            GBK | GB18030 | Big5 | EucJp | ISO2022Jp | ShiftJIS | EucKr
+        )
+    }
+
+    /// Detects a byte order mark at the start of `bytes`, returning the encoding it indicates
+    /// and its length in bytes (https://encoding.spec.whatwg.org/#bom-sniff)
+    pub fn from_bom(bytes: &[u8]) -> Option<(Self, usize)> {
+        match bytes {
+            [0xEF, 0xBB, 0xBF, ..] => Some((CharacterEncoding::Utf8, 3)),
+            [0xFE, 0xFF, ..] => Some((CharacterEncoding::Utf16BE, 2)),
+            [0xFF, 0xFE, ..] => Some((CharacterEncoding::Utf16LE, 2)),
+            _ => None,
+        }
+    }
+
+    /// Decodes a complete buffer, replacing anything that can't be decoded (including
+    /// surrogates, see [`DecodingError`]) with U+FFFD REPLACEMENT CHARACTER.
+    ///
+    /// Plain ASCII text maps to itself in most encodings, so in that case the buffer is
+    /// borrowed as is instead of being decoded character by character.
+    pub fn decode<'a>(&self, bytes: &'a [u8]) -> Cow<'a, str> {
+        let maps_ascii_to_itself = !self.is_utf16() && *self != CharacterEncoding::Replacement;
+
+        if maps_ascii_to_itself && bytes.iter().all(|&b| is_plain_ascii(b)) {
+            return Cow::Borrowed(std::str::from_utf8(bytes).unwrap());
+        }
+
+        let mut decoder = self.decoder();
+        let mut io_queue = IoQueue::from_bytes(bytes);
+        let mut decoded = String::with_capacity(bytes.len());
+
+        loop {
+            match decoder.decode(&mut io_queue) {
+                Ok(Some((c, _))) => decoded.push(c),
+                Ok(None) => return Cow::Owned(decoded),
+                Err(_) => decoded.push(char::REPLACEMENT_CHARACTER),
+            }
+        }
+    }
+
+    /// The encoding that text is encoded with when this is the encoding of a document (e.g. for
+    /// form submissions and URLs), since UTF-16 and the replacement encoding have no encoder
+    /// (https://encoding.spec.whatwg.org/#get-an-output-encoding)
+    pub const fn output_encoding(&self) -> CharacterEncoding {
         match self {
-            CharacterEncoding::Utf8 => Utf8Decoder,
-            CharacterEncoding::IBM866 => todo!(),
-            CharacterEncoding::ISO8859_2 => todo!(),
-            CharacterEncoding::ISO8859_3 => todo!(),
-            CharacterEncoding::ISO8859_4 => todo!(),
-            CharacterEncoding::ISO8859_5 => todo!(),
-            CharacterEncoding::ISO8859_6 => todo!(),
-            CharacterEncoding::ISO8859_7 => todo!(),
-            CharacterEncoding::ISO8859_8 => todo!(),
-            CharacterEncoding::ISO8859_8I => todo!(),
-            CharacterEncoding::ISO8859_10 => todo!(),
-            CharacterEncoding::ISO8859_13 => todo!(),
-            CharacterEncoding::ISO8859_14 => todo!(),
-            CharacterEncoding::ISO8859_15 => todo!(),
-            CharacterEncoding::ISO8859_16 => todo!(),
-            CharacterEncoding::KOI8R => todo!(),
-            CharacterEncoding::KOI8U => todo!(),
-            CharacterEncoding::Macintosh => todo!(),
-            CharacterEncoding::Windows874 => todo!(),
-            CharacterEncoding::Windows1250 => todo!(),
-            CharacterEncoding::Windows1251 => todo!(),
-            CharacterEncoding::Windows1252 => todo!(),
-            CharacterEncoding::Windows1253 => todo!(),
-            CharacterEncoding::Windows1254 => todo!(),
-            CharacterEncoding::Windows1255 => todo!(),
-            CharacterEncoding::Windows1256 => todo!(),
-            CharacterEncoding::Windows1257 => todo!(),
-            CharacterEncoding::Windows1258 => todo!(),
-            CharacterEncoding::XMacCyrillic => todo!(),
-            CharacterEncoding::GBK => todo!(),
-            CharacterEncoding::GB18030 => todo!(),
-            CharacterEncoding::Big5 => todo!(),
-            CharacterEncoding::EucJp => todo!(),
-            CharacterEncoding::ISO2022Jp => todo!(),
-            CharacterEncoding::ShiftJIS => todo!(),
-            CharacterEncoding::EucKr => todo!(),
-            CharacterEncoding::Replacement => todo!(),
-            CharacterEncoding::Utf16BE => todo!(),
-            CharacterEncoding::Utf16LE => todo!(),
-            CharacterEncoding::XUserDefined => todo!(),
+            CharacterEncoding::Utf16BE
+            | CharacterEncoding::Utf16LE
+            | CharacterEncoding::Replacement => CharacterEncoding::Utf8,
+            other => *other,
         }
     }
+
+    /// An encoder for the output encoding of this encoding (see
+    /// [`CharacterEncoding::output_encoding`])
+    ///
+    /// TODO: encodings without an encoder yet only get ASCII (see [`AsciiEncoder`])
+    pub fn encoder(&self) -> Box<dyn Encoder> {
+        match self.output_encoding() {
+            CharacterEncoding::Utf8 => Box::new(Utf8Encoder),
+            CharacterEncoding::Windows1251 => Box::new(SingleByteEncoder::new(&WINDOWS_1251)),
+            CharacterEncoding::Windows1252 => Box::new(SingleByteEncoder::new(&WINDOWS_1252)),
+            _ => Box::new(AsciiEncoder),
+        }
+    }
+
+    /// Encodes `text` with the encoder of the encoding, replacing characters that it can't
+    /// encode with numeric character references (e.g. `&#28450;`), like HTML serializers do
+    /// (https://encoding.spec.whatwg.org/#encode-or-fail)
+    pub fn encode(&self, text: &str) -> Vec<u8> {
+        let mut encoder = self.encoder();
+        let mut encoded = Vec::with_capacity(text.len());
+
+        for c in text.chars() {
+            if let Err(EncodingError::UnmappableCharacter) = encoder.encode(c, &mut encoded) {
+                encoded.extend(format!("&#{};", c as u32).bytes());
+            }
+        }
+
+        encoded
+    }
+
+    /// A decoder for this encoding. With the `encoding-rs` feature, this is the decoder from
+    /// `encoding_rs` (see [`CharacterEncoding::native_decoder`] for the crate's own).
+    pub fn decoder<R: Read>(&self) -> Box<dyn Decoder<R>> {
+        #[cfg(feature = "encoding-rs")]
+        {
+            Box::new(EncodingRsDecoder::new(self.to_encoding_rs()))
+        }
+
+        #[cfg(not(feature = "encoding-rs"))]
+        {
+            self.native_decoder()
+        }
+    }
+
+    /// The crate's own decoder for this encoding, regardless of the `encoding-rs` feature.
+    /// Encodings without one yet are decoded as windows-1252 for now.
+    pub fn native_decoder<R: Read>(&self) -> Box<dyn Decoder<R>> {
+        match self {
+            CharacterEncoding::Utf8 => Box::new(Utf8Decoder),
+            CharacterEncoding::IBM866 => self.fallback_decoder(),
+            CharacterEncoding::ISO8859_2 => self.fallback_decoder(),
+            CharacterEncoding::ISO8859_3 => self.fallback_decoder(),
+            CharacterEncoding::ISO8859_4 => self.fallback_decoder(),
+            CharacterEncoding::ISO8859_5 => self.fallback_decoder(),
+            CharacterEncoding::ISO8859_6 => self.fallback_decoder(),
+            CharacterEncoding::ISO8859_7 => self.fallback_decoder(),
+            CharacterEncoding::ISO8859_8 => self.fallback_decoder(),
+            CharacterEncoding::ISO8859_8I => self.fallback_decoder(),
+            CharacterEncoding::ISO8859_10 => self.fallback_decoder(),
+            CharacterEncoding::ISO8859_13 => self.fallback_decoder(),
+            CharacterEncoding::ISO8859_14 => self.fallback_decoder(),
+            CharacterEncoding::ISO8859_15 => self.fallback_decoder(),
+            CharacterEncoding::ISO8859_16 => self.fallback_decoder(),
+            CharacterEncoding::KOI8R => self.fallback_decoder(),
+            CharacterEncoding::KOI8U => self.fallback_decoder(),
+            CharacterEncoding::Macintosh => self.fallback_decoder(),
+            CharacterEncoding::Windows874 => self.fallback_decoder(),
+            CharacterEncoding::Windows1250 => self.fallback_decoder(),
+            CharacterEncoding::Windows1251 => Box::new(SingleByteDecoder::new(&WINDOWS_1251)),
+            CharacterEncoding::Windows1252 => Box::new(SingleByteDecoder::new(&WINDOWS_1252)),
+            CharacterEncoding::Windows1253 => self.fallback_decoder(),
+            CharacterEncoding::Windows1254 => self.fallback_decoder(),
+            CharacterEncoding::Windows1255 => self.fallback_decoder(),
+            CharacterEncoding::Windows1256 => self.fallback_decoder(),
+            CharacterEncoding::Windows1257 => self.fallback_decoder(),
+            CharacterEncoding::Windows1258 => self.fallback_decoder(),
+            CharacterEncoding::XMacCyrillic => self.fallback_decoder(),
+            CharacterEncoding::GBK => self.fallback_decoder(),
+            CharacterEncoding::GB18030 => self.fallback_decoder(),
+            CharacterEncoding::Big5 => self.fallback_decoder(),
+            CharacterEncoding::EucJp => self.fallback_decoder(),
+            CharacterEncoding::ISO2022Jp => Box::<Iso2022JpDecoder>::default(),
+            CharacterEncoding::ShiftJIS => self.fallback_decoder(),
+            CharacterEncoding::EucKr => self.fallback_decoder(),
+            CharacterEncoding::Replacement => Box::<ReplacementDecoder>::default(),
+            CharacterEncoding::Utf16BE => Box::new(Utf16Decoder { big_endian: true }),
+            CharacterEncoding::Utf16LE => Box::new(Utf16Decoder { big_endian: false }),
+            CharacterEncoding::XUserDefined => Box::new(XUserDefinedDecoder),
+        }
+    }
+
+    /// Stands in for the decoders that are not implemented yet, so that documents in those
+    /// encodings are decoded lossily instead of not at all. windows-1252 maps ASCII to itself,
+    /// like most of them, and has a code point for every byte.
+    ///
+    /// TODO: remove once every encoding has a decoder
+    fn fallback_decoder<R: Read>(&self) -> Box<dyn Decoder<R>> {
+        tracing::warn!(encoding = %self, "no decoder implemented, decoding as windows-1252");
+
+        Box::new(SingleByteDecoder::new(&WINDOWS_1252))
+    }
 }
 
 pub trait Decoder<R: Read> {
     /// Reads the next unicode character from the given input byte stream
     ///
-    /// Does not allow surrogates, non-characters, or control characters
-    fn decode(&self, io_queue: &mut IoQueue<R>) -> Result<Option<(char, Vec<u8>)>, DecodingError>;
+    /// Does not allow surrogates (non-characters and control characters are decoded as is, they
+    /// are reported by the lexer)
+    fn decode(
+        &mut self,
+        io_queue: &mut IoQueue<'_, R>,
+    ) -> Result<Option<(char, Vec<u8>)>, DecodingError>;
+
+    /// Returns the decoder to its initial state, discarding anything carried over from
+    /// previously decoded bytes. Stateless decoders have nothing to reset.
+    fn reset(&mut self) {}
+
+    /// Whether the next byte, if it is ASCII (other than the ISO-2022-JP escape, shift out and
+    /// shift in bytes), decodes to the same character without changing the state of the
+    /// decoder. The lexer takes runs of such bytes as is instead of decoding them one at a time.
+    fn passes_ascii_through(&self) -> bool {
+        false
+    }
+}
+
+/// Whether the byte is ASCII, except for the ISO-2022-JP escape, shift out and shift in bytes
+pub(crate) fn is_plain_ascii(byte: u8) -> bool {
+    byte.is_ascii() && !matches!(byte, 0x0E | 0x0F | 0x1B)
 }
 
 pub struct Utf8Decoder;
@@ -234,15 +455,16 @@ pub struct Utf8Decoder;
 pub enum DecodingError {
     UnexpectedEof,
     UnexpectedSurrogate,
-    UnexpectedNonCharacter,
-    UnexpectedControl,
     InvalidData,
 }
 
 impl<R: Read> Decoder<R> for Utf8Decoder {
-    fn decode(&self, io_queue: &mut IoQueue<R>) -> Result<Option<(char, Vec<u8>)>, DecodingError> {
+    fn decode(
+        &mut self,
+        io_queue: &mut IoQueue<'_, R>,
+    ) -> Result<Option<(char, Vec<u8>)>, DecodingError> {
         // If queue has no bytes, then can't decode a code-point
-        let Some(first) = io_queue.next() else {
+        let Some(first) = io_queue.next_byte() else {
             return Ok(None)
         };
 
@@ -254,7 +476,7 @@ impl<R: Read> Decoder<R> for Utf8Decoder {
         /// Macro to try and extract another byte in the 2-4 position
         macro_rules! next_byte {
             () => {{
-                let c = io_queue.next().ok_or(DecodingError::UnexpectedEof)?;
+                let c = io_queue.next_byte().ok_or(DecodingError::UnexpectedEof)?;
                 bytes.push(c);
 
                 // Multi-byte sequences always have bytes start with 10xxxxxx after the first byte
@@ -297,65 +519,867 @@ impl<R: Read> Decoder<R> for Utf8Decoder {
             return Err(DecodingError::InvalidData);
         };
 
-        // Remove ugly characters
-        match code_point {
-            // Leading surrogate
-            0xD800..=0xDBFF => return Err(DecodingError::UnexpectedSurrogate),
-            // Trailing surrogate
+        Ok(Some((check_code_point(code_point)?, bytes)))
+    }
+
+    fn passes_ascii_through(&self) -> bool {
+        true
+    }
+}
+
+/// Rejects code points that are not Unicode scalar values (surrogates, and code points past the
+/// Unicode range)
+///
+/// Non-characters and control characters are decoded like any other character, it is up to the
+/// lexer to report them (https://html.spec.whatwg.org/#preprocessing-the-input-stream)
+fn check_code_point(code_point: u32) -> Result<char, DecodingError> {
+    if let 0xD800..=0xDFFF = code_point {
+        return Err(DecodingError::UnexpectedSurrogate);
+    }
+
+    // Surrogates are gone by now, so this only fails for code points past the Unicode range
+    // (which a malformed 4 byte UTF-8 sequence can encode)
+    char::from_u32(code_point).ok_or(DecodingError::InvalidData)
+}
+
+/// https://encoding.spec.whatwg.org/#iso-2022-jp-decoder
+#[derive(Default)]
+pub struct Iso2022JpDecoder {
+    state: Iso2022JpState,
+    output: bool,
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+enum Iso2022JpState {
+    #[default]
+    Ascii,
+    Roman,
+    Katakana,
+    LeadByte,
+}
+
+impl Iso2022JpDecoder {
+    /// Handles an escape sequence (the leading 0x1B has already been consumed). The bytes
+    /// following the escape are only consumed if they form a valid sequence.
+    fn escape<R: Read>(
+        &mut self,
+        io_queue: &mut IoQueue<'_, R>,
+        bytes: &mut Vec<u8>,
+    ) -> Result<(), DecodingError> {
+        let state = match io_queue.peek_n() {
+            Some([0x28, 0x42]) => Some(Iso2022JpState::Ascii),
+            Some([0x28, 0x4A]) => Some(Iso2022JpState::Roman),
+            Some([0x28, 0x49]) => Some(Iso2022JpState::Katakana),
+            Some([0x24, 0x40 | 0x42]) => Some(Iso2022JpState::LeadByte),
+            _ => None,
+        };
+
+        let Some(state) = state else {
+            self.output = false;
+            return Err(DecodingError::InvalidData);
+        };
+
+        bytes.extend(io_queue.next_byte());
+        bytes.extend(io_queue.next_byte());
+        self.state = state;
+
+        // Two escape sequences in a row (with no output in between) are an error
+        if std::mem::replace(&mut self.output, true) {
+            return Err(DecodingError::InvalidData);
+        }
+
+        Ok(())
+    }
+}
+
+impl<R: Read> Decoder<R> for Iso2022JpDecoder {
+    fn decode(
+        &mut self,
+        io_queue: &mut IoQueue<'_, R>,
+    ) -> Result<Option<(char, Vec<u8>)>, DecodingError> {
+        let mut bytes = Vec::new();
+
+        loop {
+            let Some(byte) = io_queue.next_byte() else {
+                return Ok(None);
+            };
+            bytes.push(byte);
+
+            if byte == 0x1B {
+                self.escape(io_queue, &mut bytes)?;
+                continue;
+            }
+
+            self.output = false;
+
+            let code_point = match (self.state, byte) {
+                (Iso2022JpState::Ascii, 0x0E | 0x0F) => return Err(DecodingError::InvalidData),
+                (Iso2022JpState::Ascii, 0x00..=0x7F) => byte as u32,
+                (Iso2022JpState::Roman, 0x5C) => 0xA5,
+                (Iso2022JpState::Roman, 0x7E) => 0x203E,
+                (Iso2022JpState::Roman, 0x0E | 0x0F) => return Err(DecodingError::InvalidData),
+                (Iso2022JpState::Roman, 0x00..=0x7F) => byte as u32,
+                (Iso2022JpState::Katakana, 0x21..=0x5F) => 0xFF61 - 0x21 + byte as u32,
+                (Iso2022JpState::LeadByte, 0x21..=0x7E) => {
+                    let lead = byte;
+
+                    // A trail byte outside the range is not consumed
+                    let trail = match io_queue.peek() {
+                        Some(trail @ 0x21..=0x7E) => trail,
+                        Some(_) => return Err(DecodingError::InvalidData),
+                        None => return Err(DecodingError::UnexpectedEof),
+                    };
+                    bytes.extend(io_queue.next_byte());
+
+                    let pointer = (lead as usize - 0x21) * 94 + trail as usize - 0x21;
+
+                    jis0208_code_point(pointer).ok_or(DecodingError::InvalidData)? as u32
+                }
+                _ => return Err(DecodingError::InvalidData),
+            };
+
+            return Ok(Some((check_code_point(code_point)?, bytes)));
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    fn passes_ascii_through(&self) -> bool {
+        // Right after an escape sequence, the next character also clears the output flag
+        self.state == Iso2022JpState::Ascii && !self.output
+    }
+}
+
+/// https://encoding.spec.whatwg.org/#shared-utf-16-decoder
+///
+/// A byte order mark is not skipped here, that is done while sniffing the encoding.
+pub struct Utf16Decoder {
+    big_endian: bool,
+}
+
+impl Utf16Decoder {
+    fn code_unit(&self, bytes: [u8; 2]) -> u16 {
+        if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        }
+    }
+}
+
+impl<R: Read> Decoder<R> for Utf16Decoder {
+    fn decode(
+        &mut self,
+        io_queue: &mut IoQueue<'_, R>,
+    ) -> Result<Option<(char, Vec<u8>)>, DecodingError> {
+        let Some(first) = io_queue.next_byte() else {
+            return Ok(None)
+        };
+        let second = io_queue.next_byte().ok_or(DecodingError::UnexpectedEof)?;
+
+        let code_unit = self.code_unit([first, second]);
+        let mut bytes = vec![first, second];
+
+        let code_point = match code_unit {
+            // A lead surrogate, which must be followed by a trail surrogate. Anything else is
+            // not consumed, so it is decoded on its own.
+            0xD800..=0xDBFF => {
+                let trail = io_queue.peek_n().ok_or(DecodingError::UnexpectedEof)?;
+
+                let trail_unit = self.code_unit(trail);
+                if !(0xDC00..=0xDFFF).contains(&trail_unit) {
+                    return Err(DecodingError::UnexpectedSurrogate);
+                }
+
+                io_queue.skip(2);
+                bytes.extend(trail);
+
+                0x10000 + ((code_unit as u32 - 0xD800) << 10) + (trail_unit as u32 - 0xDC00)
+            }
+            // A trail surrogate without a lead surrogate
             0xDC00..=0xDFFF => return Err(DecodingError::UnexpectedSurrogate),
-            // Non-characters
-            0xFDD0..=0xFDEF
-            | 0xFFFE
-            | 0xFFFF
-            | 0x1FFFE
-            | 0x1FFFF
-            | 0x2FFFE
-            | 0x2FFFF
-            | 0x3FFFE
-            | 0x3FFFF
-            | 0x4FFFE
-            | 0x4FFFF
-            | 0x5FFFE
-            | 0x5FFFF
-            | 0x6FFFE
-            | 0x6FFFF
-            | 0x7FFFE
-            | 0x7FFFF
-            | 0x8FFFE
-            | 0x8FFFF
-            | 0x9FFFE
-            | 0x9FFFF
-            | 0xAFFFE
-            | 0xAFFFF
-            | 0xBFFFE
-            | 0xBFFFF
-            | 0xCFFFE
-            | 0xCFFFF
-            | 0xDFFFE
-            | 0xDFFFF
-            | 0xEFFFE
-            | 0xEFFFF
-            | 0xFFFFE
-            | 0xFFFFF
-            | 0x10FFFE
-            | 0x10FFFF => return Err(DecodingError::UnexpectedNonCharacter),
-            // Control characters
-            x @ (0x00..=0x1F | 0x7F..=0x9F)
-                if x != 0 && !char::from_u32(x).unwrap().is_ascii_whitespace() =>
-            {
-                return Err(DecodingError::UnexpectedControl)
+            _ => code_unit as u32,
+        };
+
+        Ok(Some((check_code_point(code_point)?, bytes)))
+    }
+}
+
+/// https://encoding.spec.whatwg.org/#single-byte-decoder
+pub struct SingleByteDecoder {
+    /// The code points of the bytes 0x80 to 0xFF (0 for bytes without one)
+    index: &'static [u16; 128],
+}
+
+impl SingleByteDecoder {
+    pub(crate) fn new(index: &'static [u16; 128]) -> Self {
+        Self { index }
+    }
+}
+
+impl<R: Read> Decoder<R> for SingleByteDecoder {
+    fn decode(
+        &mut self,
+        io_queue: &mut IoQueue<'_, R>,
+    ) -> Result<Option<(char, Vec<u8>)>, DecodingError> {
+        let Some(byte) = io_queue.next_byte() else {
+            return Ok(None)
+        };
+
+        let code_point = match byte {
+            0x00..=0x7F => byte as u32,
+            _ => match self.index[byte as usize - 0x80] {
+                0 => return Err(DecodingError::InvalidData),
+                code_point => code_point as u32,
+            },
+        };
+
+        Ok(Some((check_code_point(code_point)?, vec![byte])))
+    }
+
+    fn passes_ascii_through(&self) -> bool {
+        true
+    }
+}
+
+/// https://encoding.spec.whatwg.org/#replacement-decoder
+///
+/// The encodings that map to the replacement encoding (e.g. ISO-2022-KR) could be used to
+/// smuggle markup past filters, so their content is never interpreted: the whole input decodes
+/// to a single U+FFFD REPLACEMENT CHARACTER (which is reported as an error).
+#[derive(Default)]
+pub struct ReplacementDecoder {
+    error_returned: bool,
+}
+
+impl<R: Read> Decoder<R> for ReplacementDecoder {
+    fn decode(
+        &mut self,
+        io_queue: &mut IoQueue<'_, R>,
+    ) -> Result<Option<(char, Vec<u8>)>, DecodingError> {
+        if self.error_returned || !io_queue.has_next() {
+            return Ok(None);
+        }
+
+        io_queue.skip(usize::MAX);
+        self.error_returned = true;
+
+        Err(DecodingError::InvalidData)
+    }
+
+    fn reset(&mut self) {
+        self.error_returned = false;
+    }
+}
+
+/// https://encoding.spec.whatwg.org/#x-user-defined-decoder
+pub struct XUserDefinedDecoder;
+
+impl<R: Read> Decoder<R> for XUserDefinedDecoder {
+    fn decode(
+        &mut self,
+        io_queue: &mut IoQueue<'_, R>,
+    ) -> Result<Option<(char, Vec<u8>)>, DecodingError> {
+        let Some(byte) = io_queue.next_byte() else {
+            return Ok(None)
+        };
+
+        let code_point = match byte {
+            0x00..=0x7F => byte as u32,
+            _ => 0xF780 + byte as u32 - 0x80,
+        };
+
+        Ok(Some((check_code_point(code_point)?, vec![byte])))
+    }
+
+    fn passes_ascii_through(&self) -> bool {
+        true
+    }
+}
+
+pub trait Encoder {
+    /// Appends the bytes of `c` to `output`, or returns an error (without writing anything) if
+    /// the encoding has no representation of it
+    fn encode(&mut self, c: char, output: &mut Vec<u8>) -> Result<(), EncodingError>;
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum EncodingError {
+    UnmappableCharacter,
+}
+
+/// https://encoding.spec.whatwg.org/#utf-8-encoder
+pub struct Utf8Encoder;
+
+impl Encoder for Utf8Encoder {
+    fn encode(&mut self, c: char, output: &mut Vec<u8>) -> Result<(), EncodingError> {
+        output.extend(c.encode_utf8(&mut [0; 4]).bytes());
+        Ok(())
+    }
+}
+
+/// https://encoding.spec.whatwg.org/#single-byte-encoder
+pub struct SingleByteEncoder {
+    /// The code points of the bytes 0x80 to 0xFF (0 for bytes without one)
+    index: &'static [u16; 128],
+}
+
+impl SingleByteEncoder {
+    pub(crate) fn new(index: &'static [u16; 128]) -> Self {
+        Self { index }
+    }
+}
+
+impl Encoder for SingleByteEncoder {
+    fn encode(&mut self, c: char, output: &mut Vec<u8>) -> Result<(), EncodingError> {
+        if c.is_ascii() {
+            output.push(c as u8);
+            return Ok(());
+        }
+
+        let pointer = self
+            .index
+            .iter()
+            .position(|&code_point| code_point != 0 && code_point as u32 == c as u32)
+            .ok_or(EncodingError::UnmappableCharacter)?;
+
+        output.push(0x80 + pointer as u8);
+        Ok(())
+    }
+}
+
+/// Only encodes ASCII, which is a subset of every encoding that serialized HTML can be written
+/// in, so it stands in for the encoders that are not implemented yet
+pub struct AsciiEncoder;
+
+impl Encoder for AsciiEncoder {
+    fn encode(&mut self, c: char, output: &mut Vec<u8>) -> Result<(), EncodingError> {
+        if !c.is_ascii() {
+            return Err(EncodingError::UnmappableCharacter);
+        }
+
+        output.push(c as u8);
+        Ok(())
+    }
+}
+
+/// Adapts a streaming `encoding_rs` decoder to [`Decoder`], feeding it one byte at a time
+#[cfg(feature = "encoding-rs")]
+pub struct EncodingRsDecoder {
+    decoder: encoding_rs::Decoder,
+    /// Output produced along with an earlier character, returned before decoding any more
+    pending: VecDeque<Result<char, DecodingError>>,
+    finished: bool,
+    /// Whether the decoder is known not to hold part of a sequence, which is only tracked for
+    /// UTF-8 and the single-byte encodings (where an ASCII byte never starts or continues one)
+    idle: bool,
+}
+
+#[cfg(feature = "encoding-rs")]
+impl EncodingRsDecoder {
+    pub fn new(encoding: &'static encoding_rs::Encoding) -> Self {
+        Self {
+            // The byte order mark is handled by the lexer already
+            decoder: encoding.new_decoder_without_bom_handling(),
+            pending: VecDeque::new(),
+            finished: false,
+            idle: encoding == encoding_rs::UTF_8 || encoding.is_single_byte(),
+        }
+    }
+}
+
+#[cfg(feature = "encoding-rs")]
+impl<R: Read> Decoder<R> for EncodingRsDecoder {
+    fn decode(
+        &mut self,
+        io_queue: &mut IoQueue<'_, R>,
+    ) -> Result<Option<(char, Vec<u8>)>, DecodingError> {
+        use encoding_rs::DecoderResult;
+
+        if let Some(decoded) = self.pending.pop_front() {
+            return decoded.map(|c| Some((c, Vec::new())));
+        }
+
+        let mut bytes = Vec::new();
+
+        loop {
+            let byte = io_queue.next_byte();
+            if byte.is_none() && self.finished {
+                return Ok(None);
+            }
+
+            // At the end of the input, flush whatever the decoder still holds
+            let last = byte.is_none();
+            self.finished = last;
+            bytes.extend(byte);
+
+            let encoding = self.decoder.encoding();
+            self.idle = encoding.is_single_byte()
+                || encoding == encoding_rs::UTF_8 && byte.is_some_and(|b| b.is_ascii());
+
+            let mut input = byte.as_slice();
+            while !input.is_empty() || last {
+                let capacity = self
+                    .decoder
+                    .max_utf8_buffer_length_without_replacement(input.len())
+                    .unwrap_or(16);
+                let mut output = String::with_capacity(capacity);
+
+                let (result, read) =
+                    self.decoder
+                        .decode_to_string_without_replacement(input, &mut output, last);
+                input = &input[read..];
+
+                self.pending.extend(output.chars().map(Ok));
+
+                match result {
+                    DecoderResult::Malformed(..) if last => {
+                        self.pending.push_back(Err(DecodingError::UnexpectedEof))
+                    }
+                    DecoderResult::Malformed(..) => {
+                        self.pending.push_back(Err(DecodingError::InvalidData))
+                    }
+                    DecoderResult::InputEmpty if last => break,
+                    DecoderResult::InputEmpty | DecoderResult::OutputFull => {}
+                }
+            }
+
+            if let Some(decoded) = self.pending.pop_front() {
+                return decoded.map(|c| Some((c, bytes)));
+            }
+
+            if last {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = Self::new(self.decoder.encoding());
+    }
+
+    fn passes_ascii_through(&self) -> bool {
+        self.idle && self.pending.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_str_normalizes_label() {
+        assert_eq!(" UTF-8 ".parse(), Ok(CharacterEncoding::Utf8));
+        assert_eq!("\t\nutf8\x0C\r".parse(), Ok(CharacterEncoding::Utf8));
+        assert_eq!("LATIN1".parse(), Ok(CharacterEncoding::Windows1252));
+        assert_eq!("utf\u{A0}8".parse::<CharacterEncoding>(), Err(()));
+        assert_eq!("\u{A0}utf-8".parse::<CharacterEncoding>(), Err(()));
+    }
+
+    #[test]
+    fn from_str_obscure_labels() {
+        assert_eq!("iso-ir-157".parse(), Ok(CharacterEncoding::ISO8859_10));
+        assert_eq!("Latin6".parse(), Ok(CharacterEncoding::ISO8859_10));
+        assert_eq!("x-unicode20utf8".parse(), Ok(CharacterEncoding::Utf8));
+        assert_eq!("ks_c_5601-1989".parse(), Ok(CharacterEncoding::EucKr));
+        assert_eq!("HZ-GB-2312".parse(), Ok(CharacterEncoding::Replacement));
+        assert_eq!("unicodeFFFE".parse(), Ok(CharacterEncoding::Utf16BE));
+    }
+
+    #[test]
+    fn whatwg_names() {
+        use CharacterEncoding::*;
+
+        // Usable in const contexts, without allocating
+        const NAME: &str = ShiftJIS.whatwg_name();
+        assert_eq!(NAME, "Shift_JIS");
+
+        let names = [
+            (Utf8, "UTF-8"),
+            (IBM866, "IBM866"),
+            (ISO8859_2, "ISO-8859-2"),
+            (ISO8859_3, "ISO-8859-3"),
+            (ISO8859_4, "ISO-8859-4"),
+            (ISO8859_5, "ISO-8859-5"),
+            (ISO8859_6, "ISO-8859-6"),
+            (ISO8859_7, "ISO-8859-7"),
+            (ISO8859_8, "ISO-8859-8"),
+            (ISO8859_8I, "ISO-8859-8-I"),
+            (ISO8859_10, "ISO-8859-10"),
+            (ISO8859_13, "ISO-8859-13"),
+            (ISO8859_14, "ISO-8859-14"),
+            (ISO8859_15, "ISO-8859-15"),
+            (ISO8859_16, "ISO-8859-16"),
+            (KOI8R, "KOI8-R"),
+            (KOI8U, "KOI8-U"),
+            (Macintosh, "macintosh"),
+            (Windows874, "windows-874"),
+            (Windows1250, "windows-1250"),
+            (Windows1251, "windows-1251"),
+            (Windows1252, "windows-1252"),
+            (Windows1253, "windows-1253"),
+            (Windows1254, "windows-1254"),
+            (Windows1255, "windows-1255"),
+            (Windows1256, "windows-1256"),
+            (Windows1257, "windows-1257"),
+            (Windows1258, "windows-1258"),
+            (XMacCyrillic, "x-mac-cyrillic"),
+            (GBK, "GBK"),
+            (GB18030, "gb18030"),
+            (Big5, "Big5"),
+            (EucJp, "EUC-JP"),
+            (ISO2022Jp, "ISO-2022-JP"),
+            (ShiftJIS, "Shift_JIS"),
+            (EucKr, "EUC-KR"),
+            (Replacement, "replacement"),
+            (Utf16BE, "UTF-16BE"),
+            (Utf16LE, "UTF-16LE"),
+            (XUserDefined, "x-user-defined"),
+        ];
+
+        for (encoding, name) in names {
+            assert_eq!(encoding.whatwg_name(), name);
+            assert_eq!(encoding.to_string(), name);
+
+            // Every name is also a label of its encoding (in any case), except for "replacement",
+            // whose labels are those of the encodings it stands in for (e.g. "iso-2022-kr")
+            if encoding != Replacement {
+                assert_eq!(name.parse(), Ok(encoding));
+                assert_eq!(
+                    encoding.to_string().to_ascii_lowercase().parse(),
+                    Ok(encoding)
+                );
+            }
+        }
+
+        // Not a prefix match for ISO-8859-8, which is visually ordered instead of logically
+        assert_eq!("iso-8859-8-i".parse(), Ok(ISO8859_8I));
+        assert_eq!("logical".parse(), Ok(ISO8859_8I));
+        assert_eq!("csiso88598i".parse(), Ok(ISO8859_8I));
+        assert_eq!("iso-8859-8".parse(), Ok(ISO8859_8));
+        assert_eq!("visual".parse(), Ok(ISO8859_8));
+    }
+
+    #[test]
+    fn ascii_compatibility() {
+        assert!(CharacterEncoding::Utf8.is_ascii_compatible());
+        assert!(CharacterEncoding::Windows1252.is_ascii_compatible());
+        assert!(CharacterEncoding::ShiftJIS.is_ascii_compatible());
+        assert!(!CharacterEncoding::Utf16BE.is_ascii_compatible());
+        assert!(!CharacterEncoding::Utf16LE.is_ascii_compatible());
+    }
+
+    fn decode_all<R: Read>(decoder: &mut dyn Decoder<R>, io_queue: &mut IoQueue<'_, R>) -> String {
+        let mut decoded = String::new();
+
+        loop {
+            match decoder.decode(io_queue) {
+                Ok(Some((c, _))) => decoded.push(c),
+                Ok(None) => return decoded,
+                Err(_) => decoded.push(char::REPLACEMENT_CHARACTER),
+            }
+        }
+    }
+
+    #[test]
+    fn out_of_range_code_point() {
+        // Unlike `encoding_rs`, which replaces each of the bytes
+        let mut io_queue = IoQueue::from_bytes(b"a\xF7\x92\xAA\xBBb");
+        assert_eq!(
+            decode_all(
+                &mut *CharacterEncoding::Utf8.native_decoder(),
+                &mut io_queue
+            ),
+            "a\u{FFFD}b"
+        );
+    }
+
+    #[test]
+    fn decode_fast_path() {
+        let ascii = b"<p>Hello,\tworld!</p>\r\n";
+        let decoded = CharacterEncoding::Utf8.decode(ascii);
+        assert!(matches!(
+            decoded,
+            Cow::Borrowed("<p>Hello,\tworld!</p>\r\n")
+        ));
+
+        let mixed = "<p>Grüße, 世界 🦀</p>".as_bytes();
+        let decoded = CharacterEncoding::Utf8.decode(mixed);
+        assert!(matches!(decoded, Cow::Owned(_)));
+        assert_eq!(decoded, "<p>Grüße, 世界 🦀</p>");
+
+        let invalid = b"a\xFFb\x01c";
+        assert_eq!(CharacterEncoding::Utf8.decode(invalid), "a\u{FFFD}b\x01c");
+    }
+
+    #[test]
+    fn iso_2022_jp_decoder() {
+        let mut decoder = CharacterEncoding::ISO2022Jp.decoder();
+        let mut io_queue = IoQueue::from_bytes(b"a\x1B$B\x30\x21\x1B(J\\\x1B(B\\");
+
+        assert_eq!(
+            decode_all(decoder.as_mut(), &mut io_queue),
+            "a\u{4E9C}\u{A5}\\"
+        );
+    }
+
+    #[test]
+    fn iso_2022_jp_decoder_reset() {
+        let mut decoder = CharacterEncoding::ISO2022Jp.decoder();
+
+        // Switch to the Katakana mode, where 0x31 is a half-width katakana
+        let mut io_queue = IoQueue::from_bytes(b"\x1B(I\x31");
+        assert_eq!(decode_all(decoder.as_mut(), &mut io_queue), "\u{FF71}");
+
+        decoder.reset();
+
+        let mut io_queue = IoQueue::from_bytes(b"\x31");
+        assert_eq!(decode_all(decoder.as_mut(), &mut io_queue), "1");
+    }
+
+    #[test]
+    fn utf_16_decoder() {
+        let utf_16 = |text: &str, big_endian: bool| -> Vec<u8> {
+            text.encode_utf16()
+                .flat_map(|unit| match big_endian {
+                    true => unit.to_be_bytes(),
+                    false => unit.to_le_bytes(),
+                })
+                .collect()
+        };
+
+        let text = "<p>Grüße, 世界 🦀</p>";
+        assert_eq!(
+            CharacterEncoding::Utf16LE.decode(&utf_16(text, false)),
+            text
+        );
+        assert_eq!(CharacterEncoding::Utf16BE.decode(&utf_16(text, true)), text);
+
+        // A lone trail surrogate, a lead surrogate followed by something else (which is still
+        // decoded), and a trailing odd byte
+        assert_eq!(
+            CharacterEncoding::Utf16LE.decode(b"\x00\xDCa\x00\x3D\xD8b\x00c"),
+            "\u{FFFD}a\u{FFFD}b\u{FFFD}"
+        );
+    }
+
+    #[test]
+    fn utf_16_surrogates_across_reads() {
+        /// Returns at most `self.1` bytes per read, like a stream that is delivered in chunks
+        struct Chunked<'a>(&'a [u8], usize);
+
+        impl Read for Chunked<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let len = self.0.len().min(self.1).min(buf.len());
+                buf[..len].copy_from_slice(&self.0[..len]);
+                self.0 = &self.0[len..];
+
+                Ok(len)
+            }
+        }
+
+        let text = "🦀a🦀ab🦀";
+
+        for big_endian in [false, true] {
+            let bytes: Vec<u8> = text
+                .encode_utf16()
+                .flat_map(|unit| match big_endian {
+                    true => unit.to_be_bytes(),
+                    false => unit.to_le_bytes(),
+                })
+                .collect();
+
+            // Every pair starts at a different offset into a chunk for one of the sizes, so
+            // some lead surrogates are the last bytes read so far
+            for chunk_size in 1..=5 {
+                let mut io_queue = IoQueue::new(Chunked(&bytes, chunk_size));
+                let mut decoder = Utf16Decoder { big_endian };
+
+                assert_eq!(decode_all(&mut decoder, &mut io_queue), text);
             }
-            _ => {}
         }
+    }
+
+    #[test]
+    fn windows_1252_decoder() {
+        let bytes: &[u8] = b"caf\xE9 \x80\x93 \x81";
+        let mut io_queue = IoQueue::from_bytes(bytes);
+        let mut decoder = CharacterEncoding::Windows1252.decoder();
+
+        // 0x81 is not assigned, so it decodes to the C1 control with the same value
+        assert_eq!(decode_all(&mut *decoder, &mut io_queue), "café €“ \u{81}");
+    }
+
+    #[test]
+    fn windows_1251_decoder() {
+        assert_eq!(
+            CharacterEncoding::Windows1251
+                .decode(b"\xCF\xF0\xE8\xE2\xE5\xF2, \xEC\xE8\xF0! \xB9\x88"),
+            "Привет, мир! №€"
+        );
+    }
+
+    #[test]
+    fn fallback_decoder() {
+        let decode = |encoding: CharacterEncoding, bytes| {
+            decode_all(
+                &mut *encoding.native_decoder(),
+                &mut IoQueue::from_bytes(bytes),
+            )
+        };
+
+        // Not implemented yet, so decoded as windows-1252: ASCII survives, the rest doesn't
+        assert_eq!(
+            decode(
+                CharacterEncoding::ISO8859_5,
+                b"<p>\xBF\xE0\xD8\xD2\xD5\xE2</p>"
+            ),
+            "<p>¿àØÒÕâ</p>"
+        );
+        assert_eq!(
+            decode(CharacterEncoding::ShiftJIS, b"a\x82\xA0"),
+            "a‚\u{A0}"
+        );
+    }
+
+    #[test]
+    fn replacement_and_x_user_defined() {
+        let decode = |encoding: CharacterEncoding, bytes| {
+            decode_all(
+                &mut *encoding.native_decoder(),
+                &mut IoQueue::from_bytes(bytes),
+            )
+        };
+
+        // Nothing of the input gets through, markup included
+        assert_eq!(
+            decode(CharacterEncoding::Replacement, b"<script>alert(1)</script>"),
+            "\u{FFFD}"
+        );
+        assert_eq!(
+            CharacterEncoding::Replacement.decode(b"<script>alert(1)</script>"),
+            "\u{FFFD}"
+        );
+        assert_eq!(decode(CharacterEncoding::Replacement, b""), "");
+
+        assert_eq!(
+            decode(CharacterEncoding::XUserDefined, b"<p>\x80\xC1\xFF</p>"),
+            "<p>\u{F780}\u{F7C1}\u{F7FF}</p>"
+        );
+    }
+
+    #[test]
+    fn output_encoding() {
+        use CharacterEncoding::*;
+
+        assert_eq!(Utf16LE.output_encoding(), Utf8);
+        assert_eq!(Utf16BE.output_encoding(), Utf8);
+        assert_eq!(Replacement.output_encoding(), Utf8);
+        assert_eq!(Utf8.output_encoding(), Utf8);
+        assert_eq!(Windows1252.output_encoding(), Windows1252);
+
+        // Unlike the others above, x-user-defined has an encoder of its own
+        assert_eq!(XUserDefined.output_encoding(), XUserDefined);
+    }
 
-        // Make sure our code point is in the valid range (it should be by now)
-        assert!(
-            code_point < 0x10FFFF,
-            "Decoded code-point was not in the valid unicode Range"
+    #[test]
+    fn encoders() {
+        assert_eq!(
+            CharacterEncoding::Utf8.encode("Ça va 漢"),
+            "Ça va 漢".as_bytes()
         );
+        assert_eq!(CharacterEncoding::Utf16LE.encode("漢"), "漢".as_bytes());
+        assert_eq!(
+            CharacterEncoding::Windows1252.encode("Ça va € 漢"),
+            b"\xC7a va \x80 &#28450;"
+        );
+        assert_eq!(
+            CharacterEncoding::Windows1251.encode("Привет Ç"),
+            b"\xCF\xF0\xE8\xE2\xE5\xF2 &#199;"
+        );
+        assert_eq!(CharacterEncoding::ShiftJIS.encode("a漢"), b"a&#28450;");
+
+        let mut output = Vec::new();
+        assert_eq!(
+            SingleByteEncoder::new(&WINDOWS_1252).encode('漢', &mut output),
+            Err(EncodingError::UnmappableCharacter)
+        );
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn from_bom() {
+        assert_eq!(
+            CharacterEncoding::from_bom(b"\xEF\xBB\xBF<p>"),
+            Some((CharacterEncoding::Utf8, 3))
+        );
+        assert_eq!(
+            CharacterEncoding::from_bom(b"\xFE\xFF\0<"),
+            Some((CharacterEncoding::Utf16BE, 2))
+        );
+        assert_eq!(
+            CharacterEncoding::from_bom(b"\xFF\xFE"),
+            Some((CharacterEncoding::Utf16LE, 2))
+        );
+
+        assert_eq!(CharacterEncoding::from_bom(b"<p>\xEF\xBB\xBF"), None);
+        assert_eq!(CharacterEncoding::from_bom(b"\xEF\xBB"), None);
+        assert_eq!(CharacterEncoding::from_bom(b"\xFE"), None);
+        assert_eq!(CharacterEncoding::from_bom(b""), None);
+    }
+
+    #[test]
+    fn encoding_families() {
+        use CharacterEncoding::*;
+
+        let families = |encoding: CharacterEncoding| {
+            [
+                encoding.is_utf16(),
+                encoding.is_single_byte(),
+                encoding.is_cjk_multibyte(),
+            ]
+        };
 
-        // Converting to a char should now be 100% safe since we have removed
-        // non-USV code points, and ensured it is in the valid range
-        Ok(Some((char::from_u32(code_point).unwrap(), bytes)))
+        assert_eq!(families(Utf16BE), [true, false, false]);
+        assert_eq!(families(Utf16LE), [true, false, false]);
+        assert_eq!(families(Windows1252), [false, true, false]);
+        assert_eq!(families(KOI8R), [false, true, false]);
+        assert_eq!(families(XUserDefined), [false, true, false]);
+        assert_eq!(families(ShiftJIS), [false, false, true]);
+        assert_eq!(families(ISO2022Jp), [false, false, true]);
+        assert_eq!(families(GB18030), [false, false, true]);
+
+        // Neither of the families
+        assert_eq!(families(Utf8), [false, false, false]);
+        assert_eq!(families(Replacement), [false, false, false]);
+    }
+
+    #[test]
+    #[cfg(feature = "encoding-rs")]
+    fn encoding_rs_decoder_matches_native() {
+        let all_bytes: Vec<u8> = (0..=255).collect();
+        let samples: [(CharacterEncoding, &[u8]); 5] = [
+            (CharacterEncoding::Utf8, "<p>Ça va, 世界? 💩</p>".as_bytes()),
+            (CharacterEncoding::Utf8, b"a\xFFb\x80c"),
+            (CharacterEncoding::Utf8, b"truncated \xE2\x82"),
+            (
+                CharacterEncoding::Windows1252,
+                b"<p>\xC7a va? \x80 \x81\x9D</p>",
+            ),
+            (CharacterEncoding::Windows1252, &all_bytes),
+        ];
+
+        for (encoding, bytes) in samples {
+            assert_eq!(
+                decode_all(&mut *encoding.decoder(), &mut IoQueue::from_bytes(bytes)),
+                decode_all(
+                    &mut *encoding.native_decoder(),
+                    &mut IoQueue::from_bytes(bytes)
+                ),
+                "{encoding} {bytes:?}"
+            );
+        }
     }
 }
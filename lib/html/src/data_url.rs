@@ -0,0 +1,177 @@
+/// The contents of a `data:` URL (https://fetch.spec.whatwg.org/#data-urls)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataUrl {
+    /// The lowercase essence of the MIME type (e.g. `text/html`), without its parameters
+    pub mime_type: String,
+    /// The `charset` parameter of the MIME type, which defaults to `US-ASCII` (RFC 2397)
+    pub charset: String,
+    pub body: Vec<u8>,
+}
+
+/// Parses a `data:` URL and decodes its body, which is either percent-encoded or base64
+/// (https://fetch.spec.whatwg.org/#data-url-processor)
+///
+/// Returns `None` if `input` isn't a `data:` URL, has no comma, or its base64 body is invalid.
+pub fn parse_data_url(input: &str) -> Option<DataUrl> {
+    let input = input.trim_matches(|c: char| c <= ' ');
+
+    let scheme = input.get(..5)?;
+    if !scheme.eq_ignore_ascii_case("data:") {
+        return None;
+    }
+
+    let (mime_type, body) = input[5..].split_once(',')?;
+    let mut mime_type = mime_type.trim_matches(is_ascii_whitespace);
+    let body = percent_decode(body);
+
+    // A `;base64` suffix (with optional whitespace before `base64`) means the body is base64
+    let base64 = mime_type.rsplit_once(';').and_then(|(rest, suffix)| {
+        suffix
+            .trim_start_matches(is_ascii_whitespace)
+            .eq_ignore_ascii_case("base64")
+            .then_some(rest)
+    });
+
+    let body = match base64 {
+        Some(rest) => {
+            mime_type = rest.trim_end_matches(is_ascii_whitespace);
+            forgiving_base64_decode(&body)?
+        }
+        None => body,
+    };
+
+    let mut parameters = mime_type.split(';');
+    let essence = parameters
+        .next()
+        .unwrap_or("")
+        .trim_matches(is_ascii_whitespace);
+
+    let charset = parameters
+        .filter_map(|parameter| parameter.split_once('='))
+        .find(|(name, _)| {
+            name.trim_matches(is_ascii_whitespace)
+                .eq_ignore_ascii_case("charset")
+        })
+        .map(|(_, value)| value.trim_matches(is_ascii_whitespace).trim_matches('"'));
+
+    Some(DataUrl {
+        mime_type: match essence {
+            "" => "text/plain".to_owned(),
+            essence => essence.to_ascii_lowercase(),
+        },
+        charset: charset.unwrap_or("US-ASCII").to_owned(),
+        body,
+    })
+}
+
+fn is_ascii_whitespace(c: char) -> bool {
+    matches!(c, '\t' | '\n' | '\x0C' | '\r' | ' ')
+}
+
+/// https://url.spec.whatwg.org/#percent-decode
+///
+/// `%` signs that aren't followed by two hex digits are kept as is.
+fn percent_decode(input: &str) -> Vec<u8> {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .filter(|hex| hex.bytes().all(|b| b.is_ascii_hexdigit()));
+
+        match (bytes[i], hex) {
+            (b'%', Some(hex)) => {
+                decoded.push(u8::from_str_radix(hex, 16).unwrap());
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+
+    decoded
+}
+
+/// https://infra.spec.whatwg.org/#forgiving-base64-decode
+fn forgiving_base64_decode(input: &[u8]) -> Option<Vec<u8>> {
+    let mut data: Vec<u8> = input
+        .iter()
+        .copied()
+        .filter(|b| !matches!(b, b'\t' | b'\n' | b'\x0C' | b'\r' | b' '))
+        .collect();
+
+    // Up to two padding characters are allowed, but only to make up a multiple of 4
+    if data.len().is_multiple_of(4) {
+        for _ in 0..2 {
+            if data.last() == Some(&b'=') {
+                data.pop();
+            }
+        }
+    }
+
+    if data.len() % 4 == 1 {
+        return None;
+    }
+
+    let mut decoded = Vec::with_capacity(data.len() * 3 / 4);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+
+    for byte in data {
+        let value = match byte {
+            b'A'..=b'Z' => byte - b'A',
+            b'a'..=b'z' => byte - b'a' + 26,
+            b'0'..=b'9' => byte - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+
+        buffer = buffer << 6 | value as u32;
+        bits += 6;
+
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+        }
+    }
+
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_data_urls() {
+        assert_eq!(
+            parse_data_url("data:,Hello%2C%20World%21"),
+            Some(DataUrl {
+                mime_type: "text/plain".to_owned(),
+                charset: "US-ASCII".to_owned(),
+                body: b"Hello, World!".to_vec(),
+            })
+        );
+        assert_eq!(
+            parse_data_url("DATA:Text/HTML ; Charset=\"UTF-8\" ; base64 ,PGgx Pmhp PC9oMT4="),
+            Some(DataUrl {
+                mime_type: "text/html".to_owned(),
+                charset: "UTF-8".to_owned(),
+                body: b"<h1>hi</h1>".to_vec(),
+            })
+        );
+
+        // A `%` that doesn't start an escape is kept
+        assert_eq!(parse_data_url("data:,100%").unwrap().body, b"100%");
+
+        assert_eq!(parse_data_url("data:text/html"), None);
+        assert_eq!(parse_data_url("http://mario.example/,"), None);
+        assert_eq!(parse_data_url("data:;base64,abcde"), None);
+    }
+}
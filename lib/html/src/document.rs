@@ -0,0 +1,906 @@
+use std::{
+    fmt::{Debug, Display},
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use url::Url;
+
+use crate::{
+    character_encoding::CharacterEncoding,
+    prescan::extract_encoding_from_meta,
+    serializer::{serialize, serialize_to_writer},
+    HtmlParseError, HtmlParser, SourceMap, TreeSink,
+};
+
+/// A handle to a node stored in a [`Document`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeId(usize);
+
+/// Tells documents apart, so that an [`Element`] is only ever looked up in its own document
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct DocumentId(u64);
+
+impl DocumentId {
+    fn next() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+
+        Self(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+#[derive(Debug)]
+pub struct Node {
+    pub parent: Option<NodeId>,
+    pub children: Vec<NodeId>,
+    pub data: NodeData,
+}
+
+#[derive(Debug, Clone)]
+pub enum NodeData {
+    Document,
+    /// The contents of a `template` element, which are kept out of the main tree
+    DocumentFragment,
+    Doctype {
+        name: String,
+        public_id: String,
+        system_id: String,
+    },
+    Element(Element),
+    Text(String),
+    Comment(String),
+}
+
+#[derive(Clone)]
+pub struct Element {
+    /// The document and node that hold the element
+    document: DocumentId,
+    id: NodeId,
+    pub name: String,
+    pub attributes: Vec<(String, String)>,
+    /// The content fragment of a `template` element
+    /// (https://html.spec.whatwg.org/#template-contents)
+    pub template_contents: Option<NodeId>,
+}
+
+impl Element {
+    fn new(
+        document: DocumentId,
+        id: NodeId,
+        name: String,
+        attributes: Vec<(String, String)>,
+    ) -> Self {
+        Self {
+            document,
+            id,
+            name,
+            attributes,
+            template_contents: None,
+        }
+    }
+
+    /// Gets the value of the attribute with the given (lowercase) name
+    pub fn get_attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Iterates over the attributes as `(name, value)` pairs, in source order. Attributes
+    /// without a value have an empty one.
+    pub fn attributes_iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.attributes
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    /// The node that holds the element in its document
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    /// Serializes the children of the element
+    /// (https://html.spec.whatwg.org/#dom-element-innerhtml)
+    ///
+    /// Returns `None` if the element isn't part of `document`.
+    pub fn inner_html(&self, document: &Document) -> Option<String> {
+        (self.document == document.id).then(|| document.inner_html(self.id))
+    }
+
+    /// Serializes the element itself along with its children
+    /// (https://html.spec.whatwg.org/#dom-element-outerhtml)
+    ///
+    /// Returns `None` if the element isn't part of `document`.
+    pub fn outer_html(&self, document: &Document) -> Option<String> {
+        (self.document == document.id).then(|| document.outer_html(self.id))
+    }
+}
+
+// The document is left out, since its id depends on how many documents were created before
+impl Debug for Element {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Element")
+            .field("id", &self.id)
+            .field("name", &self.name)
+            .field("attributes", &self.attributes)
+            .field("template_contents", &self.template_contents)
+            .finish()
+    }
+}
+
+/// A `form` element along with the fields it would submit
+#[derive(Debug, Clone, PartialEq)]
+pub struct FormData {
+    /// The submission method (`get`, `post` or `dialog`), in lowercase
+    pub method: String,
+    /// The URL the form is submitted to, resolved against the document's base URL
+    pub action: Url,
+    /// The names and values of the successful fields, in tree order
+    pub fields: Vec<(String, String)>,
+}
+
+/// Which rendering quirks of legacy browsers a document asks for, as decided by its doctype
+/// (https://dom.spec.whatwg.org/#concept-document-mode)
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum QuirksMode {
+    Quirks,
+    LimitedQuirks,
+    #[default]
+    NoQuirks,
+}
+
+impl FromStr for QuirksMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "quirks" => Ok(QuirksMode::Quirks),
+            "limited-quirks" => Ok(QuirksMode::LimitedQuirks),
+            "no-quirks" => Ok(QuirksMode::NoQuirks),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Display for QuirksMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            QuirksMode::Quirks => "quirks",
+            QuirksMode::LimitedQuirks => "limited-quirks",
+            QuirksMode::NoQuirks => "no-quirks",
+        })
+    }
+}
+
+/// The result of parsing an HTML document. Nodes are stored in an arena and refer to
+/// each other through [`NodeId`]s, with the document node itself at [`Document::root`].
+pub struct Document {
+    id: DocumentId,
+    encoding: CharacterEncoding,
+    quirks_mode: QuirksMode,
+    url: Option<Url>,
+    source_map: Option<SourceMap>,
+    nodes: Vec<Node>,
+    parse_errors: Vec<HtmlParseError>,
+}
+
+impl Document {
+    pub fn new(encoding: CharacterEncoding) -> Self {
+        Self {
+            id: DocumentId::next(),
+            encoding,
+            quirks_mode: QuirksMode::default(),
+            url: None,
+            source_map: None,
+            nodes: vec![Node {
+                parent: None,
+                children: Vec::new(),
+                data: NodeData::Document,
+            }],
+            parse_errors: Vec::new(),
+        }
+    }
+
+    /// The character encoding the document was decoded with
+    pub fn encoding(&self) -> CharacterEncoding {
+        self.encoding
+    }
+
+    /// https://dom.spec.whatwg.org/#concept-document-mode
+    pub fn quirks_mode(&self) -> QuirksMode {
+        self.quirks_mode
+    }
+
+    /// The URL the document was loaded from, if known
+    /// (https://dom.spec.whatwg.org/#concept-document-url)
+    pub fn url(&self) -> Option<&Url> {
+        self.url.as_ref()
+    }
+
+    pub fn set_url(&mut self, url: Url) {
+        self.url = Some(url);
+    }
+
+    /// The URL that relative URLs in the document are resolved against: the `href` of the
+    /// first `base` element that has one, or the document's URL
+    /// (https://html.spec.whatwg.org/#document-base-url)
+    pub fn base_url(&self) -> Option<Url> {
+        let base_href = self
+            .elements()
+            .filter(|(_, element)| element.name == "base")
+            .find_map(|(_, element)| element.get_attribute("href"));
+
+        match base_href {
+            Some(href) => Url::options()
+                .base_url(self.url.as_ref())
+                .parse(href)
+                .ok()
+                .or_else(|| self.url.clone()),
+            None => self.url.clone(),
+        }
+    }
+
+    /// Maps character positions in the source of the document to the bytes they were decoded
+    /// from. Only documents parsed from bytes have one.
+    pub fn source_map(&self) -> Option<&SourceMap> {
+        self.source_map.as_ref()
+    }
+
+    pub(crate) fn set_source_map(&mut self, source_map: Option<SourceMap>) {
+        self.source_map = source_map;
+    }
+
+    pub(crate) fn set_encoding(&mut self, encoding: CharacterEncoding) {
+        self.encoding = encoding;
+    }
+
+    /// Removes every node but the document node, along with the URL, source map and parse
+    /// errors, so the document can be parsed into again (see [`HtmlParser::parse_into`]). The
+    /// memory allocated for the nodes is kept.
+    ///
+    /// Elements of the cleared document are no longer part of it.
+    pub fn clear(&mut self) {
+        self.id = DocumentId::next();
+        self.nodes.truncate(1);
+        self.nodes[0].children.clear();
+        self.quirks_mode = QuirksMode::default();
+        self.url = None;
+        self.source_map = None;
+        self.parse_errors.clear();
+    }
+
+    /// The number of nodes the document can hold without reallocating
+    pub fn node_capacity(&self) -> usize {
+        self.nodes.capacity()
+    }
+
+    /// The parse errors that were recovered from while parsing the document, along with a
+    /// [`HtmlParseError::EncodingMismatch`] (which is recorded even if parsing didn't recover)
+    pub fn parse_errors(&self) -> &[HtmlParseError] {
+        &self.parse_errors
+    }
+
+    pub fn root(&self) -> NodeId {
+        NodeId(0)
+    }
+
+    pub fn get(&self, id: NodeId) -> &Node {
+        &self.nodes[id.0]
+    }
+
+    pub fn children(&self, id: NodeId) -> &[NodeId] {
+        &self.get(id).children
+    }
+
+    /// Gets the element data of a node, if it is an element
+    pub fn element(&self, id: NodeId) -> Option<&Element> {
+        match &self.get(id).data {
+            NodeData::Element(element) => Some(element),
+            _ => None,
+        }
+    }
+
+    /// The first `html` element child of the document, if any
+    pub fn document_element(&self) -> Option<NodeId> {
+        self.children(self.root())
+            .iter()
+            .copied()
+            .find(|&id| self.element(id).is_some())
+    }
+
+    /// The content fragment of `id`, if it is a `template` element
+    pub fn template_contents(&self, id: NodeId) -> Option<NodeId> {
+        self.element(id)?.template_contents
+    }
+
+    /// Concatenates the text of all the text nodes under `id`, in tree order
+    pub fn text_content(&self, id: NodeId) -> String {
+        if let NodeData::Text(text) = &self.get(id).data {
+            return text.clone();
+        }
+
+        self.descendants(id)
+            .filter_map(|(_, node)| match &node.data {
+                NodeData::Text(text) => Some(text.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The text of the first `title` element in the document, with leading and trailing
+    /// whitespace removed and runs of whitespace collapsed to a single space
+    /// (https://html.spec.whatwg.org/#document.title)
+    pub fn title(&self) -> Option<String> {
+        let (title, _) = self
+            .elements()
+            .find(|(_, element)| element.name == "title")?;
+
+        let text = self.text_content(title);
+        let words: Vec<_> = text
+            .split(['\t', '\n', '\x0C', '\r', ' '])
+            .filter(|word| !word.is_empty())
+            .collect();
+
+        Some(words.join(" "))
+    }
+
+    /// The targets of all the hyperlinks (`a`, `area` and `link` elements with an `href`) in
+    /// the document, in tree order, resolved against the document's base URL. Values that
+    /// can't be parsed as URLs and `javascript:` URLs are skipped.
+    pub fn collect_links(&self) -> Vec<Url> {
+        let base_url = self.base_url();
+
+        self.elements()
+            .filter(|(_, element)| matches!(element.name.as_str(), "a" | "area" | "link"))
+            .filter_map(|(_, element)| element.get_attribute("href"))
+            .filter_map(|href| Url::options().base_url(base_url.as_ref()).parse(href).ok())
+            .filter(|url| url.scheme() != "javascript")
+            .collect()
+    }
+
+    /// The encoding declared by the first `<meta charset>` or `<meta http-equiv="content-type">`
+    /// in the document that names a supported encoding, which may not be the one it was
+    /// decoded with (see [`Document::encoding`])
+    /// (https://html.spec.whatwg.org/#parsing-main-inhead, "A start tag whose tag name is
+    /// "meta"")
+    pub fn declared_encoding(&self) -> Option<CharacterEncoding> {
+        self.elements()
+            .filter(|(_, element)| element.name == "meta")
+            .find_map(|(_, element)| {
+                if let Some(charset) = element.get_attribute("charset") {
+                    return charset.parse().ok();
+                }
+
+                let is_content_type = element
+                    .get_attribute("http-equiv")
+                    .is_some_and(|value| value.eq_ignore_ascii_case("content-type"));
+
+                element
+                    .get_attribute("content")
+                    .filter(|_| is_content_type)
+                    .and_then(extract_encoding_from_meta)
+            })
+    }
+
+    /// The delay and target of the first `<meta http-equiv="refresh">` in the document, which
+    /// asks for the page to be reloaded or redirected (e.g. `content="5; url=/next"`). The
+    /// target is resolved against the document's base URL, and is `None` for a reload.
+    ///
+    /// Returns `None` if there is no such element, or if its content can't be parsed.
+    pub fn meta_refresh(&self) -> Option<(Duration, Option<Url>)> {
+        let content = self
+            .elements()
+            .filter(|(_, element)| element.name == "meta")
+            .filter(|(_, element)| {
+                element
+                    .get_attribute("http-equiv")
+                    .is_some_and(|value| value.eq_ignore_ascii_case("refresh"))
+            })
+            .find_map(|(_, element)| element.get_attribute("content"))?;
+
+        parse_refresh(content, self.base_url().as_ref())
+    }
+
+    /// The forms in the document, with their fields
+    /// (https://html.spec.whatwg.org/#form-submission-algorithm)
+    ///
+    /// Forms whose action can't be resolved to a URL are skipped. Fields are the named `input`,
+    /// `textarea` and `select` elements inside the form that are not disabled, with the values
+    /// they would be submitted with (unchecked checkboxes and radio buttons, and buttons, are
+    /// left out).
+    pub fn forms(&self) -> Vec<FormData> {
+        // A document without a URL is at about:blank
+        let url = self
+            .url
+            .clone()
+            .unwrap_or_else(|| Url::parse("about:blank").unwrap());
+        let base_url = self.base_url().unwrap_or_else(|| url.clone());
+
+        self.elements()
+            .filter(|(_, element)| element.name == "form")
+            .filter_map(|(id, form)| {
+                let method = form
+                    .get_attribute("method")
+                    .map(str::to_ascii_lowercase)
+                    .filter(|method| method == "post" || method == "dialog")
+                    .unwrap_or_else(|| "get".to_owned());
+
+                let action = match form.get_attribute("action") {
+                    Some(action) if !action.is_empty() => base_url.join(action).ok()?,
+                    _ => url.clone(),
+                };
+
+                let fields = self
+                    .descendant_elements(id)
+                    .filter_map(|(id, element)| self.form_field(id, element))
+                    .collect();
+
+                Some(FormData {
+                    method,
+                    action,
+                    fields,
+                })
+            })
+            .collect()
+    }
+
+    /// The name and value a form field is submitted with, if any
+    fn form_field(&self, id: NodeId, element: &Element) -> Option<(String, String)> {
+        let name = element
+            .get_attribute("name")
+            .filter(|name| !name.is_empty())?;
+
+        if element.get_attribute("disabled").is_some() {
+            return None;
+        }
+
+        let value = match element.name.as_str() {
+            "input" => {
+                let input_type = element.get_attribute("type").unwrap_or("text");
+
+                match input_type.to_ascii_lowercase().as_str() {
+                    "checkbox" | "radio" => {
+                        element.get_attribute("checked")?;
+                        element.get_attribute("value").unwrap_or("on").to_owned()
+                    }
+                    "submit" | "reset" | "button" | "image" | "file" => return None,
+                    _ => element
+                        .get_attribute("value")
+                        .unwrap_or_default()
+                        .to_owned(),
+                }
+            }
+            "textarea" => self.text_content(id),
+            "select" => {
+                let options: Vec<_> = self
+                    .descendant_elements(id)
+                    .filter(|(_, element)| element.name == "option")
+                    .collect();
+
+                let (option, element) = options
+                    .iter()
+                    .find(|(_, option)| option.get_attribute("selected").is_some())
+                    .or_else(|| options.first())?;
+
+                match element.get_attribute("value") {
+                    Some(value) => value.to_owned(),
+                    None => self.text_content(*option).trim().to_owned(),
+                }
+            }
+            _ => return None,
+        };
+
+        Some((name.to_owned(), value))
+    }
+
+    /// Whether `id` is an element matched by `selector`
+    /// (https://dom.spec.whatwg.org/#dom-element-matches)
+    ///
+    /// Only compound selectors made of a type (or `*`), `#id`, `.class` and attribute presence
+    /// (`[disabled]`) are supported, e.g. `input.search[disabled]`. Anything else, such as
+    /// combinators or selector lists, matches nothing.
+    pub fn element_matches(&self, id: NodeId, selector: &str) -> bool {
+        let Some(element) = self.element(id) else {
+            return false;
+        };
+
+        let selector = selector.trim_matches(['\t', '\n', '\x0C', '\r', ' ']);
+        let is_simple_start = |c: char| matches!(c, '#' | '.' | '[');
+        let is_name_char = |c: char| c.is_alphanumeric() || matches!(c, '-' | '_');
+
+        let (type_selector, mut rest) =
+            selector.split_at(selector.find(is_simple_start).unwrap_or(selector.len()));
+
+        match type_selector {
+            "" if rest.is_empty() => return false,
+            "" | "*" => {}
+            name if name.chars().all(is_name_char) => {
+                if !name.eq_ignore_ascii_case(&element.name) {
+                    return false;
+                }
+            }
+            _ => return false,
+        }
+
+        while let Some(kind) = rest.chars().next() {
+            rest = &rest[1..];
+
+            if kind == '[' {
+                let Some((name, after)) = rest.split_once(']') else {
+                    return false;
+                };
+                let name = name.trim_matches([' ', '\t']);
+
+                if name.is_empty() || !name.chars().all(is_name_char) {
+                    return false;
+                }
+                if element.get_attribute(&name.to_ascii_lowercase()).is_none() {
+                    return false;
+                }
+
+                rest = after;
+                continue;
+            }
+
+            let (value, after) = rest.split_at(rest.find(is_simple_start).unwrap_or(rest.len()));
+            if value.is_empty() || !value.chars().all(is_name_char) {
+                return false;
+            }
+
+            let matched = match kind {
+                '#' => element.get_attribute("id") == Some(value),
+                _ => element
+                    .get_attribute("class")
+                    .is_some_and(|class| class.split_ascii_whitespace().any(|c| c == value)),
+            };
+            if !matched {
+                return false;
+            }
+
+            rest = after;
+        }
+
+        true
+    }
+
+    /// Iterates over the elements of the main tree (not including template contents), in
+    /// tree order
+    fn elements(&self) -> impl Iterator<Item = (NodeId, &Element)> {
+        self.descendant_elements(self.root())
+    }
+
+    /// Iterates over the elements under `id` (not including `id` itself), in tree order
+    fn descendant_elements(&self, id: NodeId) -> impl Iterator<Item = (NodeId, &Element)> {
+        self.descendants(id)
+            .filter_map(|(id, _)| Some((id, self.element(id)?)))
+    }
+
+    /// Iterates over every node of the document, starting with the document node itself, in
+    /// tree order (depth-first). The contents of `template` elements are not part of the tree
+    /// (see [`Document::template_contents`]).
+    pub fn iter(&self) -> impl Iterator<Item = (NodeId, &Node)> {
+        let root = self.root();
+
+        std::iter::once((root, self.get(root))).chain(self.descendants(root))
+    }
+
+    /// Iterates over the nodes under `id` (not including `id` itself), in tree order
+    pub fn descendants(&self, id: NodeId) -> impl Iterator<Item = (NodeId, &Node)> {
+        // Walk the tree with an explicit stack so deep trees can't overflow the call stack
+        let mut stack: Vec<_> = self.children(id).iter().rev().copied().collect();
+
+        std::iter::from_fn(move || {
+            let id = stack.pop()?;
+            stack.extend(self.children(id).iter().rev());
+
+            Some((id, self.get(id)))
+        })
+    }
+
+    /// Serializes the whole document back to HTML
+    pub fn to_html(&self) -> String {
+        self.outer_html(self.root())
+    }
+
+    /// Serializes the whole document back to HTML like [`Document::to_html`], but writes it to
+    /// `writer` (as UTF-8) piece by piece instead of building it up in memory first. Writers that
+    /// are expensive to write to (e.g. a file or socket) should be wrapped in a `BufWriter`.
+    pub fn to_writer<W: std::io::Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        serialize_to_writer(self, &[self.root()], writer)
+    }
+
+    /// Serializes the children of `id` (or its contents, for a `template` element)
+    pub fn inner_html(&self, id: NodeId) -> String {
+        let contents = self.template_contents(id).unwrap_or(id);
+
+        let mut html = String::new();
+        serialize(self, self.children(contents), &mut html)
+            .expect("writing to a string can't fail");
+
+        html
+    }
+
+    /// Serializes the whole document back to HTML, encoded with `encoding` (or its output
+    /// encoding, see [`CharacterEncoding::encoder`]). Characters that the encoding can't
+    /// represent are written as numeric character references.
+    ///
+    /// Note that such references are left as is in raw text elements (e.g. `script`) and
+    /// comments when the output is parsed again.
+    pub fn to_bytes(&self, encoding: CharacterEncoding) -> Vec<u8> {
+        encoding.encode(&self.to_html())
+    }
+
+    /// Serializes `id` itself along with its children
+    pub fn outer_html(&self, id: NodeId) -> String {
+        let mut html = String::new();
+        serialize(self, &[id], &mut html).expect("writing to a string can't fail");
+
+        html
+    }
+
+    /// Returns a copy of the document where conditional comments (`<!--[if IE]>...<![endif]-->`)
+    /// are replaced by their contents, parsed as markup, like legacy versions of Internet
+    /// Explorer would reveal them. The comments that mark the start and end of downlevel-revealed
+    /// conditionals (`<![if !IE]>` and `<![endif]>`) are removed.
+    ///
+    /// This has no effect on documents parsed without comments (see
+    /// [`HtmlParser::keep_comments`](crate::HtmlParser::keep_comments)).
+    pub fn downlevel_revealed(&self) -> Document {
+        let mut revealed = Document::new(self.encoding);
+        revealed.quirks_mode = self.quirks_mode;
+        revealed.url = self.url.clone();
+        revealed.parse_errors = self.parse_errors.clone();
+
+        let root = revealed.root();
+        self.copy_revealed_children(self.root(), &mut revealed, root);
+
+        revealed
+    }
+
+    /// Copies the descendants of `from` to `parent` in `into`, revealing conditional comments
+    fn copy_revealed_children(&self, from: NodeId, into: &mut Document, parent: NodeId) {
+        let mut stack: Vec<_> = self
+            .children(from)
+            .iter()
+            .rev()
+            .map(|&child| (child, parent))
+            .collect();
+
+        while let Some((id, parent)) = stack.pop() {
+            if let NodeData::Comment(comment) = &self.get(id).data {
+                if let Some(markup) = conditional_comment_contents(comment) {
+                    let fragment = HtmlParser::from_chars(markup.chars())
+                        .keep_comments(true)
+                        .parse();
+                    fragment.copy_revealed_children(fragment.root(), into, parent);
+
+                    continue;
+                }
+
+                if is_downlevel_revealed_marker(comment) {
+                    continue;
+                }
+            }
+
+            let mut data = self.get(id).data.clone();
+            if let NodeData::Element(element) = &mut data {
+                element.template_contents = None;
+            }
+
+            let copy = into.create_node(data);
+            into.append(&parent, copy);
+
+            let children = match self.template_contents(id) {
+                Some(contents) => (contents, into.create_template_contents(&copy)),
+                None => (id, copy),
+            };
+
+            stack.extend(
+                self.children(children.0)
+                    .iter()
+                    .rev()
+                    .map(|&child| (child, children.1)),
+            );
+        }
+    }
+
+    /// The number of ancestors of `id` (the document node has a depth of 0)
+    pub fn depth(&self, id: NodeId) -> usize {
+        std::iter::successors(self.get(id).parent, |&parent| self.get(parent).parent).count()
+    }
+
+    /// Creates a new node that is not attached to the tree yet
+    fn create_node(&mut self, data: NodeData) -> NodeId {
+        let id = self.next_node_id();
+
+        self.nodes.push(Node {
+            parent: None,
+            children: Vec::new(),
+            data,
+        });
+
+        id
+    }
+
+    /// The id of the node that is created next
+    fn next_node_id(&self) -> NodeId {
+        NodeId(self.nodes.len())
+    }
+}
+
+/// The markup inside a downlevel-hidden conditional comment (`<!--[if IE]>...<![endif]-->`)
+fn conditional_comment_contents(comment: &str) -> Option<&str> {
+    let (_condition, markup) = comment.strip_prefix("[if ")?.split_once("]>")?;
+
+    markup.strip_suffix("<![endif]")
+}
+
+/// Whether a comment is the start or end marker of a downlevel-revealed conditional comment
+/// (`<![if !IE]>` or `<![endif]>`), which are parsed as bogus comments
+fn is_downlevel_revealed_marker(comment: &str) -> bool {
+    comment == "[endif]" || (comment.starts_with("[if ") && comment.ends_with(']'))
+}
+
+/// https://html.spec.whatwg.org/#shared-declarative-refresh-steps
+fn parse_refresh(content: &str, base_url: Option<&Url>) -> Option<(Duration, Option<Url>)> {
+    let is_whitespace = |c: char| matches!(c, '\t' | '\n' | '\x0C' | '\r' | ' ');
+
+    // Steps 2 to 6: the delay in seconds, which may only be left out before a fraction
+    let input = content.trim_start_matches(is_whitespace);
+    let digits = input
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(input.len());
+    let (time, input) = input.split_at(digits);
+
+    if time.is_empty() && !input.starts_with('.') {
+        return None;
+    }
+
+    let time = time
+        .parse()
+        .unwrap_or(if time.is_empty() { 0 } else { u64::MAX });
+
+    // Step 7: the fraction is ignored
+    let input = input.trim_start_matches(|c: char| c.is_ascii_digit() || c == '.');
+
+    // Step 9: the delay is separated from the URL by whitespace, `;` or `,`
+    if input.starts_with(|c| !(is_whitespace(c) || c == ';' || c == ',')) {
+        return None;
+    }
+
+    let input = input.trim_start_matches(is_whitespace);
+    let input = input.strip_prefix([';', ',']).unwrap_or(input);
+    let url = input.trim_start_matches(is_whitespace);
+
+    if url.is_empty() {
+        return Some((Duration::from_secs(time), None));
+    }
+
+    // Step 10: the URL may be preceded by `url=` and quoted
+    let url = match url.get(..3) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("url") => {
+            match url[3..].trim_start_matches(is_whitespace).strip_prefix('=') {
+                Some(rest) => unquote(rest.trim_start_matches(is_whitespace)),
+                None => url,
+            }
+        }
+        _ => unquote(url),
+    };
+
+    let url = Url::options().base_url(base_url).parse(url).ok()?;
+
+    Some((Duration::from_secs(time), Some(url)))
+}
+
+/// Strips a leading quote from `value`, and everything from the matching quote onwards
+fn unquote(value: &str) -> &str {
+    match value.chars().next() {
+        Some(quote @ ('"' | '\'')) => value[1..].split(quote).next().unwrap_or_default(),
+        _ => value,
+    }
+}
+
+impl TreeSink for Document {
+    type Handle = NodeId;
+    type Output = Document;
+
+    fn document(&mut self) -> NodeId {
+        self.root()
+    }
+
+    fn create_element(&mut self, name: String, attributes: Vec<(String, String)>) -> NodeId {
+        let element = Element::new(self.id, self.next_node_id(), name, attributes);
+
+        self.create_node(NodeData::Element(element))
+    }
+
+    /// Creates the (parentless) content fragment of a `template` element
+    fn create_template_contents(&mut self, template: &NodeId) -> NodeId {
+        let id = self.create_node(NodeData::DocumentFragment);
+
+        if let NodeData::Element(element) = &mut self.nodes[template.0].data {
+            element.template_contents = Some(id);
+        }
+
+        id
+    }
+
+    fn append(&mut self, parent: &NodeId, child: NodeId) {
+        self.nodes[child.0].parent = Some(*parent);
+        self.nodes[parent.0].children.push(child);
+    }
+
+    fn insert_before(&mut self, sibling: &NodeId, child: NodeId) {
+        let parent = self.nodes[sibling.0]
+            .parent
+            .expect("can only insert before a node that has a parent");
+        let index = self
+            .children(parent)
+            .iter()
+            .position(|id| id == sibling)
+            .expect("a node is one of the children of its parent");
+
+        self.nodes[child.0].parent = Some(parent);
+        self.nodes[parent.0].children.insert(index, child);
+    }
+
+    /// Inserts text before `sibling`, merging it into the previous sibling if that is a text
+    /// node
+    fn insert_text_before(&mut self, sibling: &NodeId, text: &str) {
+        if let Some(parent) = self.nodes[sibling.0].parent {
+            let children = self.children(parent);
+            let index = children.iter().position(|id| id == sibling).unwrap_or(0);
+
+            if let Some(&previous) = index.checked_sub(1).map(|index| &children[index]) {
+                if let NodeData::Text(existing) = &mut self.nodes[previous.0].data {
+                    existing.push_str(text);
+                    return;
+                }
+            }
+        }
+
+        let child = self.create_node(NodeData::Text(text.to_owned()));
+        self.insert_before(sibling, child);
+    }
+
+    /// Appends text to `parent`, merging it into the last child if that is a text node
+    fn append_text(&mut self, parent: &NodeId, text: &str) {
+        if let Some(&last) = self.children(*parent).last() {
+            if let NodeData::Text(existing) = &mut self.nodes[last.0].data {
+                existing.push_str(text);
+                return;
+            }
+        }
+
+        let child = self.create_node(NodeData::Text(text.to_owned()));
+        self.append(parent, child);
+    }
+
+    fn append_comment(&mut self, parent: &NodeId, comment: String) {
+        let child = self.create_node(NodeData::Comment(comment));
+        self.append(parent, child);
+    }
+
+    fn append_doctype(&mut self, name: String, public_id: String, system_id: String) {
+        let child = self.create_node(NodeData::Doctype {
+            name,
+            public_id,
+            system_id,
+        });
+        self.append(&self.root(), child);
+    }
+
+    fn set_quirks_mode(&mut self, mode: QuirksMode) {
+        self.quirks_mode = mode;
+    }
+
+    fn parse_error(&mut self, error: HtmlParseError) {
+        self.parse_errors.push(error);
+    }
+
+    fn finish(self) -> Document {
+        self
+    }
+}
@@ -1,5 +1,8 @@
+use crate::{CharacterEncoding, QuirksMode};
+
 /// Represents well defined tokenization and tree construction
 /// errors in the spec (https://html.spec.whatwg.org/#parse-errors)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum HtmlParseError {
     /// This error occurs if the parser encounters an empty comment that is
     /// abruptly closed by a `U+003E` (`>`) code point (i.e., `<!-->` or `<!--->`).
@@ -459,6 +462,68 @@ pub enum HtmlParseError {
     ///
     /// In this case the parser doesn't resolve the character reference.
     UnknownNamedCharacterReference,
+
+    /* Tree construction errors (these are not individually named by the spec) */
+    /// This error occurs if the parser encounters a DOCTYPE anywhere other than
+    /// at the start of the document.
+    ///
+    /// The DOCTYPE is ignored.
+    UnexpectedDoctype,
+
+    /// This error occurs if the parser encounters an end tag that does not match
+    /// any open element.
+    ///
+    /// The end tag is ignored.
+    UnexpectedEndTag,
+
+    /// This error occurs if the parser encounters a start tag that is not allowed where it
+    /// appears, such as most elements inside a `select`.
+    ///
+    /// The start tag is ignored, or closes the element it is not allowed in.
+    UnexpectedStartTag,
+
+    /// This error occurs if text or an element that doesn't belong in a table appears
+    /// directly inside of one (e.g. `<table>text<tr>`), or if a table is nested directly
+    /// inside another one.
+    ///
+    /// The misplaced content is inserted right before the table instead (this is called
+    /// foster parenting), and a nested table closes the outer one.
+    UnexpectedContentInTable,
+
+    /// This error occurs in XML mode if an element is closed by the end tag of one of its
+    /// ancestors, or is still open at the end of the document. Both make the document
+    /// malformed XML.
+    ///
+    /// The element is closed.
+    UnclosedElement,
+
+    /* Input errors (these are not parse errors in the spec) */
+    /// This error occurs if the URL given to [`crate::HtmlParser::from_data_url`] is not a valid
+    /// `data:` URL (e.g. it has no comma, or an invalid base64 body), or isn't `text/html`.
+    ///
+    /// Nothing is parsed.
+    InvalidDataUrl,
+
+    /* Diagnostics (these are not parse errors in the spec) */
+    /// This diagnostic is reported when the byte order mark, the transport layer and the
+    /// `<meta>` declaration of a document disagree on its character encoding. Sources that
+    /// were not present are `None`.
+    ///
+    /// The encoding is picked by the usual precedence (BOM, then transport layer, then `<meta>`).
+    /// This never aborts parsing, so [`crate::HtmlParser::try_parse`] records it in
+    /// [`crate::Document::parse_errors`] as well.
+    EncodingMismatch {
+        transport: Option<CharacterEncoding>,
+        bom: Option<CharacterEncoding>,
+        meta: Option<CharacterEncoding>,
+    },
+
+    /// This diagnostic is reported when the doctype of a document is one of the legacy ones
+    /// that put it in quirks or limited-quirks mode (e.g. an HTML 4.01 Transitional doctype),
+    /// which changes how it is rendered.
+    ///
+    /// The document is parsed as usual.
+    LegacyDoctype { quirks_mode: QuirksMode },
 }
 
 pub type HtmlParseResult<T> = Result<T, HtmlParseError>;
@@ -0,0 +1,72 @@
+//! Entry points for fuzzing the byte handling of the crate (the pre-scan, the decoders and the
+//! tokenizer). They take arbitrary bytes and must never panic and always terminate, which is
+//! what a fuzzer checks for.
+
+use crate::{io_queue::IoQueue, prescan, CharacterEncoding, Lexer, StreamLexer, TokenKind};
+
+/// Pre-scans `data` for a declared character encoding
+pub fn fuzz_prescan(data: &[u8]) {
+    let _ = prescan::prescan_bytes(data);
+}
+
+/// Decodes all of `data` with the decoder for `encoding`
+pub fn fuzz_decode(encoding: CharacterEncoding, data: &[u8]) {
+    let mut io_queue = IoQueue::from_bytes(data);
+    let mut decoder = encoding.decoder();
+
+    // Every call consumes at least one byte, so this ends with the input
+    while !matches!(decoder.decode(&mut io_queue), Ok(None)) {}
+}
+
+/// Tokenizes `data` as UTF-8 up to the end-of-file token, recovering from every parse error
+pub fn fuzz_tokenize(data: &[u8]) {
+    let mut lexer = StreamLexer::new(data, CharacterEncoding::Utf8);
+
+    loop {
+        if let Ok(token) = lexer.next_token() {
+            if let TokenKind::Eof = token.kind {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prescan_regressions() {
+        // `extract_encoding_from_meta` used to slice past the end of the value, or with
+        // offsets relative to the wrong position
+        fuzz_prescan(b"<meta content=charset>");
+        fuzz_prescan(b"<meta content=\"charset \">");
+        fuzz_prescan(b"<meta content=\"charset=\">");
+        fuzz_prescan(b"<meta content=\"charset='utf-8\">");
+        fuzz_prescan(b"<meta content=\"x charset=utf-8;\">");
+        fuzz_prescan(b"<meta content=\"\xE9\xE9charset=utf-8\">");
+
+        // Buffers too short to hold anything the pre-scan looks for
+        fuzz_prescan(b"");
+        fuzz_prescan(b"<");
+    }
+
+    #[test]
+    fn decode_regressions() {
+        // A 4 byte sequence past the end of the Unicode range
+        fuzz_decode(CharacterEncoding::Utf8, b"\xF7\x92\xAA\xBB");
+        fuzz_decode(CharacterEncoding::Utf8, b"\xF0\x9F");
+        fuzz_decode(CharacterEncoding::ISO2022Jp, b"\x1B$B\x30");
+        fuzz_decode(CharacterEncoding::ISO2022Jp, b"\x1B(");
+        fuzz_decode(CharacterEncoding::Windows1252, b"\x81\x8D\x8F\x90\x9D");
+        fuzz_decode(CharacterEncoding::Utf16LE, b"\x00\xD8\x00\xD8\x00");
+    }
+
+    #[test]
+    fn tokenize_regressions() {
+        fuzz_tokenize(b"<!DOCTYPE");
+        fuzz_tokenize(b"<div id=");
+        fuzz_tokenize(b"</ p><![CDATA[<?&#x");
+        fuzz_tokenize(b"<script><!--<script>\xFF");
+    }
+}
@@ -1,145 +1,330 @@
 use std::{
-    cell::RefCell,
     collections::VecDeque,
-    io::{BufReader, Read},
+    io::{BufReader, Read, Seek, SeekFrom},
 };
 
-pub struct IoQueue<R> {
-    stream: RefCell<BufReader<R>>,
-    peeked: RefCell<VecDeque<u8>>,
-    bytes_read: RefCell<usize>,
+/// The most bytes that [`IoQueue::reserve`] allocates up front, so a bogus length (e.g. a
+/// `Content-Length` header claiming gigabytes) can't make the queue allocate it all at once
+pub const MAX_PREALLOCATION: usize = 1 << 20;
+
+/// A byte stream with an arbitrarily long lookahead buffer
+///
+/// Peeking reads into the buffer, so every method that can touch the underlying stream takes
+/// `&mut self` (rather than hiding the mutation behind `RefCell`s, where overlapping borrows
+/// would only be caught at runtime).
+pub struct IoQueue<'a, R> {
+    input: Input<'a, R>,
+    /// The length of the whole stream, when it is known up front
+    total_len: Option<u64>,
 }
 
-impl<R: Read> IoQueue<R> {
+enum Input<'a, R> {
+    /// A stream that is read as bytes are needed, keeping the bytes that were peeked
+    Reader {
+        stream: BufReader<R>,
+        peeked: VecDeque<u8>,
+        bytes_read: usize,
+    },
+    /// Bytes that are already in memory (see [`IoQueue::from_bytes`]), which are indexed
+    /// directly instead of being copied through a reader and the peek buffer
+    Memory {
+        bytes: &'a [u8],
+        position: usize,
+        /// The number of bytes after `position` that count as peeked
+        peeked: usize,
+    },
+}
+
+impl<R: Read> IoQueue<'_, R> {
     pub fn new(stream: R) -> Self {
         Self {
-            stream: RefCell::new(BufReader::new(stream)),
-            peeked: RefCell::new(VecDeque::new()),
-            bytes_read: RefCell::new(0),
+            input: Input::Reader {
+                stream: BufReader::new(stream),
+                peeked: VecDeque::new(),
+                bytes_read: 0,
+            },
+            total_len: None,
+        }
+    }
+
+    /// The number of bytes in the stream from where the queue started reading, if it is known
+    /// (see [`IoQueue::new_seekable`])
+    pub fn total_len(&self) -> Option<u64> {
+        self.total_len
+    }
+
+    /// Whether the queue reads bytes that are already in memory (see [`IoQueue::from_bytes`]),
+    /// which can be peeked at no cost
+    pub fn is_in_memory(&self) -> bool {
+        matches!(self.input, Input::Memory { .. })
+    }
+
+    /// Reserves room in the peek buffer for `additional` bytes (up to [`MAX_PREALLOCATION`]),
+    /// for when the length of the stream is known up front. Queues over bytes that are already
+    /// in memory don't need a peek buffer, so this does nothing for them.
+    pub fn reserve(&mut self, additional: usize) {
+        if let Input::Reader { peeked, .. } = &mut self.input {
+            peeked.reserve(usize::min(additional, MAX_PREALLOCATION));
         }
     }
 
+    /// The number of bytes the peek buffer can hold without reallocating. Queues over bytes that
+    /// are already in memory have no peek buffer, so this is 0 for them.
+    pub fn capacity(&self) -> usize {
+        match &self.input {
+            Input::Reader { peeked, .. } => peeked.capacity(),
+            Input::Memory { .. } => 0,
+        }
+    }
+
+    /// Releases the memory the peek buffer holds beyond the bytes that are still peeked (e.g.
+    /// once the bytes peeked while sniffing the encoding are consumed). Consumed bytes are
+    /// dropped from the buffer right away, but its allocation only ever grows otherwise.
+    ///
+    /// There is no way to rewind the queue, so nothing that was consumed is kept around, and
+    /// the buffer never holds more than what was peeked. Queues over bytes that are already in
+    /// memory have no buffer of their own, so this does nothing for them.
+    pub fn compact(&mut self) {
+        if let Input::Reader { peeked, .. } = &mut self.input {
+            peeked.shrink_to_fit();
+        }
+    }
+
+    /// The bytes that can be looked at without reading from the underlying stream: the rest of
+    /// the input for queues over bytes that are already in memory, or the start of the peek
+    /// buffer otherwise (which can be empty even if the stream has more)
+    pub fn buffered(&self) -> &[u8] {
+        match &self.input {
+            Input::Reader { peeked, .. } => peeked.as_slices().0,
+            Input::Memory {
+                bytes, position, ..
+            } => &bytes[*position..],
+        }
+    }
+
+    /// The number of bytes taken from the underlying stream so far, including the ones that
+    /// were only peeked
     pub fn bytes_read(&self) -> usize {
-        *self.bytes_read.borrow()
+        match &self.input {
+            Input::Reader { bytes_read, .. } => *bytes_read,
+            Input::Memory {
+                position, peeked, ..
+            } => position + peeked,
+        }
+    }
+
+    /// The number of bytes consumed so far, which is the offset of the next byte in the stream
+    /// (unlike [`IoQueue::bytes_read`], bytes that were only peeked don't count)
+    pub fn position(&self) -> usize {
+        self.bytes_read() - self.peek_len()
     }
 
     pub fn next_byte(&mut self) -> Option<u8> {
-        let mut peeked = self.peeked.borrow_mut();
+        let (stream, peeked, bytes_read) = match &mut self.input {
+            Input::Reader {
+                stream,
+                peeked,
+                bytes_read,
+            } => (stream, peeked, bytes_read),
+            Input::Memory {
+                bytes,
+                position,
+                peeked,
+            } => {
+                let byte = *bytes.get(*position)?;
+                *position += 1;
+                *peeked = peeked.saturating_sub(1);
+
+                return Some(byte);
+            }
+        };
 
         if !peeked.is_empty() {
             return peeked.pop_front();
         }
 
-        let mut stream = self.stream.borrow_mut();
-
         let mut buf = vec![0; 1];
-        let bytes_read = stream
+        let read = stream
             .read(&mut buf)
             .expect("Could not read from byte stream");
 
-        if bytes_read == 0 {
+        if read == 0 {
             return None;
         }
 
-        *self.bytes_read.borrow_mut() += 1;
+        *bytes_read += 1;
 
         Some(buf[0])
     }
 
-    pub fn peek(&self) -> Option<u8> {
-        let mut peeked = self.peeked.borrow_mut();
+    /// Discards the next `n` bytes, returning the number of bytes actually skipped (which is
+    /// less than `n` if the stream ended first)
+    pub fn skip(&mut self, n: usize) -> usize {
+        let (stream, peeked, bytes_read) = match &mut self.input {
+            Input::Reader {
+                stream,
+                peeked,
+                bytes_read,
+            } => (stream, peeked, bytes_read),
+            Input::Memory {
+                bytes,
+                position,
+                peeked,
+            } => {
+                let skipped = usize::min(n, bytes.len() - *position);
+                *position += skipped;
+                *peeked = peeked.saturating_sub(skipped);
+
+                return skipped;
+            }
+        };
 
-        if !peeked.is_empty() {
-            return peeked.front().copied();
-        }
+        let from_peeked = usize::min(n, peeked.len());
+        peeked.drain(..from_peeked);
 
-        let mut stream = self.stream.borrow_mut();
+        let remaining = (n - from_peeked) as u64;
+        let skipped = std::io::copy(&mut stream.by_ref().take(remaining), &mut std::io::sink())
+            .expect("Could not read from byte stream") as usize;
 
-        let mut buf = vec![0; 1];
-        let bytes_read = stream
-            .read(&mut buf)
-            .expect("Could not read from byte stream");
+        *bytes_read += skipped;
 
-        if bytes_read == 0 {
-            return None;
-        }
+        from_peeked + skipped
+    }
+
+    /// Consumes bytes for as long as they match the predicate, returning them
+    pub fn read_while(&mut self, pred: impl Fn(u8) -> bool) -> Vec<u8> {
+        let count = self.peek_while(pred);
+        let bytes = (0..count).map(|n| self.peeked_byte(n)).collect();
+        self.skip(count);
 
-        *self.bytes_read.borrow_mut() += 1;
+        bytes
+    }
+
+    /// Counts the leading bytes that match the predicate, without consuming them
+    pub fn peek_while(&mut self, pred: impl Fn(u8) -> bool) -> usize {
+        let mut count = 0;
 
-        peeked.push_back(buf[0]);
+        while self.peek_nth(count).is_some_and(&pred) {
+            count += 1;
+        }
 
-        peeked.front().copied()
+        count
     }
 
-    pub fn peek_nth(&self, n: usize) -> Option<u8> {
-        let mut peeked = self.peeked.borrow_mut();
+    pub fn peek(&mut self) -> Option<u8> {
+        self.peek_nth(0)
+    }
+
+    pub fn peek_nth(&mut self, n: usize) -> Option<u8> {
+        let (stream, peeked, bytes_read) = match &mut self.input {
+            Input::Reader {
+                stream,
+                peeked,
+                bytes_read,
+            } => (stream, peeked, bytes_read),
+            Input::Memory {
+                bytes,
+                position,
+                peeked,
+            } => {
+                // Like a reader, peeking past the end takes everything that is left
+                let remaining = bytes.len() - *position;
+                *peeked = usize::max(*peeked, usize::min(n + 1, remaining));
+
+                return bytes.get(*position + n).copied();
+            }
+        };
 
         if peeked.len() > n {
             return peeked.get(n).copied();
         }
 
-        let mut stream = self.stream.borrow_mut();
-
         let chars_to_peek = n + 1 - peeked.len();
 
-        let mut buf = vec![0; chars_to_peek];
-        let bytes_read = stream
-            .read(&mut buf)
+        // A single read can return fewer bytes than asked for without the stream having ended
+        // (e.g. a chunked response that is delivered incrementally), so keep reading until
+        // there are enough bytes or the stream ends
+        let mut buf = Vec::with_capacity(chars_to_peek);
+        let read = stream
+            .by_ref()
+            .take(chars_to_peek as u64)
+            .read_to_end(&mut buf)
             .expect("Could not read from byte stream");
 
-        buf.iter()
-            .take(bytes_read)
-            .for_each(|b| peeked.push_back(*b));
+        *bytes_read += read;
 
-        if bytes_read < chars_to_peek {
-            return None;
-        }
-
-        *self.bytes_read.borrow_mut() += bytes_read;
+        peeked.extend(buf);
 
-        return peeked.get(n).copied();
+        peeked.get(n).copied()
     }
 
-    pub fn peek_arr(&self, n: usize) -> Vec<u8> {
-        let mut res = Vec::with_capacity(n);
-        let mut i = 0;
-
-        while let Some(b) = self.peek_nth(i) {
-            res.push(b);
+    /// Peeks the next `N` bytes, or returns `None` if the stream ends before that
+    pub fn peek_n<const N: usize>(&mut self) -> Option<[u8; N]> {
+        if N > 0 {
+            self.peek_nth(N - 1)?;
+        }
 
-            if i >= n {
-                break;
-            }
+        Some(std::array::from_fn(|i| self.peeked_byte(i)))
+    }
 
-            i += 1;
+    /// Gets the `n`th byte of the peek buffer, which must already hold it
+    fn peeked_byte(&self, n: usize) -> u8 {
+        match &self.input {
+            Input::Reader { peeked, .. } => peeked[n],
+            Input::Memory {
+                bytes, position, ..
+            } => bytes[position + n],
         }
-
-        res
     }
 
-    pub fn has_next(&self) -> bool {
+    pub fn has_next(&mut self) -> bool {
         self.peek().is_some()
     }
 
-    pub fn has_next_nth(&self, n: usize) -> bool {
+    pub fn has_next_nth(&mut self, n: usize) -> bool {
         self.peek_nth(n).is_some()
     }
 
     /// Reads bytes into the peek buffer while it contains less than `max` bytes
-    pub fn peek_max(&self, max: usize) {
-        // TODO: optimize
-        for n in 0..max {
-            if self.peek_nth(n).is_none() {
-                return;
+    pub fn peek_max(&mut self, max: usize) {
+        if max > 0 {
+            self.peek_nth(max - 1);
+        }
+    }
+
+    /// Reads the rest of the stream into the peek buffer
+    pub fn peek_to_end(&mut self) {
+        match &mut self.input {
+            Input::Reader {
+                stream,
+                peeked,
+                bytes_read,
+            } => {
+                let mut buf = Vec::new();
+                *bytes_read += stream
+                    .read_to_end(&mut buf)
+                    .expect("Could not read from byte stream");
+
+                peeked.extend(buf);
             }
+            Input::Memory {
+                bytes,
+                position,
+                peeked,
+            } => *peeked = bytes.len() - *position,
         }
     }
 
     pub fn peek_len(&self) -> usize {
-        self.peeked.borrow().len()
+        match &self.input {
+            Input::Reader { peeked, .. } => peeked.len(),
+            Input::Memory { peeked, .. } => *peeked,
+        }
     }
 
-    pub fn contains_bytes(&self, start_pos: usize, bytes: &[u8]) -> bool {
+    /// Whether the bytes at `start_pos` (relative to the next byte) are `bytes`, without
+    /// consuming them. An empty pattern always matches.
+    pub fn contains_bytes(&mut self, start_pos: usize, bytes: &[u8]) -> bool {
         for (i, byte) in bytes.iter().enumerate() {
             let Some(b) = self.peek_nth(start_pos + i) else {
                 return false;
@@ -153,7 +338,27 @@ impl<R: Read> IoQueue<R> {
         true
     }
 
-    pub fn matches_sequence(&self, start_pos: usize, sequence: &[Vec<u8>]) -> bool {
+    /// Whether the bytes at `start_pos` (relative to the next byte) are the ASCII string `s`,
+    /// without consuming them
+    pub fn starts_with_ascii(&mut self, start_pos: usize, s: &str) -> bool {
+        debug_assert!(s.is_ascii(), "non-ASCII pattern");
+
+        self.contains_bytes(start_pos, s.as_bytes())
+    }
+
+    /// Like [`IoQueue::starts_with_ascii`], but ASCII letters match in either case
+    pub fn starts_with_ascii_ignore_case(&mut self, start_pos: usize, s: &str) -> bool {
+        debug_assert!(s.is_ascii(), "non-ASCII pattern");
+
+        s.bytes().enumerate().all(|(i, byte)| {
+            self.peek_nth(start_pos + i)
+                .is_some_and(|b| b.eq_ignore_ascii_case(&byte))
+        })
+    }
+
+    /// Like [`IoQueue::contains_bytes`], but each element of `sequence` is the set of bytes
+    /// that are allowed at its offset. An empty sequence always matches.
+    pub fn matches_sequence(&mut self, start_pos: usize, sequence: &[Vec<u8>]) -> bool {
         for (i, possible_bytes) in sequence.iter().enumerate() {
             let Some(byte) = self.peek_nth(start_pos + i) else {
                 return false;
@@ -168,10 +373,289 @@ impl<R: Read> IoQueue<R> {
     }
 }
 
-impl<R: Read> Iterator for IoQueue<R> {
-    type Item = u8;
+impl<'a> IoQueue<'a, &'a [u8]> {
+    /// Creates a queue over bytes that are already in memory. Unlike [`IoQueue::new`], the
+    /// bytes are read straight from the slice.
+    pub fn from_bytes(bytes: &'a [u8]) -> Self {
+        Self {
+            input: Input::Memory {
+                bytes,
+                position: 0,
+                peeked: 0,
+            },
+            total_len: Some(bytes.len() as u64),
+        }
+    }
+}
+
+impl<R: Read + Seek> IoQueue<'_, R> {
+    /// Creates a queue over a stream that can seek, which lets it find out the length of the
+    /// stream up front (see [`IoQueue::total_len`]). The length counts from the current position
+    /// of the stream, which is left where it was.
+    pub fn new_seekable(mut stream: R) -> Self {
+        let total_len = stream.stream_position().and_then(|start| {
+            let end = stream.seek(SeekFrom::End(0))?;
+            stream.seek(SeekFrom::Start(start))?;
+
+            Ok(end.saturating_sub(start))
+        });
+
+        Self {
+            total_len: total_len.ok(),
+            ..Self::new(stream)
+        }
+    }
+}
+
+impl<'a> From<&'a [u8]> for IoQueue<'a, &'a [u8]> {
+    fn from(bytes: &'a [u8]) -> Self {
+        Self::from_bytes(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_bytes() {
+        let mut io_queue = IoQueue::from_bytes(b"<html>");
+
+        assert_eq!(io_queue.next_byte(), Some(b'<'));
+        assert_eq!(io_queue.peek_n(), Some(*b"html>"));
+        assert_eq!(io_queue.skip(5), 5);
+        assert_eq!(io_queue.next_byte(), None);
+
+        let mut io_queue = IoQueue::from(&b"\xEF\xBB\xBF"[..]);
+        assert_eq!(io_queue.next_byte(), Some(0xEF));
+        assert_eq!(io_queue.bytes_read(), 1);
+    }
+
+    #[test]
+    fn reserve() {
+        let mut io_queue = IoQueue::new(&b"<html>"[..]);
+        io_queue.reserve(4096);
+        assert!(io_queue.capacity() >= 4096);
+
+        // Lengths past the limit are capped
+        let mut io_queue = IoQueue::new(&b""[..]);
+        io_queue.reserve(usize::MAX);
+        assert!((MAX_PREALLOCATION..usize::MAX).contains(&io_queue.capacity()));
+    }
+
+    #[test]
+    fn skip() {
+        let mut io_queue = IoQueue::from_bytes(&[1, 2, 3, 4, 5, 6]);
+
+        assert_eq!(io_queue.skip(3), 3);
+        assert_eq!(io_queue.next_byte(), Some(4));
+    }
+
+    #[test]
+    fn total_len() {
+        let bytes = b"<!DOCTYPE html><p>Hi!";
+
+        let mut cursor = std::io::Cursor::new(&bytes[..]);
+        assert_eq!(IoQueue::new_seekable(&mut cursor).total_len(), Some(21));
+
+        // Only the bytes after the current position count, and it stays the same as the queue
+        // is read
+        cursor.set_position(15);
+        let mut io_queue = IoQueue::new_seekable(cursor);
+        assert_eq!(io_queue.total_len(), Some(6));
+        assert_eq!(io_queue.next_byte(), Some(b'<'));
+        assert_eq!(io_queue.total_len(), Some(6));
+
+        assert_eq!(IoQueue::from_bytes(bytes).total_len(), Some(21));
+        assert_eq!(IoQueue::new(&bytes[..]).total_len(), None);
+    }
+
+    #[test]
+    fn compact() {
+        let bytes = vec![b'a'; 4096];
+        let mut io_queue = IoQueue::new(&bytes[..]);
+
+        io_queue.peek_max(4096);
+        assert!(io_queue.capacity() >= 4096);
+
+        // Consuming past the peeked bytes leaves the buffer empty, but still allocated
+        assert_eq!(io_queue.skip(4000), 4000);
+        assert!(io_queue.capacity() >= 4096);
+
+        io_queue.compact();
+        assert!(io_queue.capacity() < 4096);
+        assert_eq!(io_queue.peek_len(), 96);
+        assert_eq!(io_queue.skip(usize::MAX), 96);
+
+        io_queue.compact();
+        assert_eq!(io_queue.capacity(), 0);
+    }
+
+    #[test]
+    fn read_while() {
+        let mut io_queue = IoQueue::from_bytes(b" \t\n 1234;");
+
+        assert_eq!(io_queue.peek_while(|b| b.is_ascii_whitespace()), 4);
+        assert_eq!(io_queue.read_while(|b| b.is_ascii_whitespace()), b" \t\n ");
+        assert_eq!(io_queue.read_while(|b| b.is_ascii_whitespace()), b"");
+        assert_eq!(io_queue.read_while(|b| b.is_ascii_digit()), b"1234");
+        assert_eq!(io_queue.next_byte(), Some(b';'));
+        assert_eq!(io_queue.peek_while(|_| true), 0);
+    }
+
+    #[test]
+    fn skip_peeked() {
+        let mut io_queue = IoQueue::from_bytes(&[1, 2, 3, 4, 5, 6]);
+        io_queue.peek_nth(1);
+
+        assert_eq!(io_queue.skip(3), 3);
+        assert_eq!(io_queue.next_byte(), Some(4));
+        assert_eq!(io_queue.skip(10), 2);
+        assert_eq!(io_queue.next_byte(), None);
+    }
+
+    #[test]
+    fn peek_n() {
+        let mut io_queue = IoQueue::from_bytes(&[1, 2, 3, 4]);
+
+        assert_eq!(io_queue.peek_n::<3>(), Some([1, 2, 3]));
+        assert_eq!(io_queue.peek_n::<4>(), Some([1, 2, 3, 4]));
+        assert_eq!(io_queue.peek_n::<0>(), Some([]));
+
+        // Peeking doesn't consume anything, even if there are not enough bytes
+        assert_eq!(io_queue.peek_n::<5>(), None);
+        assert_eq!(io_queue.next_byte(), Some(1));
+        assert_eq!(io_queue.peek_n::<3>(), Some([2, 3, 4]));
+        assert_eq!(io_queue.peek_n::<4>(), None);
+    }
+
+    #[test]
+    fn interleaved_peeks_and_reads() {
+        let mut io_queue = IoQueue::new(Trickle(b"<meta charset=utf-8>"));
+
+        assert_eq!(io_queue.peek_nth(4), Some(b'a'));
+        assert_eq!(io_queue.next_byte(), Some(b'<'));
+        assert_eq!(io_queue.peek(), Some(b'm'));
+        assert_eq!(io_queue.peek_nth(5), Some(b'c'));
+        assert_eq!(io_queue.next_byte(), Some(b'm'));
+        assert!(io_queue.contains_bytes(4, b"charset"));
+        assert_eq!(io_queue.peek_n(), Some(*b"eta"));
+        assert_eq!(io_queue.skip(2), 2);
+        assert_eq!(io_queue.peek_len(), 9);
+        assert_eq!(io_queue.next_byte(), Some(b'a'));
+        assert_eq!(io_queue.position(), 5);
+
+        // Reading past the peeked bytes goes back to the stream
+        assert_eq!(io_queue.read_while(|b| b != b'='), b" charset");
+        assert_eq!(io_queue.peek_while(|b| b != b'>'), 6);
+        io_queue.skip(6);
+        assert_eq!(io_queue.next_byte(), Some(b'>'));
+        assert_eq!(io_queue.next_byte(), None);
+        assert_eq!(io_queue.peek(), None);
+        assert_eq!(io_queue.bytes_read(), 20);
+        assert_eq!(io_queue.position(), 20);
+    }
+
+    /// Returns a single byte per read, like a stream that is delivered incrementally
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let Some((&first, rest)) = self.0.split_first() else {
+                return Ok(0);
+            };
+
+            buf[0] = first;
+            self.0 = rest;
+
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn peek_short_reads() {
+        let mut io_queue = IoQueue::new(Trickle(&[1, 2, 3, 4, 5, 6]));
+
+        assert_eq!(io_queue.peek_n::<4>(), Some([1, 2, 3, 4]));
+        assert_eq!(io_queue.peek_nth(5), Some(6));
+        assert_eq!(io_queue.peek_nth(6), None);
+        assert_eq!(io_queue.bytes_read(), 6);
+    }
+
+    #[test]
+    fn pattern_matching() {
+        let mut io_queue = IoQueue::from_bytes(b"ab");
+
+        // Empty patterns match anywhere, even past the end of the stream
+        assert!(io_queue.contains_bytes(0, b""));
+        assert!(io_queue.contains_bytes(5, b""));
+        assert!(io_queue.matches_sequence(5, &[]));
+
+        assert!(io_queue.contains_bytes(1, b"b"));
+        assert!(io_queue.matches_sequence(1, &[vec![b'a', b'b']]));
+        assert!(!io_queue.contains_bytes(2, b"b"));
+        assert!(!io_queue.matches_sequence(2, &[vec![b'b']]));
+        assert!(!io_queue.contains_bytes(1, b"bc"));
+
+        // Nothing was consumed
+        assert_eq!(io_queue.next_byte(), Some(b'a'));
+    }
+
+    #[test]
+    fn ascii_matching() {
+        let mut io_queue = IoQueue::new(Trickle(b"<?xml version='1.0' ENCODING='utf-8'?>"));
+
+        assert!(io_queue.starts_with_ascii(0, "<?xml"));
+        assert!(!io_queue.starts_with_ascii(0, "<?XML"));
+        assert!(io_queue.starts_with_ascii_ignore_case(0, "<?XML"));
+
+        assert!(!io_queue.starts_with_ascii(20, "encoding"));
+        assert!(io_queue.starts_with_ascii_ignore_case(20, "encoding"));
+        assert!(!io_queue.starts_with_ascii_ignore_case(20, "encodings"));
+
+        // Past the end of the stream
+        assert!(!io_queue.starts_with_ascii_ignore_case(37, "?>>"));
+        assert!(io_queue.starts_with_ascii_ignore_case(37, ""));
+    }
+
+    #[test]
+    fn slice_and_reader_agree() {
+        let bytes: Vec<u8> = (0..=255).cycle().take(2000).collect();
+
+        let mut slice = IoQueue::from_bytes(&bytes);
+        let mut reader = IoQueue::new(Trickle(&bytes));
+
+        // Interleave every operation pseudo-randomly, comparing all of their results
+        let mut state = 0x2545_F491_u32;
+
+        while reader.has_next() {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+
+            let n = (state >> 8) as usize % 40;
+
+            match state % 6 {
+                0 => assert_eq!(slice.next_byte(), reader.next_byte()),
+                1 => assert_eq!(slice.peek_nth(n), reader.peek_nth(n)),
+                2 => assert_eq!(slice.skip(n), reader.skip(n)),
+                3 => assert_eq!(slice.peek_n::<4>(), reader.peek_n::<4>()),
+                4 => {
+                    let pred = |b: u8| b as usize % 40 != n;
+                    assert_eq!(slice.read_while(pred), reader.read_while(pred));
+                }
+                _ => {
+                    slice.peek_max(n);
+                    reader.peek_max(n);
+                }
+            }
+
+            assert_eq!(slice.peek_len(), reader.peek_len());
+            assert_eq!(slice.bytes_read(), reader.bytes_read());
+            assert_eq!(slice.position(), reader.position());
+        }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        self.next_byte()
+        assert!(!slice.has_next());
+        assert_eq!(slice.position(), bytes.len());
     }
 }
@@ -0,0 +1,707 @@
+use std::{io::Read, iter::Peekable, ops::Range};
+
+use crate::{
+    character_encoding::is_plain_ascii, io_queue::IoQueue, CharacterEncoding, Decoder,
+    DecodingError, HtmlParseError, HtmlParseResult, Span, Token, TokenKind, Tokenizer,
+};
+
+/// A source of characters for the tokenizer. Implementors provide the (newline normalized)
+/// input stream, and `next_token` drives the tokenizer state machine over it.
+///
+/// The trait is object safe, so the kind of lexer can be chosen at runtime (see
+/// [`boxed_lexer`]).
+pub trait Lexer {
+    /// Consumes the next character from the input stream, or `None` at the end of the stream
+    fn next_char(&mut self) -> HtmlParseResult<Option<char>>;
+
+    /// The number of characters consumed from the input stream so far. Characters are decoded
+    /// Unicode scalar values after newline normalization, so a CRLF pair counts as one, and
+    /// every lexer reports the same position for the same document regardless of its encoding.
+    fn get_position(&self) -> usize;
+
+    fn tokenizer(&mut self) -> &mut Tokenizer;
+
+    /// Feeds characters to the tokenizer until it produces a token (or a parse error)
+    fn next_token(&mut self) -> HtmlParseResult<Token> {
+        loop {
+            if let Some(token) = self.tokenizer().pop() {
+                return token;
+            }
+
+            let c = self.next_char()?;
+
+            // These characters are still tokenized as usual, the error is only reported
+            if let Some(error) = c.and_then(input_stream_error) {
+                self.tokenizer().input_stream_error(error);
+            }
+
+            self.tokenizer().step(c);
+        }
+    }
+}
+
+/// The parse error for characters that are not allowed in the input stream, if any
+/// (https://html.spec.whatwg.org/#preprocessing-the-input-stream)
+fn input_stream_error(c: char) -> Option<HtmlParseError> {
+    match c as u32 {
+        // https://infra.spec.whatwg.org/#noncharacter
+        0xFDD0..=0xFDEF => Some(HtmlParseError::NoncharacterInInputStream),
+        x if x & 0xFFFE == 0xFFFE => Some(HtmlParseError::NoncharacterInInputStream),
+        // https://infra.spec.whatwg.org/#control, other than ASCII whitespace and NULL
+        0x00 => None,
+        0x01..=0x1F | 0x7F..=0x9F if !c.is_ascii_whitespace() => {
+            Some(HtmlParseError::ControlCharacterInInputStream)
+        }
+        _ => None,
+    }
+}
+
+/// Maps the characters of an input stream back to the bytes they were decoded from, so that a
+/// [`Span`] can be turned into a range of the original bytes (e.g. for view-source)
+///
+/// Consecutive characters that were encoded with the same number of bytes are stored as a
+/// single run, so a mostly ASCII document only needs a handful of entries.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceMap {
+    runs: Vec<SourceRun>,
+    len: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SourceRun {
+    /// The position of the first character of the run
+    start: usize,
+    /// The byte offset of the first character of the run
+    byte_start: usize,
+    /// The number of bytes of every character of the run
+    width: usize,
+}
+
+impl SourceMap {
+    /// Records the bytes of the next character of the input stream
+    fn push(&mut self, bytes: Range<usize>) {
+        let width = bytes.end - bytes.start;
+        let position = self.len;
+        self.len += 1;
+
+        // The bytes of consecutive characters are contiguous, so only the width has to match
+        if self.runs.last().map(|run| run.width) == Some(width) {
+            return;
+        }
+
+        self.runs.push(SourceRun {
+            start: position,
+            byte_start: bytes.start,
+            width,
+        });
+    }
+
+    /// The number of characters that were recorded
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The offset of the first byte of the character at `position`. The position right after
+    /// the last character maps to the end of its bytes.
+    ///
+    /// Returns `None` for positions past that.
+    pub fn byte_offset(&self, position: usize) -> Option<usize> {
+        if position > self.len {
+            return None;
+        }
+
+        let run = self.runs[..self.runs.partition_point(|run| run.start <= position)].last()?;
+
+        Some(run.byte_start + (position - run.start) * run.width)
+    }
+
+    /// The range of bytes the characters of `span` were decoded from
+    pub fn byte_range(&self, span: Span) -> Option<Range<usize>> {
+        Some(self.byte_offset(span.start)?..self.byte_offset(span.end)?)
+    }
+}
+
+/// A lexer over an in-memory string, which is either owned (`String`) or borrowed (`&str`)
+pub struct StringLexer<S = String> {
+    input: S,
+    offset: usize,
+    position: usize,
+    tokenizer: Tokenizer,
+}
+
+impl<S: AsRef<str>> StringLexer<S> {
+    pub fn new(input: S) -> Self {
+        Self {
+            input,
+            offset: 0,
+            position: 0,
+            tokenizer: Tokenizer::new(),
+        }
+    }
+}
+
+impl<S: AsRef<str>> Lexer for StringLexer<S> {
+    /// Gets the next character and normalizes new lines according to the spec
+    /// (https://infra.spec.whatwg.org/#normalize-newlines)
+    fn next_char(&mut self) -> HtmlParseResult<Option<char>> {
+        let mut chars = self.input.as_ref()[self.offset..].chars();
+
+        let Some(c) = chars.next() else {
+            return Ok(None)
+        };
+
+        self.offset += c.len_utf8();
+        self.position += 1;
+
+        if c != '\r' {
+            return Ok(Some(c));
+        }
+
+        // Ignore CR when followed by LF, otherwise replace it with LF
+        if chars.next() == Some('\n') {
+            self.offset += 1;
+        }
+
+        Ok(Some('\n'))
+    }
+
+    fn get_position(&self) -> usize {
+        self.position
+    }
+
+    fn tokenizer(&mut self) -> &mut Tokenizer {
+        &mut self.tokenizer
+    }
+}
+
+/// The input of a lexer that is chosen at runtime (see [`boxed_lexer`])
+pub enum LexerSource {
+    Str(String),
+    /// A byte stream, which is decoded as UTF-8
+    Reader(Box<dyn Read>),
+}
+
+/// Creates a [`StringLexer`] or a [`StreamLexer`] for `source`, behind a common trait object
+pub fn boxed_lexer(source: LexerSource) -> Box<dyn Lexer> {
+    match source {
+        LexerSource::Str(input) => Box::new(StringLexer::new(input)),
+        LexerSource::Reader(reader) => Box::new(StreamLexer::new(reader, CharacterEncoding::Utf8)),
+    }
+}
+
+/// Tokenizes all of `input`, returning its tokens up to (but not including) the end-of-file
+/// token, or the first parse error
+pub fn tokenize(input: &str) -> HtmlParseResult<Vec<Token>> {
+    let mut lexer = StringLexer::new(input);
+    let mut tokens = Vec::new();
+
+    loop {
+        let token = lexer.next_token()?;
+
+        if let TokenKind::Eof = token.kind {
+            return Ok(tokens);
+        }
+
+        tokens.push(token);
+    }
+}
+
+/// A lexer over characters that were already decoded
+pub struct CharLexer<I: Iterator<Item = char>> {
+    input: Peekable<I>,
+    position: usize,
+    tokenizer: Tokenizer,
+}
+
+impl<I: Iterator<Item = char>> CharLexer<I> {
+    pub fn new(input: I) -> Self {
+        Self {
+            input: input.peekable(),
+            position: 0,
+            tokenizer: Tokenizer::new(),
+        }
+    }
+}
+
+impl<I: Iterator<Item = char>> Lexer for CharLexer<I> {
+    /// Gets the next character and normalizes new lines according to the spec
+    /// (https://infra.spec.whatwg.org/#normalize-newlines)
+    fn next_char(&mut self) -> HtmlParseResult<Option<char>> {
+        let Some(c) = self.input.next() else {
+            return Ok(None)
+        };
+
+        self.position += 1;
+
+        if c != '\r' {
+            return Ok(Some(c));
+        }
+
+        // Ignore CR when followed by LF, otherwise replace it with LF
+        self.input.next_if_eq(&'\n');
+
+        Ok(Some('\n'))
+    }
+
+    fn get_position(&self) -> usize {
+        self.position
+    }
+
+    fn tokenizer(&mut self) -> &mut Tokenizer {
+        &mut self.tokenizer
+    }
+}
+
+/// A lexer over a byte stream, which is decoded with the given character encoding
+pub struct StreamLexer<'a, R> {
+    input_byte_stream: IoQueue<'a, R>,
+    decoder: Box<dyn Decoder<R>>,
+    /// The byte ranges of the characters from the decoder and the input stream that were
+    /// peeked, respectively
+    peeked_decoded_char: Option<(char, Range<usize>)>,
+    peeked_input_char: Option<(char, Range<usize>)>,
+    /// The number of bytes ahead in the input byte stream that are known to decode to the same
+    /// ASCII characters, which are taken as is instead of going through the decoder
+    ascii_run: usize,
+    source_map: SourceMap,
+    position: usize,
+    tokenizer: Tokenizer,
+}
+
+impl<'a, R: Read> StreamLexer<'a, R> {
+    pub fn new(input_byte_stream: R, character_encoding: CharacterEncoding) -> Self {
+        Self::from_io_queue(IoQueue::new(input_byte_stream), character_encoding)
+    }
+
+    fn from_io_queue(io_queue: IoQueue<'a, R>, character_encoding: CharacterEncoding) -> Self {
+        Self {
+            input_byte_stream: io_queue,
+            decoder: character_encoding.decoder(),
+            peeked_decoded_char: None,
+            peeked_input_char: None,
+            ascii_run: 0,
+            source_map: SourceMap::default(),
+            position: 0,
+            tokenizer: Tokenizer::new(),
+        }
+    }
+
+    /// Switches to the decoder for another encoding, starting with the next undecoded byte
+    pub fn set_encoding(&mut self, character_encoding: CharacterEncoding) {
+        self.decoder = character_encoding.decoder();
+        self.ascii_run = 0;
+    }
+
+    pub fn reset_decoder(&mut self) {
+        self.decoder.reset();
+    }
+
+    /// Maps the characters consumed so far to the bytes they were decoded from
+    pub fn source_map(&self) -> &SourceMap {
+        &self.source_map
+    }
+
+    pub(crate) fn take_source_map(&mut self) -> SourceMap {
+        std::mem::take(&mut self.source_map)
+    }
+
+    /// The underlying byte stream, for sniffing the encoding before anything is decoded
+    pub(crate) fn io_queue_mut(&mut self) -> &mut IoQueue<'a, R> {
+        self.ascii_run = 0;
+        &mut self.input_byte_stream
+    }
+
+    /// Gets a character from the "input stream" and normalizes new lines
+    /// according to the spec (https://infra.spec.whatwg.org/#normalize-newlines), along with
+    /// the bytes it was decoded from
+    fn next_char_from_input_stream(&mut self) -> HtmlParseResult<Option<(char, Range<usize>)>> {
+        // If a character was already peeked, return that instead
+        if self.peeked_input_char.is_some() {
+            return Ok(self.peeked_input_char.take());
+        }
+
+        // Read the next char
+        let character = self.next_char_from_byte_stream()?;
+
+        let Some((character, bytes)) = character else {
+            return Ok(None)
+        };
+
+        // Normalize new lines
+        match (character, self.peek_char_from_byte_stream()?) {
+            // Ignore CR and return only the LF (which covers the bytes of both)
+            ('\r', Some('\n')) => Ok(self
+                .next_char_from_byte_stream()?
+                .map(|(lf, lf_bytes)| (lf, bytes.start..lf_bytes.end))),
+            ('\r', _) => Ok(Some(('\n', bytes))),
+            _ => Ok(Some((character, bytes))),
+        }
+    }
+
+    /// Peeks the next normalized char from the input stream
+    #[allow(unused)]
+    fn peek_char_from_input_stream(&mut self) -> HtmlParseResult<Option<char>> {
+        // If a character was not already peeked, decode one
+        if self.peeked_input_char.is_none() {
+            self.peeked_input_char = self.next_char_from_input_stream()?;
+        }
+
+        Ok(self.peeked_input_char.as_ref().map(|(c, _)| *c))
+    }
+
+    /// Decodes bytes from the input_byte_stream in a "lossy" manner (i.e. invalid data is
+    /// replaced with REPLACEMENT_CHARACTER), along with the range of bytes that were decoded
+    fn next_char_from_byte_stream(&mut self) -> HtmlParseResult<Option<(char, Range<usize>)>> {
+        // If a character was already peeked, return that instead
+        if self.peeked_decoded_char.is_some() {
+            return Ok(self.peeked_decoded_char.take());
+        }
+
+        let decoded = match self.next_ascii_char() {
+            Some(decoded) => decoded,
+            None => match self.decode_char()? {
+                Some(decoded) => decoded,
+                None => return Ok(None),
+            },
+        };
+
+        // Once the bytes peeked while sniffing the encoding are all decoded, the buffer they were
+        // in is released (later peeks are only a few bytes at a time)
+        if self.input_byte_stream.peek_len() == 0 && self.input_byte_stream.capacity() >= 1024 {
+            self.input_byte_stream.compact();
+        }
+
+        Ok(Some(decoded))
+    }
+
+    /// Takes the next byte as is if it is part of a run of plain ASCII, which most encodings
+    /// map to itself. Looking for the end of the run only goes through the bytes that are
+    /// already buffered, so the decoder is skipped for all of them without reading ahead.
+    fn next_ascii_char(&mut self) -> Option<(char, Range<usize>)> {
+        if self.ascii_run == 0 && self.decoder.passes_ascii_through() {
+            self.ascii_run = self
+                .input_byte_stream
+                .buffered()
+                .iter()
+                .take_while(|&&b| is_plain_ascii(b))
+                .count();
+
+            // A reader that nothing was peeked from yet has no bytes buffered
+            if self.ascii_run == 0 && self.input_byte_stream.peek().is_some_and(is_plain_ascii) {
+                self.ascii_run = 1;
+            }
+        }
+
+        if self.ascii_run == 0 {
+            return None;
+        }
+
+        self.ascii_run -= 1;
+
+        let position = self.input_byte_stream.position();
+        let byte = self.input_byte_stream.next_byte()?;
+
+        Some((byte as char, position..position + 1))
+    }
+
+    /// Decodes the next character with the decoder for the selected character encoding
+    fn decode_char(&mut self) -> HtmlParseResult<Option<(char, Range<usize>)>> {
+        // Use the decoder for the selected character encoding to get a character
+        let byte_start = self.input_byte_stream.position();
+        let decoded = self.decoder.decode(&mut self.input_byte_stream);
+        let byte_end = self.input_byte_stream.position();
+
+        let decoded = match decoded {
+            // Replace invalid or incomplete sequences with a replacement character
+            Err(DecodingError::InvalidData | DecodingError::UnexpectedEof) => {
+                return Ok(Some((char::REPLACEMENT_CHARACTER, byte_start..byte_end)))
+            }
+
+            // Valid encoded data, but invalid character for tokenization
+            Err(DecodingError::UnexpectedSurrogate) => {
+                return Err(HtmlParseError::SurrogateInInputStream)
+            }
+
+            // Forward valid input characters from the decoder
+            Ok(x) => x,
+        };
+
+        Ok(decoded.map(|(character, _)| (character, byte_start..byte_end)))
+    }
+
+    /// Peeks the next decoded char from the input byte stream
+    fn peek_char_from_byte_stream(&mut self) -> HtmlParseResult<Option<char>> {
+        // If a character was not already peeked, decode one
+        if self.peeked_decoded_char.is_none() {
+            self.peeked_decoded_char = self.next_char_from_byte_stream()?;
+        }
+
+        Ok(self.peeked_decoded_char.as_ref().map(|(c, _)| *c))
+    }
+}
+
+impl<'a> StreamLexer<'a, &'a [u8]> {
+    /// Creates a lexer over bytes that are already in memory, which are read straight from the
+    /// slice instead of through a reader (see [`IoQueue::from_bytes`])
+    pub fn from_bytes(bytes: &'a [u8], character_encoding: CharacterEncoding) -> Self {
+        Self::from_io_queue(IoQueue::from_bytes(bytes), character_encoding)
+    }
+}
+
+impl<R: Read> Lexer for StreamLexer<'_, R> {
+    fn next_char(&mut self) -> HtmlParseResult<Option<char>> {
+        let Some((c, bytes)) = self.next_char_from_input_stream()? else {
+            return Ok(None)
+        };
+
+        self.position += 1;
+        self.source_map.push(bytes);
+
+        Ok(Some(c))
+    }
+
+    fn get_position(&self) -> usize {
+        self.position
+    }
+
+    fn tokenizer(&mut self) -> &mut Tokenizer {
+        &mut self.tokenizer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn releases_peeked_bytes() {
+        let input = "<p>".repeat(2000);
+        let mut lexer = StreamLexer::new(input.as_bytes(), CharacterEncoding::Utf8);
+
+        // Like the pre-scan of the whole input
+        lexer.io_queue_mut().peek_max(input.len());
+        assert!(lexer.io_queue_mut().capacity() >= input.len());
+
+        while lexer.get_position() < 4000 {
+            lexer.next_char().unwrap();
+        }
+        assert!(lexer.io_queue_mut().capacity() >= input.len());
+
+        // Consuming past the peeked bytes releases them
+        while lexer.next_char().unwrap().is_some() {}
+        assert_eq!(lexer.io_queue_mut().capacity(), 0);
+    }
+
+    #[test]
+    fn decodes_ascii_runs() {
+        let cases: [(CharacterEncoding, &[u8]); 6] = [
+            (CharacterEncoding::Utf8, b"<p>caf\xC3\xA9 \xE2\x82\xAC5</p>"),
+            (CharacterEncoding::Utf8, b"a\xE2b\xE2\x82c\xF0"),
+            (CharacterEncoding::Windows1252, b"caf\xE9 \x80"),
+            // Escape sequences switch to and from JIS X 0208 and Roman, where ASCII bytes are
+            // not ASCII characters
+            (
+                CharacterEncoding::ISO2022Jp,
+                b"a\x1B$B\x30\x21\x1B(Bb\x1B(J\\~\x1B(B\\\x1B(B\x1B(Bc\x0Ed",
+            ),
+            (CharacterEncoding::Utf16LE, b"a\0<\0\xAC\x20"),
+            (CharacterEncoding::Replacement, b"<p>"),
+        ];
+
+        for (encoding, bytes) in cases {
+            let expected: Vec<_> = encoding.decode(bytes).chars().collect();
+
+            let mut lexers = [
+                StreamLexer::new(bytes, encoding),
+                StreamLexer::from_bytes(bytes, encoding),
+            ];
+
+            for lexer in &mut lexers {
+                let mut decoded = Vec::new();
+                let mut end = 0;
+
+                while let Some((c, range)) = lexer.next_char_from_byte_stream().unwrap() {
+                    assert_eq!(range.start, end, "{encoding}: {decoded:?}");
+                    decoded.push(c);
+                    end = range.end;
+                }
+
+                assert_eq!(decoded, expected, "{encoding}");
+            }
+        }
+    }
+
+    #[test]
+    fn lexes_document() {
+        let kinds: Vec<_> = tokenize("<!DOCTYPE html><p class=x>Hi\r\n<!-- c --></p><br/>")
+            .unwrap()
+            .into_iter()
+            .map(|token| token.kind)
+            .collect();
+
+        assert!(matches!(&kinds[0], TokenKind::Doctype(d) if d.name.as_deref() == Some("html")));
+        assert!(matches!(&kinds[1], TokenKind::StartTag(t) if t.get("class") == Some("x")));
+        assert!(matches!(&kinds[2], TokenKind::Text(t) if t == "Hi\n"));
+        assert!(matches!(&kinds[3], TokenKind::Comment(c) if c == " c "));
+        assert!(matches!(&kinds[4], TokenKind::EndTag(t) if t.name == "p"));
+        assert!(matches!(&kinds[5], TokenKind::StartTag(t) if t.name == "br" && t.self_closing));
+        assert_eq!(kinds.len(), 6);
+    }
+
+    #[test]
+    fn boxed_lexers() {
+        let input = "<p class=x>Hi</p>";
+        let mut lexers: Vec<Box<dyn Lexer>> = vec![
+            boxed_lexer(LexerSource::Str(input.to_owned())),
+            boxed_lexer(LexerSource::Reader(Box::new(input.as_bytes()))),
+        ];
+
+        for lexer in &mut lexers {
+            assert_eq!(
+                spans(lexer.as_mut()),
+                [(0, 11), (11, 13), (13, 17), (17, 17)]
+            );
+        }
+    }
+
+    #[test]
+    fn tokenize_spans() {
+        let spans = |input| {
+            tokenize(input)
+                .unwrap()
+                .iter()
+                .map(|token| (token.span.start, token.span.end))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(spans("<a href='/' id=x>"), [(0, 17)]);
+        assert_eq!(spans("<img/>text"), [(0, 6), (6, 10)]);
+        assert_eq!(spans("<!doctype html><!---->"), [(0, 15), (15, 22)]);
+        assert_eq!(spans(""), []);
+
+        let tokens = tokenize("<a href='/' id=x>").unwrap();
+        assert!(matches!(&tokens[0].kind, TokenKind::StartTag(t)
+            if t.get("href") == Some("/") && t.get("id") == Some("x") && !t.self_closing));
+
+        // Parse errors end tokenizing
+        assert!(tokenize("<p>\0</p>").is_err());
+    }
+
+    #[test]
+    fn token_start_forms() {
+        let kinds = |input| {
+            tokenize(input)
+                .unwrap()
+                .into_iter()
+                .map(|token| match token.kind {
+                    TokenKind::Doctype(doctype) => format!("doctype {}", doctype.name.unwrap()),
+                    TokenKind::StartTag(tag) => format!("<{}>", tag.name),
+                    TokenKind::EndTag(tag) => format!("</{}>", tag.name),
+                    TokenKind::Comment(comment) => format!("comment {comment}"),
+                    TokenKind::Text(text) => format!("text {text}"),
+                    other => panic!("unexpected {other:?}"),
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // Each markup declaration is told apart from the others as soon as a character of it
+        // differs, so the shared `<!` prefix is only ever read once
+        assert_eq!(
+            kinds("<!--<!DOCTYPE--><!doctype html><!DocType x></p><p>-"),
+            [
+                "comment <!DOCTYPE",
+                "doctype html",
+                "doctype x",
+                "</p>",
+                "<p>",
+                "text -",
+            ]
+        );
+
+        // Anything else is a bogus comment, which is a parse error
+        assert!(tokenize("<!doctyp>").is_err());
+    }
+
+    #[test]
+    fn string_lexer_borrows_or_owns() {
+        let mut borrowed = StringLexer::new("<p class=a>Hi\r\n</p>");
+        let mut owned = StringLexer::new(String::from("<p class=a>Hi\r\n</p>"));
+
+        let token = borrowed.next_token().unwrap();
+        assert!(matches!(token.kind, TokenKind::StartTag(tag) if tag.get("class") == Some("a")));
+        assert!(
+            matches!(borrowed.next_token().unwrap().kind, TokenKind::Text(text) if text == "Hi\n")
+        );
+
+        owned.next_token().unwrap();
+        owned.next_token().unwrap();
+        assert_eq!(owned.get_position(), borrowed.get_position());
+    }
+
+    fn spans(lexer: &mut dyn Lexer) -> Vec<(usize, usize)> {
+        let mut spans = Vec::new();
+
+        loop {
+            let token = lexer.next_token().unwrap();
+            spans.push((token.span.start, token.span.end));
+
+            if let TokenKind::Eof = token.kind {
+                return spans;
+            }
+        }
+    }
+
+    #[test]
+    fn lexers_agree_on_spans() {
+        let input = "<p title=\"日本\">crab: 🦀\r\nnaïve</p>\r<!-- ✓ -->";
+
+        let mut string_lexer = StringLexer::new(input);
+        let mut stream_lexer = StreamLexer::new(input.as_bytes(), CharacterEncoding::Utf8);
+        let mut char_lexer = CharLexer::new(input.chars());
+
+        let string_spans = spans(&mut string_lexer);
+        assert_eq!(string_spans, spans(&mut stream_lexer));
+        assert_eq!(string_spans, spans(&mut char_lexer));
+        assert_eq!(string_spans[1], (14, 27));
+        assert_eq!(string_lexer.get_position(), stream_lexer.get_position());
+    }
+
+    #[test]
+    fn maps_spans_to_bytes() {
+        let input = "<p title=\"日本\">crab: 🦀\r\nnaïve</p>";
+        let mut lexer = StreamLexer::new(input.as_bytes(), CharacterEncoding::Utf8);
+
+        let spans = spans(&mut lexer);
+        let source_map = lexer.source_map();
+        let bytes = |span: (usize, usize)| {
+            let range = source_map.byte_range(Span::new(span.0, span.1)).unwrap();
+            &input[range]
+        };
+
+        assert_eq!(bytes(spans[0]), "<p title=\"日本\">");
+        assert_eq!(bytes(spans[1]), "crab: 🦀\r\nnaïve");
+        assert_eq!(bytes(spans[2]), "</p>");
+
+        assert_eq!(source_map.len(), lexer.get_position());
+        assert_eq!(source_map.byte_offset(source_map.len()), Some(input.len()));
+        assert_eq!(source_map.byte_offset(source_map.len() + 1), None);
+    }
+
+    #[test]
+    fn maps_spans_past_escape_sequences() {
+        // `日本` in ISO-2022-JP. Escape sequences are part of the character that follows them.
+        let input = b"a\x1B$BF|K\\\x1B(Bb";
+        let mut lexer = StreamLexer::new(&input[..], CharacterEncoding::ISO2022Jp);
+
+        while lexer.next_char().unwrap().is_some() {}
+
+        let source_map = lexer.source_map();
+        assert_eq!(source_map.len(), 4);
+        assert_eq!(source_map.byte_range(Span::new(1, 3)), Some(1..8));
+        assert_eq!(source_map.byte_range(Span::new(3, 4)), Some(8..12));
+    }
+}
@@ -1,9 +1,26 @@
 pub mod character_encoding;
+pub mod data_url;
+pub mod document;
+mod encoding_indexes;
 pub mod error;
+pub mod fuzz;
 pub mod io_queue;
+pub mod lexer;
+mod named_character_references;
 pub mod parser;
 pub mod prescan;
+mod serializer;
+pub mod srcset;
+pub mod tokenizer;
+mod tree_builder;
+pub mod tree_sink;
 
 pub use character_encoding::*;
+pub use data_url::*;
+pub use document::*;
 pub use error::*;
+pub use lexer::*;
 pub use parser::*;
+pub use srcset::*;
+pub use tokenizer::*;
+pub use tree_sink::*;
@@ -1,65 +1,161 @@
 use std::io::Read;
 
 use crate::{
-    character_encoding::CharacterEncoding, io_queue::IoQueue, prescan::HtmlPreScanner, Decoder,
-    DecodingError, HtmlParseError, HtmlParseResult,
+    character_encoding::CharacterEncoding,
+    data_url::parse_data_url,
+    document::Document,
+    io_queue::IoQueue,
+    prescan::HtmlPreScanner,
+    tree_builder::{text_state_for, TreeBuilder},
+    CharLexer, DoctypeData, HtmlParseError, HtmlParseResult, Lexer, SourceMap, StreamLexer,
+    TagData, Token, TokenKind, TreeSink,
 };
 
-enum InsertionMode {
-    Initial,
-    BeforeHtml,
-    BeforeHead,
-    InHead,
-    InHeadNoscript,
-    AfterHead,
-    InBody,
-    Text,
-    InTable,
-    InTableText,
-    InCaption,
-    InColumnGroup,
-    InTableBody,
-    InRow,
-    InCell,
-    InSelect,
-    InSelectInTable,
-    InTemplate,
-    AfterBody,
-    InFrameset,
-    AfterFrameset,
-    AfterAfterBody,
-    AfterAfterFrameset,
-}
+/// The number of bytes at the start of a stream that are pre-scanned for the encoding
+const PRESCAN_WINDOW: usize = 1024;
 
-pub struct HtmlParser<R> {
+/// Parses an HTML document out of a [`ParserInput`], which is either a byte stream
+/// ([`HtmlParser::new`]) or characters that were already decoded ([`HtmlParser::from_chars`])
+pub struct HtmlParser<L> {
     character_encoding: CharacterEncoding,
     encoding_confidence: EncodingConfidence,
-    input_byte_stream: IoQueue<R>,
-    read_bytes: Vec<u8>,
-    peeked_decoded_char: Option<char>,
-    peeked_input_char: Option<char>,
-    insertion_mode: InsertionMode,
+    /// Whether the encoding was determined already, so it isn't sniffed again when parsing
+    encoding_sniffed: bool,
+    /// Reported once the tree builder is running, see [`HtmlParseError::EncodingMismatch`]
+    encoding_mismatch: Option<HtmlParseError>,
+    lexer: L,
+    options: ParseOptions,
+}
+
+/// The settings of an [`HtmlParser`], which its builder methods (e.g.
+/// [`HtmlParser::keep_comments`]) set one at a time, or [`HtmlParser::options`] all at once
+#[derive(Debug, Clone, Copy)]
+pub struct ParseOptions {
+    /// See [`HtmlParser::transport_encoding`]
+    pub transport_encoding: Option<CharacterEncoding>,
+    /// See [`HtmlParser::parent_encoding`]
+    pub parent_encoding: Option<CharacterEncoding>,
+    /// See [`HtmlParser::keep_comments`]
+    pub keep_comments: bool,
+    /// See [`HtmlParser::scripting_enabled`]
+    pub scripting_enabled: bool,
+    /// See [`HtmlParser::parse_srcdoc`]
+    pub iframe_srcdoc: bool,
+    /// See [`HtmlParser::xml_mode`]
+    pub xml_mode: bool,
+    /// See [`HtmlParser::prescan_whole_buffer`]
+    pub prescan_whole_buffer: bool,
+    /// See [`HtmlParser::limits`]
+    pub limits: ParserLimits,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        Self {
+            transport_encoding: None,
+            parent_encoding: None,
+            keep_comments: true,
+            scripting_enabled: false,
+            iframe_srcdoc: false,
+            xml_mode: false,
+            prescan_whole_buffer: false,
+            limits: ParserLimits::default(),
+        }
+    }
+}
+
+/// Bounds on the resources used while parsing, to guard against pathological documents
+#[derive(Debug, Clone, Copy)]
+pub struct ParserLimits {
+    /// The maximum number of elements that can be open at once. Elements nested deeper than
+    /// this are inserted as siblings of the deepest open element.
+    pub max_depth: usize,
+}
+
+impl Default for ParserLimits {
+    fn default() -> Self {
+        Self { max_depth: 512 }
+    }
 }
 
 /// https://html.spec.whatwg.org/#concept-encoding-confidence
-#[allow(unused)]
-#[derive(Debug, PartialEq, Eq)]
-enum EncodingConfidence {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncodingConfidence {
     Tentative,
     Certain,
+    /// The input was not decoded from bytes, so it has no encoding
     Irrelevant,
 }
 
-impl<R: Read> HtmlParser<R> {
+/// A piece of the document, as reported by [`HtmlParser::parse_events`]
+#[derive(Debug)]
+pub enum Event {
+    Start(TagData),
+    /// An end tag, with its name
+    End(String),
+    Text(String),
+    Comment(String),
+    Doctype(DoctypeData),
+}
+
+/// A source of characters for the [`HtmlParser`]
+pub trait ParserInput: Lexer {
+    /// Determines the character encoding of the input before anything is tokenized, for
+    /// inputs that are decoded from bytes. Also returns a diagnostic if the different sources
+    /// of encoding information disagree (see [`HtmlParseError::EncodingMismatch`]).
+    ///
+    /// Inputs that are not decoded from bytes return `None`.
+    fn sniff_encoding(
+        &mut self,
+        options: &ParseOptions,
+    ) -> Option<(
+        CharacterEncoding,
+        EncodingConfidence,
+        Option<HtmlParseError>,
+    )> {
+        let _ = options;
+        None
+    }
+
+    /// Takes the map from the characters that were consumed to the bytes they were decoded
+    /// from, for inputs that are decoded from bytes
+    fn take_source_map(&mut self) -> Option<SourceMap> {
+        None
+    }
+}
+
+impl<R: Read> ParserInput for StreamLexer<'_, R> {
+    fn sniff_encoding(
+        &mut self,
+        options: &ParseOptions,
+    ) -> Option<(
+        CharacterEncoding,
+        EncodingConfidence,
+        Option<HtmlParseError>,
+    )> {
+        let sniffed = HtmlParser::determine_encoding(self.io_queue_mut(), options);
+        self.io_queue_mut().compact();
+        self.set_encoding(sniffed.0);
+
+        Some(sniffed)
+    }
+
+    fn take_source_map(&mut self) -> Option<SourceMap> {
+        Some(StreamLexer::take_source_map(self))
+    }
+}
+
+impl<I: Iterator<Item = char>> ParserInput for CharLexer<I> {}
+
+impl<R: Read> HtmlParser<StreamLexer<'_, R>> {
     pub fn new(input_byte_stream: R) -> Self {
         Self {
             character_encoding: CharacterEncoding::default(),
             encoding_confidence: EncodingConfidence::Tentative,
-            input_byte_stream: IoQueue::new(input_byte_stream),
-            read_bytes: Vec::new(),
-            peeked_decoded_char: None,
-            peeked_input_char: None,
-            insertion_mode: InsertionMode::Initial,
+            encoding_sniffed: false,
+            encoding_mismatch: None,
+            lexer: StreamLexer::new(input_byte_stream, CharacterEncoding::default()),
+            options: ParseOptions::default(),
         }
     }
 
@@ -71,162 +167,385 @@ impl<R: Read> HtmlParser<R> {
         Self {
             character_encoding,
             encoding_confidence: EncodingConfidence::Certain,
-            input_byte_stream: IoQueue::new(input_byte_stream),
-            read_bytes: Vec::new(),
-            peeked_decoded_char: None,
-            peeked_input_char: None,
-            insertion_mode: InsertionMode::Initial,
+            encoding_sniffed: false,
+            encoding_mismatch: None,
+            lexer: StreamLexer::new(input_byte_stream, character_encoding),
+            options: ParseOptions::default(),
         }
     }
 
-    /// Will try to parse an HTML document, but will abort if any error condition is discovered.
-    /// This behavior is allowed in the spec if the user agent does not wish to implement
-    /// parse error recovery (https://html.spec.whatwg.org/#parse-errors)
-    pub fn try_parse(mut self) -> HtmlParseResult<Document> {
-        if self.encoding_confidence != EncodingConfidence::Certain {
-            let (encoding, confidence) = HtmlParser::determine_encoding(&self.input_byte_stream);
+    /// The length of the byte stream, if it is known up front (e.g. from the `Content-Length`
+    /// header of a response), so the peek buffer can be allocated once instead of growing while
+    /// the encoding is sniffed. Without it, it grows as needed.
+    pub fn content_length(mut self, content_length: Option<u64>) -> Self {
+        if let Some(content_length) = content_length {
+            let content_length = usize::try_from(content_length).unwrap_or(usize::MAX);
 
-            self.character_encoding = encoding;
-            self.encoding_confidence = confidence;
+            // Only the bytes that are pre-scanned are ever peeked from a stream, and they are
+            // released once they are decoded
+            self.lexer
+                .io_queue_mut()
+                .reserve(usize::min(content_length, PRESCAN_WINDOW));
         }
 
-        println!(
-            "Document Character Encoding: {}",
-            self.character_encoding.to_string()
-        );
-        println!(
-            "Document Encoding Confidence: {:?}",
-            self.encoding_confidence
-        );
+        self
+    }
+}
+
+impl<'a> HtmlParser<StreamLexer<'a, &'a [u8]>> {
+    /// Like [`HtmlParser::new`], but for bytes that are already in memory, which are read
+    /// straight from the slice instead of through a reader
+    pub fn from_bytes(bytes: &'a [u8]) -> Self {
+        Self {
+            character_encoding: CharacterEncoding::default(),
+            encoding_confidence: EncodingConfidence::Tentative,
+            encoding_sniffed: false,
+            encoding_mismatch: None,
+            lexer: StreamLexer::from_bytes(bytes, CharacterEncoding::default()),
+            options: ParseOptions::default(),
+        }
+    }
+
+    /// Parses the body of a `data:text/html` URL, in the encoding given by its `charset`
+    /// parameter (`US-ASCII` by default, which is windows-1252)
+    pub fn from_data_url(url: &str) -> HtmlParseResult<Document> {
+        let data_url = parse_data_url(url).ok_or(HtmlParseError::InvalidDataUrl)?;
 
-        while let Some(c) = self.next_char_from_byte_stream()? {
-            print!("{c}")
+        if data_url.mime_type != "text/html" {
+            return Err(HtmlParseError::InvalidDataUrl);
         }
 
-        todo!("parse document")
+        let mut parser = HtmlParser::from_bytes(&data_url.body);
+
+        if let Ok(encoding) = data_url.charset.parse() {
+            parser = parser.transport_encoding(encoding);
+        }
+
+        parser.try_parse()
+    }
+}
+
+impl<I: Iterator<Item = char>> HtmlParser<CharLexer<I>> {
+    /// Parses characters that were already decoded (e.g. from a `&str`), so no encoding is
+    /// sniffed or decoded
+    pub fn from_chars<C: IntoIterator<IntoIter = I>>(chars: C) -> Self {
+        Self {
+            character_encoding: CharacterEncoding::Utf8,
+            encoding_confidence: EncodingConfidence::Irrelevant,
+            encoding_sniffed: false,
+            encoding_mismatch: None,
+            lexer: CharLexer::new(chars.into_iter()),
+            options: ParseOptions::default(),
+        }
+    }
+}
+
+impl<'a> HtmlParser<CharLexer<std::str::Chars<'a>>> {
+    /// Parses the `srcdoc` attribute of an `iframe` as the document the `iframe` displays. Such
+    /// documents are never in quirks mode, whatever their doctype
+    /// (https://html.spec.whatwg.org/#an-iframe-srcdoc-document).
+    pub fn parse_srcdoc(srcdoc: &'a str) -> Document {
+        let mut parser = HtmlParser::from_chars(srcdoc.chars());
+        parser.options.iframe_srcdoc = true;
+
+        parser.parse()
+    }
+}
+
+impl<L: ParserInput> HtmlParser<L> {
+    /// The encoding given by the transport layer (e.g. the `charset` parameter of the
+    /// `Content-Type` header), which takes precedence over any `<meta>` declaration
+    pub fn transport_encoding(mut self, transport_encoding: CharacterEncoding) -> Self {
+        self.options.transport_encoding = Some(transport_encoding);
+        self
+    }
+
+    /// The encoding of the document this one is nested in (e.g. the parent of a same-origin
+    /// `iframe`), which is used when the document declares none itself
+    /// (https://html.spec.whatwg.org/#determining-the-character-encoding, step 6)
+    pub fn parent_encoding(mut self, parent_encoding: Option<CharacterEncoding>) -> Self {
+        self.options.parent_encoding = parent_encoding;
+        self
+    }
+
+    /// Whether comments are inserted into the document tree (the default) or dropped
+    pub fn keep_comments(mut self, keep_comments: bool) -> Self {
+        self.options.keep_comments = keep_comments;
+        self
+    }
+
+    /// Whether the document is parsed as if scripts were going to run, which turns the contents
+    /// of `noscript` elements into plain text (https://html.spec.whatwg.org/#scripting-flag).
+    /// Disabled by default, since nothing in this crate runs scripts.
+    pub fn scripting_enabled(mut self, scripting_enabled: bool) -> Self {
+        self.options.scripting_enabled = scripting_enabled;
+        self
+    }
+
+    /// Whether the document is parsed as XML (e.g. for `application/xhtml+xml` documents)
+    /// instead of HTML. Tag and attribute names are case-sensitive, empty-element tags
+    /// (`<br/>`) are the only elements without an end tag, and elements left unclosed are
+    /// parse errors.
+    ///
+    /// TODO: this reuses the HTML tokenizer, so the document is not checked for the rest of the
+    ///       well-formedness constraints of XML
+    pub fn xml_mode(mut self, xml_mode: bool) -> Self {
+        self.options.xml_mode = xml_mode;
+        self
+    }
+
+    /// Whether the pre-scan for a `<meta>` encoding declaration looks at the whole input instead
+    /// of only its first 1024 bytes, which is all the spec allows for. Disabled by default.
+    ///
+    /// This only applies to bytes that are already in memory (see [`HtmlParser::from_bytes`]).
+    /// Streams (e.g. a response) are still pre-scanned up to 1024 bytes, since scanning all of
+    /// them would mean waiting for and buffering the whole stream before parsing anything.
+    pub fn prescan_whole_buffer(mut self, prescan_whole_buffer: bool) -> Self {
+        self.options.prescan_whole_buffer = prescan_whole_buffer;
+        self
+    }
+
+    pub fn limits(mut self, limits: ParserLimits) -> Self {
+        self.options.limits = limits;
+        self
+    }
+
+    /// Replaces all of the settings at once
+    pub fn options(mut self, options: ParseOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// The encoding the input is decoded with, as decided so far. Until the encoding is sniffed
+    /// (see [`HtmlParser::sniff_encoding`]), this is the default one.
+    pub fn document_encoding(&self) -> CharacterEncoding {
+        self.character_encoding
+    }
+
+    /// How sure the parser is of [`HtmlParser::document_encoding`]
+    pub fn encoding_confidence(&self) -> EncodingConfidence {
+        self.encoding_confidence
+    }
+
+    /// Determines the encoding of the input right away instead of when parsing starts, so that
+    /// it can be inspected beforehand. Nothing is consumed from the input.
+    ///
+    /// Parsing goes on with the encoding that was sniffed, so the settings that affect it (e.g.
+    /// [`HtmlParser::transport_encoding`]) have to be set before this.
+    pub fn sniff_encoding(mut self) -> Self {
+        self.determine_document_encoding();
+        self
+    }
+
+    /// Will try to parse an HTML document, but will abort if any error condition is discovered.
+    /// This behavior is allowed in the spec if the user agent does not wish to implement
+    /// parse error recovery (https://html.spec.whatwg.org/#parse-errors)
+    pub fn try_parse(mut self) -> HtmlParseResult<Document> {
+        self.determine_document_encoding();
+
+        let document = Document::new(self.character_encoding);
+        let mut document = self.run(document, false)?;
+        document.set_source_map(self.lexer.take_source_map());
+
+        Ok(document)
     }
 
     /// Will parse an HTML document and recover from any errors as defined in the HTML parsing specification.
     /// (https://html.spec.whatwg.org/#parse-errors)
-    #[allow(unused)]
-    pub fn parse(self) -> Document {
-        todo!("Parse with error recovery")
-    }
+    ///
+    /// The errors that were recovered from are available through [`Document::parse_errors`]
+    pub fn parse(mut self) -> Document {
+        self.determine_document_encoding();
 
-    /// Gets a character from the "input stream" and normalizes new lines
-    /// according to the spec (https://infra.spec.whatwg.org/#normalize-newlines)
-    #[allow(unused)]
-    fn next_char_from_input_stream(&mut self) -> HtmlParseResult<Option<char>> {
-        // If a character was already peeked, return that instead
-        if let Some(peeked) = self.peeked_input_char {
-            return Ok(self.peeked_input_char.take());
-        }
+        let document = Document::new(self.character_encoding);
+        let mut document = self
+            .run(document, true)
+            .expect("parse errors are recorded when recovering");
+        document.set_source_map(self.lexer.take_source_map());
 
-        // Read the next char
-        let character = self.next_char_from_byte_stream()?;
+        document
+    }
 
-        let Some(character) = character else {
-            return Ok(None)
-        };
+    /// Parses the document like [`HtmlParser::parse`], but into an existing document, which is
+    /// cleared first (see [`Document::clear`]). Its memory is reused, so parsing many documents
+    /// one after the other allocates less.
+    pub fn parse_into(mut self, document: &mut Document) {
+        self.determine_document_encoding();
 
-        // Normalize new lines
-        match (character, self.peek_char_from_byte_stream()?) {
-            // Ignore CR and return only the LF
-            ('\r', Some('\n')) => self.next_char_from_byte_stream(),
-            ('\r', _) => Ok(Some('\n')),
-            _ => Ok(Some(character)),
-        }
+        let mut reused = std::mem::replace(document, Document::new(self.character_encoding));
+        reused.clear();
+        reused.set_encoding(self.character_encoding);
+
+        *document = self
+            .run(reused, true)
+            .expect("parse errors are recorded when recovering");
+        document.set_source_map(self.lexer.take_source_map());
     }
 
-    /// Peeks the next normalized char from the input stream
-    #[allow(unused)]
-    fn peek_char_from_input_stream(&mut self) -> HtmlParseResult<Option<&char>> {
-        // If a character was not already peeked, decode one
-        if self.peeked_input_char.is_none() {
-            self.peeked_input_char = self.next_char_from_input_stream()?;
-        }
+    /// Parses the document like [`HtmlParser::parse`], but builds the tree through the given
+    /// sink instead of into a [`Document`]. Parse errors are reported to the sink as well.
+    pub fn parse_with_sink<S: TreeSink>(mut self, sink: S) -> S::Output {
+        self.determine_document_encoding();
 
-        // Return reference to peeked
-        return Ok(self.peeked_input_char.as_ref());
+        self.run(sink, true)
+            .expect("parse errors are recorded when recovering")
     }
 
-    /// Decodes bytes from the input_byte_stream in a "lossy" manner (i.e. invalid data is
-    /// replaced with REPLACEMENT_CHARACTER)
-    #[allow(unused)]
-    fn next_char_from_byte_stream(&mut self) -> HtmlParseResult<Option<char>> {
-        // If a character was already peeked, return that instead
-        if let Some(peeked) = self.peeked_decoded_char {
-            return Ok(self.peeked_decoded_char.take());
-        }
-
-        // Use the decoder for the selected character encoding to get a character
-        let decoded = self
-            .character_encoding
-            .decoder()
-            .decode(&mut self.input_byte_stream);
-
-        let decoded = match decoded {
-            // Replace invalid or incomplete sequences with a replacement character
-            Err(DecodingError::InvalidData | DecodingError::UnexpectedEof) => {
-                return Ok(Some(char::REPLACEMENT_CHARACTER))
-            }
+    /// Consumes the parser and returns the parsed document tree, for callers that have no use
+    /// for the individual tokens. Equivalent to [`HtmlParser::parse`].
+    pub fn into_document(self) -> Document {
+        self.parse()
+    }
 
-            // Valid encoded data, but invalid character for tokenization
-            Err(DecodingError::UnexpectedSurrogate) => {
-                return Err(HtmlParseError::SurrogateInInputStream)
-            }
-            Err(DecodingError::UnexpectedNonCharacter) => {
-                return Err(HtmlParseError::NoncharacterInInputStream)
+    /// Decodes and tokenizes the document without building a tree, yielding every token
+    /// (up to and including the end-of-file token) along with any parse errors.
+    ///
+    /// The tokenizer still switches to the text states after `script`, `style`, `title`, etc.
+    /// like the tree builder would, so the contents of these elements come out as text.
+    pub fn tokens(mut self) -> impl Iterator<Item = HtmlParseResult<Token>> {
+        self.determine_document_encoding();
+        self.lexer.tokenizer().set_xml_mode(self.options.xml_mode);
+
+        let mut done = false;
+
+        std::iter::from_fn(move || {
+            if done {
+                return None;
             }
-            Err(DecodingError::UnexpectedControl) => {
-                return Err(HtmlParseError::ControlCharacterInInputStream)
+
+            let token = self.lexer.next_token();
+
+            match &token {
+                // XML has no elements with text contents
+                Ok(Token {
+                    kind: TokenKind::StartTag(tag),
+                    ..
+                }) if !self.options.xml_mode => {
+                    if let Some(state) = text_state_for(&tag.name, self.options.scripting_enabled) {
+                        self.lexer.tokenizer().set_state(state);
+                    }
+                }
+                Ok(Token {
+                    kind: TokenKind::Eof,
+                    ..
+                }) => done = true,
+                _ => {}
             }
 
-            // Forward valid input characters from the decoder
-            Ok(x) => x,
-        };
+            Some(token)
+        })
+    }
 
-        // if we got a valid character, extract the code-point and the underlying bytes
-        let Some((character, mut bytes)) = decoded else {
-            return Ok(None)
-        };
+    /// Parses the document without building a tree, calling `handler` for every start tag,
+    /// end tag, run of text, comment and doctype in source order (SAX-style). Only the current
+    /// token is kept in memory.
+    ///
+    /// Parse errors are recovered from but not reported. There is no tree construction, so the
+    /// events are the tags as they appear in the source: end tags are not implied, and void or
+    /// unclosed elements have no `End` event.
+    pub fn parse_events(self, mut handler: impl FnMut(Event)) {
+        let keep_comments = self.options.keep_comments;
 
-        // Append the bytes we read to the running byte tracker
-        self.read_bytes.append(&mut bytes);
+        for token in self.tokens().filter_map(Result::ok) {
+            let event = match token.kind {
+                TokenKind::StartTag(tag) => Event::Start(tag),
+                TokenKind::EndTag(tag) => Event::End(tag.name),
+                TokenKind::Text(text) => Event::Text(text),
+                TokenKind::Comment(comment) if keep_comments => Event::Comment(comment),
+                TokenKind::Doctype(doctype) => Event::Doctype(doctype),
+                TokenKind::Comment(_) | TokenKind::ProcessingInstruction { .. } => continue,
+                TokenKind::Eof => return,
+            };
 
-        Ok(Some(character))
+            handler(event);
+        }
     }
 
-    /// Peeks the next decoded char from the input byte stream
-    #[allow(unused)]
-    fn peek_char_from_byte_stream(&mut self) -> HtmlParseResult<Option<&char>> {
-        // If a character was not already peeked, decode one
-        if self.peeked_decoded_char.is_none() {
-            self.peeked_decoded_char = self.next_char_from_byte_stream()?;
+    fn determine_document_encoding(&mut self) {
+        if std::mem::replace(&mut self.encoding_sniffed, true) {
+            return;
+        }
+
+        if self.encoding_confidence == EncodingConfidence::Tentative {
+            if let Some((encoding, confidence, mismatch)) = self.lexer.sniff_encoding(&self.options)
+            {
+                self.character_encoding = encoding;
+                self.encoding_confidence = confidence;
+                self.encoding_mismatch = mismatch;
+            }
+        }
+
+        tracing::debug!(
+            encoding = %self.character_encoding,
+            confidence = ?self.encoding_confidence,
+            "determined encoding"
+        );
+    }
+
+    fn run<S: TreeSink>(&mut self, sink: S, recover: bool) -> HtmlParseResult<S::Output> {
+        let mut tree_builder = TreeBuilder::new(sink, &self.options);
+        self.lexer.tokenizer().set_xml_mode(self.options.xml_mode);
+
+        // A conflicting declaration is only a diagnostic, so it never aborts parsing (but is
+        // recorded either way)
+        if let Some(mismatch) = self.encoding_mismatch.take() {
+            tree_builder.parse_error(mismatch);
+        }
+
+        loop {
+            let token = match self.lexer.next_token() {
+                Ok(token) => token,
+                Err(error) if recover => {
+                    tree_builder.parse_error(error);
+                    continue;
+                }
+                Err(error) => return Err(error),
+            };
+
+            tracing::trace!(?token, "token");
+
+            if !self.options.keep_comments && matches!(token.kind, TokenKind::Comment(_)) {
+                continue;
+            }
+
+            let is_eof = matches!(token.kind, TokenKind::Eof);
+
+            for error in tree_builder.process_token(token, self.lexer.tokenizer()) {
+                if !recover {
+                    return Err(error);
+                }
+
+                tree_builder.parse_error(error);
+            }
+
+            if is_eof {
+                break;
+            }
         }
 
-        // Return reference to peeked
-        return Ok(self.peeked_decoded_char.as_ref());
+        Ok(tree_builder.finish())
     }
+}
 
+impl<R: Read> HtmlParser<StreamLexer<'_, R>> {
     /// https://html.spec.whatwg.org/#changing-the-encoding-while-parsing
     ///
     /// This algorithm is only invoked when a new encoding is found declared
     /// on a meta element.
     #[allow(unused)]
     fn change_encoding(&mut self, new_encoding: CharacterEncoding) {
-        if matches!(
-            self.character_encoding,
-            CharacterEncoding::Utf16BE | CharacterEncoding::Utf16LE
-        ) {
+        tracing::debug!(
+            old = %self.character_encoding,
+            new = %new_encoding,
+            "changing encoding"
+        );
+
+        if self.character_encoding.is_utf16() {
             self.encoding_confidence = EncodingConfidence::Certain;
             return;
         }
 
-        if matches!(
-            new_encoding,
-            CharacterEncoding::Utf16BE | CharacterEncoding::Utf16LE
-        ) {
+        if new_encoding.is_utf16() {
             self.character_encoding = CharacterEncoding::Utf8;
         }
 
@@ -241,10 +560,14 @@ impl<R: Read> HtmlParser<R> {
 
         if self.is_encoding_equal(new_encoding) {
             self.character_encoding = new_encoding;
+            self.lexer.set_encoding(new_encoding);
             self.encoding_confidence = EncodingConfidence::Certain;
             return;
         }
 
+        // The restarted parse must not see any state left over from the old decoder
+        self.lexer.reset_decoder();
+
         // TODO: restart the navigate algorithm
         todo!("restart navigation")
     }
@@ -260,45 +583,94 @@ impl<R: Read> HtmlParser<R> {
 
     /// Function that implements the "encoding sniffing algorithm"
     /// defined in the spec (https://html.spec.whatwg.org/#determining-the-character-encoding)
-    fn determine_encoding(io_queue: &IoQueue<R>) -> (CharacterEncoding, EncodingConfidence) {
-        // Step 1: BOM sniffing
-        let bytes = (
-            io_queue.peek_nth(0),
-            io_queue.peek_nth(1),
-            io_queue.peek_nth(2),
-        );
+    ///
+    /// Every source of encoding information is looked at, even after one of them was picked,
+    /// so that conflicting declarations can be reported.
+    fn determine_encoding(
+        io_queue: &mut IoQueue<'_, R>,
+        options: &ParseOptions,
+    ) -> (
+        CharacterEncoding,
+        EncodingConfidence,
+        Option<HtmlParseError>,
+    ) {
+        let _span = tracing::debug_span!("determine_encoding").entered();
+        let ParseOptions {
+            transport_encoding,
+            parent_encoding,
+            ..
+        } = *options;
 
-        match bytes {
-            (Some(0xEF), Some(0xBB), Some(0xBF)) => {
-                return (CharacterEncoding::Utf8, EncodingConfidence::Certain)
-            }
-            (Some(0xFE), Some(0xFF), _) => {
-                return (CharacterEncoding::Utf16BE, EncodingConfidence::Certain)
-            }
-            (Some(0xFF), Some(0xFE), _) => {
-                return (CharacterEncoding::Utf16LE, EncodingConfidence::Certain)
-            }
-            _ => {}
-        }
+        // Step 1: BOM sniffing (the BOM itself is not part of the document, so it is skipped)
+        // A 2 byte input can still start with a UTF-16 BOM
+        let bom = match io_queue.peek_n::<3>() {
+            Some(prefix) => CharacterEncoding::from_bom(&prefix),
+            None => io_queue
+                .peek_n::<2>()
+                .and_then(|prefix| CharacterEncoding::from_bom(&prefix)),
+        };
+        let bom = bom.map(|(encoding, len)| {
+            io_queue.skip(len);
+            encoding
+        });
+        tracing::debug!(?bom, "sniffed byte order mark");
 
         // Step 2: Explicitly defined user preferences
         // TODO: implement user encoding preference
 
-        // Step 3: Optionally wait for first 1024 bytes to pre-scan?
-        io_queue.peek_max(1024);
+        // Step 3: Optionally wait for first 1024 bytes to pre-scan? (or all of them, if asked to
+        //         and they are in memory already)
+        let window = if options.prescan_whole_buffer && io_queue.is_in_memory() {
+            io_queue.peek_to_end();
+            io_queue.peek_len()
+        } else {
+            io_queue.peek_max(PRESCAN_WINDOW);
+            PRESCAN_WINDOW
+        };
+
+        let meta = HtmlPreScanner::new(io_queue)
+            .with_window(window)
+            .pre_scan_byte_stream();
+        tracing::debug!(?meta, ?transport_encoding, "pre-scanned byte stream");
+
+        let declared: Vec<_> = [bom, transport_encoding, meta]
+            .into_iter()
+            .flatten()
+            .collect();
+        let mismatch = declared
+            .windows(2)
+            .any(|pair| pair[0] != pair[1])
+            .then_some(HtmlParseError::EncodingMismatch {
+                transport: transport_encoding,
+                bom,
+                meta,
+            });
+
+        if let Some(mismatch) = &mismatch {
+            tracing::debug!(?mismatch, "declared encodings disagree");
+        }
+
+        if let Some(bom) = bom {
+            return (bom, EncodingConfidence::Certain, mismatch);
+        }
 
         // Step 4: Transport layer defined character encoding
-        // TODO
+        if let Some(transport_encoding) = transport_encoding {
+            return (transport_encoding, EncodingConfidence::Certain, mismatch);
+        }
 
         // Step 5: Pre-scan the byte stream to determine the encoding
-        if let Some(encoding) = HtmlPreScanner::new(io_queue).pre_scan_byte_stream() {
-            return (encoding, EncodingConfidence::Tentative);
+        if let Some(encoding) = meta {
+            return (encoding, EncodingConfidence::Tentative, mismatch);
         }
 
         // Step 6: If this HtmlParser is parsing on behalf of a document with a parent,
-        //         use the content encoding of the parent document (some caveats) with
-        //         confidence tentative
-        // TODO
+        //         use the content encoding of the parent document with confidence tentative
+        //         (checking that the parent is same origin is up to the caller)
+        if let Some(encoding) = parent_encoding.filter(|encoding| !encoding.is_utf16()) {
+            tracing::debug!(%encoding, "using the encoding of the parent document");
+            return (encoding, EncodingConfidence::Tentative, mismatch);
+        }
 
         // Step 7: If UA has information on the likely encoding (from previous visits),
         //         use that with confidence tentative
@@ -312,11 +684,1417 @@ impl<R: Read> HtmlParser<R> {
         // Step 9: Use implementation defined default encoding
         const DEFAULT_ENCODING: CharacterEncoding = CharacterEncoding::Utf8;
 
-        (DEFAULT_ENCODING, EncodingConfidence::Tentative)
+        (DEFAULT_ENCODING, EncodingConfidence::Tentative, mismatch)
     }
 }
 
-#[allow(unused)]
-pub struct Document {
-    encoding: CharacterEncoding,
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FormData, NodeData, NodeId, QuirksMode, Span};
+    use std::fmt::Debug;
+
+    const MARIO: &str = r#"<!DOCTYPE html>
+<html>
+    <head>
+        <meta http-equiv="content-type" content="text/html; charset=utf8">
+        <title>Mario!</title>
+    </head>
+    <body id="root">
+        🦀
+        <h1 class="big_title" aria-label='heading'>Mario!</h1>
+        <p>It's a me, Mario!</p>
+    </body>
+</html>
+"#;
+
+    #[test]
+    fn into_document() {
+        let document = HtmlParser::new(
+            "<!DOCTYPE html><html><body><p class=a>Hi &amp; bye</p></body></html>".as_bytes(),
+        )
+        .into_document();
+
+        assert!(document.parse_errors().is_empty());
+        assert!(matches!(
+            &document.get(document.children(document.root())[0]).data,
+            NodeData::Doctype { name, .. } if name == "html"
+        ));
+
+        let html = document.document_element().unwrap();
+        assert_eq!(document.element(html).unwrap().name, "html");
+        assert_eq!(document.text_content(html), "Hi & bye");
+    }
+
+    #[test]
+    fn parse_events() {
+        let mut events = Vec::new();
+        HtmlParser::from_bytes(MARIO.as_bytes()).parse_events(|event| events.push(event));
+
+        assert!(
+            matches!(&events[0], Event::Doctype(doctype) if doctype.name.as_deref() == Some("html"))
+        );
+        assert!(matches!(&events[1], Event::Text(text) if text == "\n"));
+        assert!(matches!(&events[2], Event::Start(tag) if tag.name == "html"));
+        assert!(matches!(events.last(), Some(Event::Text(text)) if text == "\n"));
+
+        // Every start tag is closed by the matching end tag, except for the void `meta`
+        let mut open = Vec::new();
+        for event in &events {
+            match event {
+                Event::Start(tag) if tag.name != "meta" => open.push(tag.name.as_str()),
+                Event::End(name) => assert_eq!(open.pop(), Some(name.as_str())),
+                _ => {}
+            }
+        }
+        assert!(open.is_empty());
+
+        let text: String = events
+            .iter()
+            .filter_map(|event| match event {
+                Event::Text(text) => Some(text.trim()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(text, "Mario!🦀Mario!It's a me, Mario!");
+
+        // Comments are left out along with the tree
+        let mut events = Vec::new();
+        HtmlParser::new("<p>a<!--b--></p>".as_bytes())
+            .keep_comments(false)
+            .parse_events(|event| events.push(event));
+        assert_eq!(events.len(), 3);
+    }
+
+    #[test]
+    fn keep_comments() {
+        const HTML: &str = "<p>a<!-- note -->b</p>";
+
+        let document = HtmlParser::from_bytes(HTML.as_bytes()).parse();
+        let p = document.document_element().unwrap();
+        assert!(matches!(
+            &document.get(document.children(p)[1]).data,
+            NodeData::Comment(comment) if comment == " note "
+        ));
+
+        let document = HtmlParser::from_bytes(HTML.as_bytes())
+            .keep_comments(false)
+            .parse();
+        let p = document.document_element().unwrap();
+        assert_eq!(document.children(p).len(), 1);
+        assert_eq!(document.text_content(p), "ab");
+    }
+
+    #[test]
+    fn parse_options() {
+        let options = ParseOptions {
+            transport_encoding: Some(CharacterEncoding::Windows1251),
+            keep_comments: false,
+            scripting_enabled: true,
+            iframe_srcdoc: true,
+            limits: ParserLimits { max_depth: 2 },
+            ..ParseOptions::default()
+        };
+        let html = b"<!-- note --><div><noscript><p>x</p></noscript><p><b>\xCF</b></p></div>";
+
+        let document = HtmlParser::from_bytes(&html[..]).options(options).parse();
+
+        assert_eq!(document.encoding(), CharacterEncoding::Windows1251);
+        assert_eq!(document.quirks_mode(), QuirksMode::NoQuirks);
+        assert_eq!(
+            document.to_html(),
+            "<div><noscript>&lt;p&gt;x&lt;/p&gt;</noscript><p><b></b>\u{41F}</p></div>"
+        );
+
+        let options = ParseOptions {
+            xml_mode: true,
+            ..ParseOptions::default()
+        };
+        let document = HtmlParser::from_bytes(&b"<A><B/></A>"[..])
+            .options(options)
+            .parse();
+
+        assert_eq!(document.to_html(), "<A><B></B></A>");
+
+        let options = ParseOptions {
+            parent_encoding: Some(CharacterEncoding::KOI8R),
+            ..ParseOptions::default()
+        };
+        let document = HtmlParser::from_bytes(&b"<p>\xF0</p>"[..])
+            .options(options)
+            .parse();
+
+        assert_eq!(document.encoding(), CharacterEncoding::KOI8R);
+
+        // The declaration is past the first 1024 bytes
+        let html = [&[b' '; 1024][..], b"<meta charset=windows-1251><p>\xCF</p>"].concat();
+        let options = ParseOptions {
+            prescan_whole_buffer: true,
+            ..ParseOptions::default()
+        };
+        let document = HtmlParser::from_bytes(&html).options(options).parse();
+
+        assert_eq!(document.encoding(), CharacterEncoding::Windows1251);
+        assert_eq!(document.text_content(document.root()), "\u{41F}");
+    }
+
+    #[test]
+    fn empty_body() {
+        assert_eq!(
+            HtmlParser::determine_encoding(
+                &mut IoQueue::new(std::io::empty()),
+                &ParseOptions::default()
+            ),
+            (CharacterEncoding::Utf8, EncodingConfidence::Tentative, None)
+        );
+
+        let document = HtmlParser::new(std::io::empty()).try_parse().unwrap();
+
+        assert!(document.children(document.root()).is_empty());
+        assert!(document.parse_errors().is_empty());
+        assert_eq!(document.encoding(), CharacterEncoding::Utf8);
+    }
+
+    #[test]
+    fn content_length() {
+        for content_length in [Some(MARIO.len() as u64), Some(u64::MAX), None] {
+            let mut parser = HtmlParser::new(MARIO.as_bytes()).content_length(content_length);
+
+            // A bogus length only gets room for the pre-scan window
+            let capacity = parser.lexer.io_queue_mut().capacity();
+            match content_length {
+                Some(_) => assert!((MARIO.len()..=2 * PRESCAN_WINDOW).contains(&capacity)),
+                None => assert_eq!(capacity, 0),
+            }
+
+            let document = parser.parse();
+
+            assert_eq!(document.title().as_deref(), Some("Mario!"));
+            assert_eq!(document.source_map().unwrap().len(), MARIO.chars().count());
+        }
+    }
+
+    #[test]
+    fn utf_16_xml_declaration_without_bom() {
+        let xml = "<?xml version=\"1.0\" encoding=\"utf-16\"?><root><p>Grüße 🦀</p></root>";
+        let utf_16le: Vec<u8> = xml.encode_utf16().flat_map(u16::to_le_bytes).collect();
+        let utf_16be: Vec<u8> = xml.encode_utf16().flat_map(u16::to_be_bytes).collect();
+
+        for (bytes, encoding) in [
+            (&utf_16le, CharacterEncoding::Utf16LE),
+            (&utf_16be, CharacterEncoding::Utf16BE),
+        ] {
+            // The declaration is only peeked while pre-scanning, so decoding starts at its `<`
+            let document = HtmlParser::from_bytes(&bytes[..])
+                .xml_mode(true)
+                .try_parse()
+                .unwrap();
+
+            assert_eq!(document.encoding(), encoding);
+            assert_eq!(document.text_content(document.root()), "Grüße 🦀");
+
+            let tokens: Vec<_> = HtmlParser::from_bytes(&bytes[..])
+                .xml_mode(true)
+                .tokens()
+                .map(Result::unwrap)
+                .collect();
+
+            assert!(matches!(
+                &tokens[0].kind,
+                TokenKind::ProcessingInstruction { target, data }
+                    if target == "xml" && data == "version=\"1.0\" encoding=\"utf-16\""
+            ));
+            assert_eq!(tokens[0].span, Span::new(0, 39));
+            assert!(matches!(&tokens[3].kind, TokenKind::Text(text) if text == "Grüße 🦀"));
+        }
+    }
+
+    #[test]
+    fn parent_encoding() {
+        let parse = |html: &str, parent_encoding| {
+            HtmlParser::from_bytes(html.as_bytes())
+                .parent_encoding(parent_encoding)
+                .parse()
+                .encoding()
+        };
+
+        assert_eq!(
+            parse("<p>café</p>", Some(CharacterEncoding::Windows1252)),
+            CharacterEncoding::Windows1252
+        );
+        assert_eq!(parse("<p>café</p>", None), CharacterEncoding::Utf8);
+
+        // The document's own declaration takes precedence
+        assert_eq!(
+            parse(
+                "<meta charset=utf-8><p>café</p>",
+                Some(CharacterEncoding::Windows1252)
+            ),
+            CharacterEncoding::Utf8
+        );
+
+        // UTF-16 is never inherited
+        assert_eq!(
+            parse("<p>café</p>", Some(CharacterEncoding::Utf16LE)),
+            CharacterEncoding::Utf8
+        );
+    }
+
+    #[test]
+    fn reports_encoding_mismatch() {
+        const HTML: &[u8] = b"<meta charset=utf-8><p>caf\xE9</p>";
+        let mismatch = HtmlParseError::EncodingMismatch {
+            transport: Some(CharacterEncoding::Windows1252),
+            bom: None,
+            meta: Some(CharacterEncoding::Utf8),
+        };
+
+        let document = HtmlParser::new(HTML)
+            .transport_encoding(CharacterEncoding::Windows1252)
+            .parse();
+
+        assert_eq!(document.encoding(), CharacterEncoding::Windows1252);
+        assert_eq!(document.text_content(document.root()), "café");
+        assert_eq!(document.parse_errors(), [mismatch]);
+
+        // It doesn't abort parsing either
+        let document = HtmlParser::new(HTML)
+            .transport_encoding(CharacterEncoding::Windows1252)
+            .try_parse()
+            .unwrap();
+
+        assert_eq!(document.text_content(document.root()), "café");
+        assert_eq!(document.parse_errors(), [mismatch]);
+
+        let document = HtmlParser::new(HTML)
+            .transport_encoding(CharacterEncoding::Utf8)
+            .parse();
+
+        assert_eq!(document.encoding(), CharacterEncoding::Utf8);
+        assert!(document.parse_errors().is_empty());
+    }
+
+    #[test]
+    fn skips_utf8_bom() {
+        let document = HtmlParser::from_bytes("\u{FEFF}<p>Hi</p>".as_bytes()).parse();
+        let p = document.document_element().unwrap();
+
+        assert_eq!(document.children(document.root()).len(), 1);
+        assert_eq!(document.text_content(p), "Hi");
+
+        // A UTF-16 BOM on its own is still sniffed, even though there are fewer than 3 bytes
+        let parser = HtmlParser::from_bytes(&b"\xFF\xFE"[..]).sniff_encoding();
+        assert_eq!(parser.document_encoding(), CharacterEncoding::Utf16LE);
+        assert_eq!(parser.encoding_confidence(), EncodingConfidence::Certain);
+    }
+
+    #[test]
+    fn tokens() {
+        let kinds: Vec<String> = HtmlParser::from_bytes(MARIO.as_bytes())
+            .tokens()
+            .map(|token| match token.unwrap().kind {
+                TokenKind::Doctype(doctype) => format!("<!{}>", doctype.name.unwrap()),
+                TokenKind::StartTag(tag) => format!("<{}>", tag.name),
+                TokenKind::EndTag(tag) => format!("</{}>", tag.name),
+                TokenKind::Comment(comment) => format!("<!--{comment}-->"),
+                TokenKind::Text(text) if text.trim().is_empty() => "_".to_owned(),
+                TokenKind::Text(text) => text.trim().to_owned(),
+                TokenKind::ProcessingInstruction { target, .. } => format!("<?{target}?>"),
+                TokenKind::Eof => "EOF".to_owned(),
+            })
+            .collect();
+
+        assert_eq!(
+            kinds,
+            [
+                "<!html>",
+                "_",
+                "<html>",
+                "_",
+                "<head>",
+                "_",
+                "<meta>",
+                "_",
+                "<title>",
+                "Mario!",
+                "</title>",
+                "_",
+                "</head>",
+                "_",
+                "<body>",
+                "🦀",
+                "<h1>",
+                "Mario!",
+                "</h1>",
+                "_",
+                "<p>",
+                "It's a me, Mario!",
+                "</p>",
+                "_",
+                "</body>",
+                "_",
+                "</html>",
+                "_",
+                "EOF",
+            ]
+        );
+    }
+
+    /// Describes every node of the document, indented by depth
+    fn dump(document: &Document) -> String {
+        document
+            .iter()
+            .map(|(id, node)| format!("{}{:?}\n", "  ".repeat(document.depth(id)), node.data))
+            .collect()
+    }
+
+    #[test]
+    fn iterates_in_tree_order() {
+        let document = HtmlParser::from_bytes(MARIO.as_bytes())
+            .keep_comments(false)
+            .parse();
+
+        let names: Vec<_> = document
+            .iter()
+            .filter_map(|(_, node)| match &node.data {
+                NodeData::Element(element) => Some(element.name.as_str()),
+                NodeData::Document => Some("#document"),
+                NodeData::Doctype { .. } => Some("#doctype"),
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(
+            names,
+            [
+                "#document",
+                "#doctype",
+                "html",
+                "head",
+                "meta",
+                "title",
+                "body",
+                "h1",
+                "p"
+            ]
+        );
+        assert_eq!(
+            document.iter().count(),
+            document.descendants(document.root()).count() + 1
+        );
+
+        let body = find_element(&document, "body");
+        let text: String = document
+            .descendants(body)
+            .filter_map(|(_, node)| match &node.data {
+                NodeData::Text(text) => Some(text.trim()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(text, "🦀Mario!It's a me, Mario!");
+    }
+
+    #[test]
+    fn from_chars() {
+        let from_bytes = HtmlParser::from_bytes(MARIO.as_bytes()).parse();
+        let from_chars = HtmlParser::from_chars(MARIO.chars()).parse();
+
+        assert_eq!(dump(&from_chars), dump(&from_bytes));
+        assert!(dump(&from_chars).contains("Text(\"It's a me, Mario!\")"));
+        assert_eq!(from_chars.parse_errors(), from_bytes.parse_errors());
+    }
+
+    fn find_element(document: &Document, name: &str) -> NodeId {
+        document
+            .iter()
+            .find(|(id, _)| {
+                document
+                    .element(*id)
+                    .is_some_and(|element| element.name == name)
+            })
+            .map(|(id, _)| id)
+            .unwrap_or_else(|| panic!("no {name} element"))
+    }
+
+    #[test]
+    fn inner_and_outer_html() {
+        let document = HtmlParser::from_bytes(MARIO.as_bytes()).parse();
+
+        let body = document.element(find_element(&document, "body")).unwrap();
+        let inner_html = body.inner_html(&document).unwrap();
+        assert!(inner_html.starts_with("\n        🦀\n        <h1"));
+        assert!(inner_html.contains("<h1 class=\"big_title\" aria-label=\"heading\">Mario!</h1>"));
+        assert!(inner_html.contains("<p>It's a me, Mario!</p>"));
+        assert!(!inner_html.contains("<body"));
+
+        let h1 = document.element(find_element(&document, "h1")).unwrap();
+        let outer_html = h1.outer_html(&document).unwrap();
+        assert_eq!(
+            outer_html,
+            "<h1 class=\"big_title\" aria-label=\"heading\">Mario!</h1>"
+        );
+
+        // Parsing the serialized element gives back the same element
+        let reparsed = HtmlParser::from_bytes(outer_html.as_bytes()).parse();
+        let h1 = reparsed.document_element().unwrap();
+        assert_eq!(reparsed.outer_html(h1), outer_html);
+
+        // Copies of the element still serialize, but elements of other documents don't
+        let copy = document
+            .element(find_element(&document, "h1"))
+            .unwrap()
+            .clone();
+        assert_eq!(copy.outer_html(&document), Some(outer_html));
+        assert_eq!(copy.outer_html(&reparsed), None);
+
+        // Even if the other document has the same element at the same node
+        let mario = HtmlParser::from_bytes(b"<p>Mario</p>").parse();
+        let mut luigi = HtmlParser::from_bytes(b"<p>Luigi</p>").parse();
+        let p = mario.element(find_element(&mario, "p")).unwrap().clone();
+        assert_eq!(p.id(), find_element(&luigi, "p"));
+        assert_eq!(p.outer_html(&luigi), None);
+
+        // Or the same document, parsed into again
+        let luigi_p = luigi.element(find_element(&luigi, "p")).unwrap().clone();
+        HtmlParser::from_bytes(b"<p>Mario</p>").parse_into(&mut luigi);
+        assert_eq!(luigi_p.outer_html(&luigi), None);
+        assert_eq!(p.outer_html(&mario).unwrap(), "<p>Mario</p>");
+    }
+
+    #[test]
+    fn element_matches() {
+        let document = HtmlParser::from_bytes(MARIO.as_bytes()).parse();
+        let h1 = find_element(&document, "h1");
+        let body = find_element(&document, "body");
+
+        assert!(document.element_matches(h1, ".big_title"));
+        assert!(document.element_matches(h1, "H1.big_title[aria-label]"));
+        assert!(document.element_matches(body, "body#root"));
+        assert!(document.element_matches(body, "*"));
+
+        assert!(!document.element_matches(h1, "p.big_title"));
+        assert!(!document.element_matches(h1, ".big"));
+        assert!(!document.element_matches(body, "html body"));
+        assert!(!document.element_matches(body, ""));
+        assert!(!document.element_matches(document.root(), "*"));
+
+        let document =
+            HtmlParser::from_bytes(r#"<input name=a disabled><input name=b>"#.as_bytes()).parse();
+        let inputs: Vec<_> = document
+            .iter()
+            .map(|(id, _)| id)
+            .filter(|&id| document.element_matches(id, "input[disabled]"))
+            .collect();
+
+        assert_eq!(inputs.len(), 1);
+        assert_eq!(
+            document.element(inputs[0]).unwrap().get_attribute("name"),
+            Some("a")
+        );
+    }
+
+    #[test]
+    fn parse_into() {
+        let mut document = HtmlParser::from_bytes(MARIO.as_bytes()).parse();
+        let capacity = document.node_capacity();
+
+        HtmlParser::from_bytes("<p>caf\u{E9}</p><p>two</p>".as_bytes()).parse_into(&mut document);
+
+        assert_eq!(document.to_html(), "<p>caf\u{E9}</p><p>two</p>");
+        assert_eq!(document.encoding(), CharacterEncoding::Utf8);
+        assert_eq!(document.quirks_mode(), QuirksMode::Quirks);
+        assert!(document.parse_errors().is_empty());
+        assert_eq!(document.node_capacity(), capacity);
+
+        let fresh = HtmlParser::from_bytes("<p>caf\u{E9}</p><p>two</p>".as_bytes()).parse();
+        assert_eq!(dump(&document), dump(&fresh));
+    }
+
+    #[test]
+    fn to_writer() {
+        let document = HtmlParser::from_bytes(MARIO.as_bytes()).parse();
+        let mut html = Vec::new();
+        document.to_writer(&mut html).unwrap();
+        assert_eq!(String::from_utf8(html).unwrap(), document.to_html());
+
+        /// Checks that the output comes in small pieces rather than all at once
+        struct Pieces {
+            written: usize,
+            largest: usize,
+        }
+
+        impl std::io::Write for Pieces {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.written += buf.len();
+                self.largest = self.largest.max(buf.len());
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let html = "<div>".repeat(2_000);
+        let document = HtmlParser::from_bytes(html.as_bytes())
+            .limits(ParserLimits { max_depth: 4_000 })
+            .parse();
+        let mut pieces = Pieces {
+            written: 0,
+            largest: 0,
+        };
+        document.to_writer(&mut pieces).unwrap();
+
+        assert_eq!(pieces.written, 22_000);
+        assert_eq!(pieces.largest, 3);
+
+        // Errors of the writer are passed on
+        let mut full = [0; 8];
+        let error = document.to_writer(&mut &mut full[..]).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::WriteZero);
+        assert_eq!(&full, b"<div><di");
+    }
+
+    #[test]
+    fn serializes_void_elements_and_escapes() {
+        let html = "<!DOCTYPE html><p title='\"a&amp;b\"'>1 &lt; 2&nbsp;&amp; <br>x</p>\
+                    <script>if (1 < 2) {}</script><template><img src=a.png></template>";
+        let document = HtmlParser::from_bytes(html.as_bytes()).parse();
+
+        assert_eq!(
+            document.to_html(),
+            "<!DOCTYPE html><p title=\"&quot;a&amp;b&quot;\">1 &lt; 2&nbsp;&amp; <br>x</p>\
+             <script>if (1 < 2) {}</script><template><img src=\"a.png\"></template>"
+        );
+
+        let template = find_element(&document, "template");
+        assert_eq!(document.inner_html(template), "<img src=\"a.png\">");
+    }
+
+    #[test]
+    fn escapes_by_context() {
+        let html = "<p title='\"a & b\" <>'>1 < 2 & 3 > 0</p>";
+        let document = HtmlParser::from_bytes(html.as_bytes()).parse();
+
+        // Attribute values are double-quoted, so only `&` and `"` need escaping in them
+        let p = find_element(&document, "p");
+        assert_eq!(
+            document.outer_html(p),
+            "<p title=\"&quot;a &amp; b&quot; <>\">1 &lt; 2 &amp; 3 &gt; 0</p>"
+        );
+
+        let document = HtmlParser::new("<p title=\"it's\">it's</p>".as_bytes()).parse();
+        assert_eq!(document.to_html(), "<p title=\"it's\">it's</p>");
+    }
+
+    #[test]
+    fn downlevel_revealed() {
+        const HTML: &str = "<body><!--[if IE]><p class=ie>Upgrade!</p><![endif]-->\
+                            <![if !IE]><p>Modern</p><![endif]></body>";
+
+        let document = HtmlParser::from_bytes(HTML.as_bytes()).parse();
+        assert_eq!(
+            document.to_html(),
+            "<body><!--[if IE]><p class=ie>Upgrade!</p><![endif]-->\
+             <!--[if !IE]--><p>Modern</p><!--[endif]--></body>"
+        );
+
+        let revealed = document.downlevel_revealed();
+        assert_eq!(
+            revealed.to_html(),
+            "<body><p class=\"ie\">Upgrade!</p><p>Modern</p></body>"
+        );
+        assert_eq!(revealed.encoding(), document.encoding());
+
+        // Without comments there is nothing to reveal
+        let document = HtmlParser::from_bytes(HTML.as_bytes())
+            .keep_comments(false)
+            .parse();
+        assert_eq!(document.downlevel_revealed().to_html(), document.to_html());
+        assert_eq!(document.to_html(), "<body><p>Modern</p></body>");
+    }
+
+    #[test]
+    fn forms() {
+        const HTML: &str = r#"<body>
+            <form action="/search" method="POST">
+                <input type="text" name="q" value="mario kart">
+                <input type="hidden" name="token" value="a&amp;b">
+                <input type="checkbox" name="safe" checked>
+                <input type="checkbox" name="images">
+                <input name="disabled" value="x" disabled>
+                <input type="submit" name="go" value="Search">
+                <textarea name="notes">
+line one
+line two</textarea>
+                <select name="lang">
+                    <option value="en">English</option>
+                    <option selected> Italiano </option>
+                </select>
+            </form>
+            <form><input name="empty"></form>
+        </body>"#;
+
+        let mut document = HtmlParser::from_bytes(HTML.as_bytes()).parse();
+        document.set_url("https://mario.example/docs/index.html".parse().unwrap());
+
+        let forms = document.forms();
+        assert_eq!(
+            forms[0],
+            FormData {
+                method: "post".to_owned(),
+                action: "https://mario.example/search".parse().unwrap(),
+                fields: [
+                    ("q", "mario kart"),
+                    ("token", "a&b"),
+                    ("safe", "on"),
+                    ("notes", "line one\nline two"),
+                    ("lang", "Italiano"),
+                ]
+                .map(|(name, value)| (name.to_owned(), value.to_owned()))
+                .to_vec(),
+            }
+        );
+
+        // Without an action, the form is submitted to the document itself
+        assert_eq!(forms[1].method, "get");
+        assert_eq!(
+            forms[1].action.as_str(),
+            "https://mario.example/docs/index.html"
+        );
+        assert_eq!(forms[1].fields, [("empty".to_owned(), String::new())]);
+        assert_eq!(forms.len(), 2);
+    }
+
+    #[test]
+    fn title() {
+        let document = HtmlParser::from_bytes(MARIO.as_bytes()).parse();
+        assert_eq!(document.title().as_deref(), Some("Mario!"));
+
+        let document = HtmlParser::new(
+            "<title>\n  Tom &amp; <b>Jerry</b>\t</title><title>Other</title>".as_bytes(),
+        )
+        .parse();
+        assert_eq!(document.title().as_deref(), Some("Tom & <b>Jerry</b>"));
+
+        let document = HtmlParser::from_bytes("<p>Untitled</p>".as_bytes()).parse();
+        assert_eq!(document.title(), None);
+    }
+
+    #[test]
+    fn collect_links() {
+        const HTML: &str = r##"<!DOCTYPE html>
+<html>
+    <head>
+        <link rel="stylesheet" href="/styles/main.css">
+    </head>
+    <body>
+        <a href="https://example.com/absolute">Absolute</a>
+        <a href="relative/page.html">Relative</a>
+        <a href="../up.html?q=1">Up</a>
+        <a href="#top">Fragment</a>
+        <a href=" //cdn.example.org/x ">Scheme relative</a>
+        <a href="javascript:alert(1)">Script</a>
+        <a href="http://[bad">Malformed</a>
+        <a>No href</a>
+        <map><area href="area.html"></map>
+    </body>
+</html>"##;
+
+        let mut document = HtmlParser::from_bytes(HTML.as_bytes()).parse();
+        document.set_url("https://mario.example/docs/index.html".parse().unwrap());
+
+        let links: Vec<String> = document
+            .collect_links()
+            .iter()
+            .map(|url| url.to_string())
+            .collect();
+
+        assert_eq!(
+            links,
+            [
+                "https://mario.example/styles/main.css",
+                "https://example.com/absolute",
+                "https://mario.example/docs/relative/page.html",
+                "https://mario.example/up.html?q=1",
+                "https://mario.example/docs/index.html#top",
+                "https://cdn.example.org/x",
+                "https://mario.example/docs/area.html",
+            ]
+        );
+    }
+
+    #[test]
+    fn base_url() {
+        let mut document = HtmlParser::from_bytes(
+            "<base target=_blank><base href=/assets/><a href=img.png>".as_bytes(),
+        )
+        .parse();
+        assert_eq!(document.base_url(), None);
+        assert!(document.collect_links().is_empty());
+
+        document.set_url("https://mario.example/docs/".parse().unwrap());
+        assert_eq!(
+            document.collect_links()[0].as_str(),
+            "https://mario.example/assets/img.png"
+        );
+    }
+
+    #[test]
+    fn to_bytes() {
+        let document = HtmlParser::from_chars("<!DOCTYPE html><p>Mario!</p>".chars()).parse();
+        let html = document.to_html();
+
+        assert_eq!(
+            document.to_bytes(CharacterEncoding::Windows1252),
+            html.as_bytes()
+        );
+
+        let document = HtmlParser::from_chars("<p title=漢>漢字 &amp; €</p>".chars()).parse();
+
+        assert_eq!(
+            document.to_bytes(CharacterEncoding::Windows1252),
+            b"<p title=\"&#28450;\">&#28450;&#23383; &amp; \x80</p>"
+        );
+
+        // The references decode back to the same document
+        let bytes = document.to_bytes(CharacterEncoding::Windows1252);
+        let reparsed =
+            HtmlParser::with_definite_encoding(&bytes[..], CharacterEncoding::Windows1252).parse();
+        assert_eq!(reparsed.to_html(), document.to_html());
+    }
+
+    #[test]
+    fn quirks_mode() {
+        let quirks_mode = |html: &str| HtmlParser::from_chars(html.chars()).parse().quirks_mode();
+
+        assert_eq!(quirks_mode("<!DOCTYPE html><p>"), QuirksMode::NoQuirks);
+        assert_eq!(
+            quirks_mode("<!-- comment -->\n<!doctype HTML>"),
+            QuirksMode::NoQuirks
+        );
+        assert_eq!(quirks_mode("<p>no doctype"), QuirksMode::Quirks);
+        assert_eq!(quirks_mode(""), QuirksMode::Quirks);
+        assert_eq!(quirks_mode("<!DOCTYPE svg>"), QuirksMode::Quirks);
+        assert_eq!(
+            quirks_mode("<!DOCTYPE HTML PUBLIC \"-//W3C//DTD HTML 3.2 Final//EN\">"),
+            QuirksMode::Quirks
+        );
+        assert_eq!(
+            quirks_mode("<!DOCTYPE HTML PUBLIC \"-//W3C//DTD HTML 4.01 Transitional//EN\">"),
+            QuirksMode::Quirks
+        );
+        assert_eq!(
+            quirks_mode(
+                "<!DOCTYPE HTML PUBLIC \"-//W3C//DTD HTML 4.01 Transitional//EN\" \"http://www.w3.org/TR/html4/loose.dtd\">"
+            ),
+            QuirksMode::LimitedQuirks
+        );
+        assert_eq!(
+            quirks_mode("<!DOCTYPE html PUBLIC \"-//W3C//DTD XHTML 1.0 Strict//EN\">"),
+            QuirksMode::NoQuirks
+        );
+
+        // A late doctype is ignored
+        assert_eq!(quirks_mode("<p><!DOCTYPE html>"), QuirksMode::Quirks);
+
+        let document = HtmlParser::from_bytes(&b"<p>xml</p>"[..])
+            .xml_mode(true)
+            .parse();
+        assert_eq!(document.quirks_mode(), QuirksMode::NoQuirks);
+    }
+
+    #[test]
+    fn quirks_mode_display() {
+        for (mode, name) in [
+            (QuirksMode::Quirks, "quirks"),
+            (QuirksMode::LimitedQuirks, "limited-quirks"),
+            (QuirksMode::NoQuirks, "no-quirks"),
+        ] {
+            assert_eq!(mode.to_string(), name);
+            assert_eq!(name.parse(), Ok(mode));
+        }
+        assert_eq!("Quirks".parse::<QuirksMode>(), Err(()));
+
+        // A legacy doctype is reported, but doesn't abort the parse
+        let html = "<!DOCTYPE HTML PUBLIC \"-//W3C//DTD HTML 4.01 Transitional//EN\" \"http://www.w3.org/TR/html4/loose.dtd\"><p>";
+        let document = HtmlParser::from_bytes(html.as_bytes()).try_parse().unwrap();
+
+        assert_eq!(document.quirks_mode().to_string(), "limited-quirks");
+        assert_eq!(
+            document.parse_errors(),
+            [HtmlParseError::LegacyDoctype {
+                quirks_mode: QuirksMode::LimitedQuirks
+            }]
+        );
+    }
+
+    #[test]
+    fn parse_srcdoc() {
+        let document = HtmlParser::parse_srcdoc("<p>Hello from an iframe</p>");
+        assert_eq!(document.quirks_mode(), QuirksMode::NoQuirks);
+        assert_eq!(
+            document.text_content(find_element(&document, "p")),
+            "Hello from an iframe"
+        );
+
+        let document = HtmlParser::parse_srcdoc("<!DOCTYPE HTML PUBLIC \"HTML\"><p>");
+        assert_eq!(document.quirks_mode(), QuirksMode::NoQuirks);
+    }
+
+    #[test]
+    fn encoding_accessors() {
+        let parser = HtmlParser::from_bytes(&b"\xEF\xBB\xBF<p>caf\xC3\xA9</p>"[..]);
+        assert_eq!(parser.document_encoding(), CharacterEncoding::Utf8);
+        assert_eq!(parser.encoding_confidence(), EncodingConfidence::Tentative);
+
+        // The byte order mark makes the encoding certain
+        let parser = parser
+            .transport_encoding(CharacterEncoding::Windows1252)
+            .sniff_encoding();
+        assert_eq!(parser.document_encoding(), CharacterEncoding::Utf8);
+        assert_eq!(parser.encoding_confidence(), EncodingConfidence::Certain);
+
+        let document = parser.parse();
+        let p = find_element(&document, "p");
+        assert_eq!(document.text_content(p), "café");
+
+        // A `<meta>` declaration leaves it tentative
+        let parser = HtmlParser::from_bytes(&b"<meta charset=koi8-r>"[..]).sniff_encoding();
+        assert_eq!(parser.document_encoding(), CharacterEncoding::KOI8R);
+        assert_eq!(parser.encoding_confidence(), EncodingConfidence::Tentative);
+
+        // Parsing doesn't sniff again
+        let document = parser.transport_encoding(CharacterEncoding::Utf8).parse();
+        assert_eq!(document.encoding(), CharacterEncoding::KOI8R);
+
+        let parser = HtmlParser::from_chars("<p>".chars()).sniff_encoding();
+        assert_eq!(parser.encoding_confidence(), EncodingConfidence::Irrelevant);
+    }
+
+    #[test]
+    fn prescan_whole_buffer() {
+        // The declaration starts at byte 2000, past the 1024 bytes the pre-scan looks at
+        let mut bytes = b"<!--".to_vec();
+        bytes.resize(1997, b'-');
+        bytes.extend(b"-->");
+        assert_eq!(bytes.len(), 2000);
+        bytes.extend(b"<meta charset=windows-1252><p>caf\xE9!</p>");
+
+        let parser = HtmlParser::from_bytes(&bytes[..]).sniff_encoding();
+        assert_eq!(parser.document_encoding(), CharacterEncoding::Utf8);
+
+        // Streams are never read ahead any further than the usual window
+        let parser = HtmlParser::new(&bytes[..])
+            .prescan_whole_buffer(true)
+            .sniff_encoding();
+        assert_eq!(parser.document_encoding(), CharacterEncoding::Utf8);
+
+        let parser = HtmlParser::from_bytes(&bytes[..])
+            .prescan_whole_buffer(true)
+            .sniff_encoding();
+        assert_eq!(parser.document_encoding(), CharacterEncoding::Windows1252);
+        assert_eq!(parser.encoding_confidence(), EncodingConfidence::Tentative);
+
+        let document = parser.parse();
+        let p = find_element(&document, "p");
+        assert_eq!(document.text_content(p), "café!");
+    }
+
+    #[test]
+    fn from_data_url() {
+        let document = HtmlParser::from_data_url("data:text/html,<h1>hi</h1>").unwrap();
+        assert_eq!(document.encoding(), CharacterEncoding::Windows1252);
+        let h1 = find_element(&document, "h1");
+        assert_eq!(document.text_content(h1), "hi");
+
+        // "<title>Ça va</title>" in UTF-8
+        let document = HtmlParser::from_data_url(
+            "data:text/html;charset=utf-8;base64,PHRpdGxlPsOHYSB2YTwvdGl0bGU+",
+        )
+        .unwrap();
+        assert_eq!(document.encoding(), CharacterEncoding::Utf8);
+        assert_eq!(document.title().as_deref(), Some("Ça va"));
+
+        // Percent-encoded bytes are decoded with the URL's charset too
+        let document = HtmlParser::from_data_url("data:text/html,<title>%C7a va</title>").unwrap();
+        assert_eq!(document.title().as_deref(), Some("Ça va"));
+
+        assert_eq!(
+            HtmlParser::from_data_url("data:text/plain,<h1>hi</h1>").err(),
+            Some(HtmlParseError::InvalidDataUrl)
+        );
+        assert_eq!(
+            HtmlParser::from_data_url("data:text/html;base64,<h1>").err(),
+            Some(HtmlParseError::InvalidDataUrl)
+        );
+    }
+
+    #[test]
+    fn declared_encoding() {
+        let html = "<meta charset=windows-1251><meta charset=utf-8><p>Привет</p>";
+        let document =
+            HtmlParser::with_definite_encoding(html.as_bytes(), CharacterEncoding::Utf8).parse();
+
+        // The first declaration wins, even though the document was decoded as UTF-8
+        assert_eq!(
+            document.declared_encoding(),
+            Some(CharacterEncoding::Windows1251)
+        );
+        assert_eq!(document.encoding(), CharacterEncoding::Utf8);
+
+        let declared = |html: &str| {
+            HtmlParser::from_chars(html.chars())
+                .parse()
+                .declared_encoding()
+        };
+
+        assert_eq!(
+            declared("<meta http-equiv=Content-Type content='text/html; Charset=\"ISO-8859-2\"'>"),
+            Some(CharacterEncoding::ISO8859_2)
+        );
+        // Without the pragma, the content is not a declaration
+        assert_eq!(declared("<meta content='text/html; charset=koi8-r'>"), None);
+        // Unsupported labels are skipped
+        assert_eq!(
+            declared("<meta charset=klingon><meta charset=utf-16>"),
+            Some(CharacterEncoding::Utf16LE)
+        );
+        assert_eq!(declared("<p>no meta</p>"), None);
+    }
+
+    #[test]
+    fn meta_refresh() {
+        let refresh = |content: &str| {
+            let html = format!("<meta http-equiv=Refresh content=\"{content}\"><p>Moved</p>");
+            let mut document = HtmlParser::from_bytes(html.as_bytes()).parse();
+            document.set_url("https://mario.example/docs/".parse().unwrap());
+
+            document
+                .meta_refresh()
+                .map(|(delay, url)| (delay.as_secs(), url.map(String::from)))
+        };
+
+        assert_eq!(refresh("5"), Some((5, None)));
+        assert_eq!(refresh(" 3.5 ; "), Some((3, None)));
+        assert_eq!(
+            refresh("5; URL='next.html'"),
+            Some((5, Some("https://mario.example/docs/next.html".to_owned())))
+        );
+        assert_eq!(
+            refresh("0;url=/home?a=1;b=2"),
+            Some((0, Some("https://mario.example/home?a=1;b=2".to_owned())))
+        );
+        assert_eq!(
+            refresh("1, /away"),
+            Some((1, Some("https://mario.example/away".to_owned())))
+        );
+
+        assert_eq!(refresh("soon"), None);
+        assert_eq!(refresh("5url=next.html"), None);
+        assert_eq!(refresh(""), None);
+
+        let document = HtmlParser::from_bytes("<meta content=5>".as_bytes()).parse();
+        assert_eq!(document.meta_refresh(), None);
+    }
+
+    #[test]
+    fn unterminated_tag() {
+        // Wherever the input ends inside a tag, the whole tag is dropped (rather than being
+        // emitted as text, which is only done for a `<` right before the end)
+        for tag in [
+            "<div id=",
+            "<div class=\"foo",
+            "<div class='foo bar",
+            "<div class=foo",
+            "<div hidden",
+            "<div",
+            "</div",
+        ] {
+            let html = format!("<p>text</p>{tag}");
+
+            assert_eq!(
+                HtmlParser::from_bytes(html.as_bytes()).try_parse().err(),
+                Some(HtmlParseError::EofInTag),
+                "{tag}"
+            );
+
+            let document = HtmlParser::from_bytes(html.as_bytes()).parse();
+            assert_eq!(document.parse_errors(), [HtmlParseError::EofInTag], "{tag}");
+            assert_eq!(document.children(document.root()).len(), 1);
+            assert_eq!(document.text_content(document.root()), "text");
+        }
+
+        let document = HtmlParser::from_bytes("<p>text<".as_bytes()).parse();
+        assert_eq!(document.parse_errors(), [HtmlParseError::EofBeforeTagName]);
+        assert_eq!(document.text_content(document.root()), "text<");
+    }
+
+    #[test]
+    fn empty_tags() {
+        // `<>` is not a tag, so it stays in the text
+        let document = HtmlParser::from_bytes("<p>a<>b</p>".as_bytes()).parse();
+        assert_eq!(
+            document.parse_errors(),
+            [HtmlParseError::InvalidFirstCharacterOfTagName]
+        );
+        assert_eq!(document.text_content(document.root()), "a<>b");
+
+        // `</>` is dropped
+        let document = HtmlParser::from_bytes("<p>a</>b</p>".as_bytes()).parse();
+        assert_eq!(document.parse_errors(), [HtmlParseError::MissingEndTagName]);
+        assert_eq!(document.text_content(document.root()), "ab");
+        assert_eq!(document.to_html(), "<p>ab</p>");
+
+        assert_eq!(
+            HtmlParser::from_bytes("a</>b".as_bytes()).try_parse().err(),
+            Some(HtmlParseError::MissingEndTagName)
+        );
+    }
+
+    #[test]
+    fn space_in_end_tag() {
+        let html = "<p>text</ p>";
+
+        assert_eq!(
+            HtmlParser::from_bytes(html.as_bytes()).try_parse().err(),
+            Some(HtmlParseError::InvalidFirstCharacterOfTagName)
+        );
+
+        // The end tag becomes a bogus comment
+        let document = HtmlParser::from_bytes(html.as_bytes())
+            .keep_comments(true)
+            .parse();
+        assert_eq!(
+            document.parse_errors(),
+            [HtmlParseError::InvalidFirstCharacterOfTagName]
+        );
+        assert_eq!(
+            dump(&document),
+            "Document\n  Element(Element { id: NodeId(1), name: \"p\", attributes: [], template_contents: None })\n    Text(\"text\")\n    Comment(\" p\")\n"
+        );
+    }
+
+    #[test]
+    fn nameless_and_late_doctypes() {
+        let document = HtmlParser::from_bytes(&b"<!DOCTYPE><p>x"[..]).parse();
+        assert_eq!(
+            document.parse_errors(),
+            [HtmlParseError::MissingDoctypeName]
+        );
+        assert_eq!(document.quirks_mode(), QuirksMode::Quirks);
+        assert!(matches!(
+            &document.get(document.children(document.root())[0]).data,
+            NodeData::Doctype { name, .. } if name.is_empty()
+        ));
+
+        // A doctype after the start of the document is ignored
+        let html = "<!DOCTYPE html><p>x<!DOCTYPE html>y</p><!DOCTYPE>";
+        let document = HtmlParser::from_bytes(html.as_bytes()).parse();
+        assert_eq!(
+            document.parse_errors(),
+            [
+                HtmlParseError::UnexpectedDoctype,
+                HtmlParseError::MissingDoctypeName,
+                HtmlParseError::UnexpectedDoctype
+            ]
+        );
+        assert_eq!(document.to_html(), "<!DOCTYPE html><p>xy</p>");
+        assert_eq!(
+            HtmlParser::from_bytes(html.as_bytes()).try_parse().err(),
+            Some(HtmlParseError::UnexpectedDoctype)
+        );
+    }
+
+    #[test]
+    fn truncated_doctype() {
+        let html = "<!DOCTYPE ht";
+
+        assert_eq!(
+            HtmlParser::from_bytes(html.as_bytes()).try_parse().err(),
+            Some(HtmlParseError::EofInDoctype)
+        );
+
+        let document = HtmlParser::from_bytes(html.as_bytes()).parse();
+        assert_eq!(document.parse_errors(), [HtmlParseError::EofInDoctype]);
+        assert!(matches!(
+            &document.get(document.children(document.root())[0]).data,
+            NodeData::Doctype { name, .. } if name == "ht"
+        ));
+
+        // Inside a quoted identifier, the identifier is kept up to the end of the input
+        let html = "<!DOCTYPE html PUBLIC \"-//W3C";
+        assert_eq!(
+            HtmlParser::from_bytes(html.as_bytes()).try_parse().err(),
+            Some(HtmlParseError::EofInDoctype)
+        );
+
+        let document = HtmlParser::from_bytes(html.as_bytes()).parse();
+        assert_eq!(document.parse_errors(), [HtmlParseError::EofInDoctype]);
+        assert!(matches!(
+            &document.get(document.children(document.root())[0]).data,
+            NodeData::Doctype { name, public_id, .. } if name == "html" && public_id == "-//W3C"
+        ));
+    }
+
+    #[test]
+    fn never_panics_on_random_bytes() {
+        const FRAGMENTS: [&[u8]; 20] = [
+            b"<",
+            b">",
+            b"</",
+            b"<!--",
+            b"-->",
+            b"<!DOCTYPE",
+            b" PUBLIC \"",
+            b"\"",
+            b"'",
+            b"=",
+            b"&",
+            b"&#x",
+            b";",
+            b"<script>",
+            b"</script>",
+            b"<template>",
+            b"<?",
+            b"<![CDATA[",
+            b"\r\n",
+            b"\0",
+        ];
+
+        // xorshift64, so failures are reproducible
+        let mut state = 0x2545_F491_4F6C_DD1D_u64;
+        let mut random = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        // Found by an earlier run: a 4 byte sequence past the end of the Unicode range
+        let mut inputs = vec![b"<p>\xF7\x92\xAA\xBB</p>".to_vec()];
+
+        inputs.extend((0..2000).map(|_| {
+            let mut bytes = Vec::new();
+
+            for _ in 0..random() % 40 {
+                let r = random();
+
+                match r % 3 {
+                    0 => bytes.push((r >> 8) as u8),
+                    _ => bytes.extend(FRAGMENTS[(r >> 8) as usize % FRAGMENTS.len()]),
+                }
+            }
+
+            bytes
+        }));
+
+        // The encoding is fixed since most legacy decoders are not implemented yet
+        for bytes in inputs {
+            let parser = || HtmlParser::with_definite_encoding(&bytes[..], CharacterEncoding::Utf8);
+
+            parser().parse();
+            let _ = parser().try_parse();
+            parser().tokens().for_each(drop);
+        }
+    }
+
+    #[test]
+    fn try_parse_aborts_on_error() {
+        assert_eq!(
+            HtmlParser::from_bytes("<p>Hi</div>".as_bytes())
+                .try_parse()
+                .err(),
+            Some(HtmlParseError::UnexpectedEndTag)
+        );
+
+        let document = HtmlParser::from_bytes("<p>Hi</div>".as_bytes()).parse();
+        assert_eq!(document.parse_errors(), [HtmlParseError::UnexpectedEndTag]);
+    }
+
+    /// Records the calls it receives, with nodes referred to by their creation order
+    #[derive(Default)]
+    struct RecordingSink {
+        elements: Vec<String>,
+        events: Vec<String>,
+    }
+
+    impl TreeSink for RecordingSink {
+        type Handle = usize;
+        type Output = Vec<String>;
+
+        fn document(&mut self) -> usize {
+            self.elements.push("#document".to_owned());
+            0
+        }
+
+        fn create_element(&mut self, name: String, attributes: Vec<(String, String)>) -> usize {
+            self.events.push(format!("create {name} {attributes:?}"));
+            self.elements.push(name);
+            self.elements.len() - 1
+        }
+
+        fn append(&mut self, parent: &usize, child: usize) {
+            let (parent, child) = (&self.elements[*parent], &self.elements[child]);
+            self.events.push(format!("append {child} to {parent}"));
+        }
+
+        fn insert_before(&mut self, sibling: &usize, child: usize) {
+            let (sibling, child) = (&self.elements[*sibling], &self.elements[child]);
+            self.events.push(format!("insert {child} before {sibling}"));
+        }
+
+        fn insert_text_before(&mut self, sibling: &usize, text: &str) {
+            let sibling = &self.elements[*sibling];
+            self.events
+                .push(format!("text {:?} before {sibling}", text.trim()));
+        }
+
+        fn append_text(&mut self, parent: &usize, text: &str) {
+            let parent = &self.elements[*parent];
+            self.events
+                .push(format!("text {:?} to {parent}", text.trim()));
+        }
+
+        fn append_comment(&mut self, parent: &usize, comment: String) {
+            let parent = &self.elements[*parent];
+            self.events.push(format!("comment {comment:?} to {parent}"));
+        }
+
+        fn append_doctype(&mut self, name: String, _: String, _: String) {
+            self.events.push(format!("doctype {name}"));
+        }
+
+        fn parse_error(&mut self, error: HtmlParseError) {
+            self.events.push(format!("error {error:?}"));
+        }
+
+        fn finish(mut self) -> Vec<String> {
+            self.events.push("finish".to_owned());
+            self.events
+        }
+    }
+
+    #[test]
+    fn parse_with_sink() {
+        let events =
+            HtmlParser::from_bytes(MARIO.as_bytes()).parse_with_sink(RecordingSink::default());
+
+        assert_eq!(
+            events,
+            [
+                "doctype html",
+                "create html []",
+                "append html to #document",
+                r#"text "" to html"#,
+                "create head []",
+                "append head to html",
+                r#"text "" to head"#,
+                r#"create meta [("http-equiv", "content-type"), ("content", "text/html; charset=utf8")]"#,
+                "append meta to head",
+                r#"text "" to head"#,
+                "create title []",
+                "append title to head",
+                r#"text "Mario!" to title"#,
+                r#"text "" to head"#,
+                r#"text "" to html"#,
+                r#"create body [("id", "root")]"#,
+                "append body to html",
+                r#"text "🦀" to body"#,
+                r#"create h1 [("class", "big_title"), ("aria-label", "heading")]"#,
+                "append h1 to body",
+                r#"text "Mario!" to h1"#,
+                r#"text "" to body"#,
+                "create p []",
+                "append p to body",
+                r#"text "It's a me, Mario!" to p"#,
+                r#"text "" to body"#,
+                r#"text "" to html"#,
+                "finish",
+            ]
+        );
+    }
+
+    #[test]
+    fn source_map() {
+        let html = "\u{FEFF}<p>é</p>";
+        let document = HtmlParser::from_bytes(html.as_bytes()).parse();
+        let source_map = document.source_map().unwrap();
+
+        // The byte order mark is not part of the input stream
+        assert_eq!(source_map.len(), 8);
+        assert_eq!(source_map.byte_range(Span::new(0, 3)), Some(3..6));
+        assert_eq!(source_map.byte_range(Span::new(3, 4)), Some(6..8));
+        assert_eq!(source_map.byte_offset(8), Some(html.len()));
+
+        let document = HtmlParser::from_chars(html.chars()).parse();
+        assert!(document.source_map().is_none());
+
+        // Reading the bytes straight from the slice gives the same document as reading them
+        let document = HtmlParser::new(html.as_bytes()).parse();
+        assert_eq!(
+            HtmlParser::from_bytes(html.as_bytes()).parse().source_map(),
+            document.source_map()
+        );
+
+        let from_reader = HtmlParser::new(MARIO.as_bytes()).parse();
+        let from_bytes = HtmlParser::from_bytes(MARIO.as_bytes()).parse();
+        assert_eq!(from_bytes.to_html(), from_reader.to_html());
+        assert_eq!(from_bytes.source_map(), from_reader.source_map());
+    }
+
+    /// Records the message and fields of every event, ignoring spans
+    #[derive(Default, Clone)]
+    struct EventRecorder(std::sync::Arc<std::sync::Mutex<Vec<String>>>);
+
+    impl tracing::Subscriber for EventRecorder {
+        fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, _: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            tracing::span::Id::from_u64(1)
+        }
+
+        fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record<'_>) {}
+
+        fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+
+        fn event(&self, event: &tracing::Event<'_>) {
+            struct Fields(String);
+
+            impl tracing::field::Visit for Fields {
+                fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn Debug) {
+                    match field.name() {
+                        "message" => self.0.insert_str(0, &format!("{value:?}")),
+                        name => self.0 += &format!(" {name}={value:?}"),
+                    }
+                }
+            }
+
+            let mut fields = Fields(String::new());
+            event.record(&mut fields);
+            self.0.lock().unwrap().push(fields.0);
+        }
+
+        fn enter(&self, _: &tracing::span::Id) {}
+
+        fn exit(&self, _: &tracing::span::Id) {}
+    }
+
+    #[test]
+    fn traces_encoding_decisions() {
+        let recorder = EventRecorder::default();
+
+        tracing::subscriber::with_default(recorder.clone(), || {
+            HtmlParser::from_bytes("\u{FEFF}<p>Hi</p>".as_bytes()).parse()
+        });
+
+        let events = recorder.0.lock().unwrap();
+        assert!(events.contains(&"sniffed byte order mark bom=Some(Utf8)".to_owned()));
+        assert!(
+            events.contains(&"determined encoding encoding=UTF-8 confidence=Certain".to_owned())
+        );
+        assert!(events.iter().any(|event| event.starts_with("token token=")));
+    }
 }
@@ -4,22 +4,38 @@ use crate::{io_queue::IoQueue, CharacterEncoding};
 
 /// A data structure for implementing the byte stream pre-scanning algorithm defined in the spec
 /// (https://html.spec.whatwg.org/#prescan-a-byte-stream-to-determine-its-encoding)
-pub struct HtmlPreScanner<'a, R> {
-    byte_stream: &'a IoQueue<R>,
+pub struct HtmlPreScanner<'a, 'b, R> {
+    byte_stream: &'a mut IoQueue<'b, R>,
     position: usize,
-    max_pos: usize,
+    /// The end of the pre-scan window: the index right after the last byte that may be
+    /// examined (so the window is `0..end`)
+    end: usize,
+    /// The most bytes that are examined, 1024 by default
+    window: usize,
 }
 
-impl<'a, R: Read> HtmlPreScanner<'a, R> {
-    pub fn new(byte_stream: &'a IoQueue<R>) -> Self {
+impl<'a, 'b, R: Read> HtmlPreScanner<'a, 'b, R> {
+    pub fn new(byte_stream: &'a mut IoQueue<'b, R>) -> Self {
         Self {
             byte_stream,
             position: 0,
-            max_pos: 0,
+            end: 0,
+            window: 1024,
         }
     }
 
+    /// Sets the most bytes that are examined, instead of the first 1024 the spec allows for
+    pub fn with_window(mut self, window: usize) -> Self {
+        self.window = window;
+        self
+    }
+
     pub fn pre_scan_byte_stream(mut self) -> Option<CharacterEncoding> {
+        // An empty stream has nothing to pre-scan
+        if self.byte_stream.peek_len() == 0 {
+            return None;
+        }
+
         if let Some(encoding) = self._pre_scan_byte_stream() {
             return Some(encoding);
         }
@@ -28,8 +44,8 @@ impl<'a, R: Read> HtmlPreScanner<'a, R> {
     }
 
     fn _pre_scan_byte_stream(&mut self) -> Option<CharacterEncoding> {
-        // Keep going to the end of the byte stream peek buffer, or until 1024 bytes
-        self.max_pos = usize::min(self.byte_stream.peek_len(), 1024) - 1;
+        // Keep going to the end of the byte stream peek buffer, or until the end of the window
+        self.end = usize::min(self.byte_stream.peek_len(), self.window);
 
         // Step 1: Let fallback encoding be null
 
@@ -42,12 +58,11 @@ impl<'a, R: Read> HtmlPreScanner<'a, R> {
 
         // Step 3: Pre-scan for UTF-16 XML declarations
 
-        if self.contains_bytes(&[0x3C, 0x0, 0x3F, 0x0, 0x78, 0x0])? {
-            return Some(CharacterEncoding::Utf16LE);
-        }
-
-        if self.contains_bytes(&[0x0, 0x3C, 0x0, 0x3F, 0x0, 0x78])? {
-            return Some(CharacterEncoding::Utf16BE);
+        // (a buffer too short to hold a declaration just skips this step)
+        match self.byte_stream.peek_n() {
+            Some([0x3C, 0x0, 0x3F, 0x0, 0x78, 0x0]) => return Some(CharacterEncoding::Utf16LE),
+            Some([0x0, 0x3C, 0x0, 0x3F, 0x0, 0x78]) => return Some(CharacterEncoding::Utf16BE),
+            _ => {}
         }
 
         // Step 4: Loop
@@ -111,7 +126,7 @@ impl<'a, R: Read> HtmlPreScanner<'a, R> {
                             }
                         }
                         "content" => {
-                            let encoding = Self::extract_encoding_from_meta(value);
+                            let encoding = extract_encoding_from_meta(&value);
 
                             if let (Some(encoding), None) = (encoding, &charset) {
                                 charset = Some(encoding);
@@ -147,10 +162,7 @@ impl<'a, R: Read> HtmlPreScanner<'a, R> {
                 };
 
                 // Step 14
-                if matches!(
-                    charset,
-                    CharacterEncoding::Utf16BE | CharacterEncoding::Utf16LE
-                ) {
+                if charset.is_utf16() {
                     charset = CharacterEncoding::Utf8
                 }
 
@@ -167,11 +179,7 @@ impl<'a, R: Read> HtmlPreScanner<'a, R> {
                 || self.matches_sequence(&[vec![0x3C], vec![0x2F], letters.clone()])?
             {
                 // Step 1
-                while !self.matches_sequence(&[vec![0x09, 0x0A, 0x0C, 0x0D, 0x20, 0x3E]])? {
-                    self.position += 1;
-                }
-
-                self.assert_pos()?;
+                self.advance_to_any(&[0x09, 0x0A, 0x0C, 0x0D, 0x20, 0x3E])?;
 
                 // Step 2
                 while self.get_attribute()?.is_some() {}
@@ -189,82 +197,6 @@ impl<'a, R: Read> HtmlPreScanner<'a, R> {
         }
     }
 
-    fn extract_encoding_from_meta(value: String) -> Option<CharacterEncoding> {
-        // Step 1
-        let mut position = 0;
-
-        'outer: loop {
-            // Step 2
-            while &value[position..position + 7] != "charset" {
-                if position > value.len() {
-                    return None;
-                }
-
-                position += 1;
-            }
-
-            position += 7;
-
-            // Step 3
-            while value.chars().nth(position).unwrap().is_ascii_whitespace() {
-                if position > value.len() {
-                    return None;
-                }
-
-                position += 1;
-            }
-
-            // Step 4
-            if value.chars().nth(position).unwrap() != '=' {
-                position -= 1;
-                continue 'outer;
-            }
-
-            position += 1;
-
-            // Step 5
-            while value.chars().nth(position).unwrap().is_ascii_whitespace() {
-                if position > value.len() {
-                    return None;
-                }
-
-                position += 1;
-            }
-
-            if value.chars().nth(position).unwrap() == '"'
-                || value.chars().nth(position).unwrap() == '\''
-            {
-                let quote = value.chars().nth(position).unwrap();
-
-                if !&value[position..].contains(quote) {
-                    return None;
-                }
-
-                let last_index = value[position..]
-                    .chars()
-                    .enumerate()
-                    .find(|(_, c)| *c == quote)
-                    .map(|(i, _)| i)
-                    .unwrap();
-
-                let string = &value[position..last_index];
-
-                return string.parse().ok();
-            }
-
-            let last_index = value[position..]
-                .chars()
-                .enumerate()
-                .find(|(_, c)| c.is_ascii_whitespace() || *c == ';')
-                .map(|(i, _)| i)
-                .unwrap_or_else(|| value.len());
-
-            let string = &value[position..last_index];
-
-            return string.parse().ok();
-        }
-    }
-
     fn get_attribute(&mut self) -> Option<Option<(String, String)>> {
         // Skip ascii whitespace
         while self.matches_sequence(&[vec![0x09, 0x0A, 0x0C, 0x0D, 0x20, 0x2F]])? {
@@ -374,29 +306,25 @@ impl<'a, R: Read> HtmlPreScanner<'a, R> {
         self.position = 0;
 
         // Step 2
-        if !self.contains_bytes(&[0x3C, 0x3F, 0x78, 0x6D, 0x6C])? {
+        if !self.starts_with_ascii("<?xml")? {
             return None;
         }
 
         // Step 3
-        let mut found = false;
-        for i in self.position..self.max_pos {
-            if self.byte_stream.peek_nth(i).unwrap() == 0x3E {
-                found = true;
-            }
-        }
+        let declaration_end =
+            (self.position..self.end).find(|&i| self.byte_stream.peek_nth(i) == Some(0x3E))?;
 
-        if !found {
-            return None;
-        }
+        // Everything below must be inside of the declaration, so a malformed or truncated one
+        // (e.g. without an `encoding`) stops at its `>` instead of matching something past it
+        self.end = declaration_end;
 
         // Step 4
-        while !self.contains_bytes(&[0x65, 0x6E, 0x63, 0x6F, 0x64, 0x69, 0x6E, 0x67])? {
+        while !self.starts_with_ascii("encoding")? {
             self.position += 1;
         }
 
         // Step 5
-        self.position += 8;
+        self.position += "encoding".len();
 
         // Step 6
         while self.current_byte()? <= 0x20 {
@@ -428,16 +356,8 @@ impl<'a, R: Read> HtmlPreScanner<'a, R> {
         self.position += 1;
 
         // Step 13
-        let mut encoding_end_position = None;
-        for i in self.position..self.max_pos {
-            if self.byte_stream.peek_nth(i).unwrap() == 0x3E {
-                encoding_end_position = Some(i);
-            }
-        }
-
-        let Some(encoding_end_position) = encoding_end_position else {
-            return None;
-        };
+        let encoding_end_position = (self.position..self.end)
+            .find(|&i| self.byte_stream.peek_nth(i) == Some(quote_mark))?;
 
         // Step 14
         let mut potential_encoding = Vec::new();
@@ -453,13 +373,10 @@ impl<'a, R: Read> HtmlPreScanner<'a, R> {
         // Step 16
         let potential_encoding = String::from_utf8(potential_encoding).ok()?;
 
-        let mut encoding = potential_encoding.parse().ok()?;
+        let mut encoding: CharacterEncoding = potential_encoding.parse().ok()?;
 
         // Step 17
-        if matches!(
-            encoding,
-            CharacterEncoding::Utf16BE | CharacterEncoding::Utf16LE
-        ) {
+        if encoding.is_utf16() {
             encoding = CharacterEncoding::Utf8;
         }
 
@@ -469,31 +386,377 @@ impl<'a, R: Read> HtmlPreScanner<'a, R> {
 
     /* Helper methods for structure */
 
-    fn contains_bytes(&self, bytes: &[u8]) -> Option<bool> {
-        if self.position + bytes.len() > self.max_pos {
+    /// Whether the bytes at the current position are `bytes`. Returns `None` if they would
+    /// extend past the end of the pre-scan window.
+    ///
+    /// The pattern must not be empty: it would match without examining any byte, so a loop
+    /// that advances until a match would never advance.
+    fn contains_bytes(&mut self, bytes: &[u8]) -> Option<bool> {
+        debug_assert!(!bytes.is_empty(), "empty pattern");
+
+        if self.position + bytes.len() > self.end {
             return None;
         }
 
         Some(self.byte_stream.contains_bytes(self.position, bytes))
     }
 
-    fn matches_sequence(&self, sequence: &[Vec<u8>]) -> Option<bool> {
-        if self.position + sequence.len() > self.max_pos {
+    /// Like [`HtmlPreScanner::contains_bytes`], with the bytes of an ASCII string
+    fn starts_with_ascii(&mut self, s: &str) -> Option<bool> {
+        self.contains_bytes(s.as_bytes())
+    }
+
+    /// Like [`HtmlPreScanner::contains_bytes`], with a set of allowed bytes at each offset
+    fn matches_sequence(&mut self, sequence: &[Vec<u8>]) -> Option<bool> {
+        debug_assert!(!sequence.is_empty(), "empty pattern");
+
+        if self.position + sequence.len() > self.end {
             return None;
         }
 
         Some(self.byte_stream.matches_sequence(self.position, sequence))
     }
 
+    /// Advances the position to the next byte that is one of `bytes`. Returns `None` if there
+    /// is no such byte before the end of the pre-scan window, which aborts the pre-scan.
+    fn advance_to_any(&mut self, bytes: &[u8]) -> Option<()> {
+        loop {
+            self.assert_pos()?;
+
+            if bytes.contains(&self.current_byte()?) {
+                return Some(());
+            }
+
+            self.position += 1;
+        }
+    }
+
     fn assert_pos(&self) -> Option<()> {
-        if self.position > self.max_pos {
+        if self.position >= self.end {
             None
         } else {
             Some(())
         }
     }
 
-    fn current_byte(&self) -> Option<u8> {
+    fn current_byte(&mut self) -> Option<u8> {
+        self.assert_pos()?;
+
         self.byte_stream.peek_nth(self.position)
     }
 }
+
+/// https://html.spec.whatwg.org/#algorithm-for-extracting-a-character-encoding-from-a-meta-element
+///
+/// Positions are byte offsets into `value`. Only ASCII bytes are ever matched, so they always
+/// fall on character boundaries.
+pub(crate) fn extract_encoding_from_meta(value: &str) -> Option<CharacterEncoding> {
+    let is_whitespace = |c: char| matches!(c, '\t' | '\n' | '\x0C' | '\r' | ' ');
+
+    // Lowercasing ASCII letters keeps every byte offset the same
+    let value = value.to_ascii_lowercase();
+
+    // Step 1
+    let mut position = 0;
+
+    loop {
+        // Step 2
+        position += value[position..].find("charset")? + "charset".len();
+
+        // Step 3
+        position = value[position..]
+            .find(|c| !is_whitespace(c))
+            .map_or(value.len(), |i| position + i);
+
+        // Step 4
+        if !value[position..].starts_with('=') {
+            continue;
+        }
+
+        // Step 5
+        let rest = value[position + 1..].trim_start_matches(is_whitespace);
+
+        // Step 6
+        let encoding = match rest.chars().next()? {
+            quote @ ('"' | '\'') => {
+                let rest = &rest[1..];
+                &rest[..rest.find(quote)?]
+            }
+            _ => {
+                let end = rest
+                    .find(|c| is_whitespace(c) || c == ';')
+                    .unwrap_or(rest.len());
+                &rest[..end]
+            }
+        };
+
+        return encoding.parse().ok();
+    }
+}
+
+/// Pre-scans the start of `bytes` for a declared character encoding, from a `<meta>` element or
+/// an XML declaration, without parsing the document
+pub fn prescan_bytes(bytes: &[u8]) -> Option<CharacterEncoding> {
+    let mut io_queue = IoQueue::from_bytes(bytes);
+    io_queue.peek_max(1024);
+
+    HtmlPreScanner::new(&mut io_queue).pre_scan_byte_stream()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unquoted_value_abutting_tag_end() {
+        assert_eq!(
+            prescan_bytes(b"<html><meta charset=utf-8><title>x</title></html>"),
+            Some(CharacterEncoding::Utf8)
+        );
+        assert_eq!(
+            prescan_bytes(b"<meta charset=windows-1252><p>"),
+            Some(CharacterEncoding::Windows1252)
+        );
+    }
+
+    #[test]
+    fn unquoted_value_with_trailing_space() {
+        assert_eq!(
+            prescan_bytes(b"<html><meta charset=utf-8 ><title>x</title></html>"),
+            Some(CharacterEncoding::Utf8)
+        );
+        assert_eq!(
+            prescan_bytes(b"<meta charset=windows-1252 ><p>"),
+            Some(CharacterEncoding::Windows1252)
+        );
+    }
+
+    #[test]
+    fn unterminated_tag_at_window_end() {
+        // The tag name runs up to the end of the window
+        let mut bytes = b"<html><div".to_vec();
+        bytes.resize(1024, b'a');
+        assert_eq!(prescan_bytes(&bytes), None);
+
+        // A declaration past the end of the window is never seen
+        bytes.extend(b"><meta charset=windows-1252><p>");
+        assert_eq!(prescan_bytes(&bytes), None);
+    }
+
+    #[test]
+    fn tiny_buffers() {
+        // Too short for a UTF-16 XML declaration, which only skips that step of the pre-scan
+        assert_eq!(prescan_bytes(b"<p>x"), None);
+        assert_eq!(
+            prescan_bytes(b"<\0?\0x\0"),
+            Some(CharacterEncoding::Utf16LE)
+        );
+        assert_eq!(
+            prescan_bytes(b"\0<\0?\0x"),
+            Some(CharacterEncoding::Utf16BE)
+        );
+    }
+
+    #[test]
+    fn meta_charset() {
+        assert_eq!(
+            prescan_bytes(b"<!DOCTYPE html><html><head><meta charset=\"shift_jis\"><title>"),
+            Some(CharacterEncoding::ShiftJIS)
+        );
+        assert_eq!(
+            prescan_bytes(
+                b"<meta http-equiv=\"Content-Type\" content=\"text/html; charset=windows-1252\"><p>"
+            ),
+            Some(CharacterEncoding::Windows1252)
+        );
+        assert_eq!(
+            prescan_bytes(
+                b"<meta http-equiv=content-type content=\"text/html;charset='euc-jp'\"><p>"
+            ),
+            Some(CharacterEncoding::EucJp)
+        );
+    }
+
+    #[test]
+    fn pragma_in_any_attribute_order() {
+        assert_eq!(
+            prescan_bytes(
+                b"<meta http-equiv=\"Content-Type\" content=\"text/html; charset=utf-8\"><p>"
+            ),
+            Some(CharacterEncoding::Utf8)
+        );
+        assert_eq!(
+            prescan_bytes(
+                b"<meta content=\"text/html; charset=utf-8\" http-equiv=\"content-type\"><p>"
+            ),
+            Some(CharacterEncoding::Utf8)
+        );
+    }
+
+    #[test]
+    fn content_without_pragma() {
+        assert_eq!(
+            prescan_bytes(b"<meta content=\"text/html; charset=utf-8\"><p>"),
+            None
+        );
+        assert_eq!(
+            prescan_bytes(b"<meta http-equiv=refresh content=\"charset=utf-8\"><p>"),
+            None
+        );
+
+        // The pre-scan moves on to the next `<meta>`
+        assert_eq!(
+            prescan_bytes(
+                b"<meta content=\"text/html; charset=utf-8\"><meta charset=windows-1252><p>"
+            ),
+            Some(CharacterEncoding::Windows1252)
+        );
+    }
+
+    #[test]
+    fn content_parameters() {
+        use CharacterEncoding::*;
+
+        // Parameters after the charset
+        assert_eq!(
+            extract_encoding_from_meta("text/html; charset=utf-8; foo=bar"),
+            Some(Utf8)
+        );
+        assert_eq!(
+            extract_encoding_from_meta("charset=windows-1251;foo=bar"),
+            Some(Windows1251)
+        );
+        assert_eq!(
+            extract_encoding_from_meta("charset=koi8-r foo=bar"),
+            Some(KOI8R)
+        );
+
+        // Quoted, with or without spaces around the value
+        assert_eq!(
+            extract_encoding_from_meta("text/html;charset=\"utf-8\""),
+            Some(Utf8)
+        );
+        assert_eq!(
+            extract_encoding_from_meta("text/html; charset = ' windows-1252 ';x"),
+            Some(Windows1252)
+        );
+        assert_eq!(extract_encoding_from_meta("charset=\"utf-8"), None);
+
+        // In any case
+        assert_eq!(
+            extract_encoding_from_meta("TEXT/HTML; CHARSET=UTF-8"),
+            Some(Utf8)
+        );
+        assert_eq!(
+            prescan_bytes(b"<META HTTP-EQUIV=Content-Type CONTENT='text/html; CHARSET=Shift_JIS'>"),
+            Some(ShiftJIS)
+        );
+
+        // A `charset` that isn't followed by `=` is skipped
+        assert_eq!(
+            extract_encoding_from_meta("charsetx; charset=big5"),
+            Some(Big5)
+        );
+    }
+
+    #[test]
+    fn xml_declaration() {
+        assert_eq!(
+            prescan_bytes(b"<?xml version=\"1.0\" encoding=\"windows-1252\"?>\n<html></html>"),
+            Some(CharacterEncoding::Windows1252)
+        );
+        assert_eq!(
+            prescan_bytes(b"<?xml version='1.0' encoding = 'koi8-r' ?>"),
+            Some(CharacterEncoding::KOI8R)
+        );
+
+        // Without an encoding, nothing after the declaration is mistaken for one
+        assert_eq!(
+            prescan_bytes(b"<?xml version=\"1.0\"?><p title='encoding=\"utf-16\"'>"),
+            None
+        );
+        assert_eq!(
+            prescan_bytes(b"<?xml version=\"1.0\" encoding=\"koi8-r?><p title=\">"),
+            None
+        );
+
+        // Truncated declarations
+        assert_eq!(
+            prescan_bytes(b"<?xml version=\"1.0\" encoding=\"koi8-r\""),
+            None
+        );
+        assert_eq!(prescan_bytes(b"<?xml version=\"1.0\" encoding"), None);
+        assert_eq!(prescan_bytes(b"<?xml encoding>"), None);
+        assert_eq!(prescan_bytes(b"<?xml encoding=>"), None);
+        assert_eq!(prescan_bytes(b"<?xm"), None);
+    }
+
+    #[test]
+    fn no_declared_encoding() {
+        assert_eq!(prescan_bytes(b""), None);
+        assert_eq!(
+            prescan_bytes(b"<!DOCTYPE html><html><head><title>x</title></head></html>"),
+            None
+        );
+    }
+
+    #[test]
+    fn declaration_at_window_end() {
+        // The `>` is the very last byte of the buffer
+        assert_eq!(
+            prescan_bytes(b"<meta charset=utf-8>"),
+            Some(CharacterEncoding::Utf8)
+        );
+        assert_eq!(
+            prescan_bytes(b"<?xml version=\"1.0\" encoding=\"windows-1252\"?>"),
+            Some(CharacterEncoding::Windows1252)
+        );
+
+        // The `>` is the last byte of the 1024 byte window
+        let mut bytes = vec![b' '; 1024 - 20];
+        bytes.extend(b"<meta charset=utf-8>");
+        assert_eq!(prescan_bytes(&bytes), Some(CharacterEncoding::Utf8));
+
+        // One byte later, it is out of the window, so the attribute value is never terminated
+        bytes.insert(0, b' ');
+        assert_eq!(prescan_bytes(&bytes), None);
+    }
+
+    #[test]
+    fn comment_at_window_end() {
+        assert_eq!(prescan_bytes(b"<!---->"), None);
+        assert_eq!(prescan_bytes(b"<!--"), None);
+
+        let mut bytes = b"<!-- -->".to_vec();
+        bytes.resize(1024 - 4, b' ');
+        bytes.extend(b"<!--<meta charset=utf-8>");
+        assert_eq!(prescan_bytes(&bytes), None);
+    }
+
+    #[test]
+    fn single_byte_patterns_at_window_end() {
+        let mut io_queue = IoQueue::from_bytes(b"ab>");
+        io_queue.peek_max(1024);
+        let mut scanner = HtmlPreScanner::new(&mut io_queue);
+        scanner.end = 3;
+
+        scanner.position = 2;
+        assert_eq!(scanner.contains_bytes(b">"), Some(true));
+        assert_eq!(scanner.matches_sequence(&[vec![b'<', b'>']]), Some(true));
+        assert_eq!(scanner.contains_bytes(b">>"), None);
+
+        scanner.position = 3;
+        assert_eq!(scanner.contains_bytes(b">"), None);
+        assert_eq!(scanner.matches_sequence(&[vec![b'>']]), None);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "empty pattern")]
+    fn empty_pattern() {
+        let mut io_queue = IoQueue::from_bytes(b"ab");
+        io_queue.peek_max(1024);
+
+        let _ = HtmlPreScanner::new(&mut io_queue).contains_bytes(b"");
+    }
+}
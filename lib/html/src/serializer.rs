@@ -0,0 +1,156 @@
+use std::fmt::Write;
+
+use crate::{tree_builder::VOID_ELEMENTS, Document, NodeData, NodeId};
+
+/// Elements whose text children are serialized as is, since their contents are parsed as raw
+/// text (https://html.spec.whatwg.org/#serialising-html-fragments)
+///
+/// `noscript` is only one of them when scripting is enabled, which the document doesn't record,
+/// so it is treated as if scripting was disabled.
+const RAW_TEXT_ELEMENTS: [&str; 7] = [
+    "style",
+    "script",
+    "xmp",
+    "iframe",
+    "noembed",
+    "noframes",
+    "plaintext",
+];
+
+enum Step<'a> {
+    Node(NodeId),
+    EndTag(&'a str),
+}
+
+/// Serializes `nodes` along with their descendants, and writes the result to `output` as it
+/// goes. The document node and template contents only serialize their children.
+/// (https://html.spec.whatwg.org/#serialising-html-fragments)
+pub(crate) fn serialize(
+    document: &Document,
+    nodes: &[NodeId],
+    output: &mut impl Write,
+) -> std::fmt::Result {
+    let mut stack: Vec<_> = nodes.iter().rev().map(|&id| Step::Node(id)).collect();
+
+    while let Some(step) = stack.pop() {
+        let id = match step {
+            Step::Node(id) => id,
+            Step::EndTag(name) => {
+                output.write_str("</")?;
+                output.write_str(name)?;
+                output.write_char('>')?;
+                continue;
+            }
+        };
+
+        match &document.get(id).data {
+            NodeData::Element(element) => {
+                output.write_char('<')?;
+                output.write_str(&element.name)?;
+
+                for (name, value) in element.attributes_iter() {
+                    output.write_char(' ')?;
+                    output.write_str(name)?;
+                    output.write_str("=\"")?;
+                    escape(value, true, output)?;
+                    output.write_char('"')?;
+                }
+
+                output.write_char('>')?;
+
+                if VOID_ELEMENTS.contains(&element.name.as_str()) {
+                    continue;
+                }
+
+                let contents = element.template_contents.unwrap_or(id);
+
+                stack.push(Step::EndTag(&element.name));
+                stack.extend(
+                    document
+                        .children(contents)
+                        .iter()
+                        .rev()
+                        .map(|&child| Step::Node(child)),
+                );
+            }
+            NodeData::Text(text) => {
+                let parent = document.get(id).parent.and_then(|p| document.element(p));
+
+                match parent {
+                    Some(parent) if RAW_TEXT_ELEMENTS.contains(&parent.name.as_str()) => {
+                        output.write_str(text)?
+                    }
+                    _ => escape(text, false, output)?,
+                }
+            }
+            NodeData::Comment(comment) => {
+                output.write_str("<!--")?;
+                output.write_str(comment)?;
+                output.write_str("-->")?;
+            }
+            NodeData::Doctype { name, .. } => {
+                output.write_str("<!DOCTYPE ")?;
+                output.write_str(name)?;
+                output.write_char('>')?;
+            }
+            NodeData::Document | NodeData::DocumentFragment => stack.extend(
+                document
+                    .children(id)
+                    .iter()
+                    .rev()
+                    .map(|&child| Step::Node(child)),
+            ),
+        }
+    }
+
+    Ok(())
+}
+
+/// Like [`serialize`], but writes the result to a byte stream, as UTF-8
+pub(crate) fn serialize_to_writer(
+    document: &Document,
+    nodes: &[NodeId],
+    writer: &mut impl std::io::Write,
+) -> std::io::Result<()> {
+    /// Passes the serialized text on to the byte stream, and keeps the error it fails with
+    /// (formatting errors carry no information)
+    struct Adapter<'w, W> {
+        writer: &'w mut W,
+        error: Option<std::io::Error>,
+    }
+
+    impl<W: std::io::Write> Write for Adapter<'_, W> {
+        fn write_str(&mut self, s: &str) -> std::fmt::Result {
+            self.writer.write_all(s.as_bytes()).map_err(|error| {
+                self.error = Some(error);
+                std::fmt::Error
+            })
+        }
+    }
+
+    let mut adapter = Adapter {
+        writer,
+        error: None,
+    };
+
+    serialize(document, nodes, &mut adapter)
+        .map_err(|_| adapter.error.take().expect("only the byte stream can fail"))
+}
+
+/// https://html.spec.whatwg.org/#escapingString
+///
+/// Attribute values are always double-quoted, so `'`, `<` and `>` are left as is in them.
+fn escape(text: &str, attribute_mode: bool, output: &mut impl Write) -> std::fmt::Result {
+    for c in text.chars() {
+        match c {
+            '&' => output.write_str("&amp;")?,
+            '\u{A0}' => output.write_str("&nbsp;")?,
+            '"' if attribute_mode => output.write_str("&quot;")?,
+            '<' if !attribute_mode => output.write_str("&lt;")?,
+            '>' if !attribute_mode => output.write_str("&gt;")?,
+            c => output.write_char(c)?,
+        }
+    }
+
+    Ok(())
+}
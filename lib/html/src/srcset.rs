@@ -0,0 +1,215 @@
+/// An image candidate from a `srcset` attribute
+/// (https://html.spec.whatwg.org/#image-candidate-string)
+#[derive(Debug, Clone, PartialEq)]
+pub struct SrcsetCandidate {
+    pub url: String,
+    /// The descriptor following the URL, if any. A candidate without one is equivalent to a
+    /// density of `1x`.
+    pub descriptor: Option<Descriptor>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Descriptor {
+    /// A pixel density descriptor (e.g. `2x`)
+    Density(f32),
+    /// A width descriptor (e.g. `480w`), in CSS pixels
+    Width(u32),
+}
+
+/// Parses the value of a `srcset` attribute (on an `img` element, or a `source` element in a
+/// `picture`) into its image candidates
+/// (https://html.spec.whatwg.org/#parse-a-srcset-attribute)
+///
+/// Candidates with invalid descriptors are skipped. Height descriptors are accepted (next to
+/// a width) but not reported.
+pub fn parse_srcset(input: &str) -> Vec<SrcsetCandidate> {
+    let mut candidates = Vec::new();
+    let mut rest = input;
+
+    loop {
+        // Steps 1 and 2: skip whitespace and commas between candidates
+        rest = rest.trim_start_matches(|c| is_ascii_whitespace(c) || c == ',');
+
+        if rest.is_empty() {
+            return candidates;
+        }
+
+        // Step 3: the URL runs up to the next whitespace
+        let url_end = rest.find(is_ascii_whitespace).unwrap_or(rest.len());
+        let (url, after_url) = rest.split_at(url_end);
+        rest = after_url;
+
+        // Step 4: trailing commas end the candidate right after its URL
+        let descriptors = if url.ends_with(',') {
+            Vec::new()
+        } else {
+            let (descriptors, after_descriptors) = tokenize_descriptors(rest);
+            rest = after_descriptors;
+
+            descriptors
+        };
+
+        let url = url.trim_end_matches(',');
+
+        if let Some(descriptor) = parse_descriptors(&descriptors) {
+            candidates.push(SrcsetCandidate {
+                url: url.to_owned(),
+                descriptor,
+            });
+        }
+    }
+}
+
+/// Splits the descriptors following a URL on whitespace, up to the comma that ends the
+/// candidate (commas in parentheses don't count). Returns the descriptors and the rest of the
+/// input after the comma.
+fn tokenize_descriptors(input: &str) -> (Vec<&str>, &str) {
+    let input = input.trim_start_matches(is_ascii_whitespace);
+
+    let mut descriptors = Vec::new();
+    let mut start = 0;
+    let mut in_parens = false;
+
+    for (i, c) in input.char_indices() {
+        match c {
+            '(' => in_parens = true,
+            ')' => in_parens = false,
+            ',' if !in_parens => {
+                descriptors.push(&input[start..i]);
+
+                return (descriptors, &input[i + 1..]);
+            }
+            c if is_ascii_whitespace(c) && !in_parens => {
+                descriptors.push(&input[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+
+    descriptors.push(&input[start..]);
+
+    (descriptors, "")
+}
+
+/// Returns `None` if the descriptors are invalid, in which case the candidate is dropped
+/// (https://html.spec.whatwg.org/#parse-a-srcset-attribute, step 13)
+fn parse_descriptors(descriptors: &[&str]) -> Option<Option<Descriptor>> {
+    let mut width = None;
+    let mut density = None;
+    let mut height = None;
+
+    for descriptor in descriptors.iter().filter(|d| !d.is_empty()) {
+        // Descriptors end with a single ASCII letter, anything else is invalid
+        if !descriptor.is_char_boundary(descriptor.len() - 1) {
+            return None;
+        }
+
+        let (value, kind) = descriptor.split_at(descriptor.len() - 1);
+
+        match kind {
+            "w" if width.is_none() && density.is_none() => {
+                width = Some(parse_non_zero_integer(value)?);
+            }
+            "x" if width.is_none() && density.is_none() && height.is_none() => {
+                density = Some(parse_density(value)?);
+            }
+            "h" if height.is_none() && density.is_none() => {
+                height = Some(parse_non_zero_integer(value)?);
+            }
+            _ => return None,
+        }
+    }
+
+    // A height is only allowed together with a width
+    if height.is_some() && width.is_none() {
+        return None;
+    }
+
+    Some(match (width, density) {
+        (Some(width), _) => Some(Descriptor::Width(width)),
+        (_, Some(density)) => Some(Descriptor::Density(density)),
+        _ => None,
+    })
+}
+
+/// https://html.spec.whatwg.org/#rules-for-parsing-non-negative-integers
+fn parse_non_zero_integer(value: &str) -> Option<u32> {
+    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    value.parse().ok().filter(|&n| n > 0)
+}
+
+/// https://html.spec.whatwg.org/#rules-for-parsing-floating-point-number-values
+fn parse_density(value: &str) -> Option<f32> {
+    // Rust accepts a few forms that aren't valid floating-point numbers (`inf`, `+1`, ...)
+    let valid_chars = value
+        .bytes()
+        .all(|b| b.is_ascii_digit() || matches!(b, b'.' | b'e' | b'E' | b'-' | b'+'));
+
+    if !valid_chars || value.starts_with('+') {
+        return None;
+    }
+
+    value
+        .parse()
+        .ok()
+        .filter(|&n: &f32| n >= 0.0 && n.is_finite())
+}
+
+/// https://infra.spec.whatwg.org/#ascii-whitespace
+fn is_ascii_whitespace(c: char) -> bool {
+    matches!(c, '\t' | '\n' | '\x0C' | '\r' | ' ')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(url: &str, descriptor: Option<Descriptor>) -> SrcsetCandidate {
+        SrcsetCandidate {
+            url: url.to_owned(),
+            descriptor,
+        }
+    }
+
+    #[test]
+    fn density_list() {
+        assert_eq!(
+            parse_srcset("mario.png, mario@2x.png 2x,\n  mario@1.5x.png   1.5x"),
+            [
+                candidate("mario.png", None),
+                candidate("mario@2x.png", Some(Descriptor::Density(2.0))),
+                candidate("mario@1.5x.png", Some(Descriptor::Density(1.5))),
+            ]
+        );
+    }
+
+    #[test]
+    fn width_list() {
+        assert_eq!(
+            parse_srcset("small.jpg 480w, /img/large.jpg?v=1,2 1080w 720h,data:image/gif,"),
+            [
+                candidate("small.jpg", Some(Descriptor::Width(480))),
+                candidate("/img/large.jpg?v=1,2", Some(Descriptor::Width(1080))),
+                candidate("data:image/gif", None),
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_malformed_candidates() {
+        assert_eq!(
+            parse_srcset("a.png 0w, b.png 1x 2x, c.png 100w 2x, d.png +2x, e.png 5h, f.png 3x"),
+            [candidate("f.png", Some(Descriptor::Density(3.0)))]
+        );
+        assert_eq!(
+            parse_srcset("a.png foo(1, 2), b.png 2x"),
+            [candidate("b.png", Some(Descriptor::Density(2.0)))]
+        );
+        assert_eq!(parse_srcset("a.png 2×"), []);
+        assert!(parse_srcset(" , ,").is_empty());
+    }
+}
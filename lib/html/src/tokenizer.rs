@@ -0,0 +1,2416 @@
+use std::collections::VecDeque;
+
+use crate::{
+    named_character_references::NAMED_CHARACTER_REFERENCES, HtmlParseError, HtmlParseResult,
+};
+
+/// A range of character positions in the input stream (`start` inclusive, `end` exclusive).
+/// Spans are ordered by their start position, then by their end position.
+///
+/// A span whose `end` is before its `start` (which the public fields allow) is treated as empty.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Self { start, end }
+    }
+
+    /// Whether the character at `position` is part of the span
+    pub fn contains(&self, position: usize) -> bool {
+        (self.start..self.end).contains(&position)
+    }
+
+    /// The number of characters in the span
+    pub fn len(&self) -> usize {
+        self.end.saturating_sub(self.start)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start >= self.end
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TokenKind {
+    Doctype(DoctypeData),
+    StartTag(TagData),
+    EndTag(TagData),
+    Comment(String),
+    /// A run of consecutive character tokens
+    Text(String),
+    /// A processing instruction like `<?xml version="1.0"?>`, which is only produced in XML
+    /// mode (in HTML, `<?` starts a bogus comment instead)
+    ProcessingInstruction {
+        target: String,
+        data: String,
+    },
+    Eof,
+}
+
+/// The attributes are kept in source order, so tags with the same attributes in a different
+/// order are not equal
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TagData {
+    pub name: String,
+    pub self_closing: bool,
+    pub attributes: Vec<(String, String)>,
+}
+
+impl TagData {
+    /// Gets the value of the attribute with the given (lowercase) name
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The value of the `id` attribute
+    pub fn id(&self) -> Option<&str> {
+        self.get("id")
+    }
+
+    /// The classes in the `class` attribute, which is split on ASCII whitespace, without
+    /// duplicates (https://dom.spec.whatwg.org/#dom-element-classlist)
+    pub fn class_list(&self) -> Vec<&str> {
+        let mut classes = Vec::new();
+
+        for class in self
+            .get("class")
+            .unwrap_or_default()
+            .split(['\t', '\n', '\x0C', '\r', ' '])
+        {
+            if !class.is_empty() && !classes.contains(&class) {
+                classes.push(class);
+            }
+        }
+
+        classes
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DoctypeData {
+    pub name: Option<String>,
+    pub public_identifier: Option<String>,
+    pub system_identifier: Option<String>,
+    pub force_quirks: bool,
+}
+
+/// The states of the tokenization state machine
+/// (https://html.spec.whatwg.org/#tokenization)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenizerState {
+    Data,
+    Rcdata,
+    Rawtext,
+    ScriptData,
+    Plaintext,
+    TagOpen,
+    EndTagOpen,
+    TagName,
+    RcdataLessThanSign,
+    RcdataEndTagOpen,
+    RcdataEndTagName,
+    RawtextLessThanSign,
+    RawtextEndTagOpen,
+    RawtextEndTagName,
+    ScriptDataLessThanSign,
+    ScriptDataEndTagOpen,
+    ScriptDataEndTagName,
+    ScriptDataEscapeStart,
+    ScriptDataEscapeStartDash,
+    ScriptDataEscaped,
+    ScriptDataEscapedDash,
+    ScriptDataEscapedDashDash,
+    ScriptDataEscapedLessThanSign,
+    ScriptDataEscapedEndTagOpen,
+    ScriptDataEscapedEndTagName,
+    ScriptDataDoubleEscapeStart,
+    ScriptDataDoubleEscaped,
+    ScriptDataDoubleEscapedDash,
+    ScriptDataDoubleEscapedDashDash,
+    ScriptDataDoubleEscapedLessThanSign,
+    ScriptDataDoubleEscapeEnd,
+    BeforeAttributeName,
+    AttributeName,
+    AfterAttributeName,
+    BeforeAttributeValue,
+    AttributeValueDoubleQuoted,
+    AttributeValueSingleQuoted,
+    AttributeValueUnquoted,
+    AfterAttributeValueQuoted,
+    SelfClosingStartTag,
+    BogusComment,
+    MarkupDeclarationOpen,
+    CommentStart,
+    CommentStartDash,
+    Comment,
+    CommentLessThanSign,
+    CommentLessThanSignBang,
+    CommentLessThanSignBangDash,
+    CommentLessThanSignBangDashDash,
+    CommentEndDash,
+    CommentEnd,
+    CommentEndBang,
+    Doctype,
+    BeforeDoctypeName,
+    DoctypeName,
+    AfterDoctypeName,
+    AfterDoctypePublicKeyword,
+    BeforeDoctypePublicIdentifier,
+    DoctypePublicIdentifierDoubleQuoted,
+    DoctypePublicIdentifierSingleQuoted,
+    AfterDoctypePublicIdentifier,
+    BetweenDoctypePublicAndSystemIdentifiers,
+    AfterDoctypeSystemKeyword,
+    BeforeDoctypeSystemIdentifier,
+    DoctypeSystemIdentifierDoubleQuoted,
+    DoctypeSystemIdentifierSingleQuoted,
+    AfterDoctypeSystemIdentifier,
+    BogusDoctype,
+    CdataSection,
+    CdataSectionBracket,
+    CdataSectionEnd,
+    CharacterReference,
+    NamedCharacterReference,
+    AmbiguousAmpersand,
+    NumericCharacterReference,
+    HexadecimalCharacterReferenceStart,
+    DecimalCharacterReferenceStart,
+    HexadecimalCharacterReference,
+    DecimalCharacterReference,
+    NumericCharacterReferenceEnd,
+    /// Not part of the HTML spec, used for processing instructions in XML mode
+    ProcessingInstruction,
+}
+
+/// An incremental implementation of the HTML tokenizer. Characters are fed in one at a time
+/// through [`Tokenizer::step`], and any tokens (or parse errors) they produce are queued up
+/// to be taken with [`Tokenizer::pop`].
+pub struct Tokenizer {
+    state: TokenizerState,
+    /// Characters (and their positions) that still need to be consumed. `None` is EOF.
+    input: VecDeque<(Option<char>, usize)>,
+    /// Characters consumed by a state that needs to look ahead before deciding what to do
+    lookahead: Vec<(Option<char>, usize)>,
+    position: usize,
+    output: VecDeque<HtmlParseResult<Token>>,
+    eof_emitted: bool,
+    xml_mode: bool,
+
+    text: String,
+    text_start: usize,
+    text_end: usize,
+    token_start: usize,
+
+    current_tag: TagData,
+    current_tag_is_end: bool,
+    current_attribute: Option<(String, String)>,
+    current_comment: String,
+    current_doctype: DoctypeData,
+    temporary_buffer: String,
+    last_start_tag_name: Option<String>,
+
+    return_state: TokenizerState,
+    character_reference_code: u32,
+    character_reference_start: usize,
+    /// The length of the temporary buffer and the replacement characters of the longest
+    /// named character reference matched so far
+    character_reference_match: Option<(usize, &'static str)>,
+}
+
+impl Default for Tokenizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Tokenizer {
+    pub fn new() -> Self {
+        Self {
+            state: TokenizerState::Data,
+            input: VecDeque::new(),
+            lookahead: Vec::new(),
+            position: 0,
+            output: VecDeque::new(),
+            eof_emitted: false,
+            xml_mode: false,
+            text: String::new(),
+            text_start: 0,
+            text_end: 0,
+            token_start: 0,
+            current_tag: TagData::default(),
+            current_tag_is_end: false,
+            current_attribute: None,
+            current_comment: String::new(),
+            current_doctype: DoctypeData::default(),
+            temporary_buffer: String::new(),
+            last_start_tag_name: None,
+            return_state: TokenizerState::Data,
+            character_reference_code: 0,
+            character_reference_start: 0,
+            character_reference_match: None,
+        }
+    }
+
+    pub fn state(&self) -> TokenizerState {
+        self.state
+    }
+
+    /// Switches the tokenizer into the given state. This is used by the tree builder
+    /// (e.g. to switch to RCDATA after a `<title>`), and by tests to exercise states in isolation.
+    pub fn set_state(&mut self, state: TokenizerState) {
+        self.state = state;
+    }
+
+    /// Whether the input is tokenized as XML (e.g. for `application/xhtml+xml` documents),
+    /// which makes `<?` start a processing instruction instead of a bogus comment, and keeps
+    /// the case of tag and attribute names
+    pub fn set_xml_mode(&mut self, xml_mode: bool) {
+        self.xml_mode = xml_mode;
+    }
+
+    /// Sets the tag name that end tags are compared against in the RCDATA, RAWTEXT,
+    /// and script data states (https://html.spec.whatwg.org/#appropriate-end-tag-token)
+    pub fn set_last_start_tag_name(&mut self, name: Option<String>) {
+        self.last_start_tag_name = name;
+    }
+
+    /// Takes the next token or parse error produced by the tokenizer, if any
+    pub fn pop(&mut self) -> Option<HtmlParseResult<Token>> {
+        self.output.pop_front()
+    }
+
+    /// Consumes a single character from the input stream (or `None` for the end of the stream)
+    pub fn step(&mut self, c: Option<char>) {
+        if self.eof_emitted {
+            if c.is_none() {
+                self.output.push_back(Ok(Token {
+                    kind: TokenKind::Eof,
+                    span: Span::new(self.position, self.position),
+                }));
+            }
+
+            return;
+        }
+
+        let position = self.position;
+
+        if c.is_some() {
+            self.position += 1;
+        }
+
+        self.input.push_back((c, position));
+
+        while let Some((c, position)) = self.input.pop_front() {
+            self.consume(c, position);
+        }
+    }
+
+    /// Reports an error found in the input stream before it reached the tokenizer (e.g. a control
+    /// character), in order with the tokens
+    pub fn input_stream_error(&mut self, error: HtmlParseError) {
+        self.error(error);
+    }
+
+    /* Helper methods for emitting tokens */
+
+    fn reconsume(&mut self, state: TokenizerState, c: Option<char>, position: usize) {
+        self.state = state;
+        self.input.push_front((c, position));
+    }
+
+    /// Tag and attribute names are lowercased in HTML, but case-sensitive in XML
+    fn fold_case(&self, c: char) -> char {
+        if self.xml_mode {
+            c
+        } else {
+            c.to_ascii_lowercase()
+        }
+    }
+
+    fn error(&mut self, error: HtmlParseError) {
+        // Keep errors ordered relative to the surrounding text
+        self.flush_text();
+        self.output.push_back(Err(error));
+    }
+
+    fn emit_char(&mut self, c: char, position: usize) {
+        self.emit_str(&c.to_string(), position, position + 1);
+    }
+
+    fn emit_str(&mut self, s: &str, start: usize, end: usize) {
+        if self.text.is_empty() {
+            self.text_start = start;
+        }
+
+        self.text.push_str(s);
+        self.text_end = end;
+    }
+
+    fn flush_text(&mut self) {
+        if self.text.is_empty() {
+            return;
+        }
+
+        self.output.push_back(Ok(Token {
+            kind: TokenKind::Text(std::mem::take(&mut self.text)),
+            span: Span::new(self.text_start, self.text_end),
+        }));
+    }
+
+    fn emit(&mut self, kind: TokenKind, end: usize) {
+        self.flush_text();
+        self.output.push_back(Ok(Token {
+            kind,
+            span: Span::new(self.token_start, end),
+        }));
+    }
+
+    fn emit_eof(&mut self, position: usize) {
+        self.token_start = position;
+        self.emit(TokenKind::Eof, position);
+        self.eof_emitted = true;
+    }
+
+    fn new_tag(&mut self, is_end: bool) {
+        self.current_tag = TagData::default();
+        self.current_tag_is_end = is_end;
+        self.current_attribute = None;
+    }
+
+    fn emit_tag(&mut self, end: usize) {
+        self.commit_attribute();
+
+        let tag = std::mem::take(&mut self.current_tag);
+
+        if self.current_tag_is_end {
+            if !tag.attributes.is_empty() {
+                self.error(HtmlParseError::EndTagWithAttributes);
+            }
+
+            if tag.self_closing {
+                self.error(HtmlParseError::EndTagWithTrailingSolidus);
+            }
+
+            self.emit(TokenKind::EndTag(tag), end);
+        } else {
+            self.last_start_tag_name = Some(tag.name.clone());
+            self.emit(TokenKind::StartTag(tag), end);
+        }
+    }
+
+    fn new_attribute(&mut self, name: &str) {
+        self.commit_attribute();
+        self.current_attribute = Some((name.to_owned(), String::new()));
+    }
+
+    fn current_attribute(&mut self) -> &mut (String, String) {
+        self.current_attribute.get_or_insert_with(Default::default)
+    }
+
+    /// Adds the current attribute to the current tag, dropping it if the tag
+    /// already has an attribute with the same name
+    fn commit_attribute(&mut self) {
+        let Some((name, value)) = self.current_attribute.take() else {
+            return;
+        };
+
+        if self.current_tag.attributes.iter().any(|(n, _)| *n == name) {
+            self.error(HtmlParseError::DuplicateAttribute);
+            return;
+        }
+
+        self.current_tag.attributes.push((name, value));
+    }
+
+    fn emit_comment(&mut self, end: usize) {
+        let comment = std::mem::take(&mut self.current_comment);
+        self.emit(TokenKind::Comment(comment), end);
+    }
+
+    /// Emits the current comment as a processing instruction, whose target runs up to the
+    /// first whitespace
+    fn emit_processing_instruction(&mut self, end: usize) {
+        let content = std::mem::take(&mut self.current_comment);
+        let (target, data) = content
+            .split_once(|c: char| c.is_ascii_whitespace())
+            .unwrap_or((&content, ""));
+
+        self.emit(
+            TokenKind::ProcessingInstruction {
+                target: target.to_owned(),
+                data: data
+                    .trim_start_matches(|c: char| c.is_ascii_whitespace())
+                    .to_owned(),
+            },
+            end,
+        );
+    }
+
+    fn new_doctype(&mut self) {
+        self.current_doctype = DoctypeData::default();
+    }
+
+    fn emit_doctype(&mut self, end: usize) {
+        let doctype = std::mem::take(&mut self.current_doctype);
+        self.emit(TokenKind::Doctype(doctype), end);
+    }
+
+    /// https://html.spec.whatwg.org/#appropriate-end-tag-token
+    fn is_appropriate_end_tag(&self) -> bool {
+        self.last_start_tag_name.as_deref() == Some(self.current_tag.name.as_str())
+    }
+
+    /// Re-queues everything held in the lookahead buffer, followed by the given character
+    fn unconsume_lookahead(&mut self, c: Option<char>, position: usize) {
+        self.input.push_front((c, position));
+
+        for entry in std::mem::take(&mut self.lookahead).into_iter().rev() {
+            self.input.push_front(entry);
+        }
+    }
+
+    /// Checks whether the lookahead buffer (plus `c`) is the given keyword, or could still become it
+    fn lookahead_matches(&self, c: Option<char>, keyword: &str, ignore_case: bool) -> Lookahead {
+        let chars = self.lookahead.iter().map(|(c, _)| *c).chain([c]);
+
+        let mut len = 0;
+        for (c, k) in chars.zip(keyword.chars()) {
+            let Some(c) = c else {
+                return Lookahead::Mismatch;
+            };
+
+            let equal = if ignore_case {
+                c.eq_ignore_ascii_case(&k)
+            } else {
+                c == k
+            };
+
+            if !equal {
+                return Lookahead::Mismatch;
+            }
+
+            len += 1;
+        }
+
+        if len == keyword.len() {
+            Lookahead::Match
+        } else {
+            Lookahead::Partial
+        }
+    }
+
+    /// Handles the shared "anything else" branch of the RCDATA, RAWTEXT, and script data
+    /// end tag name states by emitting `</` and the temporary buffer as text
+    fn end_tag_name_anything_else(
+        &mut self,
+        return_state: TokenizerState,
+        c: Option<char>,
+        position: usize,
+    ) {
+        let buffer = format!("</{}", self.temporary_buffer);
+        self.emit_str(&buffer, self.token_start, position);
+        self.reconsume(return_state, c, position);
+    }
+
+    /// Handles the RCDATA, RAWTEXT, and script data end tag name states, which only differ
+    /// in the state they return to when the end tag is not appropriate
+    fn end_tag_name(&mut self, return_state: TokenizerState, c: Option<char>, position: usize) {
+        match c {
+            Some('\t' | '\n' | '\x0C' | ' ') if self.is_appropriate_end_tag() => {
+                self.state = TokenizerState::BeforeAttributeName;
+            }
+            Some('/') if self.is_appropriate_end_tag() => {
+                self.state = TokenizerState::SelfClosingStartTag;
+            }
+            Some('>') if self.is_appropriate_end_tag() => {
+                self.state = TokenizerState::Data;
+                self.emit_tag(position + 1);
+            }
+            Some(c) if c.is_ascii_alphabetic() => {
+                self.current_tag.name.push(c.to_ascii_lowercase());
+                self.temporary_buffer.push(c);
+            }
+            _ => self.end_tag_name_anything_else(return_state, c, position),
+        }
+    }
+
+    /// Handles the RCDATA, RAWTEXT, and script data end tag open states
+    fn end_tag_open(
+        &mut self,
+        name_state: TokenizerState,
+        return_state: TokenizerState,
+        c: Option<char>,
+        position: usize,
+    ) {
+        match c {
+            Some(c) if c.is_ascii_alphabetic() => {
+                self.new_tag(true);
+                self.reconsume(name_state, Some(c), position);
+            }
+            _ => {
+                self.emit_str("</", self.token_start, position);
+                self.reconsume(return_state, c, position);
+            }
+        }
+    }
+
+    /// Consumes a single character in the current state
+    fn consume(&mut self, c: Option<char>, position: usize) {
+        use TokenizerState::*;
+
+        match self.state {
+            // https://html.spec.whatwg.org/#data-state
+            Data => match c {
+                Some('&') => self.start_character_reference(Data, position),
+                Some('<') => {
+                    self.token_start = position;
+                    self.state = TagOpen;
+                }
+                Some('\0') => {
+                    self.error(HtmlParseError::UnexpectedNullCharacter);
+                    self.emit_char('\0', position);
+                }
+                Some(c) => self.emit_char(c, position),
+                None => self.emit_eof(position),
+            },
+            // https://html.spec.whatwg.org/#rcdata-state
+            Rcdata => match c {
+                Some('&') => self.start_character_reference(Rcdata, position),
+                Some('<') => {
+                    self.token_start = position;
+                    self.state = RcdataLessThanSign;
+                }
+                Some('\0') => {
+                    self.error(HtmlParseError::UnexpectedNullCharacter);
+                    self.emit_char(char::REPLACEMENT_CHARACTER, position);
+                }
+                Some(c) => self.emit_char(c, position),
+                None => self.emit_eof(position),
+            },
+            // https://html.spec.whatwg.org/#rawtext-state
+            Rawtext => match c {
+                Some('<') => {
+                    self.token_start = position;
+                    self.state = RawtextLessThanSign;
+                }
+                Some('\0') => {
+                    self.error(HtmlParseError::UnexpectedNullCharacter);
+                    self.emit_char(char::REPLACEMENT_CHARACTER, position);
+                }
+                Some(c) => self.emit_char(c, position),
+                None => self.emit_eof(position),
+            },
+            // https://html.spec.whatwg.org/#script-data-state
+            ScriptData => match c {
+                Some('<') => {
+                    self.token_start = position;
+                    self.state = ScriptDataLessThanSign;
+                }
+                Some('\0') => {
+                    self.error(HtmlParseError::UnexpectedNullCharacter);
+                    self.emit_char(char::REPLACEMENT_CHARACTER, position);
+                }
+                Some(c) => self.emit_char(c, position),
+                None => self.emit_eof(position),
+            },
+            // https://html.spec.whatwg.org/#plaintext-state
+            Plaintext => match c {
+                Some('\0') => {
+                    self.error(HtmlParseError::UnexpectedNullCharacter);
+                    self.emit_char(char::REPLACEMENT_CHARACTER, position);
+                }
+                Some(c) => self.emit_char(c, position),
+                None => self.emit_eof(position),
+            },
+            // https://html.spec.whatwg.org/#tag-open-state
+            TagOpen => match c {
+                Some('!') => self.state = MarkupDeclarationOpen,
+                Some('/') => self.state = EndTagOpen,
+                Some(c) if c.is_ascii_alphabetic() => {
+                    self.new_tag(false);
+                    self.reconsume(TagName, Some(c), position);
+                }
+                Some('?') if self.xml_mode => {
+                    self.current_comment.clear();
+                    self.state = ProcessingInstruction;
+                }
+                Some('?') => {
+                    self.error(HtmlParseError::UnexpectedQuestionMarkInsteadOfTagName);
+                    self.current_comment.clear();
+                    self.reconsume(BogusComment, c, position);
+                }
+                None => {
+                    self.error(HtmlParseError::EofBeforeTagName);
+                    self.emit_char('<', self.token_start);
+                    self.emit_eof(position);
+                }
+                Some(_) => {
+                    self.error(HtmlParseError::InvalidFirstCharacterOfTagName);
+                    self.emit_char('<', self.token_start);
+                    self.reconsume(Data, c, position);
+                }
+            },
+            // https://html.spec.whatwg.org/#end-tag-open-state
+            EndTagOpen => match c {
+                Some(c) if c.is_ascii_alphabetic() => {
+                    self.new_tag(true);
+                    self.reconsume(TagName, Some(c), position);
+                }
+                Some('>') => {
+                    self.error(HtmlParseError::MissingEndTagName);
+                    self.state = Data;
+                }
+                None => {
+                    self.error(HtmlParseError::EofBeforeTagName);
+                    self.emit_str("</", self.token_start, position);
+                    self.emit_eof(position);
+                }
+                Some(_) => {
+                    self.error(HtmlParseError::InvalidFirstCharacterOfTagName);
+                    self.current_comment.clear();
+                    self.reconsume(BogusComment, c, position);
+                }
+            },
+            // https://html.spec.whatwg.org/#tag-name-state
+            TagName => match c {
+                Some('\t' | '\n' | '\x0C' | ' ') => self.state = BeforeAttributeName,
+                Some('/') => self.state = SelfClosingStartTag,
+                Some('>') => {
+                    self.state = Data;
+                    self.emit_tag(position + 1);
+                }
+                Some('\0') => {
+                    self.error(HtmlParseError::UnexpectedNullCharacter);
+                    self.current_tag.name.push(char::REPLACEMENT_CHARACTER);
+                }
+                Some(c) => {
+                    let c = self.fold_case(c);
+                    self.current_tag.name.push(c);
+                }
+                None => {
+                    self.error(HtmlParseError::EofInTag);
+                    self.emit_eof(position);
+                }
+            },
+            // https://html.spec.whatwg.org/#rcdata-less-than-sign-state
+            RcdataLessThanSign => match c {
+                Some('/') => {
+                    self.temporary_buffer.clear();
+                    self.state = RcdataEndTagOpen;
+                }
+                _ => {
+                    self.emit_char('<', self.token_start);
+                    self.reconsume(Rcdata, c, position);
+                }
+            },
+            // https://html.spec.whatwg.org/#rcdata-end-tag-open-state
+            RcdataEndTagOpen => self.end_tag_open(RcdataEndTagName, Rcdata, c, position),
+            // https://html.spec.whatwg.org/#rcdata-end-tag-name-state
+            RcdataEndTagName => self.end_tag_name(Rcdata, c, position),
+            // https://html.spec.whatwg.org/#rawtext-less-than-sign-state
+            RawtextLessThanSign => match c {
+                Some('/') => {
+                    self.temporary_buffer.clear();
+                    self.state = RawtextEndTagOpen;
+                }
+                _ => {
+                    self.emit_char('<', self.token_start);
+                    self.reconsume(Rawtext, c, position);
+                }
+            },
+            // https://html.spec.whatwg.org/#rawtext-end-tag-open-state
+            RawtextEndTagOpen => self.end_tag_open(RawtextEndTagName, Rawtext, c, position),
+            // https://html.spec.whatwg.org/#rawtext-end-tag-name-state
+            RawtextEndTagName => self.end_tag_name(Rawtext, c, position),
+            // https://html.spec.whatwg.org/#script-data-less-than-sign-state
+            ScriptDataLessThanSign => match c {
+                Some('/') => {
+                    self.temporary_buffer.clear();
+                    self.state = ScriptDataEndTagOpen;
+                }
+                Some('!') => {
+                    self.state = ScriptDataEscapeStart;
+                    self.emit_str("<!", self.token_start, position + 1);
+                }
+                _ => {
+                    self.emit_char('<', self.token_start);
+                    self.reconsume(ScriptData, c, position);
+                }
+            },
+            // https://html.spec.whatwg.org/#script-data-end-tag-open-state
+            ScriptDataEndTagOpen => {
+                self.end_tag_open(ScriptDataEndTagName, ScriptData, c, position)
+            }
+            // https://html.spec.whatwg.org/#script-data-end-tag-name-state
+            ScriptDataEndTagName => self.end_tag_name(ScriptData, c, position),
+            // https://html.spec.whatwg.org/#script-data-escape-start-state
+            ScriptDataEscapeStart => match c {
+                Some('-') => {
+                    self.state = ScriptDataEscapeStartDash;
+                    self.emit_char('-', position);
+                }
+                _ => self.reconsume(ScriptData, c, position),
+            },
+            // https://html.spec.whatwg.org/#script-data-escape-start-dash-state
+            ScriptDataEscapeStartDash => match c {
+                Some('-') => {
+                    self.state = ScriptDataEscapedDashDash;
+                    self.emit_char('-', position);
+                }
+                _ => self.reconsume(ScriptData, c, position),
+            },
+            // https://html.spec.whatwg.org/#script-data-escaped-state
+            ScriptDataEscaped => match c {
+                Some('-') => {
+                    self.state = ScriptDataEscapedDash;
+                    self.emit_char('-', position);
+                }
+                Some('<') => {
+                    self.token_start = position;
+                    self.state = ScriptDataEscapedLessThanSign;
+                }
+                Some('\0') => {
+                    self.error(HtmlParseError::UnexpectedNullCharacter);
+                    self.emit_char(char::REPLACEMENT_CHARACTER, position);
+                }
+                Some(c) => self.emit_char(c, position),
+                None => {
+                    self.error(HtmlParseError::EofInScriptHtmlCommentLikeText);
+                    self.emit_eof(position);
+                }
+            },
+            // https://html.spec.whatwg.org/#script-data-escaped-dash-state
+            ScriptDataEscapedDash => match c {
+                Some('-') => {
+                    self.state = ScriptDataEscapedDashDash;
+                    self.emit_char('-', position);
+                }
+                Some('<') => {
+                    self.token_start = position;
+                    self.state = ScriptDataEscapedLessThanSign;
+                }
+                Some('\0') => {
+                    self.error(HtmlParseError::UnexpectedNullCharacter);
+                    self.state = ScriptDataEscaped;
+                    self.emit_char(char::REPLACEMENT_CHARACTER, position);
+                }
+                Some(c) => {
+                    self.state = ScriptDataEscaped;
+                    self.emit_char(c, position);
+                }
+                None => {
+                    self.error(HtmlParseError::EofInScriptHtmlCommentLikeText);
+                    self.emit_eof(position);
+                }
+            },
+            // https://html.spec.whatwg.org/#script-data-escaped-dash-dash-state
+            ScriptDataEscapedDashDash => match c {
+                Some('-') => self.emit_char('-', position),
+                Some('<') => {
+                    self.token_start = position;
+                    self.state = ScriptDataEscapedLessThanSign;
+                }
+                Some('>') => {
+                    self.state = ScriptData;
+                    self.emit_char('>', position);
+                }
+                Some('\0') => {
+                    self.error(HtmlParseError::UnexpectedNullCharacter);
+                    self.state = ScriptDataEscaped;
+                    self.emit_char(char::REPLACEMENT_CHARACTER, position);
+                }
+                Some(c) => {
+                    self.state = ScriptDataEscaped;
+                    self.emit_char(c, position);
+                }
+                None => {
+                    self.error(HtmlParseError::EofInScriptHtmlCommentLikeText);
+                    self.emit_eof(position);
+                }
+            },
+            // https://html.spec.whatwg.org/#script-data-escaped-less-than-sign-state
+            ScriptDataEscapedLessThanSign => match c {
+                Some('/') => {
+                    self.temporary_buffer.clear();
+                    self.state = ScriptDataEscapedEndTagOpen;
+                }
+                Some(c) if c.is_ascii_alphabetic() => {
+                    self.temporary_buffer.clear();
+                    self.emit_char('<', self.token_start);
+                    self.reconsume(ScriptDataDoubleEscapeStart, Some(c), position);
+                }
+                _ => {
+                    self.emit_char('<', self.token_start);
+                    self.reconsume(ScriptDataEscaped, c, position);
+                }
+            },
+            // https://html.spec.whatwg.org/#script-data-escaped-end-tag-open-state
+            ScriptDataEscapedEndTagOpen => {
+                self.end_tag_open(ScriptDataEscapedEndTagName, ScriptDataEscaped, c, position)
+            }
+            // https://html.spec.whatwg.org/#script-data-escaped-end-tag-name-state
+            ScriptDataEscapedEndTagName => self.end_tag_name(ScriptDataEscaped, c, position),
+            // https://html.spec.whatwg.org/#script-data-double-escape-start-state
+            ScriptDataDoubleEscapeStart => match c {
+                Some(c @ ('\t' | '\n' | '\x0C' | ' ' | '/' | '>')) => {
+                    self.state = if self.temporary_buffer == "script" {
+                        ScriptDataDoubleEscaped
+                    } else {
+                        ScriptDataEscaped
+                    };
+                    self.emit_char(c, position);
+                }
+                Some(c) if c.is_ascii_alphabetic() => {
+                    self.temporary_buffer.push(c.to_ascii_lowercase());
+                    self.emit_char(c, position);
+                }
+                _ => self.reconsume(ScriptDataEscaped, c, position),
+            },
+            // https://html.spec.whatwg.org/#script-data-double-escaped-state
+            ScriptDataDoubleEscaped => match c {
+                Some('-') => {
+                    self.state = ScriptDataDoubleEscapedDash;
+                    self.emit_char('-', position);
+                }
+                Some('<') => {
+                    self.state = ScriptDataDoubleEscapedLessThanSign;
+                    self.emit_char('<', position);
+                }
+                Some('\0') => {
+                    self.error(HtmlParseError::UnexpectedNullCharacter);
+                    self.emit_char(char::REPLACEMENT_CHARACTER, position);
+                }
+                Some(c) => self.emit_char(c, position),
+                None => {
+                    self.error(HtmlParseError::EofInScriptHtmlCommentLikeText);
+                    self.emit_eof(position);
+                }
+            },
+            // https://html.spec.whatwg.org/#script-data-double-escaped-dash-state
+            ScriptDataDoubleEscapedDash => match c {
+                Some('-') => {
+                    self.state = ScriptDataDoubleEscapedDashDash;
+                    self.emit_char('-', position);
+                }
+                Some('<') => {
+                    self.state = ScriptDataDoubleEscapedLessThanSign;
+                    self.emit_char('<', position);
+                }
+                Some('\0') => {
+                    self.error(HtmlParseError::UnexpectedNullCharacter);
+                    self.state = ScriptDataDoubleEscaped;
+                    self.emit_char(char::REPLACEMENT_CHARACTER, position);
+                }
+                Some(c) => {
+                    self.state = ScriptDataDoubleEscaped;
+                    self.emit_char(c, position);
+                }
+                None => {
+                    self.error(HtmlParseError::EofInScriptHtmlCommentLikeText);
+                    self.emit_eof(position);
+                }
+            },
+            // https://html.spec.whatwg.org/#script-data-double-escaped-dash-dash-state
+            ScriptDataDoubleEscapedDashDash => match c {
+                Some('-') => self.emit_char('-', position),
+                Some('<') => {
+                    self.state = ScriptDataDoubleEscapedLessThanSign;
+                    self.emit_char('<', position);
+                }
+                Some('>') => {
+                    self.state = ScriptData;
+                    self.emit_char('>', position);
+                }
+                Some('\0') => {
+                    self.error(HtmlParseError::UnexpectedNullCharacter);
+                    self.state = ScriptDataDoubleEscaped;
+                    self.emit_char(char::REPLACEMENT_CHARACTER, position);
+                }
+                Some(c) => {
+                    self.state = ScriptDataDoubleEscaped;
+                    self.emit_char(c, position);
+                }
+                None => {
+                    self.error(HtmlParseError::EofInScriptHtmlCommentLikeText);
+                    self.emit_eof(position);
+                }
+            },
+            // https://html.spec.whatwg.org/#script-data-double-escaped-less-than-sign-state
+            ScriptDataDoubleEscapedLessThanSign => match c {
+                Some('/') => {
+                    self.temporary_buffer.clear();
+                    self.state = ScriptDataDoubleEscapeEnd;
+                    self.emit_char('/', position);
+                }
+                _ => self.reconsume(ScriptDataDoubleEscaped, c, position),
+            },
+            // https://html.spec.whatwg.org/#script-data-double-escape-end-state
+            ScriptDataDoubleEscapeEnd => match c {
+                Some(c @ ('\t' | '\n' | '\x0C' | ' ' | '/' | '>')) => {
+                    self.state = if self.temporary_buffer == "script" {
+                        ScriptDataEscaped
+                    } else {
+                        ScriptDataDoubleEscaped
+                    };
+                    self.emit_char(c, position);
+                }
+                Some(c) if c.is_ascii_alphabetic() => {
+                    self.temporary_buffer.push(c.to_ascii_lowercase());
+                    self.emit_char(c, position);
+                }
+                _ => self.reconsume(ScriptDataDoubleEscaped, c, position),
+            },
+            // https://html.spec.whatwg.org/#before-attribute-name-state
+            BeforeAttributeName => match c {
+                Some('\t' | '\n' | '\x0C' | ' ') => {}
+                Some('/' | '>') | None => self.reconsume(AfterAttributeName, c, position),
+                Some('=') => {
+                    self.error(HtmlParseError::UnexpectedEqualsSignBeforeAttributeName);
+                    self.new_attribute("=");
+                    self.state = AttributeName;
+                }
+                Some(_) => {
+                    self.new_attribute("");
+                    self.reconsume(AttributeName, c, position);
+                }
+            },
+            // https://html.spec.whatwg.org/#attribute-name-state
+            AttributeName => match c {
+                Some('\t' | '\n' | '\x0C' | ' ' | '/' | '>') | None => {
+                    self.reconsume(AfterAttributeName, c, position)
+                }
+                Some('=') => self.state = BeforeAttributeValue,
+                Some('\0') => {
+                    self.error(HtmlParseError::UnexpectedNullCharacter);
+                    self.current_attribute().0.push(char::REPLACEMENT_CHARACTER);
+                }
+                Some(c) => {
+                    if matches!(c, '"' | '\'' | '<') {
+                        self.error(HtmlParseError::UnexpectedCharacterInAttributeName);
+                    }
+
+                    let c = self.fold_case(c);
+                    self.current_attribute().0.push(c);
+                }
+            },
+            // https://html.spec.whatwg.org/#after-attribute-name-state
+            AfterAttributeName => match c {
+                Some('\t' | '\n' | '\x0C' | ' ') => {}
+                Some('/') => self.state = SelfClosingStartTag,
+                Some('=') => self.state = BeforeAttributeValue,
+                Some('>') => {
+                    self.state = Data;
+                    self.emit_tag(position + 1);
+                }
+                Some(_) => {
+                    self.new_attribute("");
+                    self.reconsume(AttributeName, c, position);
+                }
+                None => {
+                    self.error(HtmlParseError::EofInTag);
+                    self.emit_eof(position);
+                }
+            },
+            // https://html.spec.whatwg.org/#before-attribute-value-state
+            BeforeAttributeValue => match c {
+                Some('\t' | '\n' | '\x0C' | ' ') => {}
+                Some('"') => self.state = AttributeValueDoubleQuoted,
+                Some('\'') => self.state = AttributeValueSingleQuoted,
+                Some('>') => {
+                    self.error(HtmlParseError::MissingAttributeValue);
+                    self.state = Data;
+                    self.emit_tag(position + 1);
+                }
+                _ => self.reconsume(AttributeValueUnquoted, c, position),
+            },
+            // https://html.spec.whatwg.org/#attribute-value-(double-quoted)-state
+            AttributeValueDoubleQuoted => match c {
+                Some('"') => self.state = AfterAttributeValueQuoted,
+                Some('&') => self.start_character_reference(AttributeValueDoubleQuoted, position),
+                Some('\0') => {
+                    self.error(HtmlParseError::UnexpectedNullCharacter);
+                    self.current_attribute().1.push(char::REPLACEMENT_CHARACTER);
+                }
+                Some(c) => self.current_attribute().1.push(c),
+                None => {
+                    self.error(HtmlParseError::EofInTag);
+                    self.emit_eof(position);
+                }
+            },
+            // https://html.spec.whatwg.org/#attribute-value-(single-quoted)-state
+            AttributeValueSingleQuoted => match c {
+                Some('\'') => self.state = AfterAttributeValueQuoted,
+                Some('&') => self.start_character_reference(AttributeValueSingleQuoted, position),
+                Some('\0') => {
+                    self.error(HtmlParseError::UnexpectedNullCharacter);
+                    self.current_attribute().1.push(char::REPLACEMENT_CHARACTER);
+                }
+                Some(c) => self.current_attribute().1.push(c),
+                None => {
+                    self.error(HtmlParseError::EofInTag);
+                    self.emit_eof(position);
+                }
+            },
+            // https://html.spec.whatwg.org/#attribute-value-(unquoted)-state
+            AttributeValueUnquoted => match c {
+                Some('\t' | '\n' | '\x0C' | ' ') => self.state = BeforeAttributeName,
+                Some('&') => self.start_character_reference(AttributeValueUnquoted, position),
+                Some('>') => {
+                    self.state = Data;
+                    self.emit_tag(position + 1);
+                }
+                Some('\0') => {
+                    self.error(HtmlParseError::UnexpectedNullCharacter);
+                    self.current_attribute().1.push(char::REPLACEMENT_CHARACTER);
+                }
+                Some(c) => {
+                    if matches!(c, '"' | '\'' | '<' | '=' | '`') {
+                        self.error(HtmlParseError::UnexpectedCharacterInUnquotedAttributeValue);
+                    }
+
+                    self.current_attribute().1.push(c);
+                }
+                None => {
+                    self.error(HtmlParseError::EofInTag);
+                    self.emit_eof(position);
+                }
+            },
+            // https://html.spec.whatwg.org/#after-attribute-value-(quoted)-state
+            AfterAttributeValueQuoted => match c {
+                Some('\t' | '\n' | '\x0C' | ' ') => self.state = BeforeAttributeName,
+                Some('/') => self.state = SelfClosingStartTag,
+                Some('>') => {
+                    self.state = Data;
+                    self.emit_tag(position + 1);
+                }
+                Some(_) => {
+                    self.error(HtmlParseError::MissingWhitespaceBetweenAttributes);
+                    self.reconsume(BeforeAttributeName, c, position);
+                }
+                None => {
+                    self.error(HtmlParseError::EofInTag);
+                    self.emit_eof(position);
+                }
+            },
+            // https://html.spec.whatwg.org/#self-closing-start-tag-state
+            SelfClosingStartTag => match c {
+                Some('>') => {
+                    self.current_tag.self_closing = true;
+                    self.state = Data;
+                    self.emit_tag(position + 1);
+                }
+                Some(_) => {
+                    self.error(HtmlParseError::UnexpectedSolidusInTag);
+                    self.reconsume(BeforeAttributeName, c, position);
+                }
+                None => {
+                    self.error(HtmlParseError::EofInTag);
+                    self.emit_eof(position);
+                }
+            },
+            // https://html.spec.whatwg.org/#bogus-comment-state
+            BogusComment => match c {
+                Some('>') => {
+                    self.state = Data;
+                    self.emit_comment(position + 1);
+                }
+                Some('\0') => {
+                    self.error(HtmlParseError::UnexpectedNullCharacter);
+                    self.current_comment.push(char::REPLACEMENT_CHARACTER);
+                }
+                Some(c) => self.current_comment.push(c),
+                None => {
+                    self.emit_comment(position);
+                    self.emit_eof(position);
+                }
+            },
+            // https://www.w3.org/TR/xml/#sec-pi
+            ProcessingInstruction => match c {
+                Some('>') if self.current_comment.ends_with('?') => {
+                    self.current_comment.pop();
+                    self.state = Data;
+                    self.emit_processing_instruction(position + 1);
+                }
+                Some(c) => self.current_comment.push(c),
+                None => {
+                    self.error(HtmlParseError::EofInTag);
+                    self.emit_processing_instruction(position);
+                    self.emit_eof(position);
+                }
+            },
+            // https://html.spec.whatwg.org/#markup-declaration-open-state
+            MarkupDeclarationOpen => {
+                let comment = self.lookahead_matches(c, "--", false);
+                let doctype = self.lookahead_matches(c, "DOCTYPE", true);
+                let cdata = self.lookahead_matches(c, "[CDATA[", false);
+
+                if comment == Lookahead::Match {
+                    self.lookahead.clear();
+                    self.current_comment.clear();
+                    self.state = CommentStart;
+                } else if doctype == Lookahead::Match {
+                    self.lookahead.clear();
+                    self.state = Doctype;
+                } else if cdata == Lookahead::Match {
+                    // CDATA sections are only allowed in foreign content, which isn't supported
+                    self.lookahead.clear();
+                    self.error(HtmlParseError::CdataInHtmlContent);
+                    self.current_comment = "[CDATA[".to_owned();
+                    self.state = BogusComment;
+                } else if [comment, doctype, cdata].contains(&Lookahead::Partial) {
+                    self.lookahead.push((c, position));
+                } else {
+                    self.error(HtmlParseError::IncorrectlyOpenedComment);
+                    self.current_comment.clear();
+                    self.state = BogusComment;
+                    self.unconsume_lookahead(c, position);
+                }
+            }
+            // https://html.spec.whatwg.org/#comment-start-state
+            CommentStart => match c {
+                Some('-') => self.state = CommentStartDash,
+                Some('>') => {
+                    self.error(HtmlParseError::AbruptClosingOfEmptyComment);
+                    self.state = Data;
+                    self.emit_comment(position + 1);
+                }
+                _ => self.reconsume(Comment, c, position),
+            },
+            // https://html.spec.whatwg.org/#comment-start-dash-state
+            CommentStartDash => match c {
+                Some('-') => self.state = CommentEnd,
+                Some('>') => {
+                    self.error(HtmlParseError::AbruptClosingOfEmptyComment);
+                    self.state = Data;
+                    self.emit_comment(position + 1);
+                }
+                Some(_) => {
+                    self.current_comment.push('-');
+                    self.reconsume(Comment, c, position);
+                }
+                None => {
+                    self.error(HtmlParseError::EofInComment);
+                    self.emit_comment(position);
+                    self.emit_eof(position);
+                }
+            },
+            // https://html.spec.whatwg.org/#comment-state
+            Comment => match c {
+                Some('<') => {
+                    self.current_comment.push('<');
+                    self.state = CommentLessThanSign;
+                }
+                Some('-') => self.state = CommentEndDash,
+                Some('\0') => {
+                    self.error(HtmlParseError::UnexpectedNullCharacter);
+                    self.current_comment.push(char::REPLACEMENT_CHARACTER);
+                }
+                Some(c) => self.current_comment.push(c),
+                None => {
+                    self.error(HtmlParseError::EofInComment);
+                    self.emit_comment(position);
+                    self.emit_eof(position);
+                }
+            },
+            // https://html.spec.whatwg.org/#comment-less-than-sign-state
+            CommentLessThanSign => match c {
+                Some('!') => {
+                    self.current_comment.push('!');
+                    self.state = CommentLessThanSignBang;
+                }
+                Some('<') => self.current_comment.push('<'),
+                _ => self.reconsume(Comment, c, position),
+            },
+            // https://html.spec.whatwg.org/#comment-less-than-sign-bang-state
+            CommentLessThanSignBang => match c {
+                Some('-') => self.state = CommentLessThanSignBangDash,
+                _ => self.reconsume(Comment, c, position),
+            },
+            // https://html.spec.whatwg.org/#comment-less-than-sign-bang-dash-state
+            CommentLessThanSignBangDash => match c {
+                Some('-') => self.state = CommentLessThanSignBangDashDash,
+                _ => self.reconsume(CommentEndDash, c, position),
+            },
+            // https://html.spec.whatwg.org/#comment-less-than-sign-bang-dash-dash-state
+            CommentLessThanSignBangDashDash => match c {
+                Some('>') | None => self.reconsume(CommentEnd, c, position),
+                Some(_) => {
+                    self.error(HtmlParseError::NestedComment);
+                    self.reconsume(CommentEnd, c, position);
+                }
+            },
+            // https://html.spec.whatwg.org/#comment-end-dash-state
+            CommentEndDash => match c {
+                Some('-') => self.state = CommentEnd,
+                Some(_) => {
+                    self.current_comment.push('-');
+                    self.reconsume(Comment, c, position);
+                }
+                None => {
+                    self.error(HtmlParseError::EofInComment);
+                    self.emit_comment(position);
+                    self.emit_eof(position);
+                }
+            },
+            // https://html.spec.whatwg.org/#comment-end-state
+            CommentEnd => match c {
+                Some('>') => {
+                    self.state = Data;
+                    self.emit_comment(position + 1);
+                }
+                Some('!') => self.state = CommentEndBang,
+                Some('-') => self.current_comment.push('-'),
+                Some(_) => {
+                    self.current_comment.push_str("--");
+                    self.reconsume(Comment, c, position);
+                }
+                None => {
+                    self.error(HtmlParseError::EofInComment);
+                    self.emit_comment(position);
+                    self.emit_eof(position);
+                }
+            },
+            // https://html.spec.whatwg.org/#comment-end-bang-state
+            CommentEndBang => match c {
+                Some('-') => {
+                    self.current_comment.push_str("--!");
+                    self.state = CommentEndDash;
+                }
+                Some('>') => {
+                    self.error(HtmlParseError::IncorrectlyClosedComment);
+                    self.state = Data;
+                    self.emit_comment(position + 1);
+                }
+                Some(_) => {
+                    self.current_comment.push_str("--!");
+                    self.reconsume(Comment, c, position);
+                }
+                None => {
+                    self.error(HtmlParseError::EofInComment);
+                    self.emit_comment(position);
+                    self.emit_eof(position);
+                }
+            },
+            // https://html.spec.whatwg.org/#doctype-state
+            Doctype => match c {
+                Some('\t' | '\n' | '\x0C' | ' ') => self.state = BeforeDoctypeName,
+                Some('>') => self.reconsume(BeforeDoctypeName, c, position),
+                Some(_) => {
+                    self.error(HtmlParseError::MissingWhitespaceBeforeDoctypeName);
+                    self.reconsume(BeforeDoctypeName, c, position);
+                }
+                None => {
+                    self.error(HtmlParseError::EofInDoctype);
+                    self.new_doctype();
+                    self.current_doctype.force_quirks = true;
+                    self.emit_doctype(position);
+                    self.emit_eof(position);
+                }
+            },
+            // https://html.spec.whatwg.org/#before-doctype-name-state
+            BeforeDoctypeName => match c {
+                Some('\t' | '\n' | '\x0C' | ' ') => {}
+                Some('\0') => {
+                    self.error(HtmlParseError::UnexpectedNullCharacter);
+                    self.new_doctype();
+                    self.current_doctype.name = Some(char::REPLACEMENT_CHARACTER.to_string());
+                    self.state = DoctypeName;
+                }
+                Some('>') => {
+                    self.error(HtmlParseError::MissingDoctypeName);
+                    self.new_doctype();
+                    self.current_doctype.force_quirks = true;
+                    self.state = Data;
+                    self.emit_doctype(position + 1);
+                }
+                Some(c) => {
+                    self.new_doctype();
+                    self.current_doctype.name = Some(c.to_ascii_lowercase().to_string());
+                    self.state = DoctypeName;
+                }
+                None => {
+                    self.error(HtmlParseError::EofInDoctype);
+                    self.new_doctype();
+                    self.current_doctype.force_quirks = true;
+                    self.emit_doctype(position);
+                    self.emit_eof(position);
+                }
+            },
+            // https://html.spec.whatwg.org/#doctype-name-state
+            DoctypeName => match c {
+                Some('\t' | '\n' | '\x0C' | ' ') => self.state = AfterDoctypeName,
+                Some('>') => {
+                    self.state = Data;
+                    self.emit_doctype(position + 1);
+                }
+                Some(c) => {
+                    let c = match c {
+                        '\0' => {
+                            self.error(HtmlParseError::UnexpectedNullCharacter);
+                            char::REPLACEMENT_CHARACTER
+                        }
+                        c => c.to_ascii_lowercase(),
+                    };
+
+                    self.current_doctype
+                        .name
+                        .get_or_insert_with(String::new)
+                        .push(c);
+                }
+                None => {
+                    self.error(HtmlParseError::EofInDoctype);
+                    self.current_doctype.force_quirks = true;
+                    self.emit_doctype(position);
+                    self.emit_eof(position);
+                }
+            },
+            // https://html.spec.whatwg.org/#after-doctype-name-state
+            AfterDoctypeName => match c {
+                Some('\t' | '\n' | '\x0C' | ' ') if self.lookahead.is_empty() => {}
+                Some('>') if self.lookahead.is_empty() => {
+                    self.state = Data;
+                    self.emit_doctype(position + 1);
+                }
+                None if self.lookahead.is_empty() => {
+                    self.error(HtmlParseError::EofInDoctype);
+                    self.current_doctype.force_quirks = true;
+                    self.emit_doctype(position);
+                    self.emit_eof(position);
+                }
+                _ => {
+                    let public = self.lookahead_matches(c, "PUBLIC", true);
+                    let system = self.lookahead_matches(c, "SYSTEM", true);
+
+                    if public == Lookahead::Match {
+                        self.lookahead.clear();
+                        self.state = AfterDoctypePublicKeyword;
+                    } else if system == Lookahead::Match {
+                        self.lookahead.clear();
+                        self.state = AfterDoctypeSystemKeyword;
+                    } else if public == Lookahead::Partial || system == Lookahead::Partial {
+                        self.lookahead.push((c, position));
+                    } else {
+                        self.error(HtmlParseError::InvalidCharacterSequenceAfterDoctypeName);
+                        self.current_doctype.force_quirks = true;
+                        self.state = BogusDoctype;
+                        self.unconsume_lookahead(c, position);
+                    }
+                }
+            },
+            // https://html.spec.whatwg.org/#after-doctype-public-keyword-state
+            AfterDoctypePublicKeyword => match c {
+                Some('\t' | '\n' | '\x0C' | ' ') => self.state = BeforeDoctypePublicIdentifier,
+                Some(quote @ ('"' | '\'')) => {
+                    self.error(HtmlParseError::MissingWhitespaceAfterDoctypePublicKeyword);
+                    self.current_doctype.public_identifier = Some(String::new());
+                    self.state = if quote == '"' {
+                        DoctypePublicIdentifierDoubleQuoted
+                    } else {
+                        DoctypePublicIdentifierSingleQuoted
+                    };
+                }
+                _ => self.missing_doctype_identifier(
+                    c,
+                    position,
+                    HtmlParseError::MissingDoctypePublicIdentifier,
+                    HtmlParseError::MissingQuoteBeforeDoctypePublicIdentifier,
+                ),
+            },
+            // https://html.spec.whatwg.org/#before-doctype-public-identifier-state
+            BeforeDoctypePublicIdentifier => match c {
+                Some('\t' | '\n' | '\x0C' | ' ') => {}
+                Some(quote @ ('"' | '\'')) => {
+                    self.current_doctype.public_identifier = Some(String::new());
+                    self.state = if quote == '"' {
+                        DoctypePublicIdentifierDoubleQuoted
+                    } else {
+                        DoctypePublicIdentifierSingleQuoted
+                    };
+                }
+                _ => self.missing_doctype_identifier(
+                    c,
+                    position,
+                    HtmlParseError::MissingDoctypePublicIdentifier,
+                    HtmlParseError::MissingQuoteBeforeDoctypePublicIdentifier,
+                ),
+            },
+            // https://html.spec.whatwg.org/#doctype-public-identifier-(double-quoted)-state
+            DoctypePublicIdentifierDoubleQuoted => self.doctype_identifier(c, position, '"', true),
+            // https://html.spec.whatwg.org/#doctype-public-identifier-(single-quoted)-state
+            DoctypePublicIdentifierSingleQuoted => self.doctype_identifier(c, position, '\'', true),
+            // https://html.spec.whatwg.org/#after-doctype-public-identifier-state
+            AfterDoctypePublicIdentifier => match c {
+                Some('\t' | '\n' | '\x0C' | ' ') => {
+                    self.state = BetweenDoctypePublicAndSystemIdentifiers
+                }
+                Some('>') => {
+                    self.state = Data;
+                    self.emit_doctype(position + 1);
+                }
+                Some(quote @ ('"' | '\'')) => {
+                    self.error(
+                        HtmlParseError::MissingWhitespaceBetweenDoctypePublicAndSystemIdentifiers,
+                    );
+                    self.current_doctype.system_identifier = Some(String::new());
+                    self.state = if quote == '"' {
+                        DoctypeSystemIdentifierDoubleQuoted
+                    } else {
+                        DoctypeSystemIdentifierSingleQuoted
+                    };
+                }
+                _ => self.missing_doctype_identifier(
+                    c,
+                    position,
+                    HtmlParseError::MissingDoctypeSystemIdentifier,
+                    HtmlParseError::MissingQuoteBeforeDoctypeSystemIdentifier,
+                ),
+            },
+            // https://html.spec.whatwg.org/#between-doctype-public-and-system-identifiers-state
+            BetweenDoctypePublicAndSystemIdentifiers => match c {
+                Some('\t' | '\n' | '\x0C' | ' ') => {}
+                Some('>') => {
+                    self.state = Data;
+                    self.emit_doctype(position + 1);
+                }
+                Some(quote @ ('"' | '\'')) => {
+                    self.current_doctype.system_identifier = Some(String::new());
+                    self.state = if quote == '"' {
+                        DoctypeSystemIdentifierDoubleQuoted
+                    } else {
+                        DoctypeSystemIdentifierSingleQuoted
+                    };
+                }
+                _ => self.missing_doctype_identifier(
+                    c,
+                    position,
+                    HtmlParseError::MissingDoctypeSystemIdentifier,
+                    HtmlParseError::MissingQuoteBeforeDoctypeSystemIdentifier,
+                ),
+            },
+            // https://html.spec.whatwg.org/#after-doctype-system-keyword-state
+            AfterDoctypeSystemKeyword => match c {
+                Some('\t' | '\n' | '\x0C' | ' ') => self.state = BeforeDoctypeSystemIdentifier,
+                Some(quote @ ('"' | '\'')) => {
+                    self.error(HtmlParseError::MissingWhitespaceAfterDoctypeSystemKeyword);
+                    self.current_doctype.system_identifier = Some(String::new());
+                    self.state = if quote == '"' {
+                        DoctypeSystemIdentifierDoubleQuoted
+                    } else {
+                        DoctypeSystemIdentifierSingleQuoted
+                    };
+                }
+                _ => self.missing_doctype_identifier(
+                    c,
+                    position,
+                    HtmlParseError::MissingDoctypeSystemIdentifier,
+                    HtmlParseError::MissingQuoteBeforeDoctypeSystemIdentifier,
+                ),
+            },
+            // https://html.spec.whatwg.org/#before-doctype-system-identifier-state
+            BeforeDoctypeSystemIdentifier => match c {
+                Some('\t' | '\n' | '\x0C' | ' ') => {}
+                Some(quote @ ('"' | '\'')) => {
+                    self.current_doctype.system_identifier = Some(String::new());
+                    self.state = if quote == '"' {
+                        DoctypeSystemIdentifierDoubleQuoted
+                    } else {
+                        DoctypeSystemIdentifierSingleQuoted
+                    };
+                }
+                _ => self.missing_doctype_identifier(
+                    c,
+                    position,
+                    HtmlParseError::MissingDoctypeSystemIdentifier,
+                    HtmlParseError::MissingQuoteBeforeDoctypeSystemIdentifier,
+                ),
+            },
+            // https://html.spec.whatwg.org/#doctype-system-identifier-(double-quoted)-state
+            DoctypeSystemIdentifierDoubleQuoted => self.doctype_identifier(c, position, '"', false),
+            // https://html.spec.whatwg.org/#doctype-system-identifier-(single-quoted)-state
+            DoctypeSystemIdentifierSingleQuoted => {
+                self.doctype_identifier(c, position, '\'', false)
+            }
+            // https://html.spec.whatwg.org/#after-doctype-system-identifier-state
+            AfterDoctypeSystemIdentifier => match c {
+                Some('\t' | '\n' | '\x0C' | ' ') => {}
+                Some('>') => {
+                    self.state = Data;
+                    self.emit_doctype(position + 1);
+                }
+                Some(_) => {
+                    self.error(HtmlParseError::UnexpectedCharacterAfterDoctypeSystemIdentifier);
+                    self.reconsume(BogusDoctype, c, position);
+                }
+                None => {
+                    self.error(HtmlParseError::EofInDoctype);
+                    self.current_doctype.force_quirks = true;
+                    self.emit_doctype(position);
+                    self.emit_eof(position);
+                }
+            },
+            // https://html.spec.whatwg.org/#bogus-doctype-state
+            BogusDoctype => match c {
+                Some('>') => {
+                    self.state = Data;
+                    self.emit_doctype(position + 1);
+                }
+                Some('\0') => self.error(HtmlParseError::UnexpectedNullCharacter),
+                Some(_) => {}
+                None => {
+                    self.emit_doctype(position);
+                    self.emit_eof(position);
+                }
+            },
+            // https://html.spec.whatwg.org/#character-reference-state
+            CharacterReference => {
+                self.temporary_buffer.clear();
+                self.temporary_buffer.push('&');
+
+                match c {
+                    Some(c) if c.is_ascii_alphanumeric() => {
+                        self.character_reference_match = None;
+                        self.reconsume(NamedCharacterReference, Some(c), position);
+                    }
+                    Some('#') => {
+                        self.temporary_buffer.push('#');
+                        self.state = NumericCharacterReference;
+                    }
+                    _ => {
+                        self.flush_character_reference(position);
+                        self.reconsume(self.return_state, c, position);
+                    }
+                }
+            }
+            // https://html.spec.whatwg.org/#named-character-reference-state
+            NamedCharacterReference => {
+                // Keep consuming characters for as long as they could still be part of a name
+                // in the table, remembering the longest full match seen along the way
+                if let Some(c) = c {
+                    let candidate = format!("{}{c}", &self.temporary_buffer[1..]);
+
+                    if let Some(replacement) = Self::named_character_reference(&candidate) {
+                        self.temporary_buffer.push(c);
+
+                        match replacement {
+                            Some(replacement) => {
+                                self.lookahead.clear();
+                                self.character_reference_match =
+                                    Some((self.temporary_buffer.len(), replacement));
+                            }
+                            None => self.lookahead.push((Some(c), position)),
+                        }
+
+                        return;
+                    }
+                }
+
+                // Give back every character consumed after the longest match
+                let end = self.lookahead.first().map_or(position, |(_, p)| *p);
+                let next = self.lookahead.first().map_or(c, |(c, _)| *c);
+                let matched_len = self.character_reference_match.map_or(1, |(len, _)| len);
+                self.temporary_buffer.truncate(matched_len);
+                self.unconsume_lookahead(c, position);
+
+                let Some((_, replacement)) = self.character_reference_match.take() else {
+                    self.flush_character_reference(end);
+                    self.state = AmbiguousAmpersand;
+                    return;
+                };
+
+                // For historical reasons, `&amp=` and `&ampx` are left as is in attribute values
+                if self.is_consumed_as_part_of_attribute()
+                    && !self.temporary_buffer.ends_with(';')
+                    && matches!(next, Some(c) if c == '=' || c.is_ascii_alphanumeric())
+                {
+                    self.flush_character_reference(end);
+                    self.state = self.return_state;
+                    return;
+                }
+
+                if !self.temporary_buffer.ends_with(';') {
+                    self.error(HtmlParseError::MissingSemicolonAfterCharacterReference);
+                }
+
+                self.temporary_buffer = replacement.to_owned();
+                self.flush_character_reference(end);
+                self.state = self.return_state;
+            }
+            // https://html.spec.whatwg.org/#ambiguous-ampersand-state
+            AmbiguousAmpersand => match c {
+                Some(c) if c.is_ascii_alphanumeric() => {
+                    if self.is_consumed_as_part_of_attribute() {
+                        self.current_attribute().1.push(c);
+                    } else {
+                        self.emit_char(c, position);
+                    }
+                }
+                Some(';') => {
+                    self.error(HtmlParseError::UnknownNamedCharacterReference);
+                    self.reconsume(self.return_state, c, position);
+                }
+                _ => self.reconsume(self.return_state, c, position),
+            },
+            // https://html.spec.whatwg.org/#numeric-character-reference-state
+            NumericCharacterReference => {
+                self.character_reference_code = 0;
+
+                match c {
+                    Some(x @ ('x' | 'X')) => {
+                        self.temporary_buffer.push(x);
+                        self.state = HexadecimalCharacterReferenceStart;
+                    }
+                    _ => self.reconsume(DecimalCharacterReferenceStart, c, position),
+                }
+            }
+            // https://html.spec.whatwg.org/#hexadecimal-character-reference-start-state
+            HexadecimalCharacterReferenceStart => match c {
+                Some(c) if c.is_ascii_hexdigit() => {
+                    self.reconsume(HexadecimalCharacterReference, Some(c), position)
+                }
+                _ => {
+                    self.error(HtmlParseError::AbsenceOfDigitsInNumericCharacterReference);
+                    self.flush_character_reference(position);
+                    self.reconsume(self.return_state, c, position);
+                }
+            },
+            // https://html.spec.whatwg.org/#decimal-character-reference-start-state
+            DecimalCharacterReferenceStart => match c {
+                Some(c) if c.is_ascii_digit() => {
+                    self.reconsume(DecimalCharacterReference, Some(c), position)
+                }
+                _ => {
+                    self.error(HtmlParseError::AbsenceOfDigitsInNumericCharacterReference);
+                    self.flush_character_reference(position);
+                    self.reconsume(self.return_state, c, position);
+                }
+            },
+            // https://html.spec.whatwg.org/#hexadecimal-character-reference-state
+            HexadecimalCharacterReference => match c {
+                Some(c) if c.is_ascii_hexdigit() => self.add_to_character_reference_code(c, 16),
+                Some(';') => self.state = NumericCharacterReferenceEnd,
+                _ => {
+                    self.error(HtmlParseError::MissingSemicolonAfterCharacterReference);
+                    self.reconsume(NumericCharacterReferenceEnd, c, position);
+                }
+            },
+            // https://html.spec.whatwg.org/#decimal-character-reference-state
+            DecimalCharacterReference => match c {
+                Some(c) if c.is_ascii_digit() => self.add_to_character_reference_code(c, 10),
+                Some(';') => self.state = NumericCharacterReferenceEnd,
+                _ => {
+                    self.error(HtmlParseError::MissingSemicolonAfterCharacterReference);
+                    self.reconsume(NumericCharacterReferenceEnd, c, position);
+                }
+            },
+            // https://html.spec.whatwg.org/#numeric-character-reference-end-state
+            //
+            // This state doesn't consume anything, so the current character is always reconsumed
+            NumericCharacterReferenceEnd => {
+                let character = self.check_character_reference_code();
+
+                self.temporary_buffer.clear();
+                self.temporary_buffer.push(character);
+                self.flush_character_reference(position);
+                self.reconsume(self.return_state, c, position);
+            }
+            // https://html.spec.whatwg.org/#cdata-section-state
+            CdataSection => match c {
+                Some(']') => self.state = CdataSectionBracket,
+                Some(c) => self.emit_char(c, position),
+                None => {
+                    self.error(HtmlParseError::EofInCdata);
+                    self.emit_eof(position);
+                }
+            },
+            // https://html.spec.whatwg.org/#cdata-section-bracket-state
+            CdataSectionBracket => match c {
+                Some(']') => self.state = CdataSectionEnd,
+                _ => {
+                    self.emit_char(']', position.saturating_sub(1));
+                    self.reconsume(CdataSection, c, position);
+                }
+            },
+            // https://html.spec.whatwg.org/#cdata-section-end-state
+            CdataSectionEnd => match c {
+                Some(']') => self.emit_char(']', position.saturating_sub(2)),
+                Some('>') => self.state = Data,
+                _ => {
+                    self.emit_str("]]", position.saturating_sub(2), position);
+                    self.reconsume(CdataSection, c, position);
+                }
+            },
+        }
+    }
+
+    /* Helper methods for character references */
+
+    fn start_character_reference(&mut self, return_state: TokenizerState, position: usize) {
+        self.return_state = return_state;
+        self.character_reference_start = position;
+        self.state = TokenizerState::CharacterReference;
+    }
+
+    /// https://html.spec.whatwg.org/#charref-in-attribute
+    fn is_consumed_as_part_of_attribute(&self) -> bool {
+        matches!(
+            self.return_state,
+            TokenizerState::AttributeValueDoubleQuoted
+                | TokenizerState::AttributeValueSingleQuoted
+                | TokenizerState::AttributeValueUnquoted
+        )
+    }
+
+    /// https://html.spec.whatwg.org/#flush-code-points-consumed-as-a-character-reference
+    fn flush_character_reference(&mut self, end: usize) {
+        let buffer = std::mem::take(&mut self.temporary_buffer);
+
+        if self.is_consumed_as_part_of_attribute() {
+            self.current_attribute().1.push_str(&buffer);
+        } else {
+            self.emit_str(&buffer, self.character_reference_start, end);
+        }
+    }
+
+    /// Looks up a (partial) name in the named character references table. Returns `None` if
+    /// no name starts with `prefix`, and `Some(replacement)` otherwise, where `replacement`
+    /// is only present if `prefix` is a complete name.
+    fn named_character_reference(prefix: &str) -> Option<Option<&'static str>> {
+        let index = NAMED_CHARACTER_REFERENCES.partition_point(|(name, _)| *name < prefix);
+        let (name, replacement) = NAMED_CHARACTER_REFERENCES.get(index)?;
+
+        if *name == prefix {
+            Some(Some(replacement))
+        } else if name.starts_with(prefix) {
+            Some(None)
+        } else {
+            None
+        }
+    }
+
+    fn add_to_character_reference_code(&mut self, digit: char, radix: u32) {
+        let digit = digit.to_digit(radix).unwrap();
+
+        // Anything past the unicode range is an error either way, so stop growing to avoid overflow
+        if self.character_reference_code <= 0x10FFFF {
+            self.character_reference_code = self.character_reference_code * radix + digit;
+        }
+    }
+
+    /// Implements the checks of the numeric character reference end state, returning
+    /// the character the reference resolves to
+    fn check_character_reference_code(&mut self) -> char {
+        let code = self.character_reference_code;
+
+        match code {
+            0x00 => {
+                self.error(HtmlParseError::NullCharacterReference);
+                return char::REPLACEMENT_CHARACTER;
+            }
+            0x110000.. => {
+                self.error(HtmlParseError::CharacterReferenceOutsideUnicodeRange);
+                return char::REPLACEMENT_CHARACTER;
+            }
+            0xD800..=0xDFFF => {
+                self.error(HtmlParseError::SurrogateCharacterReference);
+                return char::REPLACEMENT_CHARACTER;
+            }
+            _ => {}
+        }
+
+        if (0xFDD0..=0xFDEF).contains(&code) || code & 0xFFFE == 0xFFFE {
+            self.error(HtmlParseError::NoncharacterCharacterReference);
+        }
+
+        if code == 0x0D
+            || ((0x00..=0x1F).contains(&code) || (0x7F..=0x9F).contains(&code))
+                && !matches!(code, 0x09 | 0x0A | 0x0C | 0x20)
+        {
+            self.error(HtmlParseError::ControlCharacterReference);
+        }
+
+        let code = match code {
+            0x80 => 0x20AC,
+            0x82 => 0x201A,
+            0x83 => 0x0192,
+            0x84 => 0x201E,
+            0x85 => 0x2026,
+            0x86 => 0x2020,
+            0x87 => 0x2021,
+            0x88 => 0x02C6,
+            0x89 => 0x2030,
+            0x8A => 0x0160,
+            0x8B => 0x2039,
+            0x8C => 0x0152,
+            0x8E => 0x017D,
+            0x91 => 0x2018,
+            0x92 => 0x2019,
+            0x93 => 0x201C,
+            0x94 => 0x201D,
+            0x95 => 0x2022,
+            0x96 => 0x2013,
+            0x97 => 0x2014,
+            0x98 => 0x02DC,
+            0x99 => 0x2122,
+            0x9A => 0x0161,
+            0x9B => 0x203A,
+            0x9C => 0x0153,
+            0x9E => 0x017E,
+            0x9F => 0x0178,
+            code => code,
+        };
+
+        // Surrogates and out of range values were replaced above
+        char::from_u32(code).unwrap()
+    }
+
+    /// Handles the DOCTYPE public and system identifier (quoted) states
+    fn doctype_identifier(&mut self, c: Option<char>, position: usize, quote: char, public: bool) {
+        match c {
+            Some(c) if c == quote => {
+                self.state = if public {
+                    TokenizerState::AfterDoctypePublicIdentifier
+                } else {
+                    TokenizerState::AfterDoctypeSystemIdentifier
+                };
+            }
+            Some('>') => {
+                self.error(if public {
+                    HtmlParseError::AbruptDoctypePublicIdentifier
+                } else {
+                    HtmlParseError::AbruptDoctypeSystemIdentifier
+                });
+                self.current_doctype.force_quirks = true;
+                self.state = TokenizerState::Data;
+                self.emit_doctype(position + 1);
+            }
+            Some(c) => {
+                let c = if c == '\0' {
+                    self.error(HtmlParseError::UnexpectedNullCharacter);
+                    char::REPLACEMENT_CHARACTER
+                } else {
+                    c
+                };
+
+                let identifier = if public {
+                    &mut self.current_doctype.public_identifier
+                } else {
+                    &mut self.current_doctype.system_identifier
+                };
+
+                identifier.get_or_insert_with(String::new).push(c);
+            }
+            None => {
+                self.error(HtmlParseError::EofInDoctype);
+                self.current_doctype.force_quirks = true;
+                self.emit_doctype(position);
+                self.emit_eof(position);
+            }
+        }
+    }
+
+    /// Handles the shared `>`, EOF, and "anything else" branches of the states
+    /// that expect a DOCTYPE public or system identifier
+    fn missing_doctype_identifier(
+        &mut self,
+        c: Option<char>,
+        position: usize,
+        missing: HtmlParseError,
+        missing_quote: HtmlParseError,
+    ) {
+        self.current_doctype.force_quirks = true;
+
+        match c {
+            Some('>') => {
+                self.error(missing);
+                self.state = TokenizerState::Data;
+                self.emit_doctype(position + 1);
+            }
+            Some(_) => {
+                self.error(missing_quote);
+                self.reconsume(TokenizerState::BogusDoctype, c, position);
+            }
+            None => {
+                self.error(HtmlParseError::EofInDoctype);
+                self.emit_doctype(position);
+                self.emit_eof(position);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Lookahead {
+    Match,
+    Partial,
+    Mismatch,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed(tokenizer: &mut Tokenizer, input: &str) -> Vec<HtmlParseResult<Token>> {
+        input.chars().for_each(|c| tokenizer.step(Some(c)));
+
+        std::iter::from_fn(|| tokenizer.pop()).collect()
+    }
+
+    fn start_tag(result: &HtmlParseResult<Token>) -> &TagData {
+        match result {
+            Ok(Token {
+                kind: TokenKind::StartTag(tag),
+                ..
+            }) => tag,
+            other => panic!("expected a start tag, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn tokens_compare_equal() {
+        let tag = |name: &str, attributes: &[(&str, &str)]| TagData {
+            name: name.to_owned(),
+            self_closing: false,
+            attributes: attributes
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        };
+        let token = |kind, start, end| {
+            Ok(Token {
+                kind,
+                span: Span::new(start, end),
+            })
+        };
+
+        let mut tokenizer = Tokenizer::new();
+        let tokens = feed(&mut tokenizer, "<p ID=a class='b c'>Hi<!--x--></P>");
+
+        assert_eq!(
+            tokens,
+            vec![
+                token(
+                    TokenKind::StartTag(tag("p", &[("id", "a"), ("class", "b c")])),
+                    0,
+                    20
+                ),
+                token(TokenKind::Text("Hi".to_owned()), 20, 22),
+                token(TokenKind::Comment("x".to_owned()), 22, 30),
+                token(TokenKind::EndTag(tag("p", &[])), 30, 34),
+            ]
+        );
+
+        // Attributes are compared in order
+        assert_ne!(
+            tag("p", &[("id", "a"), ("class", "b")]),
+            tag("p", &[("class", "b"), ("id", "a")])
+        );
+    }
+
+    #[test]
+    fn processing_instruction_in_xml_mode() {
+        let mut tokenizer = Tokenizer::new();
+        tokenizer.set_xml_mode(true);
+
+        let tokens = feed(&mut tokenizer, "<?xml version=\"1.0\"?><a>");
+
+        assert!(matches!(
+            &tokens[0],
+            Ok(Token {
+                kind: TokenKind::ProcessingInstruction { target, data },
+                span: Span { start: 0, end: 21 },
+            }) if target == "xml" && data == "version=\"1.0\""
+        ));
+        assert_eq!(start_tag(&tokens[1]).name, "a");
+    }
+
+    #[test]
+    fn processing_instruction_in_html_mode() {
+        let mut tokenizer = Tokenizer::new();
+        let tokens = feed(&mut tokenizer, "<?xml version=\"1.0\"?><a>");
+
+        assert!(matches!(
+            tokens[0],
+            Err(HtmlParseError::UnexpectedQuestionMarkInsteadOfTagName)
+        ));
+        assert!(matches!(
+            &tokens[1],
+            Ok(Token {
+                kind: TokenKind::Comment(comment),
+                ..
+            }) if comment == "?xml version=\"1.0\"?"
+        ));
+        assert_eq!(start_tag(&tokens[2]).name, "a");
+    }
+
+    #[test]
+    fn span_contains() {
+        let span = Span::new(3, 6);
+
+        assert!(!span.contains(2));
+        assert!(span.contains(3));
+        assert!(span.contains(5));
+        assert!(!span.contains(6));
+        assert_eq!(span.len(), 3);
+
+        let empty = Span::new(4, 4);
+        assert!(empty.is_empty());
+        assert!(!empty.contains(4));
+
+        let inverted = Span::new(5, 2);
+        assert!(inverted.is_empty());
+        assert_eq!(inverted.len(), 0);
+        assert!(!inverted.contains(3));
+    }
+
+    #[test]
+    fn span_ordering() {
+        let mut spans = vec![
+            Span::new(5, 9),
+            Span::new(0, 5),
+            Span::new(5, 6),
+            Span::new(0, 2),
+        ];
+        spans.sort();
+
+        assert_eq!(
+            spans,
+            [
+                Span::new(0, 2),
+                Span::new(0, 5),
+                Span::new(5, 6),
+                Span::new(5, 9)
+            ]
+        );
+    }
+
+    #[test]
+    fn tag_name_state() {
+        let mut tokenizer = Tokenizer::new();
+        tokenizer.set_state(TokenizerState::TagName);
+
+        let output = feed(&mut tokenizer, "DiV\0>");
+
+        assert_eq!(
+            output[0].as_ref().err(),
+            Some(&HtmlParseError::UnexpectedNullCharacter)
+        );
+        assert_eq!(start_tag(&output[1]).name, "div\u{FFFD}");
+        assert_eq!(tokenizer.state(), TokenizerState::Data);
+    }
+
+    #[test]
+    fn tag_name_state_whitespace() {
+        let mut tokenizer = Tokenizer::new();
+        tokenizer.set_state(TokenizerState::TagName);
+
+        assert!(feed(&mut tokenizer, "p\n").is_empty());
+        assert_eq!(tokenizer.state(), TokenizerState::BeforeAttributeName);
+    }
+
+    #[test]
+    fn before_attribute_name_state() {
+        let mut tokenizer = Tokenizer::new();
+        tokenizer.set_state(TokenizerState::BeforeAttributeName);
+
+        let output = feed(&mut tokenizer, " \t=foo ID='bar' />");
+
+        assert_eq!(
+            output[0].as_ref().err(),
+            Some(&HtmlParseError::UnexpectedEqualsSignBeforeAttributeName)
+        );
+
+        let tag = start_tag(&output[1]);
+        assert_eq!(
+            tag.attributes,
+            vec![
+                ("=foo".to_owned(), String::new()),
+                ("id".to_owned(), "bar".to_owned())
+            ]
+        );
+        assert!(tag.self_closing);
+    }
+
+    #[test]
+    fn class_list_and_id() {
+        let mut tokenizer = Tokenizer::new();
+        let output = feed(
+            &mut tokenizer,
+            "<p class=\"  big\ttitle   big\n\" id=main><br>",
+        );
+
+        let tag = start_tag(&output[0]);
+        assert_eq!(tag.class_list(), ["big", "title"]);
+        assert_eq!(tag.id(), Some("main"));
+
+        let tag = start_tag(&output[1]);
+        assert!(tag.class_list().is_empty());
+        assert_eq!(tag.id(), None);
+    }
+
+    #[test]
+    fn attribute_edge_cases() {
+        let attributes = |input: &str| {
+            let mut tokenizer = Tokenizer::new();
+            let mut output = feed(&mut tokenizer, input);
+            let tag = output.pop().unwrap();
+            let attributes = start_tag(&tag).attributes.clone();
+            let errors: Vec<_> = output.into_iter().filter_map(Result::err).collect();
+
+            (attributes, errors)
+        };
+        let owned = |attributes: &[(&str, &str)]| -> Vec<(String, String)> {
+            attributes
+                .iter()
+                .map(|&(name, value)| (name.to_owned(), value.to_owned()))
+                .collect()
+        };
+
+        // A leading `=` is part of the name
+        assert_eq!(
+            attributes("<a =x>"),
+            (
+                owned(&[("=x", "")]),
+                vec![HtmlParseError::UnexpectedEqualsSignBeforeAttributeName]
+            )
+        );
+
+        // Attributes without a value, before another attribute and before the end of the tag
+        assert_eq!(
+            attributes("<a b c=1 d>"),
+            (owned(&[("b", ""), ("c", "1"), ("d", "")]), vec![])
+        );
+
+        // Only the first of duplicate attributes is kept, even without a value
+        assert_eq!(
+            attributes("<a x=1 X=2 x>"),
+            (
+                owned(&[("x", "1")]),
+                vec![
+                    HtmlParseError::DuplicateAttribute,
+                    HtmlParseError::DuplicateAttribute
+                ]
+            )
+        );
+    }
+
+    #[test]
+    fn attribute_value_unquoted_state() {
+        let mut tokenizer = Tokenizer::new();
+        tokenizer.set_state(TokenizerState::AttributeValueUnquoted);
+
+        let output = feed(&mut tokenizer, "a\"b c=d>");
+
+        assert_eq!(
+            output[0].as_ref().err(),
+            Some(&HtmlParseError::UnexpectedCharacterInUnquotedAttributeValue)
+        );
+
+        let tag = start_tag(&output[1]);
+        assert_eq!(
+            tag.attributes,
+            vec![
+                (String::new(), "a\"b".to_owned()),
+                ("c".to_owned(), "d".to_owned())
+            ]
+        );
+    }
+
+    #[test]
+    fn attribute_value_unquoted_abutting_tag_end() {
+        for input in ["<meta charset=utf-8>", "<meta charset=utf-8 >"] {
+            let mut tokenizer = Tokenizer::new();
+            let output = feed(&mut tokenizer, input);
+
+            assert_eq!(output.len(), 1);
+
+            let tag = start_tag(&output[0]);
+            assert_eq!(tag.name, "meta");
+            assert_eq!(
+                tag.attributes,
+                vec![("charset".to_owned(), "utf-8".to_owned())]
+            );
+            assert_eq!(tokenizer.state(), TokenizerState::Data);
+        }
+    }
+
+    #[test]
+    fn attribute_value_unquoted_state_eof() {
+        let mut tokenizer = Tokenizer::new();
+        tokenizer.set_state(TokenizerState::AttributeValueUnquoted);
+
+        let mut output = feed(&mut tokenizer, "abc");
+        tokenizer.step(None);
+        output.extend(std::iter::from_fn(|| tokenizer.pop()));
+
+        assert_eq!(output[0].as_ref().err(), Some(&HtmlParseError::EofInTag));
+        assert!(matches!(
+            output[1],
+            Ok(Token {
+                kind: TokenKind::Eof,
+                ..
+            })
+        ));
+    }
+
+    /// Tokenizes `input` to the end, returning the text content and the errors encountered
+    fn text_and_errors(input: &str) -> (String, Vec<HtmlParseError>) {
+        let mut tokenizer = Tokenizer::new();
+        let mut output = feed(&mut tokenizer, input);
+        tokenizer.step(None);
+        output.extend(std::iter::from_fn(|| tokenizer.pop()));
+
+        let mut text = String::new();
+        let mut errors = Vec::new();
+
+        for result in output {
+            match result {
+                Ok(Token {
+                    kind: TokenKind::Text(t),
+                    ..
+                }) => text.push_str(&t),
+                Ok(_) => {}
+                Err(error) => errors.push(error),
+            }
+        }
+
+        (text, errors)
+    }
+
+    #[test]
+    fn null_character_reference() {
+        assert_eq!(
+            text_and_errors("a&#0;b"),
+            (
+                "a\u{FFFD}b".to_owned(),
+                vec![HtmlParseError::NullCharacterReference]
+            )
+        );
+    }
+
+    #[test]
+    fn windows_1252_character_reference() {
+        assert_eq!(
+            text_and_errors("&#128;"),
+            (
+                "€".to_owned(),
+                vec![HtmlParseError::ControlCharacterReference]
+            )
+        );
+    }
+
+    #[test]
+    fn surrogate_character_reference() {
+        assert_eq!(
+            text_and_errors("&#xD800;"),
+            (
+                "\u{FFFD}".to_owned(),
+                vec![HtmlParseError::SurrogateCharacterReference]
+            )
+        );
+    }
+
+    #[test]
+    fn out_of_range_character_reference() {
+        assert_eq!(
+            text_and_errors("&#x110000;&#99999999999999;"),
+            (
+                "\u{FFFD}\u{FFFD}".to_owned(),
+                vec![
+                    HtmlParseError::CharacterReferenceOutsideUnicodeRange,
+                    HtmlParseError::CharacterReferenceOutsideUnicodeRange
+                ]
+            )
+        );
+    }
+
+    #[test]
+    fn astral_character_reference() {
+        assert_eq!(text_and_errors("&#x1F4A9;"), ("💩".to_owned(), vec![]));
+        assert_eq!(
+            text_and_errors("&#128169 "),
+            (
+                "💩 ".to_owned(),
+                vec![HtmlParseError::MissingSemicolonAfterCharacterReference]
+            )
+        );
+    }
+
+    #[test]
+    fn numeric_character_reference_without_digits() {
+        assert_eq!(
+            text_and_errors("&#x;"),
+            (
+                "&#x;".to_owned(),
+                vec![HtmlParseError::AbsenceOfDigitsInNumericCharacterReference]
+            )
+        );
+    }
+
+    #[test]
+    fn named_character_reference() {
+        assert_eq!(text_and_errors("&amp;&lt;"), ("&<".to_owned(), vec![]));
+        assert_eq!(
+            text_and_errors("&notit;"),
+            (
+                "¬it;".to_owned(),
+                vec![HtmlParseError::MissingSemicolonAfterCharacterReference]
+            )
+        );
+        assert_eq!(
+            text_and_errors("&foo;"),
+            (
+                "&foo;".to_owned(),
+                vec![HtmlParseError::UnknownNamedCharacterReference]
+            )
+        );
+    }
+
+    #[test]
+    fn character_reference_in_attribute_value() {
+        let mut tokenizer = Tokenizer::new();
+        let output = feed(&mut tokenizer, "<a title=\"&#x3C;&amp;\">");
+
+        assert_eq!(start_tag(&output[0]).get("title"), Some("<&"));
+    }
+
+    #[test]
+    fn legacy_character_reference_in_attribute_value() {
+        let mut tokenizer = Tokenizer::new();
+        let output = feed(
+            &mut tokenizer,
+            "<a href=\"?x=1&amp=2&copy2\" title=\"&amp;=&amp &copy\">",
+        );
+
+        // Followed by `=` or an alphanumeric, a reference without a semicolon is kept as is.
+        // The others in `title` are decoded, after reporting their missing semicolons.
+        let tag = start_tag(output.last().unwrap());
+        assert_eq!(tag.get("href"), Some("?x=1&amp=2&copy2"));
+        assert_eq!(tag.get("title"), Some("&=& ©"));
+        assert_eq!(output.len(), 3);
+
+        // Text has no such exception
+        assert_eq!(
+            text_and_errors("&amp=2&copy2"),
+            (
+                "&=2©2".to_owned(),
+                vec![
+                    HtmlParseError::MissingSemicolonAfterCharacterReference,
+                    HtmlParseError::MissingSemicolonAfterCharacterReference
+                ]
+            )
+        );
+    }
+}
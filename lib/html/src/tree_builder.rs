@@ -0,0 +1,1566 @@
+use crate::{
+    DoctypeData, HtmlParseError, ParseOptions, ParserLimits, QuirksMode, TagData, Token, TokenKind,
+    Tokenizer, TokenizerState, TreeSink,
+};
+
+/// https://html.spec.whatwg.org/#the-insertion-mode
+#[allow(unused)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum InsertionMode {
+    Initial,
+    BeforeHtml,
+    BeforeHead,
+    InHead,
+    InHeadNoscript,
+    AfterHead,
+    InBody,
+    Text,
+    InTable,
+    InTableText,
+    InCaption,
+    InColumnGroup,
+    InTableBody,
+    InRow,
+    InCell,
+    InSelect,
+    InSelectInTable,
+    InTemplate,
+    AfterBody,
+    InFrameset,
+    AfterFrameset,
+    AfterAfterBody,
+    AfterAfterFrameset,
+}
+
+/// https://html.spec.whatwg.org/#void-elements
+pub(crate) const VOID_ELEMENTS: [&str; 13] = [
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "source", "track",
+    "wbr",
+];
+
+/// The tokenizer state to switch to after the start tag of an element whose contents are
+/// parsed as text (https://html.spec.whatwg.org/#parsing-html-fragments, step 4)
+///
+/// The contents of `noscript` are only text when scripting is enabled, otherwise they are
+/// parsed as regular markup.
+pub(crate) fn text_state_for(tag_name: &str, scripting_enabled: bool) -> Option<TokenizerState> {
+    match tag_name {
+        "title" | "textarea" => Some(TokenizerState::Rcdata),
+        "style" | "xmp" | "iframe" | "noembed" | "noframes" => Some(TokenizerState::Rawtext),
+        "noscript" if scripting_enabled => Some(TokenizerState::Rawtext),
+        "script" => Some(TokenizerState::ScriptData),
+        "plaintext" => Some(TokenizerState::Plaintext),
+        _ => None,
+    }
+}
+
+/// The elements that content misplaced inside of gets moved out of, when they are the current
+/// node (https://html.spec.whatwg.org/#foster-parent)
+const TABLE_CONTEXT_ELEMENTS: [&str; 5] = ["table", "tbody", "tfoot", "thead", "tr"];
+
+/// The start tags that close an open `p` element (when it is in button scope)
+/// (https://html.spec.whatwg.org/#parsing-main-inbody)
+const CLOSES_P_ELEMENTS: [&str; 38] = [
+    "address",
+    "article",
+    "aside",
+    "blockquote",
+    "center",
+    "details",
+    "dialog",
+    "dir",
+    "div",
+    "dl",
+    "fieldset",
+    "figcaption",
+    "figure",
+    "footer",
+    "header",
+    "hgroup",
+    "main",
+    "menu",
+    "nav",
+    "ol",
+    "p",
+    "search",
+    "section",
+    "summary",
+    "ul",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "pre",
+    "listing",
+    "form",
+    "plaintext",
+    "table",
+    "hr",
+    "xmp",
+];
+
+/// The start tags that stay in the `head` element, rather than ending it
+/// (https://html.spec.whatwg.org/#parsing-main-inhead)
+const HEAD_CONTENT_ELEMENTS: [&str; 11] = [
+    "base", "basefont", "bgsound", "link", "meta", "noframes", "noscript", "script", "style",
+    "template", "title",
+];
+
+/// https://html.spec.whatwg.org/#generate-implied-end-tags
+const IMPLIED_END_TAG_ELEMENTS: [&str; 10] = [
+    "dd", "dt", "li", "optgroup", "option", "p", "rb", "rp", "rt", "rtc",
+];
+
+/// The elements that delimit a scope (https://html.spec.whatwg.org/#has-an-element-in-scope)
+const SCOPE_BOUNDARY_ELEMENTS: [&str; 9] = [
+    "applet", "caption", "html", "table", "td", "th", "marquee", "object", "template",
+];
+
+/// https://html.spec.whatwg.org/#formatting
+const FORMATTING_ELEMENTS: [&str; 14] = [
+    "a", "b", "big", "code", "em", "font", "i", "nobr", "s", "small", "strike", "strong", "tt", "u",
+];
+
+/// The elements that put a marker on the list of active formatting elements, so that formatting
+/// elements from outside of them aren't reopened inside of them
+const FORMATTING_MARKER_ELEMENTS: [&str; 7] = [
+    "applet", "caption", "marquee", "object", "td", "th", "template",
+];
+
+/// The start tags that don't reopen formatting elements in the in body insertion mode (along with
+/// [`CLOSES_P_ELEMENTS`] and [`HEAD_CONTENT_ELEMENTS`])
+const NO_RECONSTRUCT_ELEMENTS: [&str; 23] = [
+    "body", "caption", "col", "colgroup", "dd", "dt", "frame", "frameset", "head", "html",
+    "iframe", "li", "noembed", "rb", "rp", "rt", "rtc", "tbody", "td", "textarea", "tfoot", "th",
+    "thead",
+];
+
+/// https://html.spec.whatwg.org/#special
+const SPECIAL_ELEMENTS: [&str; 83] = [
+    "address",
+    "applet",
+    "area",
+    "article",
+    "aside",
+    "base",
+    "basefont",
+    "bgsound",
+    "blockquote",
+    "body",
+    "br",
+    "button",
+    "caption",
+    "center",
+    "col",
+    "colgroup",
+    "dd",
+    "details",
+    "dir",
+    "div",
+    "dl",
+    "dt",
+    "embed",
+    "fieldset",
+    "figcaption",
+    "figure",
+    "footer",
+    "form",
+    "frame",
+    "frameset",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "head",
+    "header",
+    "hgroup",
+    "hr",
+    "html",
+    "iframe",
+    "img",
+    "input",
+    "keygen",
+    "li",
+    "link",
+    "listing",
+    "main",
+    "marquee",
+    "menu",
+    "meta",
+    "nav",
+    "noembed",
+    "noframes",
+    "noscript",
+    "object",
+    "ol",
+    "p",
+    "param",
+    "plaintext",
+    "pre",
+    "script",
+    "search",
+    "section",
+    "select",
+    "source",
+    "style",
+    "summary",
+    "table",
+    "tbody",
+    "td",
+    "template",
+    "textarea",
+    "tfoot",
+    "th",
+    "thead",
+    "title",
+    "tr",
+    "track",
+    "ul",
+    "wbr",
+    "xmp",
+];
+
+/// The public identifiers that put a document in quirks mode when they start its doctype
+/// (https://html.spec.whatwg.org/#the-initial-insertion-mode)
+const QUIRKY_PUBLIC_ID_PREFIXES: [&str; 55] = [
+    "+//Silmaril//dtd html Pro v0r11 19970101//",
+    "-//AS//DTD HTML 3.0 asWedit + extensions//",
+    "-//AdvaSoft Ltd//DTD HTML 3.0 asWedit + extensions//",
+    "-//IETF//DTD HTML 2.0 Level 1//",
+    "-//IETF//DTD HTML 2.0 Level 2//",
+    "-//IETF//DTD HTML 2.0 Strict Level 1//",
+    "-//IETF//DTD HTML 2.0 Strict Level 2//",
+    "-//IETF//DTD HTML 2.0 Strict//",
+    "-//IETF//DTD HTML 2.0//",
+    "-//IETF//DTD HTML 2.1E//",
+    "-//IETF//DTD HTML 3.0//",
+    "-//IETF//DTD HTML 3.2 Final//",
+    "-//IETF//DTD HTML 3.2//",
+    "-//IETF//DTD HTML 3//",
+    "-//IETF//DTD HTML Level 0//",
+    "-//IETF//DTD HTML Level 1//",
+    "-//IETF//DTD HTML Level 2//",
+    "-//IETF//DTD HTML Level 3//",
+    "-//IETF//DTD HTML Strict Level 0//",
+    "-//IETF//DTD HTML Strict Level 1//",
+    "-//IETF//DTD HTML Strict Level 2//",
+    "-//IETF//DTD HTML Strict Level 3//",
+    "-//IETF//DTD HTML Strict//",
+    "-//IETF//DTD HTML//",
+    "-//Metrius//DTD Metrius Presentational//",
+    "-//Microsoft//DTD Internet Explorer 2.0 HTML Strict//",
+    "-//Microsoft//DTD Internet Explorer 2.0 HTML//",
+    "-//Microsoft//DTD Internet Explorer 2.0 Tables//",
+    "-//Microsoft//DTD Internet Explorer 3.0 HTML Strict//",
+    "-//Microsoft//DTD Internet Explorer 3.0 HTML//",
+    "-//Microsoft//DTD Internet Explorer 3.0 Tables//",
+    "-//Netscape Comm. Corp.//DTD HTML//",
+    "-//Netscape Comm. Corp.//DTD Strict HTML//",
+    "-//O'Reilly and Associates//DTD HTML 2.0//",
+    "-//O'Reilly and Associates//DTD HTML Extended 1.0//",
+    "-//O'Reilly and Associates//DTD HTML Extended Relaxed 1.0//",
+    "-//SQ//DTD HTML 2.0 HoTMetaL + extensions//",
+    "-//SoftQuad Software//DTD HoTMetaL PRO 6.0::19990601::extensions to HTML 4.0//",
+    "-//SoftQuad//DTD HoTMetaL PRO 4.0::19970916::extensions to HTML 4.0//",
+    "-//Spyglass//DTD HTML 2.0 Extended//",
+    "-//Sun Microsystems Corp.//DTD HotJava HTML//",
+    "-//Sun Microsystems Corp.//DTD HotJava Strict HTML//",
+    "-//W3C//DTD HTML 3 1995-03-24//",
+    "-//W3C//DTD HTML 3.2 Draft//",
+    "-//W3C//DTD HTML 3.2 Final//",
+    "-//W3C//DTD HTML 3.2//",
+    "-//W3C//DTD HTML 3.2S Draft//",
+    "-//W3C//DTD HTML 4.0 Frameset//",
+    "-//W3C//DTD HTML 4.0 Transitional//",
+    "-//W3C//DTD HTML Experimental 19960712//",
+    "-//W3C//DTD HTML Experimental 970421//",
+    "-//W3C//DTD W3 HTML//",
+    "-//W3O//DTD W3 HTML 3.0//",
+    "-//WebTechs//DTD Mozilla HTML 2.0//",
+    "-//WebTechs//DTD Mozilla HTML//",
+];
+
+/// The quirks mode that a doctype puts its document in
+/// (https://html.spec.whatwg.org/#the-initial-insertion-mode)
+fn quirks_mode_for(doctype: &DoctypeData) -> QuirksMode {
+    let public_id = doctype.public_identifier.as_deref();
+    let system_id = doctype.system_identifier.as_deref();
+
+    let public_id_is =
+        |id: &str| public_id.is_some_and(|public_id| public_id.eq_ignore_ascii_case(id));
+    let public_id_starts_with = |prefix: &str| {
+        public_id.is_some_and(|public_id| {
+            public_id
+                .get(..prefix.len())
+                .is_some_and(|start| start.eq_ignore_ascii_case(prefix))
+        })
+    };
+    let html_4_01 = public_id_starts_with("-//W3C//DTD HTML 4.01 Frameset//")
+        || public_id_starts_with("-//W3C//DTD HTML 4.01 Transitional//");
+
+    let quirks = doctype.force_quirks
+        || doctype.name.as_deref() != Some("html")
+        || public_id_is("-//W3O//DTD W3 HTML Strict 3.0//EN//")
+        || public_id_is("-/W3C/DTD HTML 4.0 Transitional/EN")
+        || public_id_is("HTML")
+        || system_id.is_some_and(|system_id| {
+            system_id
+                .eq_ignore_ascii_case("http://www.ibm.com/data/dtd/v11/ibmxhtml1-transitional.dtd")
+        })
+        || QUIRKY_PUBLIC_ID_PREFIXES
+            .iter()
+            .any(|prefix| public_id_starts_with(prefix))
+        || (system_id.is_none() && html_4_01);
+
+    let limited_quirks = public_id_starts_with("-//W3C//DTD XHTML 1.0 Frameset//")
+        || public_id_starts_with("-//W3C//DTD XHTML 1.0 Transitional//")
+        || (system_id.is_some() && html_4_01);
+
+    if quirks {
+        QuirksMode::Quirks
+    } else if limited_quirks {
+        QuirksMode::LimitedQuirks
+    } else {
+        QuirksMode::NoQuirks
+    }
+}
+
+/// Where an element that is about to be inserted goes
+enum Placement<H> {
+    /// In the appropriate place (the current node, or the contents of a `template`)
+    Append,
+    /// Right before the given `table`, since it is misplaced inside of it
+    Foster(H),
+}
+
+/// https://html.spec.whatwg.org/#list-of-active-formatting-elements
+enum FormattingEntry<H> {
+    Marker,
+    /// A formatting element, along with its tag so that it can be reopened
+    Element {
+        handle: H,
+        name: String,
+        attributes: Vec<(String, String)>,
+    },
+}
+
+/// An element on the stack of open elements
+struct OpenElement<H> {
+    handle: H,
+    name: String,
+    /// The content fragment, if this is a `template` element
+    template_contents: Option<H>,
+}
+
+/// Builds a tree out of the tokens produced by the tokenizer, through a [`TreeSink`]
+/// (https://html.spec.whatwg.org/#tree-construction)
+///
+/// TODO: this only implements a small part of the tree construction stage. Apart from the rules
+///       of the table and select insertion modes, the implied ends of `p` and `a` elements, the
+///       reopening of formatting elements, and the implied end of the `head` (along with the
+///       start of the `body`), elements are nested as they appear in the source, and end tags
+///       close the nearest open element with the same name.
+pub(crate) struct TreeBuilder<S: TreeSink> {
+    sink: S,
+    document: S::Handle,
+    limits: ParserLimits,
+    /// https://html.spec.whatwg.org/#scripting-flag
+    scripting_enabled: bool,
+    /// Whether the document is the `srcdoc` of an `iframe`, which is never in quirks mode
+    /// (https://html.spec.whatwg.org/#an-iframe-srcdoc-document)
+    iframe_srcdoc: bool,
+    /// Whether the document is XML (see [`crate::HtmlParser::xml_mode`]), in which case no
+    /// element is void or has text contents, and every unclosed element is a parse error
+    xml_mode: bool,
+    insertion_mode: InsertionMode,
+    open_elements: Vec<OpenElement<S::Handle>>,
+    active_formatting_elements: Vec<FormattingEntry<S::Handle>>,
+    /// https://html.spec.whatwg.org/#stack-of-template-insertion-modes
+    template_insertion_modes: Vec<InsertionMode>,
+    /// Set after a `pre`, `listing` or `textarea` start tag, whose first newline is dropped
+    ignore_next_newline: bool,
+    /// Whether a `head` element was inserted at the top of the document, after which body
+    /// content implies a `body` (https://html.spec.whatwg.org/#head-element-pointer)
+    head_inserted: bool,
+    body_inserted: bool,
+}
+
+impl<S: TreeSink> TreeBuilder<S> {
+    pub fn new(mut sink: S, options: &ParseOptions) -> Self {
+        Self {
+            document: sink.document(),
+            sink,
+            limits: options.limits,
+            scripting_enabled: options.scripting_enabled,
+            iframe_srcdoc: options.iframe_srcdoc,
+            xml_mode: options.xml_mode,
+            insertion_mode: InsertionMode::Initial,
+            open_elements: Vec::new(),
+            active_formatting_elements: Vec::new(),
+            template_insertion_modes: Vec::new(),
+            ignore_next_newline: false,
+            head_inserted: false,
+            body_inserted: false,
+        }
+    }
+
+    pub fn parse_error(&mut self, error: HtmlParseError) {
+        self.sink.parse_error(error);
+    }
+
+    pub fn finish(self) -> S::Output {
+        self.sink.finish()
+    }
+
+    /// https://html.spec.whatwg.org/#appropriate-place-for-inserting-a-node
+    ///
+    /// The contents of a `template` go into its content fragment instead of its children
+    fn insertion_location(&self) -> S::Handle {
+        match self.open_elements.last() {
+            Some(OpenElement {
+                template_contents: Some(contents),
+                ..
+            }) => contents.clone(),
+            Some(element) => element.handle.clone(),
+            None => self.document.clone(),
+        }
+    }
+
+    /// Leaves the initial insertion mode for anything other than a doctype, comment or
+    /// whitespace. Without a doctype, the document is in quirks mode (unless it is XML or an
+    /// `iframe` `srcdoc`).
+    fn leave_initial_mode(&mut self) {
+        if self.insertion_mode != InsertionMode::Initial {
+            return;
+        }
+
+        if !self.xml_mode && !self.iframe_srcdoc {
+            self.sink.set_quirks_mode(QuirksMode::Quirks);
+        }
+
+        self.insertion_mode = InsertionMode::BeforeHtml;
+    }
+
+    /// https://html.spec.whatwg.org/#reset-the-insertion-mode-appropriately
+    ///
+    /// TODO: only template contents, tables, selects and the `head` are distinguished for now
+    fn reset_insertion_mode(&mut self) {
+        for (position, element) in self.open_elements.iter().enumerate().rev() {
+            let is_current = position + 1 == self.open_elements.len();
+
+            let mode = match element.name.as_str() {
+                _ if element.template_contents.is_some() => {
+                    self.template_insertion_modes.last().copied()
+                }
+                // XML documents have none of the HTML elements below
+                _ if self.xml_mode => None,
+                "select" => Some(InsertionMode::InSelect),
+                "td" | "th" => Some(InsertionMode::InCell),
+                "tr" => Some(InsertionMode::InRow),
+                "tbody" | "thead" | "tfoot" => Some(InsertionMode::InTableBody),
+                "caption" => Some(InsertionMode::InCaption),
+                "colgroup" => Some(InsertionMode::InColumnGroup),
+                "table" => Some(InsertionMode::InTable),
+                // Elements inside the head (e.g. the text of a `title`) don't end it
+                "head" if is_current => Some(InsertionMode::InHead),
+                "head" | "body" => Some(InsertionMode::InBody),
+                _ => None,
+            };
+
+            if let Some(mode) = mode {
+                self.insertion_mode = mode;
+                return;
+            }
+        }
+
+        self.insertion_mode = if self.head_inserted && !self.body_inserted {
+            InsertionMode::AfterHead
+        } else {
+            InsertionMode::InBody
+        };
+    }
+
+    fn current_name(&self) -> Option<&str> {
+        self.open_elements
+            .last()
+            .map(|element| element.name.as_str())
+    }
+
+    /// Pops the open elements from `position` onwards
+    fn pop_from(&mut self, position: usize) {
+        let closed_templates = self.open_elements[position..]
+            .iter()
+            .filter(|element| element.template_contents.is_some())
+            .count();
+        let closed_markers = self.open_elements[position..]
+            .iter()
+            .filter(|element| FORMATTING_MARKER_ELEMENTS.contains(&element.name.as_str()))
+            .count();
+
+        for _ in 0..closed_markers {
+            self.clear_formatting_to_marker();
+        }
+
+        self.open_elements.truncate(position);
+        self.template_insertion_modes
+            .truncate(self.template_insertion_modes.len() - closed_templates);
+        self.reset_insertion_mode();
+    }
+
+    /// Pops the open elements up to and including the nearest one with one of the given names
+    fn close_element(&mut self, names: &[&str]) {
+        if let Some(position) = self
+            .open_elements
+            .iter()
+            .rposition(|element| names.contains(&element.name.as_str()))
+        {
+            self.pop_from(position);
+        }
+    }
+
+    /// Pops open elements until the current node has one of the given names (or is a
+    /// `template`) (https://html.spec.whatwg.org/#clear-the-stack-back-to-a-table-context)
+    fn clear_to_context(&mut self, names: &[&str]) {
+        let position = self.open_elements.iter().rposition(|element| {
+            names.contains(&element.name.as_str()) || element.template_contents.is_some()
+        });
+
+        self.pop_from(position.map_or(0, |position| position + 1));
+    }
+
+    fn is_open(&self, handle: &S::Handle) -> bool {
+        self.open_elements
+            .iter()
+            .any(|element| element.handle == *handle)
+    }
+
+    /// The position in the list of active formatting elements of the last element with the given
+    /// name, if there is one after the last marker
+    fn active_formatting_element(&self, name: &str) -> Option<usize> {
+        for (position, entry) in self.active_formatting_elements.iter().enumerate().rev() {
+            match entry {
+                FormattingEntry::Marker => return None,
+                FormattingEntry::Element { name: n, .. } if n == name => return Some(position),
+                FormattingEntry::Element { .. } => {}
+            }
+        }
+
+        None
+    }
+
+    /// https://html.spec.whatwg.org/#push-onto-the-list-of-active-formatting-elements
+    fn push_formatting_element(
+        &mut self,
+        handle: S::Handle,
+        name: String,
+        attributes: Vec<(String, String)>,
+    ) {
+        // Only the last 3 identical elements are kept (the "Noah's Ark" clause)
+        let identical: Vec<_> = self
+            .active_formatting_elements
+            .iter()
+            .enumerate()
+            .rev()
+            .take_while(|(_, entry)| !matches!(entry, FormattingEntry::Marker))
+            .filter(|(_, entry)| {
+                matches!(entry, FormattingEntry::Element { name: n, attributes: a, .. }
+                    if *n == name && *a == attributes)
+            })
+            .map(|(position, _)| position)
+            .collect();
+
+        if identical.len() >= 3 {
+            self.active_formatting_elements
+                .remove(identical[identical.len() - 1]);
+        }
+
+        self.active_formatting_elements
+            .push(FormattingEntry::Element {
+                handle,
+                name,
+                attributes,
+            });
+    }
+
+    /// https://html.spec.whatwg.org/#clear-the-list-of-active-formatting-elements-up-to-the-last-marker
+    fn clear_formatting_to_marker(&mut self) {
+        while let Some(entry) = self.active_formatting_elements.pop() {
+            if let FormattingEntry::Marker = entry {
+                return;
+            }
+        }
+    }
+
+    /// Reopens the formatting elements that were closed (e.g. by the end of a `p`) while they
+    /// were still active, so that the content after them keeps their formatting
+    /// (https://html.spec.whatwg.org/#reconstruct-the-active-formatting-elements)
+    fn reconstruct_formatting_elements(&mut self) {
+        let mut first = self.active_formatting_elements.len();
+
+        while first > 0 {
+            match &self.active_formatting_elements[first - 1] {
+                FormattingEntry::Marker => break,
+                FormattingEntry::Element { handle, .. } if self.is_open(handle) => break,
+                FormattingEntry::Element { .. } => first -= 1,
+            }
+        }
+
+        for position in first..self.active_formatting_elements.len() {
+            if self.open_elements.len() >= self.limits.max_depth {
+                return;
+            }
+
+            let FormattingEntry::Element {
+                name, attributes, ..
+            } = &self.active_formatting_elements[position]
+            else {
+                unreachable!("markers end the elements to reopen");
+            };
+
+            let element = self.sink.create_element(name.clone(), attributes.clone());
+            let name = name.clone();
+            self.sink
+                .append(&self.insertion_location(), element.clone());
+
+            if let FormattingEntry::Element { handle, .. } =
+                &mut self.active_formatting_elements[position]
+            {
+                *handle = element.clone();
+            }
+
+            self.open_elements.push(OpenElement {
+                handle: element,
+                name,
+                template_contents: None,
+            });
+        }
+    }
+
+    /// The position of the nearest open element with the given name, if it is in button scope
+    /// (https://html.spec.whatwg.org/#has-an-element-in-button-scope)
+    fn in_button_scope(&self, name: &str) -> Option<usize> {
+        for (position, element) in self.open_elements.iter().enumerate().rev() {
+            if element.name == name {
+                return Some(position);
+            }
+
+            if SCOPE_BOUNDARY_ELEMENTS.contains(&element.name.as_str()) || element.name == "button"
+            {
+                return None;
+            }
+        }
+
+        None
+    }
+
+    /// https://html.spec.whatwg.org/#close-a-p-element
+    fn close_p_element(&mut self, position: usize, errors: &mut Vec<HtmlParseError>) {
+        // Only elements with implied end tags may still be open inside of it
+        let implied = self.open_elements[position + 1..]
+            .iter()
+            .rev()
+            .take_while(|element| {
+                element.name != "p" && IMPLIED_END_TAG_ELEMENTS.contains(&element.name.as_str())
+            })
+            .count();
+
+        if position + 1 + implied != self.open_elements.len() {
+            errors.push(HtmlParseError::UnexpectedEndTag);
+        }
+
+        self.pop_from(position);
+    }
+
+    /// Ends the `head` and inserts an implied `body` for content that belongs in the body (which
+    /// is anything but a `body`, `frameset` or `html` start tag after the head)
+    /// (https://html.spec.whatwg.org/#parsing-main-inhead and
+    /// https://html.spec.whatwg.org/#the-after-head-insertion-mode, "Anything else")
+    ///
+    /// `tag_name` is `None` for text.
+    fn leave_head(&mut self, tag_name: Option<&str>) {
+        if tag_name.is_some_and(|name| HEAD_CONTENT_ELEMENTS.contains(&name)) {
+            return;
+        }
+
+        if self.insertion_mode == InsertionMode::InHead {
+            self.pop_from(self.open_elements.len() - 1);
+        }
+
+        if self.insertion_mode == InsertionMode::AfterHead
+            && !matches!(tag_name, Some("body" | "frameset" | "html"))
+        {
+            self.insert_implied("body");
+        }
+    }
+
+    /// Inserts an element that is implied by a start tag (e.g. the `tbody` of a `<tr>` that is
+    /// directly inside a `table`)
+    fn insert_implied(&mut self, name: &str) {
+        self.body_inserted |= name == "body";
+
+        let element = self.sink.create_element(name.to_owned(), Vec::new());
+        self.sink
+            .append(&self.insertion_location(), element.clone());
+
+        self.open_elements.push(OpenElement {
+            handle: element,
+            name: name.to_owned(),
+            template_contents: None,
+        });
+        self.reset_insertion_mode();
+    }
+
+    /// The `table` to insert content misplaced inside of it before, if the current node is one
+    /// of the elements that content can't go directly into
+    fn foster_parent_target(&self) -> Option<S::Handle> {
+        if self.xml_mode || !TABLE_CONTEXT_ELEMENTS.contains(&self.current_name()?) {
+            return None;
+        }
+
+        self.open_elements
+            .iter()
+            .rfind(|element| element.name == "table")
+            .map(|element| element.handle.clone())
+    }
+
+    /// Applies the rules of the select and table insertion modes for a start tag, before its
+    /// element is inserted: the elements that it ends are closed, and the ones that it implies
+    /// are inserted. Returns where the element goes, or `None` if the start tag is ignored.
+    ///
+    /// (https://html.spec.whatwg.org/#parsing-main-inselect to
+    /// https://html.spec.whatwg.org/#parsing-main-intd)
+    fn prepare_start_tag(
+        &mut self,
+        tag: &TagData,
+        errors: &mut Vec<HtmlParseError>,
+    ) -> Option<Placement<S::Handle>> {
+        use InsertionMode::*;
+
+        let name = tag.name.as_str();
+
+        if self.insertion_mode == InSelect {
+            match name {
+                "option" | "optgroup" | "hr" | "script" | "template" => {}
+                // A nested select ends the current one instead
+                "select" => {
+                    errors.push(HtmlParseError::UnexpectedStartTag);
+                    self.close_element(&["select"]);
+                    return None;
+                }
+                "input" | "keygen" | "textarea" => {
+                    errors.push(HtmlParseError::UnexpectedStartTag);
+                    self.close_element(&["select"]);
+                }
+                _ => {
+                    errors.push(HtmlParseError::UnexpectedStartTag);
+                    return None;
+                }
+            }
+        }
+
+        // An option ends the option before it, in or out of a select
+        if matches!(name, "option" | "optgroup") && self.current_name() == Some("option") {
+            self.pop_from(self.open_elements.len() - 1);
+        }
+
+        if self.insertion_mode == InSelect && matches!(name, "optgroup" | "hr") {
+            self.close_element(&["option"]);
+
+            if self.current_name() == Some("optgroup") {
+                self.pop_from(self.open_elements.len() - 1);
+            }
+        }
+
+        let table_structure = matches!(
+            name,
+            "caption" | "col" | "colgroup" | "tbody" | "tfoot" | "thead" | "tr" | "td" | "th"
+        );
+
+        // A new cell, row or table section ends the ones that are open
+        if self.insertion_mode == InCell && table_structure {
+            self.close_element(&["td", "th"]);
+        }
+
+        if self.insertion_mode == InRow && table_structure && !matches!(name, "td" | "th") {
+            self.close_element(&["tr"]);
+        }
+
+        if self.insertion_mode == InTableBody
+            && matches!(
+                name,
+                "caption" | "col" | "colgroup" | "tbody" | "tfoot" | "thead"
+            )
+        {
+            self.close_element(&["tbody", "tfoot", "thead"]);
+        }
+
+        if matches!(self.insertion_mode, InBody | InCaption | InCell) {
+            self.prepare_in_body_start_tag(name, errors);
+        }
+
+        match (self.insertion_mode, name) {
+            (InTable, "td" | "th") => {
+                self.clear_to_context(&["table"]);
+                self.insert_implied("tbody");
+                self.insert_implied("tr");
+            }
+            (InTable, "tr") => {
+                self.clear_to_context(&["table"]);
+                self.insert_implied("tbody");
+            }
+            (InTableBody, "td" | "th") => {
+                self.clear_to_context(&["tbody", "tfoot", "thead"]);
+                self.insert_implied("tr");
+            }
+            (InTableBody, "tr") => self.clear_to_context(&["tbody", "tfoot", "thead"]),
+            (InRow, "td" | "th") => self.clear_to_context(&["tr"]),
+            (InTable, "caption" | "colgroup" | "col" | "tbody" | "tfoot" | "thead") => {
+                self.clear_to_context(&["table"]);
+            }
+            // A table directly in a table ends the outer one
+            (InTable | InTableBody | InRow, "table") => {
+                errors.push(HtmlParseError::UnexpectedContentInTable);
+                self.close_element(&["table"]);
+
+                return self.prepare_start_tag(tag, errors);
+            }
+            (InTable | InTableBody | InRow, "style" | "script" | "template" | "form") => {}
+            (InTable | InTableBody | InRow, "input")
+                if tag
+                    .get("type")
+                    .is_some_and(|value| value.eq_ignore_ascii_case("hidden")) => {}
+            (InTable | InTableBody | InRow, _) => {
+                if let Some(table) = self.foster_parent_target() {
+                    errors.push(HtmlParseError::UnexpectedContentInTable);
+                    return Some(Placement::Foster(table));
+                }
+            }
+            _ => {}
+        }
+
+        Some(Placement::Append)
+    }
+
+    /// Closes the elements that a start tag ends by the rules of the in body insertion mode, and
+    /// reopens the formatting elements it goes into
+    /// (https://html.spec.whatwg.org/#parsing-main-inbody)
+    fn prepare_in_body_start_tag(&mut self, name: &str, errors: &mut Vec<HtmlParseError>) {
+        if CLOSES_P_ELEMENTS.contains(&name) {
+            if let Some(position) = self.in_button_scope("p") {
+                self.close_p_element(position, errors);
+            }
+
+            // Headings don't nest
+            if name.len() == 2
+                && name.starts_with('h')
+                && matches!(
+                    self.current_name(),
+                    Some("h1" | "h2" | "h3" | "h4" | "h5" | "h6")
+                )
+            {
+                errors.push(HtmlParseError::UnexpectedStartTag);
+                self.pop_from(self.open_elements.len() - 1);
+            }
+        }
+
+        // A link can't contain another one, so an active `a` is closed first. Cells, captions
+        // and templates act as markers, so an `a` outside of them stays open.
+        if name == "a" {
+            if let Some(active) = self.active_formatting_element("a") {
+                errors.push(HtmlParseError::UnexpectedStartTag);
+
+                let FormattingEntry::Element { handle, .. } =
+                    self.active_formatting_elements.remove(active)
+                else {
+                    unreachable!("the entry was found by name");
+                };
+
+                if let Some(position) = self
+                    .open_elements
+                    .iter()
+                    .position(|element| element.handle == handle)
+                {
+                    // Formatting elements inside of the `a` are closed along with it (and reopened
+                    // below), but blocks stay open, since the new link belongs in them.
+                    //
+                    // TODO: the adoption agency algorithm would also move the blocks out of the
+                    //       old `a` (https://html.spec.whatwg.org/#adoption-agency-algorithm)
+                    let inside_block = self.open_elements[position + 1..]
+                        .iter()
+                        .any(|element| SPECIAL_ELEMENTS.contains(&element.name.as_str()));
+
+                    if inside_block {
+                        self.open_elements.remove(position);
+                    } else {
+                        self.pop_from(position);
+                    }
+                }
+            }
+        }
+
+        if !CLOSES_P_ELEMENTS.contains(&name)
+            && !HEAD_CONTENT_ELEMENTS.contains(&name)
+            && !NO_RECONSTRUCT_ELEMENTS.contains(&name)
+        {
+            self.reconstruct_formatting_elements();
+        }
+    }
+
+    /// Processes a single token. Parse errors are reported through the returned vector
+    /// so the caller can decide whether to recover from them.
+    pub fn process_token(
+        &mut self,
+        token: Token,
+        tokenizer: &mut Tokenizer,
+    ) -> Vec<HtmlParseError> {
+        let mut errors = Vec::new();
+        let ignore_next_newline = std::mem::take(&mut self.ignore_next_newline);
+
+        match token.kind {
+            TokenKind::Doctype(doctype) => {
+                if self.insertion_mode != InsertionMode::Initial {
+                    errors.push(HtmlParseError::UnexpectedDoctype);
+                    return errors;
+                }
+
+                if !self.xml_mode && !self.iframe_srcdoc {
+                    let quirks_mode = quirks_mode_for(&doctype);
+                    self.sink.set_quirks_mode(quirks_mode);
+
+                    // Only a diagnostic, so it's reported straight to the sink rather than
+                    // aborting the parse. Malformed doctypes already have a parse error.
+                    if quirks_mode != QuirksMode::NoQuirks && !doctype.force_quirks {
+                        self.sink
+                            .parse_error(HtmlParseError::LegacyDoctype { quirks_mode });
+                    }
+                }
+
+                self.sink.append_doctype(
+                    doctype.name.unwrap_or_default(),
+                    doctype.public_identifier.unwrap_or_default(),
+                    doctype.system_identifier.unwrap_or_default(),
+                );
+                self.insertion_mode = InsertionMode::BeforeHtml;
+            }
+            TokenKind::StartTag(tag) => {
+                self.leave_initial_mode();
+                self.reset_insertion_mode();
+
+                let placement = if self.xml_mode {
+                    Placement::Append
+                } else {
+                    self.leave_head(Some(&tag.name));
+
+                    match self.prepare_start_tag(&tag, &mut errors) {
+                        Some(placement) => placement,
+                        None => return errors,
+                    }
+                };
+
+                // In XML, only empty-element tags (`<br/>`) have no end tag
+                let is_void = if self.xml_mode {
+                    tag.self_closing
+                } else {
+                    VOID_ELEMENTS.contains(&tag.name.as_str())
+                };
+
+                if !self.xml_mode {
+                    if let Some(state) = text_state_for(&tag.name, self.scripting_enabled) {
+                        tokenizer.set_state(state);
+                    }
+
+                    // A newline immediately after these start tags is ignored, as a convenience for authors
+                    if matches!(tag.name.as_str(), "pre" | "listing" | "textarea") {
+                        self.ignore_next_newline = true;
+                    }
+                }
+
+                if tag.self_closing && !is_void {
+                    errors.push(HtmlParseError::NonVoidHtmlElementStartTagWithTrailingSolidus);
+                }
+
+                if !self.xml_mode {
+                    match tag.name.as_str() {
+                        // Only a `head` at the top of the document is the head of the document
+                        "head" => {
+                            self.head_inserted |= self
+                                .open_elements
+                                .iter()
+                                .all(|element| element.name == "html")
+                        }
+                        "body" => self.body_inserted = true,
+                        _ => {}
+                    }
+                }
+
+                let element = self
+                    .sink
+                    .create_element(tag.name.clone(), tag.attributes.clone());
+
+                match placement {
+                    Placement::Append => {
+                        let parent = self.insertion_location();
+                        self.sink.append(&parent, element.clone());
+                    }
+                    Placement::Foster(table) => self.sink.insert_before(&table, element.clone()),
+                }
+
+                let template_contents = if tag.name == "template" {
+                    self.template_insertion_modes
+                        .push(InsertionMode::InTemplate);
+                    self.insertion_mode = InsertionMode::InTemplate;
+
+                    Some(self.sink.create_template_contents(&element))
+                } else {
+                    None
+                };
+
+                // Past the maximum depth, elements are still inserted but never opened, so their
+                // content ends up next to them instead of nesting any further
+                if !is_void && self.open_elements.len() < self.limits.max_depth {
+                    if !self.xml_mode {
+                        if FORMATTING_ELEMENTS.contains(&tag.name.as_str()) {
+                            self.push_formatting_element(
+                                element.clone(),
+                                tag.name.clone(),
+                                tag.attributes.clone(),
+                            );
+                        } else if FORMATTING_MARKER_ELEMENTS.contains(&tag.name.as_str()) {
+                            self.active_formatting_elements
+                                .push(FormattingEntry::Marker);
+                        }
+                    }
+
+                    self.open_elements.push(OpenElement {
+                        handle: element,
+                        name: tag.name,
+                        template_contents,
+                    });
+                    self.reset_insertion_mode();
+                }
+            }
+            TokenKind::EndTag(tag) => {
+                self.leave_initial_mode();
+
+                // A `</p>` without a `p` to close produces an empty one
+                if !self.xml_mode && tag.name == "p" && self.in_button_scope("p").is_none() {
+                    errors.push(HtmlParseError::UnexpectedEndTag);
+                    self.insert_implied("p");
+                }
+
+                // A formatting element that was closed already (e.g. by the end of a `p`) is
+                // no longer reopened, and its end tag is ignored
+                let active = self
+                    .active_formatting_element(&tag.name)
+                    .filter(|_| !self.xml_mode);
+
+                if let Some(active) = active {
+                    if let FormattingEntry::Element { handle, .. } =
+                        &self.active_formatting_elements[active]
+                    {
+                        if !self.is_open(handle) {
+                            errors.push(HtmlParseError::UnexpectedEndTag);
+                            self.active_formatting_elements.remove(active);
+
+                            return errors;
+                        }
+                    }
+                }
+
+                let position = self
+                    .open_elements
+                    .iter()
+                    .rposition(|element| element.name == tag.name);
+
+                match position {
+                    Some(position) => {
+                        if self.xml_mode {
+                            for _ in position + 1..self.open_elements.len() {
+                                errors.push(HtmlParseError::UnclosedElement);
+                            }
+                        }
+
+                        let handle = self.open_elements[position].handle.clone();
+                        self.active_formatting_elements.retain(|entry| {
+                            !matches!(entry, FormattingEntry::Element { handle: h, .. } if *h == handle)
+                        });
+
+                        self.pop_from(position);
+                    }
+                    None => errors.push(HtmlParseError::UnexpectedEndTag),
+                }
+            }
+            TokenKind::Comment(comment) => {
+                self.sink
+                    .append_comment(&self.insertion_location(), comment);
+            }
+            TokenKind::Text(text) => {
+                // Whitespace before the first element is dropped, everything else is kept as is
+                let text = if self.open_elements.is_empty() {
+                    text.trim_start_matches(['\t', '\n', '\x0C', '\r', ' '])
+                } else if ignore_next_newline {
+                    text.strip_prefix('\n').unwrap_or(&text)
+                } else {
+                    &text
+                };
+
+                if !text.is_empty() {
+                    self.leave_initial_mode();
+                    self.reset_insertion_mode();
+
+                    // Whitespace stays where it is, but anything else ends the head
+                    let mut text = text;
+                    if matches!(
+                        self.insertion_mode,
+                        InsertionMode::InHead | InsertionMode::AfterHead
+                    ) {
+                        let whitespace = text
+                            .find(|c| !matches!(c, '\t' | '\n' | '\x0C' | '\r' | ' '))
+                            .unwrap_or(text.len());
+
+                        if whitespace < text.len() {
+                            if whitespace > 0 {
+                                self.sink
+                                    .append_text(&self.insertion_location(), &text[..whitespace]);
+                            }
+
+                            self.leave_head(None);
+                            text = &text[whitespace..];
+                        }
+                    }
+
+                    let is_whitespace = text
+                        .chars()
+                        .all(|c| matches!(c, '\t' | '\n' | '\x0C' | '\r' | ' '));
+
+                    match self.foster_parent_target() {
+                        // Text directly inside a table is moved before it, unless it's only whitespace
+                        Some(table) if !is_whitespace => {
+                            errors.push(HtmlParseError::UnexpectedContentInTable);
+                            self.sink.insert_text_before(&table, text);
+                        }
+                        _ => {
+                            if !self.xml_mode
+                                && matches!(
+                                    self.insertion_mode,
+                                    InsertionMode::InBody
+                                        | InsertionMode::InCaption
+                                        | InsertionMode::InCell
+                                )
+                            {
+                                self.reconstruct_formatting_elements();
+                            }
+
+                            self.sink.append_text(&self.insertion_location(), text);
+                        }
+                    }
+                }
+            }
+            // TODO: processing instructions are not part of the tree yet
+            TokenKind::ProcessingInstruction { .. } => {}
+            TokenKind::Eof => {
+                self.leave_initial_mode();
+
+                if self.xml_mode {
+                    for _ in &self.open_elements {
+                        errors.push(HtmlParseError::UnclosedElement);
+                    }
+                }
+
+                self.open_elements.clear();
+                self.active_formatting_elements.clear();
+                self.template_insertion_modes.clear();
+            }
+        }
+
+        errors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Document, HtmlParseError, HtmlParser, NodeData, NodeId, ParserLimits};
+
+    fn parse(html: &str) -> Document {
+        HtmlParser::from_bytes(html.as_bytes()).parse()
+    }
+
+    fn find(document: &Document, id: NodeId, name: &str) -> Option<NodeId> {
+        document
+            .children(id)
+            .iter()
+            .find_map(|&child| match document.element(child) {
+                Some(element) if element.name == name => Some(child),
+                _ => find(document, child, name),
+            })
+    }
+
+    #[test]
+    fn preserves_whitespace_in_pre() {
+        let document = parse("<body><pre>\n\n  indented\n\tcode  </pre></body>");
+        let pre = find(&document, document.root(), "pre").unwrap();
+
+        // Only the first newline is dropped
+        assert_eq!(document.text_content(pre), "\n  indented\n\tcode  ");
+    }
+
+    #[test]
+    fn preserves_inter_tag_whitespace() {
+        let document = parse("  \n<p><b>one</b> <i>two</i>\n</p>");
+        let p = find(&document, document.root(), "p").unwrap();
+
+        assert_eq!(document.text_content(p), "one two\n");
+        assert_eq!(document.children(p).len(), 4);
+    }
+
+    #[test]
+    fn vertical_tab_and_nbsp_are_text() {
+        let document = parse("\x0B\u{A0}<p>a\x0Bb\u{A0}c</p>");
+        assert_eq!(
+            document.text_content(document.root()),
+            "\x0B\u{A0}a\x0Bb\u{A0}c"
+        );
+
+        // Control characters are parse errors, but they are kept
+        assert_eq!(
+            document.parse_errors(),
+            [
+                HtmlParseError::ControlCharacterInInputStream,
+                HtmlParseError::ControlCharacterInInputStream
+            ]
+        );
+
+        let from_chars = HtmlParser::from_chars("a\x0Bb".chars()).parse();
+        assert_eq!(from_chars.text_content(from_chars.root()), "a\x0Bb");
+        assert_eq!(from_chars.parse_errors().len(), 1);
+    }
+
+    #[test]
+    fn template_contents() {
+        let document = parse("<body><template><p>x</p><script>a</template></script></template>");
+        let body = find(&document, document.root(), "body").unwrap();
+        let template = find(&document, body, "template").unwrap();
+
+        assert!(document.children(template).is_empty());
+        assert!(find(&document, body, "p").is_none());
+
+        let contents = document.template_contents(template).unwrap();
+        assert!(matches!(
+            document.get(contents).data,
+            NodeData::DocumentFragment
+        ));
+        assert_eq!(document.get(contents).parent, None);
+
+        let p = find(&document, contents, "p").unwrap();
+        assert_eq!(document.text_content(p), "x");
+
+        let script = find(&document, contents, "script").unwrap();
+        assert_eq!(document.text_content(script), "a</template>");
+        assert!(document.parse_errors().is_empty());
+    }
+
+    #[test]
+    fn caps_nesting_depth() {
+        let html = "<div>".repeat(10_000) + "deep" + &"</div>".repeat(10_000);
+        let document = parse(&html);
+
+        let div = find(&document, document.root(), "div").unwrap();
+        assert_eq!(document.text_content(div), "deep");
+
+        let mut deepest = document.root();
+        while let Some(&child) = document.children(deepest).first() {
+            deepest = child;
+        }
+
+        assert_eq!(
+            document.depth(deepest),
+            ParserLimits::default().max_depth + 1
+        );
+    }
+
+    #[test]
+    fn noscript_without_scripting() {
+        let document = parse("<body><noscript><p>x</p></noscript></body>");
+        let noscript = find(&document, document.root(), "noscript").unwrap();
+
+        let p = find(&document, noscript, "p").unwrap();
+        assert_eq!(document.text_content(p), "x");
+    }
+
+    #[test]
+    fn noscript_with_scripting() {
+        let document =
+            HtmlParser::from_bytes("<body><noscript><p>x</p></noscript></body>".as_bytes())
+                .scripting_enabled(true)
+                .parse();
+        let noscript = find(&document, document.root(), "noscript").unwrap();
+
+        assert!(find(&document, noscript, "p").is_none());
+        assert_eq!(document.text_content(noscript), "<p>x</p>");
+    }
+
+    #[test]
+    fn attributes_in_source_order() {
+        let document = parse("<a href=\"x\" rel=\"y\" data-z>link</a>");
+        let a = find(&document, document.root(), "a").unwrap();
+
+        let attributes: Vec<_> = document.element(a).unwrap().attributes_iter().collect();
+        assert_eq!(attributes, [("href", "x"), ("rel", "y"), ("data-z", "")]);
+    }
+
+    #[test]
+    fn xml_mode_is_case_sensitive() {
+        let html = "<root><BR/><Item Name=\"x\"><script>a<b/></script></Item></root>";
+
+        let xml = HtmlParser::from_bytes(html.as_bytes())
+            .xml_mode(true)
+            .parse();
+        assert!(find(&xml, xml.root(), "br").is_none());
+        assert!(find(&xml, xml.root(), "BR").is_some());
+
+        let item = find(&xml, xml.root(), "Item").unwrap();
+        assert_eq!(xml.element(item).unwrap().get_attribute("Name"), Some("x"));
+
+        // Elements like `script` are parsed as markup, and `<b/>` has no end tag
+        let script = find(&xml, item, "script").unwrap();
+        assert!(find(&xml, script, "b").is_some());
+        assert!(xml.parse_errors().is_empty());
+
+        let html = parse(html);
+        assert!(find(&html, html.root(), "BR").is_none());
+        assert!(find(&html, html.root(), "br").is_some());
+    }
+
+    #[test]
+    fn xml_mode_unclosed_elements() {
+        let parse_xml = |xml: &str| {
+            HtmlParser::from_bytes(xml.as_bytes())
+                .xml_mode(true)
+                .parse()
+        };
+
+        // Closed by the end tag of its parent
+        let document = parse_xml("<root><a>x</root>");
+        assert_eq!(document.parse_errors(), [HtmlParseError::UnclosedElement]);
+
+        // Still open at the end of the document
+        let document = parse_xml("<root><br>");
+        assert_eq!(
+            document.parse_errors(),
+            [
+                HtmlParseError::UnclosedElement,
+                HtmlParseError::UnclosedElement
+            ]
+        );
+
+        // Neither is an error in HTML
+        assert!(parse("<div><p>x</div>").parse_errors().is_empty());
+        assert!(parse("<div><br>").parse_errors().is_empty());
+    }
+
+    #[test]
+    fn fosters_content_out_of_tables() {
+        let document = parse("<body><table>x<tr><td>y</table></body>");
+        let body = find(&document, document.root(), "body").unwrap();
+
+        // The text goes before the table, and the row gets an implied `tbody`
+        assert_eq!(
+            document.inner_html(body),
+            "x<table><tbody><tr><td>y</td></tr></tbody></table>"
+        );
+        assert_eq!(
+            document.parse_errors(),
+            [HtmlParseError::UnexpectedContentInTable]
+        );
+
+        // Elements are fostered too, but whitespace stays in the table
+        let document = parse("<body><table> <b>bold</b><tr><td>a<td>b</table></body>");
+        let body = find(&document, document.root(), "body").unwrap();
+
+        assert_eq!(
+            document.inner_html(body),
+            "<b>bold</b><table> <tbody><tr><td>a</td><td>b</td></tr></tbody></table>"
+        );
+    }
+
+    #[test]
+    fn nested_table_rows_and_cells() {
+        let document = parse("<body><table><tr><td>1<tr><td>2<tbody><td>3</table></body>");
+        let table = find(&document, document.root(), "table").unwrap();
+
+        assert_eq!(
+            document.inner_html(table),
+            "<tbody><tr><td>1</td></tr><tr><td>2</td></tr></tbody><tbody><tr><td>3</td></tr></tbody>"
+        );
+        assert!(document.parse_errors().is_empty());
+    }
+
+    #[test]
+    fn select_options() {
+        let document = parse("<body><select><option>a<option>b<div>c</div></select></body>");
+        let select = find(&document, document.root(), "select").unwrap();
+
+        // Options close each other, and other elements are ignored
+        assert_eq!(
+            document.inner_html(select),
+            "<option>a</option><option>bc</option>"
+        );
+        assert_eq!(
+            document.parse_errors(),
+            [
+                HtmlParseError::UnexpectedStartTag,
+                HtmlParseError::UnexpectedEndTag
+            ]
+        );
+
+        let document =
+            parse("<body><select><optgroup><option>a<optgroup><option>b<select>c</body>");
+        let body = find(&document, document.root(), "body").unwrap();
+
+        assert_eq!(
+            document.inner_html(body),
+            "<select><optgroup><option>a</option></optgroup><optgroup><option>b</option></optgroup></select>c"
+        );
+    }
+
+    #[test]
+    fn paragraphs_close_implicitly() {
+        let document = parse("<body><p>one<p>two<div>three</div><h1>four<h2>five</body>");
+        let body = find(&document, document.root(), "body").unwrap();
+
+        assert_eq!(
+            document.inner_html(body),
+            "<p>one</p><p>two</p><div>three</div><h1>four</h1><h2>five</h2>"
+        );
+        assert_eq!(
+            document.parse_errors(),
+            [HtmlParseError::UnexpectedStartTag]
+        );
+
+        // Not through a button, and a stray end tag gets an empty paragraph
+        let document = parse("<body><p><button><p>a</button></p></p></body>");
+        let body = find(&document, document.root(), "body").unwrap();
+
+        assert_eq!(
+            document.inner_html(body),
+            "<p><button><p>a</p></button></p><p></p>"
+        );
+    }
+
+    #[test]
+    fn body_content_ends_the_head() {
+        let document = parse("<head><title>t</title><p>x</p>");
+        assert_eq!(
+            document.to_html(),
+            "<head><title>t</title></head><body><p>x</p></body>"
+        );
+
+        // Whitespace stays in the head, and text starts the body too
+        let document = parse("<html><head><meta charset=utf-8> Hi!</html>");
+        assert_eq!(
+            document.to_html(),
+            "<html><head><meta charset=\"utf-8\"> </head><body>Hi!</body></html>"
+        );
+
+        // After an explicit end of the head
+        let document = parse("<html><head></head>\n<div>x</div></html>");
+        assert_eq!(
+            document.to_html(),
+            "<html><head></head>\n<body><div>x</div></body></html>"
+        );
+
+        // An explicit body isn't doubled
+        let document = parse("<head></head><body id=a><p>x</body>");
+        assert_eq!(
+            document.to_html(),
+            "<head></head><body id=\"a\"><p>x</p></body>"
+        );
+    }
+
+    #[test]
+    fn links_close_open_links() {
+        let document = parse("<body><a href=1>1<a href=2>2</a></body>");
+        let body = find(&document, document.root(), "body").unwrap();
+
+        assert_eq!(
+            document.inner_html(body),
+            "<a href=\"1\">1</a><a href=\"2\">2</a>"
+        );
+        assert_eq!(
+            document.parse_errors(),
+            [HtmlParseError::UnexpectedStartTag]
+        );
+
+        // A cell is a boundary
+        let document = parse("<body><a><table><td><a>x</a></td></table></a></body>");
+        let td = find(&document, document.root(), "td").unwrap();
+
+        assert_eq!(document.inner_html(td), "<a>x</a>");
+    }
+
+    #[test]
+    fn links_in_blocks_stay_in_them() {
+        let body = |html: &str| {
+            let document = parse(html);
+            let body = find(&document, document.root(), "body").unwrap();
+
+            document.inner_html(body)
+        };
+
+        // The new link (and the text after it) stay in the block they are written in
+        assert_eq!(
+            body("<body><a>1<div><a>2</a>3</div>4"),
+            "<a>1<div><a>2</a>3</div></a>4"
+        );
+        assert_eq!(
+            body("<body><a>1<ul><li><a>2</a>3</li></ul>4"),
+            "<a>1<ul><li><a>2</a>3</li></ul></a>4"
+        );
+
+        // Formatting elements closed along with the old link are reopened
+        assert_eq!(
+            body("<body><a>1<b>x<a>2</a>y</b>"),
+            "<a>1<b>x</b></a><b><a>2</a>y</b>"
+        );
+    }
+
+    #[test]
+    fn reopens_formatting_elements() {
+        let document = parse("<body><p><b><i>1</p>2</i>3</b>4");
+        let body = find(&document, document.root(), "body").unwrap();
+
+        assert_eq!(
+            document.inner_html(body),
+            "<p><b><i>1</i></b></p><b><i>2</i>3</b>4"
+        );
+
+        // A formatting element whose end tag was seen is not reopened, and a cell is a boundary
+        let document = parse("<body><p><b>1</b></p>2<table><td><p>3</td></table>");
+        let body = find(&document, document.root(), "body").unwrap();
+
+        assert_eq!(
+            document.inner_html(body),
+            "<p><b>1</b></p>2<table><tbody><tr><td><p>3</p></td></tr></tbody></table>"
+        );
+
+        // The end tag of a formatting element that is only active is ignored
+        let document = parse("<body><p><b>1</p></b>2");
+        let body = find(&document, document.root(), "body").unwrap();
+
+        assert_eq!(document.inner_html(body), "<p><b>1</b></p>2");
+    }
+
+    #[test]
+    fn xml_mode_has_no_table_rules() {
+        let document = HtmlParser::from_bytes(b"<table>x<td>y</td></table>".as_slice())
+            .xml_mode(true)
+            .parse();
+        let table = find(&document, document.root(), "table").unwrap();
+
+        assert_eq!(document.inner_html(table), "x<td>y</td>");
+    }
+}
@@ -0,0 +1,62 @@
+use crate::{HtmlParseError, QuirksMode};
+
+/// Receives the nodes created during tree construction, so callers can build their own
+/// tree representation instead of a [`Document`](crate::Document).
+///
+/// Nodes are created detached and then appended to their parent through a `Handle`, which
+/// is whatever the sink uses to refer to its nodes (an index, an `Rc`, ...).
+pub trait TreeSink {
+    /// Handles to the same node must compare equal
+    type Handle: Clone + PartialEq;
+    /// What the sink produces once the whole document was processed
+    type Output;
+
+    /// The document node, which is the root of the tree
+    fn document(&mut self) -> Self::Handle;
+
+    /// Creates a new element that is not attached to the tree yet
+    fn create_element(&mut self, name: String, attributes: Vec<(String, String)>) -> Self::Handle;
+
+    /// Creates the content fragment of a `template` element, which its contents get appended
+    /// to instead of the element itself (https://html.spec.whatwg.org/#template-contents).
+    ///
+    /// By default the contents are appended to the element like any other children.
+    fn create_template_contents(&mut self, template: &Self::Handle) -> Self::Handle {
+        template.clone()
+    }
+
+    /// Appends `child` as the last child of `parent`
+    fn append(&mut self, parent: &Self::Handle, child: Self::Handle);
+
+    /// Inserts `child` right before `sibling`, as a child of the same parent. This is how
+    /// content that is misplaced inside a table is moved out of it
+    /// (https://html.spec.whatwg.org/#foster-parent).
+    fn insert_before(&mut self, sibling: &Self::Handle, child: Self::Handle);
+
+    /// Inserts text right before `sibling`, like [`TreeSink::insert_before`]. Consecutive calls
+    /// for the same sibling may be made for what is a single text node in the document.
+    fn insert_text_before(&mut self, sibling: &Self::Handle, text: &str);
+
+    /// Appends text to `parent`. Consecutive calls for the same parent may be made for what is
+    /// a single text node in the document.
+    fn append_text(&mut self, parent: &Self::Handle, text: &str);
+
+    fn append_comment(&mut self, parent: &Self::Handle, comment: String);
+
+    /// Appends a doctype to the document node
+    fn append_doctype(&mut self, name: String, public_id: String, system_id: String);
+
+    /// Sets the quirks mode of the document, once its doctype (or the lack of one) decided it.
+    /// Ignored by default.
+    fn set_quirks_mode(&mut self, mode: QuirksMode) {
+        let _ = mode;
+    }
+
+    /// Called for every parse error that was recovered from. Ignored by default.
+    fn parse_error(&mut self, error: HtmlParseError) {
+        let _ = error;
+    }
+
+    /// Consumes the sink once the end of the document is reached
+    fn finish(self) -> Self::Output;
+}
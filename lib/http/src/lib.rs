@@ -1,13 +1,139 @@
-use std::{convert::Infallible, str::FromStr, time::Duration};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    fmt::Display,
+    io::{self, Read},
+    str::FromStr,
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use lazy_static::lazy_static;
-use reqwest::blocking::{Client, ClientBuilder};
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue, ACCEPT_CHARSET, ACCEPT_LANGUAGE, CONTENT_TYPE},
+    IntoUrl, Method,
+};
 
 pub use reqwest::blocking::*;
 pub use reqwest::StatusCode;
 
+/// The `Accept-Language` header sent when none is configured
+pub const DEFAULT_ACCEPT_LANGUAGE: &str = "en-US,en;q=0.9";
+
+/// The largest response body that is read by default, see [`ClientConfig::max_body_bytes`]
+pub const DEFAULT_MAX_BODY_BYTES: u64 = 64 << 20;
+
 lazy_static! {
-    pub static ref HTTP_CLIENT: Client = ClientBuilder::new()
+    /// The settings of [`HTTP_CLIENT`], including the limit on the bodies read from its responses
+    pub static ref CLIENT_CONFIG: ClientConfig = ClientConfig::default();
+    pub static ref HTTP_CLIENT: Client = client_builder(&CLIENT_CONFIG)
+        .build()
+        .expect("Failed to create HTTP client");
+}
+
+/// Content negotiation headers sent with every request made by a client, and limits on the
+/// responses it gets
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    pub accept_language: Option<String>,
+    pub accept_charset: Option<String>,
+    /// The most bytes read from a response body through [`ClientConfig::limit_body`], so a
+    /// server can't make the browser read an endless body into memory. `None` means no limit.
+    pub max_body_bytes: Option<u64>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            accept_language: Some(DEFAULT_ACCEPT_LANGUAGE.to_string()),
+            accept_charset: None,
+            max_body_bytes: Some(DEFAULT_MAX_BODY_BYTES),
+        }
+    }
+}
+
+impl ClientConfig {
+    /// Wraps a response (or any other body) so that reading it fails with a [`BodyTooLarge`]
+    /// error once it goes past [`ClientConfig::max_body_bytes`]
+    pub fn limit_body<R: Read>(&self, body: R) -> LimitedBody<R> {
+        LimitedBody {
+            inner: body,
+            limit: self.max_body_bytes.unwrap_or(u64::MAX),
+            read: 0,
+        }
+    }
+}
+
+/// A response body that can only be read up to a limit (see [`ClientConfig::limit_body`])
+#[derive(Debug)]
+pub struct LimitedBody<R> {
+    inner: R,
+    limit: u64,
+    read: u64,
+}
+
+impl<R> LimitedBody<R> {
+    /// Gets back the body, e.g. to drain the rest of it after the limit was hit
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for LimitedBody<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.limit - self.read;
+
+        // A body of exactly the limit is fine, so only a byte past it is an error
+        if remaining == 0 {
+            return match self.inner.read(&mut [0])? {
+                0 => Ok(0),
+                _ => Err(io::Error::other(BodyTooLarge { limit: self.limit })),
+            };
+        }
+
+        let len =
+            usize::try_from(remaining).map_or(buf.len(), |remaining| remaining.min(buf.len()));
+        let read = self.inner.read(&mut buf[..len])?;
+        self.read += read as u64;
+
+        Ok(read)
+    }
+}
+
+/// The error a [`LimitedBody`] fails with when the body is longer than its limit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BodyTooLarge {
+    pub limit: u64,
+}
+
+impl Display for BodyTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "response body is larger than {} bytes", self.limit)
+    }
+}
+
+impl std::error::Error for BodyTooLarge {}
+
+/// Creates a client builder with the browser's default settings and the given
+/// content negotiation headers
+pub fn client_builder(config: &ClientConfig) -> ClientBuilder {
+    let mut headers = HeaderMap::new();
+
+    if let Some(accept_language) = &config.accept_language {
+        headers.insert(
+            ACCEPT_LANGUAGE,
+            HeaderValue::from_str(accept_language).expect("Invalid Accept-Language header"),
+        );
+    }
+
+    if let Some(accept_charset) = &config.accept_charset {
+        headers.insert(
+            ACCEPT_CHARSET,
+            HeaderValue::from_str(accept_charset).expect("Invalid Accept-Charset header"),
+        );
+    }
+
+    ClientBuilder::new()
         .connect_timeout(Duration::from_secs(10))
         .connection_verbose(true)
         .user_agent(concat!(
@@ -15,9 +141,211 @@ lazy_static! {
             "/",
             env!("CARGO_PKG_VERSION"),
         ))
+        .default_headers(headers)
         .timeout(Duration::from_secs(60))
-        .build()
-        .expect("Failed to create HTTP client");
+}
+
+/// Per-request overrides of the client's content negotiation headers
+pub trait RequestNegotiation {
+    fn accept_language(self, accept_language: &str) -> Self;
+    fn accept_charset(self, accept_charset: &str) -> Self;
+}
+
+impl RequestNegotiation for RequestBuilder {
+    fn accept_language(self, accept_language: &str) -> Self {
+        self.header(ACCEPT_LANGUAGE, accept_language)
+    }
+
+    fn accept_charset(self, accept_charset: &str) -> Self {
+        self.header(ACCEPT_CHARSET, accept_charset)
+    }
+}
+
+/// Fetches resources with GET requests, so the network can be swapped out (e.g. for a
+/// [`MockFetcher`] in tests)
+pub trait HttpFetcher {
+    fn get(&self, url: &str) -> reqwest::Result<Response>;
+}
+
+impl HttpFetcher for Client {
+    fn get(&self, url: &str) -> reqwest::Result<Response> {
+        Client::get(self, url).send()
+    }
+}
+
+/// An [`HttpFetcher`] that serves canned responses from memory, without touching the network.
+/// URLs without a response get an empty `404 Not Found`.
+///
+/// The responses don't know which URL they were fetched from, so [`Response::url`] is a
+/// placeholder for them.
+#[derive(Debug, Clone, Default)]
+pub struct MockFetcher {
+    responses: HashMap<String, (StatusCode, HeaderMap, Vec<u8>)>,
+}
+
+impl MockFetcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serves a response with the given status, headers and body for `url`
+    pub fn with_response(
+        mut self,
+        url: &str,
+        status: StatusCode,
+        headers: &[(&str, &str)],
+        body: impl Into<Vec<u8>>,
+    ) -> Self {
+        let headers = headers
+            .iter()
+            .map(|(name, value)| {
+                (
+                    HeaderName::from_bytes(name.as_bytes()).expect("Invalid header name"),
+                    HeaderValue::from_str(value).expect("Invalid header value"),
+                )
+            })
+            .collect();
+
+        self.responses
+            .insert(url.to_owned(), (status, headers, body.into()));
+        self
+    }
+}
+
+impl HttpFetcher for MockFetcher {
+    fn get(&self, url: &str) -> reqwest::Result<Response> {
+        let (status, headers, body) = self.responses.get(url).cloned().unwrap_or((
+            StatusCode::NOT_FOUND,
+            HeaderMap::new(),
+            Vec::new(),
+        ));
+
+        let mut response = http_crate::Response::new(body);
+        *response.status_mut() = status;
+        *response.headers_mut() = headers;
+
+        Ok(Response::from(response))
+    }
+}
+
+/// When and how often a failed request is retried
+#[derive(Debug, Clone)]
+pub struct RetryConfig<'a> {
+    /// The number of attempts made after the first one
+    pub max_retries: u32,
+    /// The delay before the first retry, which doubles with every retry after it
+    pub base_delay: Duration,
+    /// The response statuses that are retried (connection errors always are)
+    pub retry_on: &'a [StatusCode],
+}
+
+impl Default for RetryConfig<'static> {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            base_delay: Duration::from_millis(250),
+            retry_on: &[
+                StatusCode::BAD_GATEWAY,
+                StatusCode::SERVICE_UNAVAILABLE,
+                StatusCode::GATEWAY_TIMEOUT,
+            ],
+        }
+    }
+}
+
+/// Sends a GET request, retrying it with exponential backoff on connection errors and on the
+/// statuses in `config.retry_on`. The result of the last attempt is returned.
+pub fn get_with_retry<U: IntoUrl>(url: U, config: &RetryConfig) -> reqwest::Result<Response> {
+    send_with_retry(&HTTP_CLIENT, HTTP_CLIENT.get(url).build()?, config)
+}
+
+/// Sends a request like [`get_with_retry`], but only retries it if its method is idempotent
+/// (https://httpwg.org/specs/rfc9110.html#idempotent.methods) and its body can be sent again.
+/// Other requests are sent once.
+pub fn send_with_retry(
+    client: &Client,
+    request: Request,
+    config: &RetryConfig,
+) -> reqwest::Result<Response> {
+    let idempotent = matches!(
+        *request.method(),
+        Method::GET | Method::HEAD | Method::PUT | Method::DELETE | Method::OPTIONS | Method::TRACE
+    );
+
+    for attempt in 0..config.max_retries {
+        let Some(retry) = request.try_clone().filter(|_| idempotent) else {
+            break;
+        };
+
+        match client.execute(retry) {
+            Ok(response) if !config.retry_on.contains(&response.status()) => return Ok(response),
+            Err(error) if !(error.is_connect() || error.is_timeout() || error.is_request()) => {
+                return Err(error)
+            }
+            _ => {}
+        }
+
+        thread::sleep(config.base_delay.saturating_mul(1 << attempt.min(16)));
+    }
+
+    client.execute(request)
+}
+
+/// Submits form fields as `application/x-www-form-urlencoded`, like a `<form method="post">`
+/// (https://html.spec.whatwg.org/#application/x-www-form-urlencoded-encoding-algorithm)
+pub fn post_form<U: IntoUrl>(url: U, fields: &[(&str, &str)]) -> reqwest::Result<Response> {
+    HTTP_CLIENT.post(url).form(fields).send()
+}
+
+/// Submits form fields as `multipart/form-data`, like a `<form method="post">` with
+/// `enctype="multipart/form-data"` (https://html.spec.whatwg.org/#multipart-form-data)
+pub fn post_multipart<U: IntoUrl>(url: U, fields: &[(&str, &str)]) -> reqwest::Result<Response> {
+    let boundary = multipart_boundary(fields);
+
+    HTTP_CLIENT
+        .post(url)
+        .header(
+            CONTENT_TYPE,
+            format!("multipart/form-data; boundary={boundary}"),
+        )
+        .body(encode_multipart(fields, &boundary))
+        .send()
+}
+
+/// Picks a boundary that doesn't occur in any of the fields
+fn multipart_boundary(fields: &[(&str, &str)]) -> String {
+    let seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_nanos())
+        .unwrap_or_default();
+
+    (0..)
+        .map(|n| format!("BowserFormBoundary{:x}", seed.wrapping_add(n)))
+        .find(|boundary| {
+            !fields
+                .iter()
+                .any(|(name, value)| name.contains(boundary) || value.contains(boundary))
+        })
+        .unwrap()
+}
+
+fn encode_multipart(fields: &[(&str, &str)], boundary: &str) -> String {
+    let mut body = String::new();
+
+    for (name, value) in fields {
+        // Field names are escaped the same way browsers do
+        let name = name
+            .replace('"', "%22")
+            .replace('\r', "%0D")
+            .replace('\n', "%0A");
+
+        body.push_str(&format!(
+            "--{boundary}\r\nContent-Disposition: form-data; name=\"{name}\"\r\n\r\n{value}\r\n"
+        ));
+    }
+
+    body.push_str(&format!("--{boundary}--\r\n"));
+    body
 }
 
 #[derive(Debug)]
@@ -52,6 +380,18 @@ impl FromStr for ContentType {
     }
 }
 
+impl Display for ContentType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.media_type)?;
+
+        if let Some(charset) = &self.charset {
+            write!(f, "; {charset}")?;
+        }
+
+        Ok(())
+    }
+}
+
 impl ContentType {
     pub fn media_type(&self) -> &MediaType {
         &self.media_type
@@ -62,7 +402,7 @@ impl ContentType {
     }
 }
 
-#[derive(Debug, Default, PartialEq, Eq, Hash)]
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash)]
 pub enum MediaType {
     TextHTML, // text/html
     #[default]
@@ -89,6 +429,18 @@ impl FromStr for MediaType {
     }
 }
 
+impl Display for MediaType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            MediaType::TextHTML => "text/html",
+            MediaType::ApplicationOctetStream => "application/octet-stream",
+            MediaType::Other(other) => other,
+        };
+
+        write!(f, "{name}")
+    }
+}
+
 #[derive(Debug)]
 pub enum Charset {
     UTF8, // charset=utf-8
@@ -121,8 +473,69 @@ impl FromStr for Charset {
     }
 }
 
+/// The most bytes at the start of a resource that MIME sniffing looks at
+/// (https://mimesniff.spec.whatwg.org/#reading-the-resource-header)
+pub const RESOURCE_HEADER_LEN: usize = 1445;
+
+/// Checks whether a resource starts with one of the HTML signatures from the MIME sniffing
+/// standard (https://mimesniff.spec.whatwg.org/#rules-for-identifying-an-unknown-mime-type),
+/// for servers that mislabel their HTML (e.g. as `application/octet-stream`)
+pub fn sniff_is_html(bytes: &[u8]) -> bool {
+    const SIGNATURES: [&[u8]; 17] = [
+        b"<!DOCTYPE HTML",
+        b"<HTML",
+        b"<HEAD",
+        b"<SCRIPT",
+        b"<IFRAME",
+        b"<H1",
+        b"<DIV",
+        b"<FONT",
+        b"<TABLE",
+        b"<A",
+        b"<STYLE",
+        b"<TITLE",
+        b"<B",
+        b"<BODY",
+        b"<BR",
+        b"<P",
+        b"<!--",
+    ];
+
+    let bytes = bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(bytes);
+
+    // Leading whitespace bytes are ignored
+    let start = bytes
+        .iter()
+        .position(|b| !matches!(b, 0x09 | 0x0A | 0x0C | 0x0D | 0x20))
+        .unwrap_or(bytes.len());
+    let bytes = &bytes[start..];
+
+    SIGNATURES.iter().any(|signature| {
+        // Each signature must be followed by a tag-terminating byte (space or `>`)
+        bytes.len() > signature.len()
+            && bytes[..signature.len()].eq_ignore_ascii_case(signature)
+            && matches!(bytes[signature.len()], 0x20 | 0x3E)
+    })
+}
+
+impl Display for Charset {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let value = match self {
+            Charset::UTF8 => "utf-8",
+            Charset::Other(other) => other,
+        };
+
+        write!(f, "charset={value}")
+    }
+}
+
 pub trait ResponseContentType {
     fn content_type(&self) -> Option<ContentType>;
+
+    /// Whether the response opted out of MIME sniffing with `X-Content-Type-Options: nosniff`,
+    /// in which case its declared type must be honored
+    /// (https://fetch.spec.whatwg.org/#determine-nosniff)
+    fn is_nosniff(&self) -> bool;
 }
 
 impl ResponseContentType for Response {
@@ -133,4 +546,461 @@ impl ResponseContentType for Response {
 
         Some(header.parse().unwrap())
     }
+
+    fn is_nosniff(&self) -> bool {
+        // Only the first value of the (comma separated) header counts
+        self.headers()
+            .get("x-content-type-options")
+            .and_then(|header| header.to_str().ok())
+            .and_then(|header| header.split(',').next())
+            .is_some_and(|value| {
+                value
+                    .trim_matches(|c| c == ' ' || c == '\t')
+                    .eq_ignore_ascii_case("nosniff")
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::{TcpListener, TcpStream},
+        thread,
+    };
+
+    /// A request received by [`serve`]
+    struct Request {
+        /// Counting from 1 for the first request the server received
+        number: usize,
+        /// With lowercase names
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
+    }
+
+    /// Serves requests on a local port, one connection each, until `respond` (which writes the
+    /// response) returns a result. The handle gives back that result once the server is done.
+    fn serve<T: Send + 'static>(
+        mut respond: impl FnMut(Request, &mut TcpStream) -> Option<T> + Send + 'static,
+    ) -> (String, thread::JoinHandle<T>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        let handle = thread::spawn(move || {
+            for number in 1.. {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(&stream);
+
+                let headers: Vec<_> = reader
+                    .by_ref()
+                    .lines()
+                    .map(|line| line.unwrap())
+                    .skip(1)
+                    .take_while(|line| !line.is_empty())
+                    .map(|line| {
+                        let (name, value) = line.split_once(": ").unwrap_or_default();
+                        (name.to_ascii_lowercase(), value.to_owned())
+                    })
+                    .collect();
+
+                let content_length = headers
+                    .iter()
+                    .find(|(name, _)| name == "content-length")
+                    .map_or(0, |(_, value)| value.parse().unwrap());
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+
+                let request = Request {
+                    number,
+                    headers,
+                    body,
+                };
+
+                if let Some(result) = respond(request, &mut stream) {
+                    return result;
+                }
+            }
+
+            unreachable!()
+        });
+
+        (url, handle)
+    }
+
+    /// Writes a response with the given status and body, closing the connection after it
+    fn write_response(stream: &mut TcpStream, status: &str, body: impl AsRef<[u8]>) {
+        let body = body.as_ref();
+
+        write!(
+            stream,
+            "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        )
+        .unwrap();
+        stream.write_all(body).unwrap();
+    }
+
+    /// Serves a single request, responding with the request's headers as the body
+    fn echo_headers_server() -> (String, thread::JoinHandle<()>) {
+        serve(|request, stream| {
+            let headers: Vec<_> = request
+                .headers
+                .iter()
+                .map(|(name, value)| format!("{name}: {}", value.to_ascii_lowercase()))
+                .collect();
+
+            write_response(stream, "200 OK", headers.join("\n"));
+            Some(())
+        })
+    }
+
+    /// Serves requests, failing the first `failures` of them with a 503 before responding with
+    /// a 200. Returns the number of requests that were served.
+    fn flaky_server(failures: usize) -> (String, thread::JoinHandle<usize>) {
+        serve(move |request, stream| {
+            if request.number > failures {
+                write_response(stream, "200 OK", "hi");
+                Some(request.number)
+            } else {
+                write_response(stream, "503 Service Unavailable", "hi");
+                None
+            }
+        })
+    }
+
+    /// Serves a single request, responding with the request's content type and body
+    fn echo_body_server() -> (String, thread::JoinHandle<()>) {
+        serve(|request, stream| {
+            let content_type = request
+                .headers
+                .iter()
+                .find(|(name, _)| name == "content-type")
+                .map_or("", |(_, value)| value.as_str());
+
+            write_response(
+                stream,
+                "200 OK",
+                [content_type.as_bytes(), b"\n", &request.body].concat(),
+            );
+            Some(())
+        })
+    }
+
+    /// The page served by `mario`
+    const MARIO: &str = r#"<!DOCTYPE html>
+<html>
+    <head>
+        <meta http-equiv="content-type" content="text/html; charset=utf8">
+        <title>Mario!</title>
+    </head>
+    <body id="root">
+        🦀
+        <h1 class="big_title" aria-label='heading'>Mario!</h1>
+        <p>It's a me, Mario!</p>
+    </body>
+</html>
+"#;
+
+    /// Serves a single request, responding with the given body in small chunks over time
+    fn chunked_server(body: &'static str) -> (String, thread::JoinHandle<()>) {
+        serve(move |_, stream| {
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nTransfer-Encoding: chunked\r\n\r\n"
+            )
+            .unwrap();
+
+            for chunk in body.as_bytes().chunks(16) {
+                write!(stream, "{:x}\r\n", chunk.len()).unwrap();
+                stream.write_all(chunk).unwrap();
+                write!(stream, "\r\n").unwrap();
+                stream.flush().unwrap();
+
+                thread::sleep(Duration::from_millis(5));
+            }
+
+            write!(stream, "0\r\n\r\n").unwrap();
+            Some(())
+        })
+    }
+
+    #[test]
+    fn content_type_round_trip() {
+        let content_type: ContentType = "Text/HTML; Charset=UTF-8".parse().unwrap();
+        assert_eq!(content_type.to_string(), "text/html; charset=utf-8");
+
+        let content_type: ContentType = "application/octet-stream".parse().unwrap();
+        assert_eq!(content_type.to_string(), "application/octet-stream");
+
+        let content_type: ContentType = "Image/PNG; charset=\"Latin1\"".parse().unwrap();
+        assert_eq!(content_type.to_string(), "Image/PNG; charset=latin1");
+    }
+
+    #[test]
+    fn sniffs_html() {
+        assert!(sniff_is_html(b"<!doctype html><html></html>"));
+        assert!(sniff_is_html(b"\xEF\xBB\xBF\n  <HTML lang=en>"));
+        assert!(sniff_is_html(b"<p>Hi"));
+        assert!(!sniff_is_html(b"<pre>Hi"));
+        assert!(!sniff_is_html(b"<html"));
+        assert!(!sniff_is_html(b"\x89PNG\r\n\x1A\n\0\0\0\rIHDR"));
+        assert!(!sniff_is_html(b""));
+    }
+
+    #[test]
+    fn sends_default_accept_language() {
+        let (url, server) = echo_headers_server();
+
+        let body = HTTP_CLIENT.get(url).send().unwrap().text().unwrap();
+        server.join().unwrap();
+
+        assert!(body.contains("accept-language: en-us,en;q=0.9"));
+        assert!(!body.contains("accept-charset"));
+    }
+
+    #[test]
+    fn overrides_negotiation_headers() {
+        let (url, server) = echo_headers_server();
+
+        let client = client_builder(&ClientConfig {
+            accept_language: Some("fr".to_string()),
+            accept_charset: Some("utf-8".to_string()),
+            ..ClientConfig::default()
+        })
+        .build()
+        .unwrap();
+
+        let body = client
+            .get(url)
+            .accept_language("de-DE")
+            .send()
+            .unwrap()
+            .text()
+            .unwrap();
+        server.join().unwrap();
+
+        assert!(body.contains("accept-language: de-de"));
+        assert!(!body.contains("accept-language: fr"));
+        assert!(body.contains("accept-charset: utf-8"));
+    }
+
+    #[test]
+    fn retries_get() {
+        let (url, server) = flaky_server(2);
+
+        let config = RetryConfig {
+            base_delay: Duration::from_millis(1),
+            ..Default::default()
+        };
+
+        let response = get_with_retry(url, &config).unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(server.join().unwrap(), 3);
+    }
+
+    #[test]
+    fn gives_up_after_max_retries() {
+        let (url, server) = flaky_server(2);
+
+        let config = RetryConfig {
+            max_retries: 1,
+            base_delay: Duration::from_millis(1),
+            ..Default::default()
+        };
+
+        let response = get_with_retry(&url, &config).unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        // Requests that are not idempotent are never retried
+        let post = HTTP_CLIENT.post(&url).body("x").build().unwrap();
+        let response = send_with_retry(&HTTP_CLIENT, post, &config).unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(server.join().unwrap(), 3);
+    }
+
+    #[test]
+    fn posts_form() {
+        let (url, server) = echo_body_server();
+
+        let body = post_form(url, &[("name", "Mario Mario"), ("job", "plumber & hero")])
+            .unwrap()
+            .text()
+            .unwrap();
+        server.join().unwrap();
+
+        assert_eq!(
+            body,
+            "application/x-www-form-urlencoded\nname=Mario+Mario&job=plumber+%26+hero"
+        );
+    }
+
+    #[test]
+    fn posts_multipart() {
+        let (url, server) = echo_body_server();
+
+        let body = post_multipart(url, &[("name", "Mario"), ("quote", "It's a me!\r\n")])
+            .unwrap()
+            .text()
+            .unwrap();
+        server.join().unwrap();
+
+        let (content_type, body) = body.split_once('\n').unwrap();
+        let boundary = content_type
+            .strip_prefix("multipart/form-data; boundary=")
+            .unwrap();
+
+        assert_eq!(
+            body,
+            format!(
+                "--{boundary}\r\n\
+                 Content-Disposition: form-data; name=\"name\"\r\n\r\n\
+                 Mario\r\n\
+                 --{boundary}\r\n\
+                 Content-Disposition: form-data; name=\"quote\"\r\n\r\n\
+                 It's a me!\r\n\r\n\
+                 --{boundary}--\r\n"
+            )
+        );
+    }
+
+    /// Describes every node of the document, indented by depth
+    fn dump(document: &html::Document, id: html::NodeId, depth: usize) -> String {
+        let mut tree = format!("{}{:?}\n", "  ".repeat(depth), document.get(id).data);
+
+        for &child in document.children(id) {
+            tree += &dump(document, child, depth + 1);
+        }
+
+        tree
+    }
+
+    #[test]
+    fn parses_response_with_content_length() {
+        let (url, server) = flaky_server(0);
+
+        let response = HTTP_CLIENT.get(url).send().unwrap();
+        let content_length = response.content_length();
+        assert_eq!(content_length, Some(2));
+
+        let document = html::HtmlParser::new(response)
+            .content_length(content_length)
+            .parse();
+        server.join().unwrap();
+
+        assert_eq!(document.text_content(document.root()), "hi");
+    }
+
+    #[test]
+    fn mock_fetcher() {
+        let fetcher = MockFetcher::new().with_response(
+            "http://mario.example/",
+            StatusCode::OK,
+            &[("Content-Type", "text/html; charset=utf-8")],
+            "<p>It's a me!</p>",
+        );
+
+        let response = HttpFetcher::get(&fetcher, "http://mario.example/").unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.content_type().unwrap().to_string(),
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(response.text().unwrap(), "<p>It's a me!</p>");
+
+        let response = HttpFetcher::get(&fetcher, "http://mario.example/luigi").unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.bytes().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn nosniff() {
+        let fetcher = MockFetcher::new()
+            .with_response("http://mario.example/", StatusCode::OK, &[], "")
+            .with_response(
+                "http://mario.example/nosniff",
+                StatusCode::OK,
+                &[("X-Content-Type-Options", "NoSniff, other")],
+                "",
+            )
+            .with_response(
+                "http://mario.example/sniff",
+                StatusCode::OK,
+                &[("X-Content-Type-Options", "other, nosniff")],
+                "",
+            );
+
+        let is_nosniff = |url| HttpFetcher::get(&fetcher, url).unwrap().is_nosniff();
+
+        assert!(!is_nosniff("http://mario.example/"));
+        assert!(is_nosniff("http://mario.example/nosniff"));
+        assert!(!is_nosniff("http://mario.example/sniff"));
+    }
+
+    /// Serves a single request, responding with `MARIO` padded to over a megabyte
+    fn large_server() -> (String, thread::JoinHandle<()>) {
+        serve(|_, stream| {
+            let body = format!("{MARIO}<!--{}-->", " ".repeat(1 << 20));
+
+            write_response(stream, "200 OK", body);
+            Some(())
+        })
+    }
+
+    #[test]
+    fn limits_body_size() {
+        let (url, server) = large_server();
+        let config = ClientConfig {
+            max_body_bytes: Some(1 << 16),
+            ..ClientConfig::default()
+        };
+
+        let mut body = config.limit_body(HTTP_CLIENT.get(url).send().unwrap());
+        let mut read = Vec::new();
+        let error = body.read_to_end(&mut read).unwrap_err();
+
+        assert_eq!(read.len(), 1 << 16);
+        assert!(read.starts_with(MARIO.as_bytes()));
+        assert_eq!(
+            error.get_ref().unwrap().downcast_ref(),
+            Some(&BodyTooLarge { limit: 1 << 16 })
+        );
+
+        // Let the server finish writing
+        io::copy(&mut body.into_inner(), &mut io::sink()).unwrap();
+        server.join().unwrap();
+
+        // A body of exactly the limit can be read
+        let config = ClientConfig {
+            max_body_bytes: Some(MARIO.len() as u64),
+            ..ClientConfig::default()
+        };
+        let mut read = Vec::new();
+        config
+            .limit_body(MARIO.as_bytes())
+            .read_to_end(&mut read)
+            .unwrap();
+        assert_eq!(read, MARIO.as_bytes());
+    }
+
+    #[test]
+    fn parses_chunked_response() {
+        let (url, server) = chunked_server(MARIO);
+
+        let response = HTTP_CLIENT.get(url).send().unwrap();
+        assert_eq!(response.headers()["transfer-encoding"], "chunked");
+
+        // The parser reads straight from the response, as the chunks arrive
+        let chunked = html::HtmlParser::new(response).parse();
+        server.join().unwrap();
+
+        let buffered = html::HtmlParser::new(MARIO.as_bytes()).parse();
+
+        assert_eq!(chunked.encoding(), html::CharacterEncoding::Utf8);
+        assert_eq!(chunked.title().as_deref(), Some("Mario!"));
+        assert_eq!(
+            dump(&chunked, chunked.root(), 0),
+            dump(&buffered, buffered.root(), 0)
+        );
+    }
 }